@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+// app_config bench: how long the steps of a run take, measured over a few
+// iterations, to pick a poll interval that leaves room for them and find
+// the slow ones, e.g. a template looking up many SSM keys.
+
+/// Timings:
+/// The durations measured of each step, in the order steps were first seen
+#[derive(Debug, Default)]
+pub struct Timings {
+    steps: Vec<(String, Vec<Duration>)>,
+}
+
+impl Timings {
+    /// Add <duration> to those of <step>
+    pub fn record(&mut self, step: &str, duration: Duration) {
+        match self.steps.iter_mut().find(|(name, _)| name == step) {
+            Some((_, durations)) => durations.push(duration),
+            None => self.steps.push((step.to_string(), vec![duration])),
+        }
+    }
+
+    /// A table of the steps, with the number of times each was measured,
+    /// the shortest, mean, 95th percentile and longest time
+    pub fn summary(&self) -> String {
+        let width = self.steps.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(4);
+        let mut table = format!(
+            "{:<w$}  {:>5}  {:>10}  {:>10}  {:>10}  {:>10}\n",
+            "step",
+            "n",
+            "min",
+            "mean",
+            "p95",
+            "max",
+            w = width
+        );
+        for (name, durations) in &self.steps {
+            let mut sorted = durations.clone();
+            sorted.sort();
+            let mean = sorted.iter().sum::<Duration>() / sorted.len() as u32;
+            // The nearest rank
+            let p95 = sorted[(sorted.len() * 95).div_ceil(100) - 1];
+            table.push_str(&format!(
+                "{:<w$}  {:>5}  {:>10}  {:>10}  {:>10}  {:>10}\n",
+                name,
+                sorted.len(),
+                millis(sorted[0]),
+                millis(mean),
+                millis(p95),
+                millis(sorted[sorted.len() - 1]),
+                w = width
+            ));
+        }
+        table
+    }
+}
+
+/// <duration> in milliseconds, e.g. "12.345ms"
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut timings = Timings::default();
+        for ms in (1..=20).rev() {
+            timings.record("poll", Duration::from_millis(ms));
+        }
+        timings.record("render template", Duration::from_micros(1500));
+
+        let exp = "\
+step                 n         min        mean         p95         max
+poll                20     1.000ms    10.500ms    19.000ms    20.000ms
+render template      1     1.500ms     1.500ms     1.500ms     1.500ms
+";
+        assert_eq!(timings.summary(), exp);
+    }
+}
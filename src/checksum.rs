@@ -0,0 +1,89 @@
+use crate::hooks::sha256;
+use eyre::{Result, WrapErr};
+use serde_derive::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// What to do when a rendered file no longer matches its checksum sidecar
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDrift {
+    /// Warn and log it to the audit log, leaving the file as it is
+    Report,
+    /// Run the hooks again on the cached data, which renders the file anew
+    Rerender,
+}
+
+/// The sidecar holding the checksum of <path>
+fn sidecar(path: &str) -> String {
+    format!("{}.sha256", path)
+}
+
+/// Write the checksum of <contents>, just written to <path>, next to it.
+/// The sidecar is in the format of sha256sum, so `sha256sum -c` checks it too.
+pub fn write(path: &str, contents: &[u8]) -> Result<()> {
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    let line = format!("{}  {}\n", sha256(contents), name);
+    fs::write(sidecar(path), line)
+        .wrap_err_with(|| format!("Could not write the checksum of {}", path))
+}
+
+/// Remove the sidecar of <path>, if it has one
+pub fn remove(path: &str) -> Result<()> {
+    match fs::remove_file(sidecar(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("Could not remove the checksum of {}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether <path> was changed or removed since its sidecar was written.
+/// Files without a sidecar were never checksummed, so have not drifted.
+pub fn drifted(path: &str) -> Result<bool> {
+    let line = match fs::read_to_string(sidecar(path)) {
+        Ok(line) => line,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", sidecar(path))),
+    };
+    let expected = line.split_whitespace().next().unwrap_or_default();
+
+    match fs::read(path) {
+        Ok(contents) => Ok(sha256(contents) != expected),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e).wrap_err_with(|| format!("Could not read {}", path)),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_drifted() {
+        let dir = std::env::temp_dir().join(format!("app_config_checksum_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.conf").to_string_lossy().to_string();
+
+        fs::write(&path, "port = 80\n").unwrap();
+        assert!(!drifted(&path).unwrap());
+
+        write(&path, b"port = 80\n").unwrap();
+        let line = fs::read_to_string(sidecar(&path)).unwrap();
+        assert!(line.ends_with("  app.conf\n"), "{}", line);
+        assert!(!drifted(&path).unwrap());
+
+        fs::write(&path, "port = 8080\n").unwrap();
+        assert!(drifted(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+        assert!(drifted(&path).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,21 +1,286 @@
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-const NAME: &str = env!("CARGO_PKG_NAME");
-
-pub fn build_cli() -> clap::App<'static, 'static> {
-    clap_app!( app_config =>
-        (version: VERSION)
-        (name: NAME)
-        (about: "app_config: watch AWS appConfig for changes and take action")
-        (@subcommand check =>
-            (about: "Look for Updates")
-            (@arg FILE: -f --file +takes_value +required)
-        )
-        (@subcommand query =>
-            (about: "Print last data received")
-            (@arg FILE: -f --file +takes_value +required)
-        )
-        (@subcommand bash =>
-            (about: "Generate a bash autocompletion script")
-        )
-    )
+use clap::error::ErrorKind;
+use crate::diff;
+use crate::duration;
+use crate::hooks::template::DataType;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// app_config: watch AWS appConfig for changes and take action
+#[derive(Debug, Parser)]
+#[command(name = "app_config", version, about)]
+pub struct Cli {
+    /// Config file of a pipeline, repeat to give several where a subcommand
+    /// takes more than one
+    #[arg(
+        short = 'f',
+        long = "file",
+        visible_alias = "config",
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath
+    )]
+    files: Vec<String>,
+
+    /// Only print errors and the data asked for
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Never color messages, as when NO_COLOR is set
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    #[command(subcommand)]
+    pub command: Cmd,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+    /// Look for Updates
+    Check(CheckArgs),
+    /// Keep checking for updates, each pipeline on its own schedule
+    Daemon,
+    /// Check config files without running them
+    Validate,
+    /// Warn about config files that are valid but look like mistakes
+    Lint,
+    /// Render the templates with the data of test cases, and compare what
+    /// they render to golden files
+    Test(TestArgs),
+    /// Measure how long polling, rendering and running hooks take
+    Bench(BenchArgs),
+    /// Print last data received
+    Query(QueryArgs),
+    /// Print the last data received as shell exports, to eval
+    Export(ExportArgs),
+    /// Print the audit log
+    Audit(AuditArgs),
+    /// Print the report of the latest run
+    Report(ReportArgs),
+    /// Hold back changes until thawed, they are still polled and cached
+    Freeze(FreezeArgs),
+    /// Let changes be applied again, those held back first
+    Thaw,
+    /// Apply the change waiting for approval, with settings.require_approval
+    Approve(ApproveArgs),
+    /// Resume the latest run, which failed part way, running only the hooks
+    /// it did not complete
+    Replay,
+    /// Remove the files hooks wrote that no hook writes for the cached data
+    /// any more, e.g. after an output was renamed
+    Cleanup(CleanupArgs),
+    /// Generate a bash autocompletion script
+    Bash,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Number of pipelines checked at once
+    #[arg(short, long, value_name = "N", default_value_t = 4,
+          value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: u16,
+    /// Apply the cached data without contacting the providers
+    #[arg(long)]
+    pub offline: bool,
+    /// Apply the data even if it did not change
+    #[arg(long)]
+    pub bootstrap: bool,
+    /// Only run the hooks with this name, their kind unless they have one
+    #[arg(long, value_name = "HOOK")]
+    pub only: Vec<String>,
+    /// Do not run the hooks with this name
+    #[arg(long, value_name = "HOOK")]
+    pub skip: Vec<String>,
+    /// Retry, backing off, until the data has been applied once, then exit.
+    /// The data is applied even if it did not change, as with --bootstrap
+    #[arg(long)]
+    pub wait_for_initial: bool,
+    /// Show what each file write changes, and ask before it and before
+    /// running commands
+    #[arg(long)]
+    pub interactive: bool,
+    /// Lines of unchanged text shown around each change, with --interactive
+    #[arg(long, value_name = "N", default_value_t = diff::CONTEXT, requires = "interactive")]
+    pub context: usize,
+    /// Show the changes with the file as it is on the left, and as it would
+    /// be written on the right, with --interactive
+    #[arg(long, requires = "interactive")]
+    pub side_by_side: bool,
+    /// Show the keys that change in JSON, YAML and TOML files, rather than
+    /// the lines, with --interactive
+    #[arg(long, requires = "interactive", conflicts_with = "side_by_side")]
+    pub semantic: bool,
+    /// Refuse to run pipelines that pay to download their data again on
+    /// every run, for want of a state_file
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Print the data converted to this format, parsed as settings.source_type
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    /// Only print the values at this jq style path, e.g. .database.host
+    #[arg(long)]
+    pub path: Option<String>,
+    /// Poll the provider first if nothing is cached yet, the hooks are left
+    /// to the next check
+    #[arg(long)]
+    pub refresh: bool,
+    /// With --refresh, also poll if the provider was last reached longer
+    /// ago than this, e.g. 10m
+    #[arg(long, value_name = "DURATION", requires = "refresh", value_parser = parse_duration)]
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Put this in front of every variable name, e.g. APP_
+    #[arg(long, default_value = "")]
+    pub prefix: String,
+}
+
+/// Formats the query subcommand can print the data in
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    pub fn data_type(self) -> DataType {
+        match self {
+            Format::Json => DataType::JSON,
+            Format::Yaml => DataType::YAML,
+            Format::Toml => DataType::TOML,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct TestArgs {
+    /// Directory of the test cases, one directory each holding the data as
+    /// `input` and the golden files, named after the outputs
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub cases: PathBuf,
+    /// Write what the templates render to the golden files, rather than
+    /// compare
+    #[arg(long)]
+    pub update: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Number of times each step is measured
+    #[arg(long, value_name = "N", default_value_t = 10,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub iterations: u32,
+    /// Also run the hooks, which do what they do in a check: write files,
+    /// run commands, restart services...
+    #[arg(long)]
+    pub run_hooks: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct AuditArgs {
+    /// Number of entries to print
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+    pub limit: usize,
+    /// Print entries as JSON lines
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct FreezeArgs {
+    /// Why changes are frozen, logged while they are held back
+    #[arg(long, default_value = "no reason given")]
+    pub reason: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ApproveArgs {
+    /// Only approve the change if it is this version of the data, as the
+    /// provider numbers them
+    #[arg(long, value_name = "N")]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct CleanupArgs {
+    /// Print the files that would be removed, without removing them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl Cli {
+    /// The config files given with -f, exits with a usage error if there
+    /// are none
+    pub fn files(&self) -> &[String] {
+        if self.files.is_empty() {
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "a config file is required: -f <FILE>")
+                .exit();
+        }
+        &self.files
+    }
+
+    /// The one config file given with -f, for subcommands that take a single
+    /// pipeline.  Exits with a usage error unless there is exactly one.
+    pub fn file(&self) -> &str {
+        match self.files() {
+            [file] => file,
+            _ => Cli::command()
+                .error(ErrorKind::TooManyValues, "this subcommand takes a single -f <FILE>")
+                .exit(),
+        }
+    }
+}
+
+/// A duration given on the command line, written as in config files
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    duration::parse(text).map_err(|e| e.to_string())
+}
+
+/// Write the bash completion script for app_config to stdout
+pub fn bash_completion() {
+    let mut cmd = Cli::command();
+    let mut out = std::io::stdout();
+    clap_complete::generate(clap_complete::Shell::Bash, &mut cmd, "app_config", &mut out);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from(["app_config", "check", "-f", "a.toml", "--config", "b.toml"]);
+        assert_eq!(cli.files(), ["a.toml", "b.toml"]);
+        match cli.command {
+            Cmd::Check(args) => assert_eq!(args.jobs, 4),
+            cmd => panic!("parsed as {:?}", cmd),
+        }
+
+        // The config file may come before the subcommand
+        let cli = Cli::parse_from(["app_config", "-f", "a.toml", "audit", "-n", "5"]);
+        assert_eq!(cli.file(), "a.toml");
+
+        assert!(Cli::try_parse_from(["app_config", "check", "-j", "0"]).is_err());
+        assert!(Cli::try_parse_from(["app_config", "check", "--side-by-side"]).is_err());
+        let e = Cli::try_parse_from(["app_config", "chekc"]).unwrap_err();
+        assert!(e.to_string().contains("similar subcommand exists: 'check'"), "{}", e);
+    }
 }
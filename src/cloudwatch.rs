@@ -0,0 +1,256 @@
+use crate::credentials;
+use crate::http;
+use rusoto_cloudwatch::{CloudWatch as CloudWatchApi, Dimension, MetricDatum, PutMetricDataInput};
+use rusoto_core::{Region, RusotoError};
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, CreateLogStreamError, CreateLogStreamRequest,
+    InputLogEvent, PutLogEventsRequest,
+};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// CloudWatchConf holds the [settings.cloudwatch] section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(rename = "cloudwatch")]
+pub struct CloudWatchConf {
+    pub namespace: Option<String>,
+    pub metrics: Option<bool>,
+    pub log_group: Option<String>,
+    pub log_stream: Option<String>,
+}
+
+impl CloudWatchConf {
+    pub fn convert(&self) -> CloudWatch {
+        CloudWatch::new(
+            self.namespace.as_deref().unwrap_or("app_config"),
+            self.metrics.unwrap_or(true),
+            self.log_group.clone(),
+            self.log_stream.clone().unwrap_or_else(hostname),
+        )
+    }
+}
+
+
+// // // // // // // // // // CloudWatch // // // // // // // // // //
+
+/// The outcome of one `check` run, as reported to CloudWatch
+#[derive(Debug, PartialEq)]
+pub struct RunOutcome {
+    pub config: String,
+    pub applied: bool,
+    pub sha256: Option<String>,
+    pub error: Option<String>,
+}
+
+/// CloudWatch emits the ConfigApplied and HookFailure custom metrics, and
+/// optionally a structured log event to <log_group>, after every run.
+/// Both metrics carry a "Config" dimension naming the config file.
+#[derive(Debug, PartialEq)]
+pub struct CloudWatch {
+    namespace: String,
+    metrics: bool,
+    log_group: Option<String>,
+    log_stream: String,
+}
+
+impl CloudWatch {
+    /// Create a new CloudWatch struct
+    pub fn new(
+        namespace: &str,
+        metrics: bool,
+        log_group: Option<String>,
+        log_stream: String,
+    ) -> CloudWatch {
+        CloudWatch {
+            namespace: namespace.to_string(),
+            metrics,
+            log_group,
+            log_stream,
+        }
+    }
+
+    /// Send the metrics and log event for <outcome>
+    pub fn emit(&self, outcome: &RunOutcome) -> Result<()> {
+        if self.metrics {
+            put_metrics(&self.namespace, self.metric_data(outcome), Region::default())?;
+        }
+        if let Some(group) = &self.log_group {
+            put_log_event(group, &self.log_stream, &log_message(outcome))?;
+        }
+        Ok(())
+    }
+
+    fn metric_data(&self, outcome: &RunOutcome) -> Vec<MetricDatum> {
+        let dimensions = vec![Dimension {
+            name: "Config".to_string(),
+            value: outcome.config.clone(),
+        }];
+        let datum = |name: &str, value: bool| MetricDatum {
+            metric_name: name.to_string(),
+            dimensions: Some(dimensions.clone()),
+            unit: Some("Count".to_string()),
+            value: Some(if value { 1.0 } else { 0.0 }),
+            ..Default::default()
+        };
+
+        vec![
+            datum("ConfigApplied", outcome.applied && outcome.error.is_none()),
+            datum("HookFailure", outcome.applied && outcome.error.is_some()),
+        ]
+    }
+}
+
+/// The structured (json) log line describing <outcome>
+fn log_message(outcome: &RunOutcome) -> String {
+    serde_json::json!({
+        "config": outcome.config,
+        "applied": outcome.applied,
+        "sha256": outcome.sha256,
+        "error": outcome.error,
+    })
+    .to_string()
+}
+
+/// Best effort local hostname, used as the default log stream
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "app_config".to_string())
+}
+
+/// put_metrics()
+/// Make the call to CloudWatch in <region> and wait for the reply
+#[tokio::main]
+pub async fn put_metrics(
+    namespace: &str,
+    metric_data: Vec<MetricDatum>,
+    region: Region,
+) -> Result<()> {
+    let client = rusoto_cloudwatch::CloudWatchClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        region,
+    );
+
+    let request = PutMetricDataInput {
+        namespace: namespace.to_string(),
+        metric_data,
+    };
+
+    match client.put_metric_data(request).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(eyre!("Unable to put CloudWatch metrics: {:?}", e)),
+    }
+}
+
+/// put_log_event()
+/// Make sure the log stream exists, then send it <message>
+#[tokio::main]
+async fn put_log_event(group: &str, stream: &str, message: &str) -> Result<()> {
+    let client = CloudWatchLogsClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        Region::default(),
+    );
+
+    let request = CreateLogStreamRequest {
+        log_group_name: group.to_string(),
+        log_stream_name: stream.to_string(),
+    };
+    match client.create_log_stream(request).await {
+        Ok(()) => {}
+        Err(RusotoError::Service(CreateLogStreamError::ResourceAlreadyExists(_))) => {}
+        Err(e) => return Err(eyre!("Unable to create CloudWatch log stream: {:?}", e)),
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+
+    let request = PutLogEventsRequest {
+        log_group_name: group.to_string(),
+        log_stream_name: stream.to_string(),
+        log_events: vec![InputLogEvent {
+            message: message.to_string(),
+            timestamp,
+        }],
+        sequence_token: None,
+    };
+
+    match client.put_log_events(request).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(eyre!("Unable to put CloudWatch log event: {:?}", e)),
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [settings.cloudwatch]
+        log_group = "/app_config/runs"
+        log_stream = "host1"
+        "#
+        .to_string()
+    }
+
+    fn gen_outcome(error: Option<String>) -> RunOutcome {
+        RunOutcome {
+            config: "myApp.toml".to_string(),
+            applied: true,
+            sha256: Some("abc".to_string()),
+            error,
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = CloudWatch::new(
+            &"app_config",
+            true,
+            Some("/app_config/runs".to_string()),
+            "host1".to_string(),
+        );
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: CloudWatchConf = maps["settings"]["cloudwatch"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_metric_data() {
+        let cw = CloudWatch::new(&"app_config", true, None, "host1".to_string());
+
+        let res = cw.metric_data(&gen_outcome(None));
+        assert_eq!(res[0].metric_name, "ConfigApplied");
+        assert_eq!(res[0].value, Some(1.0));
+        assert_eq!(res[1].metric_name, "HookFailure");
+        assert_eq!(res[1].value, Some(0.0));
+
+        let res = cw.metric_data(&gen_outcome(Some("boom".to_string())));
+        assert_eq!(res[0].value, Some(0.0));
+        assert_eq!(res[1].value, Some(1.0));
+    }
+
+    #[test]
+    fn test_log_message() {
+        let msg: serde_json::Value =
+            serde_json::from_str(&log_message(&gen_outcome(None))).unwrap();
+        assert_eq!(msg["config"], "myApp.toml");
+        assert_eq!(msg["applied"], true);
+        assert_eq!(msg["error"], serde_json::Value::Null);
+    }
+}
@@ -1,77 +1,46 @@
 use shellexpand::tilde;
 use std::fs;
-
-use crate::hooks::{CommandConf, FileConf, Hook, RawConf, TemplateConf};
-use crate::providers::{AppCfgConf, MockConf, ParamStoreConf, Provider};
+use std::time::Duration;
+
+use crate::hooks::{self, Hook, LeaderOnly, Named};
+use crate::data::ConfigData;
+use crate::coordination::Coordinator;
+use crate::decode::Decoder;
+use crate::normalize::Normalizer;
+use crate::window::Window;
+use crate::duration;
+use crate::hooks::template::DataType;
+use crate::path;
+use crate::providers::{self, Provider};
+use crate::schema::Schema;
+use crate::settings::Settings;
+use crate::state;
+use eyre::WrapErr;
 
 type TResult<T> = Result<T, toml::de::Error>;
 
-// This is a bit hard to read, but here is the deal.
-// There is a BTree in <maps> that contains the structure of the config file
-// There is a Vec in <hooks> where we store our final structs
-// This macro will loop over every hook in <maps>, convert the hook into a struct
-// and push the result into <hooks>.
-#[macro_export]
-macro_rules! parse_hooks {
-    ( $( $maps:expr, $hooks:expr, $($section:expr, $conf:ty),+)? ) => {
-        { $(
-    for hook_section in $maps["hooks"].as_table().unwrap().keys() {
-        $(
-        if hook_section.as_str() == $section {
-            let conf: TResult<$conf> = $maps["hooks"][$section]
-                .clone().try_into();
-            match conf {
-                Err(e) => config_err(&e, $section),
-                Ok(conf) => {
-                    let x = conf.convert();
-                    $hooks.push( Box::new(x) );
-                },
-            }
-        }
-        )+
-    }
-        )? }
-    };
-}
-
-// Like for parse_hooks above, but instead we only want one provider. So it is
-// an if / else if / else if ... / chain.  Erroring out if nothing matches.
-// There is a BTree in <maps> that contains the structure of the config file
-// This macro will check for each provider in <maps>, convert the provider into a
-// struct and save the result into <provider>.
-#[macro_export]
-macro_rules! parse_providers {
-    ( $( $maps:expr, $provider_type:expr, $provider:expr,
-                                    $($section:expr, $conf:ty),+)? ) => {
-        { $(
-        if ! true { }
-        $(
-        // AppCfg
-        else if $provider_type.as_str() == $section {
-            let conf: TResult<$conf> = $maps["providers"][$section]
-                                                    .clone().try_into();
-            // Pretty print any parsing errors
-            if let Err(e) = &conf { config_err(&e, $section); }
-
-            let x = conf.unwrap().convert();
-            $provider = Box::new(x);
-        }
-        )+
-        // If no valid provider found, panic with an error
-        else {
-            eprintln!("Error, no valid providers found");
-            std::process::exit(exitcode::CONFIG);
-        }
-        )? }
-    };
-}
+/// Providers that cache their data in a state_file of their own
+const CACHING_PROVIDERS: [&str; 3] = ["appconfig", "param_store", "exec"];
 
 /// Config:
 /// Parse toml config file and validate all the parameters
 #[derive(Debug)]
 pub struct Config {
+    pub path: String,
     pub provider: Box<dyn Provider>,
     pub hooks: Vec<Box<dyn Hook>>,
+    pub pre_hooks: Vec<Box<dyn Hook>>,
+    pub post_hooks: Vec<Box<dyn Hook>>,
+    pub on_error: Vec<Box<dyn Hook>>,
+    pub settings: Settings,
+    pub schema: Option<Schema>,
+    pub decode: Vec<Decoder>,
+    pub normalize: Vec<Normalizer>,
+    pub allow_stale: Option<Duration>,
+    pub min_poll_interval: Option<Duration>,
+    pub min_apply_interval: Option<Duration>,
+    pub windows: Vec<Window>,
+    pub coordinator: Option<Coordinator>,
 }
 
 impl Config {
@@ -87,8 +56,13 @@ impl Config {
                 std::process::exit(exitcode::OSFILE);
             }
         };
+        Config::parse(path, &file_contents)
+    }
 
-        let toml_maps: toml::Value = match toml::from_str(&file_contents) {
+    /// Parse <file_contents>, as read from <path>, into a Config struct.
+    /// Will panic if it can not parse them.
+    pub fn parse(path: &str, file_contents: &str) -> Config {
+        let mut toml_maps: toml::Value = match toml::from_str(file_contents) {
             Ok(config) => config,
             Err(e) => {
                 eprintln!("Could not parse {}: {}", path, e);
@@ -96,21 +70,172 @@ impl Config {
             }
         };
 
-        // Extract provider from config file
-        let p: Box<dyn Provider> = Config::get_provider(&toml_maps);
+        // State files left out are kept in settings.state_dir, if it is set
+        Config::derive_state_files(&mut toml_maps, path);
+
+        // Extract global settings from config file
+        let s: Settings = Config::get_settings(&toml_maps);
+
+        // Extract provider from config file, it keeps its state under the
+        // pipeline's name
+        let mut p: Box<dyn Provider> =
+            Config::get_provider(&toml_maps, &pipeline_name(path, &s));
+
+        // Extract the provider's decode chain from config file
+        let d: Vec<Decoder> = Config::get_decode(&toml_maps);
+
+        // And what of its data does not count as a change
+        let n: Vec<Normalizer> = Config::get_normalize(&toml_maps);
 
         // Extract hooks from config file
-        let h: Vec<Box<dyn Hook>> = Config::get_hooks(&toml_maps);
+        let mut h: Vec<Box<dyn Hook>> = Config::get_hooks(&toml_maps);
+
+        // Extract the hooks run before and after them from config file
+        let mut pre: Vec<Box<dyn Hook>> = Config::get_hook_section(&toml_maps, "pre_hooks");
+        let mut post: Vec<Box<dyn Hook>> = Config::get_hook_section(&toml_maps, "post_hooks");
+
+        // Extract failure notification hooks from config file
+        let mut e: Vec<Box<dyn Hook>> = Config::get_on_error(&toml_maps);
+
+        // Static values, e.g. per host constants, the hooks render along
+        // with the provider's data.  Hooks have no `when` conditions yet for
+        // them to feed, only templates use them.
+        let vars = Config::get_vars(&toml_maps);
+        // Hooks calling AWS give up after settings.timeout, as providers do
+        let timeout = s.timeout.as_ref().map(|t| parse_duration("timeout", t));
+        for hook in h.iter_mut().chain(&mut pre).chain(&mut post).chain(&mut e) {
+            hook.set_vars(&vars);
+            hook.set_pipeline(path, &pipeline_name(path, &s));
+            if let Some(timeout) = timeout {
+                hook.set_timeout(timeout);
+            }
+        }
+
+        // Calls to the provider's upstream source give up after its timeout
+        if let Some(timeout) = Config::get_timeout(&toml_maps, &s) {
+            p.set_timeout(timeout);
+        }
+
+        // How old cached data may be to stand in for an unreachable provider
+        let allow_stale = s.allow_stale.as_ref().map(|d| parse_duration("allow_stale", d));
+
+        // How soon after a poll the provider may be polled again
+        let min_poll_interval = s
+            .min_poll_interval
+            .as_ref()
+            .map(|d| parse_duration("min_poll_interval", d));
+
+        // How soon after hooks ran on new data they may run again
+        let min_apply_interval = s
+            .min_apply_interval
+            .as_ref()
+            .map(|d| parse_duration("min_apply_interval", d));
+
+        // When changes may be applied, any time if there are no windows
+        let windows = s
+            .maintenance_windows
+            .iter()
+            .flatten()
+            .map(|window| window.convert())
+            .collect();
+
+        // Where hosts running the same pipeline take turns
+        let coordinator = s
+            .coordination
+            .as_ref()
+            .map(|coordination| coordination.convert(&pipeline_name(path, &s)));
+
+        // It is also where they elect the one running leader_only hooks
+        let mut all_hooks = h.iter().chain(&pre).chain(&post).chain(&e);
+        if coordinator.is_none() && all_hooks.any(|hook| hook.leader_only()) {
+            eprintln!("Error, leader_only hooks need [settings.coordination] to elect a leader");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // How often the daemon checks the pipeline, and where it listens for
+        // checks in between, it reads the settings itself but mistakes
+        // should show up on every run
+        if let Some(interval) = &s.interval {
+            parse_duration("interval", interval);
+        }
+        #[cfg(feature = "aws")]
+        if let Some(listen) = &s.listen {
+            listen.convert();
+        }
+
+        // Compile the schema provider data has to match
+        let schema = s.schema.as_ref().map(|path| Schema::from_file(path));
 
         Config {
+            path: path.to_string(),
             provider: p,
             hooks: h,
+            pre_hooks: pre,
+            post_hooks: post,
+            on_error: e,
+            settings: s,
+            schema,
+            decode: d,
+            normalize: n,
+            allow_stale,
+            min_poll_interval,
+            min_apply_interval,
+            windows,
+            coordinator,
         }
     }
 
+    /// Check data from the provider against settings.schema, if there is
+    /// one.  The data is parsed as settings.source_type, yaml by default
+    /// which covers json as well.
+    pub fn validate(&self, data: &ConfigData) -> eyre::Result<()> {
+        let schema = match &self.schema {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+
+        let source_type = self.settings.source_type.clone().unwrap_or(DataType::YAML);
+        let value = data
+            .parsed(&source_type)
+            .wrap_err_with(|| format!("Provider data is not valid {:?}", source_type))?;
+        schema.validate(&serde_json::to_value(&*value)?)
+    }
+
+    /// With settings.for_each, the parts of <data> the hooks run on, one by
+    /// one.  The data is parsed as settings.source_type, yaml by default.
+    /// Without it, there is nothing to split.
+    pub fn for_each(&self, data: &ConfigData) -> eyre::Result<Option<Vec<ConfigData>>> {
+        let for_each = match &self.settings.for_each {
+            Some(for_each) => for_each,
+            None => return Ok(None),
+        };
+
+        let source_type = self.settings.source_type.clone().unwrap_or(DataType::YAML);
+        let value = data
+            .parsed(&source_type)
+            .wrap_err_with(|| format!("Provider data is not valid {:?}", source_type))?;
+        let elements = path::select(&value, for_each)?
+            .into_iter()
+            .map(|element| ConfigData::from_value(element, data))
+            .collect::<eyre::Result<Vec<ConfigData>>>()?;
+        Ok(Some(elements))
+    }
+
+    /// Name of this pipeline, settings.name if set, else the name of the
+    /// config file without its extension
+    pub fn name(&self) -> String {
+        pipeline_name(&self.path, &self.settings)
+    }
+
+    /// Whether the data of this pipeline holds secrets, settings.sensitive
+    pub fn sensitive(&self) -> bool {
+        self.settings.sensitive.unwrap_or(false)
+    }
+
     /// Parse the config file looking for one and only one backend provider
+    /// for the pipeline named <pipeline>
     /// Will panic on any errors.
-    fn get_provider(maps: &toml::Value) -> Box<dyn Provider> {
+    fn get_provider(maps: &toml::Value, pipeline: &str) -> Box<dyn Provider> {
         // Validate Providers are present
         if !maps.as_table().unwrap().contains_key("providers") {
             eprintln!("Error, configuation must include a backend provider");
@@ -122,30 +247,159 @@ impl Config {
             std::process::exit(exitcode::CONFIG);
         }
 
-        let mut provider: Box<dyn Provider>;
-        // This is done just to let us use a macro to parse the providers. Rust
-        // gets confused.  We will panic before this provider ever gets further.
-        provider = Box::new(
-            MockConf {
-                data: "".to_string(),
+        // Since we know we have just one provider key, let's get it
+        let (kind, section) = maps["providers"].as_table().unwrap().iter().next().unwrap();
+
+        // Each provider module registers the kind it provides, those of
+        // features left out of the build are missing
+        let registry = providers::Registry::default();
+        let build = match registry.get(kind) {
+            Some(build) => build,
+            None => {
+                match registry.missing_feature(kind) {
+                    Some(feature) => eprintln!(
+                        "Error, the {} provider needs app_config built with the {} feature",
+                        kind, feature
+                    ),
+                    None => eprintln!("Error, no valid providers found"),
+                }
+                std::process::exit(exitcode::CONFIG);
             }
-            .convert(),
-        );
+        };
+        match build(section.clone(), pipeline) {
+            Ok(provider) => provider,
+            // Pretty print any parsing errors
+            Err(e) => config_err(&e, kind),
+        }
+    }
 
-        // Since we know we have just one provider key, let's get it
-        let provider_type = maps["providers"].as_table().unwrap().keys().last().unwrap();
-
-        // This macro will find the configured provider in <maps> and instantiate
-        // the provider struct in <provider>. It will panic if no provider is found
-        // or if there is a parsing error in the provider section.
-        parse_providers!(
-            maps, provider_type, provider,
-            "mock", MockConf,
-            "appconfig", AppCfgConf,
-            "param_store", ParamStoreConf
-        );
+    /// Parse the optional decode chain of the provider, it applies to the
+    /// data of every provider so it is not part of their own sections
+    /// Will panic on any errors.
+    fn get_decode(maps: &toml::Value) -> Vec<Decoder> {
+        let provider = match maps["providers"].as_table().unwrap().values().next() {
+            Some(provider) => provider,
+            None => return Vec::new(),
+        };
+        match provider.get("decode") {
+            None => Vec::new(),
+            Some(decode) => {
+                let decode: TResult<Vec<Decoder>> = decode.clone().try_into();
+                match decode {
+                    Ok(decode) => decode,
+                    Err(e) => config_err(&e, "decode"),
+                }
+            }
+        }
+    }
+
+    /// Parse the optional normalize chain of the provider, given in its
+    /// section like decode
+    /// Will panic on any errors.
+    fn get_normalize(maps: &toml::Value) -> Vec<Normalizer> {
+        let provider = match maps["providers"].as_table().unwrap().values().next() {
+            Some(provider) => provider,
+            None => return Vec::new(),
+        };
+        match provider.get("normalize") {
+            None => Vec::new(),
+            Some(normalize) => {
+                let normalize: TResult<Vec<Normalizer>> = normalize.clone().try_into();
+                match normalize {
+                    Ok(normalize) => normalize,
+                    Err(e) => config_err(&e, "normalize"),
+                }
+            }
+        }
+    }
+
+    /// Parse the optional timeout of the provider, given in its own section
+    /// or for every provider in settings.timeout.  The provider's wins.
+    /// Will panic on any errors.
+    fn get_timeout(maps: &toml::Value, settings: &Settings) -> Option<Duration> {
+        let provider = maps["providers"].as_table().unwrap().values().next();
+        let timeout = match provider.and_then(|p| p.get("timeout")) {
+            Some(timeout) => match timeout.as_str() {
+                Some(timeout) => timeout.to_string(),
+                None => {
+                    eprintln!("Error, the provider's timeout must be a string, e.g. \"10s\"");
+                    std::process::exit(exitcode::CONFIG);
+                }
+            },
+            None => settings.timeout.clone()?,
+        };
 
-        provider
+        Some(parse_duration("timeout", &timeout))
+    }
+
+    /// With settings.state_dir, give the pipeline in <maps>, read from
+    /// <path>, a state file there wherever it has none: in [settings], and
+    /// for a provider that caches its data.
+    /// Will panic if state_dir is invalid or can not be created.
+    fn derive_state_files(maps: &mut toml::Value, path: &str) {
+        let settings = match maps.get("settings") {
+            Some(settings) => settings.clone(),
+            None => return,
+        };
+        let state_dir: TResult<Option<state::StateDir>> = settings
+            .get("state_dir")
+            .map(|dir| dir.clone().try_into())
+            .transpose();
+        let dir = match state_dir {
+            Ok(state_dir) => match state_dir.and_then(|dir| dir.path()) {
+                Some(dir) => dir,
+                None => return,
+            },
+            Err(e) => config_err(&e, "state_dir"),
+        };
+        let (kind, provider) = match maps.get("providers").and_then(|p| p.as_table()) {
+            Some(providers) if providers.len() == 1 => {
+                let (kind, provider) = providers.iter().next().unwrap();
+                (kind.clone(), provider.clone())
+            }
+            // Config::get_provider tells what is wrong
+            _ => return,
+        };
+
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!("Error, unable to create state_dir {}: {}", dir.display(), e);
+            std::process::exit(exitcode::CANTCREAT);
+        }
+        let name = Settings {
+            name: settings.get("name").and_then(|n| n.as_str()).map(|n| n.to_string()),
+            ..Settings::default()
+        };
+        let file = state::derived_file(&dir, &pipeline_name(path, &name), &kind, &provider);
+        let file = toml::Value::from(file);
+
+        if let Some(settings) = maps.get_mut("settings").and_then(|s| s.as_table_mut()) {
+            settings.entry("state_file").or_insert_with(|| file.clone());
+        }
+        if CACHING_PROVIDERS.contains(&kind.as_str()) {
+            let provider = maps
+                .get_mut("providers")
+                .and_then(|p| p.get_mut(&kind))
+                .and_then(|p| p.as_table_mut());
+            if let Some(provider) = provider {
+                provider.entry("state_file").or_insert(file);
+            }
+        }
+    }
+
+    /// Parse the optional [vars] table of the config file
+    /// Will panic if it is not a table.
+    fn get_vars(maps: &toml::Value) -> serde_yaml::Mapping {
+        let vars = match maps.get("vars") {
+            None => return serde_yaml::Mapping::new(),
+            Some(vars) => vars,
+        };
+        match serde_yaml::to_value(vars) {
+            Ok(serde_yaml::Value::Mapping(vars)) => vars,
+            _ => {
+                eprintln!("Error, vars must be a table");
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
     }
 
     /// Parse the config file looking for hooks
@@ -155,38 +409,190 @@ impl Config {
     // e.g. # Cargo.toml
     // e.g. toml = { version = "0.5.7", features=["preserve_order"] }
     fn get_hooks(maps: &toml::Value) -> Vec<Box<dyn Hook>> {
-        let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+        Config::get_hook_section(maps, "hooks")
+    }
+
+    /// Parse the config file looking for hooks to run when a run fails
+    /// Uses the same hook types, and ordering rules, as get_hooks
+    /// Will panic on any errors.
+    fn get_on_error(maps: &toml::Value) -> Vec<Box<dyn Hook>> {
+        Config::get_hook_section(maps, "on_error")
+    }
 
-        // Validate there are at least some hooks in the config file
-        if !maps.as_table().unwrap().contains_key("hooks") {
-            return hooks;
+    /// Parse the hooks of the optional <section> of the config file, e.g.
+    /// pre_hooks, with the same hook types and ordering rules as get_hooks
+    /// Will panic on any errors.
+    fn get_hook_section(maps: &toml::Value, section: &str) -> Vec<Box<dyn Hook>> {
+        if !maps.as_table().unwrap().contains_key(section) {
+            return Vec::new();
         }
 
-        // This macro will instantiate a struct for each hook found in
-        // maps["hooks"], and push that hook into the 'hooks' vector
-        parse_hooks!(
-            maps, hooks,
-            "template", TemplateConf,
-            "file", FileConf,
-            "raw", RawConf,
-            "command", CommandConf
-        );
+        Config::parse_hook_table(&maps[section])
+    }
+
+    /// Convert every hook in a table of the config file into its struct,
+    /// under its name if it was given one, and only for the leading replica
+    /// with leader_only.  Sections that name no hook are left alone.
+    fn parse_hook_table(table: &toml::Value) -> Vec<Box<dyn Hook>> {
+        let registry = hooks::Registry::default();
+        let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+
+        for (kind, section) in table.as_table().unwrap() {
+            let build = match (registry.get(kind), registry.missing_feature(kind)) {
+                (Some(build), _) => build,
+                (None, Some(feature)) => {
+                    eprintln!(
+                        "Error, the {} hook needs app_config built with the {} feature",
+                        kind, feature
+                    );
+                    std::process::exit(exitcode::CONFIG);
+                }
+                (None, None) => continue,
+            };
+            let hook = match build(section.clone()) {
+                Ok(hook) => hook,
+                Err(e) => config_err(&e, kind),
+            };
+
+            let hook: Box<dyn Hook> = match section.get("name").map(|n| n.as_str()) {
+                None => hook,
+                Some(Some(name)) => Box::new(Named::new(name, hook)),
+                Some(None) => {
+                    eprintln!("Error, the name of the {} hook must be a string", kind);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            };
+            match section.get("leader_only").map(|l| l.as_bool()) {
+                None | Some(Some(false)) => hooks.push(hook),
+                Some(Some(true)) => hooks.push(Box::new(LeaderOnly::new(hook))),
+                Some(None) => {
+                    eprintln!("Error, leader_only of {} must be a boolean", kind);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            }
+        }
 
         hooks
     }
+
+    /// Parse the optional [settings] section of the config file
+    /// Will panic on any errors.
+    fn get_settings(maps: &toml::Value) -> Settings {
+        // The on_error hooks are told a failure is over once a run succeeds,
+        // which failures have to be counted across runs for
+        let state_file = maps.get("settings").and_then(|s| s.get("state_file"));
+        if maps.get("on_error").is_some() && state_file.is_none() {
+            eprintln!("Error, on_error hooks require a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        if !maps.as_table().unwrap().contains_key("settings") {
+            return Settings::default();
+        }
+
+        // Builds that do not call AWS know nothing of these, rather than
+        // ignore them, say why
+        #[cfg(not(feature = "aws"))]
+        for section in &["cloudwatch", "aws_credentials", "listen"] {
+            if maps["settings"].get(section).is_some() {
+                eprintln!(
+                    "Error, settings.{} needs app_config built with the aws feature",
+                    section
+                );
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+
+        let settings: TResult<Settings> = maps["settings"].clone().try_into();
+        // Pretty print any parsing errors
+        if let Err(e) = &settings {
+            config_err(&e, "settings");
+        }
+        let settings = settings.unwrap();
+
+        // Consecutive failures can only be counted across runs with a state file
+        if settings.failure_threshold.unwrap_or(1) > 1 && settings.state_file.is_none() {
+            eprintln!("Error, failure_threshold above 1 requires a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // As can the age of cached data
+        if settings.allow_stale.is_some() && settings.state_file.is_none() {
+            eprintln!("Error, allow_stale requires a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // And can polls
+        if settings.min_poll_interval.is_some() && settings.state_file.is_none() {
+            eprintln!("Error, min_poll_interval requires a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+        if settings.daily_poll_budget.is_some() && settings.state_file.is_none() {
+            eprintln!("Error, daily_poll_budget requires a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // Without one, every run would be the first
+        if settings.bootstrap == Some(false) && settings.state_file.is_none() {
+            eprintln!("Error, bootstrap = false requires a settings.state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        settings
+    }
+}
+
+/// Name of the pipeline configured in <path>, settings.name if set, else
+/// the name of the file without its extension
+fn pipeline_name(path: &str, settings: &Settings) -> String {
+    match &settings.name {
+        Some(name) => name.clone(),
+        None => std::path::Path::new(path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string()),
+    }
+}
+
+/// Instantiate the provider of <kind> configured by <section>, for data
+/// needed beside that of the pipeline's own provider, e.g. a template's
+/// extra_data.  Its state is kept under the name of the <pipeline>.
+/// Will panic on any errors.
+pub fn parse_provider(kind: &str, section: &toml::Value, pipeline: &str) -> Box<dyn Provider> {
+    let mut providers = toml::value::Table::new();
+    providers.insert(kind.to_string(), section.clone());
+    let mut maps = toml::value::Table::new();
+    maps.insert("providers".to_string(), toml::Value::Table(providers));
+    Config::get_provider(&toml::Value::Table(maps), pipeline)
 }
 
-fn config_err(e: &toml::de::Error, section: &str) {
+/// Parse the duration given for <setting>
+/// Will panic if it is invalid.
+pub fn parse_duration(setting: &str, text: &str) -> Duration {
+    match duration::parse(text) {
+        Ok(duration) => duration,
+        Err(e) => {
+            eprintln!("Error, invalid {}: {}", setting, e);
+            std::process::exit(exitcode::CONFIG);
+        }
+    }
+}
+
+fn config_err(e: &toml::de::Error, section: &str) -> ! {
     eprintln!("Could not parse {} config: {:#?}", section, e);
     std::process::exit(exitcode::CONFIG);
 }
 
-#[cfg(test)]
+// The configs tested read from appconfig
+#[cfg(all(test, feature = "aws"))]
 mod test {
     use super::*;
-    use crate::hooks::template::DataType;
-    use crate::hooks::{Command, File, Hook, Template};
-    use crate::providers::AppCfg;
+    use crate::hooks::command::Command;
+    use crate::hooks::file::File;
+    use crate::hooks::template::{DataType, Engine, Template};
+    use crate::hooks::Hook;
+    use crate::providers::appcfg::AppCfg;
+    use crate::sandbox::Sandbox;
 
     fn gen_full_config() -> String {
         "[providers.appconfig]
@@ -219,11 +625,12 @@ client_id = \"42\""
     }
 
     fn gen_appconfig_struct() -> AppCfg {
-        AppCfg::new(&"myApp", &"dev", &"myConf", &"42", &None)
+        AppCfg::new(&"myApp", &"dev", &"myConf", &"42", false, &None, "test")
     }
 
     fn gen_template_struct() -> Template {
         Template::new(
+            "./tests/test_template.tmpl",
             &String::from(
                 "{{#each hosts}}
 [Peer]
@@ -233,16 +640,19 @@ PublicKey = {{this.public_key}}
 ",
             ),
             DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            Default::default(),
             None,
         )
     }
 
     fn gen_file_struct() -> File {
-        File::new(&"raw_output.txt")
+        File::new(&"raw_output.txt", DataType::YAML)
     }
 
     fn gen_command_struct() -> Command {
-        Command::new(&"echo", true)
+        Command::new(&"echo", true, Sandbox::default())
     }
 
     #[test]
@@ -254,34 +664,173 @@ PublicKey = {{this.public_key}}
         let config_str = gen_full_config();
         let tml: toml::Value = toml::from_str(&config_str).unwrap();
         let expected_str = format!("{:?}", gen_appconfig_struct());
-        let provider_str = format!("{:?}", Config::get_provider(&tml));
+        let provider_str = format!("{:?}", Config::get_provider(&tml, "test"));
         assert_eq!(expected_str, provider_str);
     }
 
+    /// <hook> as the struct of its kind
+    fn downcast<T: 'static>(hook: &dyn Hook) -> &T {
+        hook.as_any().downcast_ref::<T>().unwrap()
+    }
+
     #[test]
     fn test_get_hooks() {
         let config_str = gen_full_config();
         let tml: toml::Value = toml::from_str(&config_str).unwrap();
         let h = Config::get_hooks(&tml);
-        let hook_str = format!("{:?}", h);
-        let expected: Vec<Box<dyn Hook>> = vec![
-            Box::new(gen_template_struct()),
-            Box::new(gen_file_struct()),
-            Box::new(gen_command_struct()),
-        ];
+        assert_eq!(h.len(), 3);
 
-        let expected_str = format!("{:?}", expected);
-        assert_eq!(hook_str, expected_str);
+        // Templates hold what can not be compared, like their key cache
+        let template: &Template = downcast(h[0].as_ref());
+        assert_eq!(format!("{:?}", template), format!("{:?}", gen_template_struct()));
+        assert_eq!(downcast::<File>(h[1].as_ref()), &gen_file_struct());
+        assert_eq!(downcast::<Command>(h[2].as_ref()), &gen_command_struct());
+    }
+
+    #[test]
+    fn test_hook_names() {
+        let tml: toml::Value =
+            toml::from_str("[hooks.command]\nname = \"restart\"\ncommand = \"echo\"\n[hooks.raw]")
+                .unwrap();
+        let hooks = Config::get_hooks(&tml);
+        let names: Vec<&str> = hooks.iter().map(|h| h.name()).collect();
+        assert_eq!(names, ["restart", "raw"]);
+        assert_eq!(hooks[0].kind(), "command");
+        assert!(!hooks[0].leader_only());
+
+        let tml: toml::Value = toml::from_str(
+            "[hooks.command]\nname = \"push\"\ncommand = \"echo\"\nleader_only = true",
+        )
+        .unwrap();
+        let hooks = Config::get_hooks(&tml);
+        assert_eq!(hooks[0].name(), "push");
+        assert!(hooks[0].leader_only());
+        // The hook they wrap is still a command
+        assert!(hooks[0].as_any().is::<Command>());
     }
 
     #[test]
     fn test_get_empty_hooks() {
         let config_str = gen_min_config();
         let tml: toml::Value = toml::from_str(&config_str).unwrap();
-        let h = Config::get_hooks(&tml);
-        let hook_str = format!("{:?}", h);
+        assert!(Config::get_hooks(&tml).is_empty());
+    }
 
-        let expected_str = format!("[]");
-        assert_eq!(expected_str, hook_str);
+    #[test]
+    fn test_get_on_error() {
+        let config_str = format!(
+            "{}\n[on_error.command]\ncommand = \"echo\"\npipe_data = true",
+            gen_min_config()
+        );
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let h = Config::get_on_error(&tml);
+        assert_eq!(h.len(), 1);
+        assert_eq!(downcast::<Command>(h[0].as_ref()), &gen_command_struct());
+
+        // The main hooks are unaffected
+        assert!(Config::get_hooks(&tml).is_empty());
+    }
+
+    #[test]
+    fn test_get_hook_section() {
+        let config_str = format!(
+            "{}\n[pre_hooks.command]\ncommand = \"echo\"\npipe_data = true\n[post_hooks.file]\n\
+             outfile = \"raw_output.txt\"",
+            gen_min_config()
+        );
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+
+        let h = Config::get_hook_section(&tml, "pre_hooks");
+        assert_eq!(h.len(), 1);
+        assert_eq!(downcast::<Command>(h[0].as_ref()), &gen_command_struct());
+
+        let h = Config::get_hook_section(&tml, "post_hooks");
+        assert_eq!(h.len(), 1);
+        assert_eq!(downcast::<File>(h[0].as_ref()), &gen_file_struct());
+
+        assert!(Config::get_hooks(&tml).is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::from_file("./tests/schema.toml");
+        let data = |d: &str| ConfigData::new(d, "mock", None);
+        assert!(config.validate(&data("hosts: [{name: web, port: 80}]")).is_ok());
+
+        let res = config.validate(&data("hosts: [{name: web, port: http}]")).unwrap_err();
+        let res = format!("{:#}", res);
+        assert!(res.contains("at /hosts/0/port:"), "{}", res);
+
+        assert!(config.validate(&data("hosts: [")).is_err());
+    }
+
+    #[test]
+    fn test_for_each() {
+        let config = Config::from_file("./tests/for_each.toml");
+        let data = ConfigData::new("clusters: [{name: east}, {name: west}]", "mock", None);
+        let res = config.for_each(&data).unwrap().unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[1].text().unwrap(), r#"{"name":"west"}"#);
+
+        let data = ConfigData::new("clusters: {}", "mock", None);
+        assert_eq!(config.for_each(&data).unwrap().unwrap().len(), 0);
+
+        let data = ConfigData::new("nodes: []", "mock", None);
+        assert!(config.for_each(&data).is_err());
+
+        let config = Config::from_file("./tests/mock.toml");
+        assert!(config.for_each(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_name() {
+        let config = Config::from_file("./tests/mock.toml");
+        assert_eq!(config.name(), "mock");
+    }
+
+    #[test]
+    fn test_get_vars() {
+        let tml: toml::Value = toml::from_str(&gen_min_config()).unwrap();
+        assert!(Config::get_vars(&tml).is_empty());
+
+        let config_str = format!("{}\n[vars]\ndatacenter = \"dc1\"\nweight = 3", gen_min_config());
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let vars = Config::get_vars(&tml);
+        assert_eq!(vars.get(&"datacenter".into()), Some(&"dc1".into()));
+        assert_eq!(vars.get(&"weight".into()), Some(&3.into()));
+    }
+
+    #[test]
+    fn test_get_settings() {
+        let config_str = gen_min_config();
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let s = Config::get_settings(&tml);
+        assert!(s.cloudwatch.is_none());
+
+        let config_str = format!("{}\n[settings.cloudwatch]\nnamespace = \"fleet\"", gen_min_config());
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let s = Config::get_settings(&tml);
+        assert_eq!(s.cloudwatch.unwrap().namespace, Some("fleet".to_string()));
+    }
+
+    #[test]
+    fn test_get_timeout() {
+        let tml: toml::Value = toml::from_str(&gen_min_config()).unwrap();
+        let s = Config::get_settings(&tml);
+        assert_eq!(Config::get_timeout(&tml, &s), None);
+
+        let config_str = format!("{}\n[settings]\ntimeout = \"30s\"", gen_min_config());
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let s = Config::get_settings(&tml);
+        assert_eq!(Config::get_timeout(&tml, &s), Some(Duration::from_secs(30)));
+
+        // The provider's own timeout wins over the global one
+        let config_str = format!(
+            "{}\ntimeout = \"500ms\"\n[settings]\ntimeout = \"30s\"",
+            gen_min_config()
+        );
+        let tml: toml::Value = toml::from_str(&config_str).unwrap();
+        let s = Config::get_settings(&tml);
+        assert_eq!(Config::get_timeout(&tml, &s), Some(Duration::from_millis(500)));
     }
 }
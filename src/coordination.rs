@@ -0,0 +1,321 @@
+use crate::config::parse_duration;
+use crate::hooks::consul::Consul;
+use crate::http;
+use crate::identity;
+use eyre::{eyre, Result, WrapErr};
+use serde_derive::Deserialize;
+use serde_json::json;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How long a lease is held unless released, should its holder not do so
+const DEFAULT_TTL: &str = "10m";
+/// The shortest and longest session TTL Consul accepts
+const TTL_RANGE: (Duration, Duration) = (Duration::from_secs(10), Duration::from_secs(86400));
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// CoordinationConf holds the [settings.coordination] section of the config
+// file
+#[derive(Debug, Deserialize)]
+#[serde(rename = "coordination")]
+pub struct CoordinationConf {
+    pub backend: Option<String>,
+    pub address: Option<String>,
+    pub token: Option<String>,
+    pub key: Option<String>,
+    pub concurrency: Option<usize>,
+    pub lease_ttl: Option<String>,
+}
+
+impl CoordinationConf {
+    /// Will panic if the backend is not consul, the concurrency is 0 or the
+    /// lease_ttl is not one Consul takes
+    pub fn convert(&self, pipeline: &str) -> Coordinator {
+        match self.backend.as_deref() {
+            None | Some("consul") => {}
+            Some(backend) => {
+                eprintln!("Error, unknown coordination backend {}, only consul is", backend);
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+        if self.concurrency == Some(0) {
+            eprintln!("Error, coordination concurrency must be at least 1");
+            std::process::exit(exitcode::CONFIG);
+        }
+        let ttl = parse_duration("lease_ttl", self.lease_ttl.as_deref().unwrap_or(DEFAULT_TTL));
+        if ttl < TTL_RANGE.0 || ttl > TTL_RANGE.1 {
+            eprintln!("Error, lease_ttl must be within {:?} and {:?}", TTL_RANGE.0, TTL_RANGE.1);
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        let key = match &self.key {
+            Some(key) => key.trim_matches('/').to_string(),
+            None => format!("app_config/{}", pipeline),
+        };
+        Coordinator::new(
+            Consul::new(self.address.clone(), self.token.clone()),
+            &key,
+            self.concurrency,
+            ttl,
+        )
+    }
+}
+
+
+// // // // // // // // // // Coordinator // // // // // // // // // //
+
+/// Coordinator:
+/// Where the hosts running the same pipeline agree on who does what: locks
+/// under <key> in Consul's KV store, held through sessions with a <ttl>.
+/// With <concurrency>, no more than that many hosts apply new data at once,
+/// each holding a lease from before its hooks run until they all passed,
+/// health checks included, however long that takes.  A host whose hooks
+/// failed keeps its lease until the ttl is over, holding the rollout back.
+/// The hosts also elect a leader, the only one to run leader_only hooks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coordinator {
+    consul: Consul,
+    key: String,
+    concurrency: Option<usize>,
+    ttl: Duration,
+}
+
+impl Coordinator {
+    pub fn new(
+        consul: Consul,
+        key: &str,
+        concurrency: Option<usize>,
+        ttl: Duration,
+    ) -> Coordinator {
+        Coordinator {
+            consul,
+            key: key.to_string(),
+            concurrency,
+            ttl,
+        }
+    }
+
+    /// How many hosts may apply new data at once, if that is limited
+    pub fn concurrency(&self) -> Option<usize> {
+        self.concurrency
+    }
+
+    /// Take one of the <slots> locks named <name>, if one is free
+    pub fn lease(&self, name: &str, slots: usize) -> Result<Option<Lease>> {
+        let host = identity::lookup("hostname").unwrap_or_default();
+        let session = self.create_session(&format!("app_config {} on {}", self.key, host))?;
+        for slot in 0..slots {
+            let key = format!("{}/{}/{}", self.key, name, slot);
+            if self.acquire(&key, &session, &host)? {
+                return Ok(Some(Lease {
+                    renewal: Renewal::start(self.consul.clone(), session.clone(), self.ttl / 2),
+                    consul: self.consul.clone(),
+                    session,
+                    key,
+                }));
+            }
+        }
+        destroy_session(&self.consul, &session)?;
+        Ok(None)
+    }
+
+    /// Take the lead among the hosts, or keep it, through <session> if it
+    /// is still alive.  Returns the session to keep for the next time, and
+    /// whether this host leads.  Renewing the session on every check keeps
+    /// the lead with the same host, as long as checks come within the ttl.
+    pub fn lead(&self, session: Option<&str>) -> Result<(String, bool)> {
+        let host = identity::lookup("hostname").unwrap_or_default();
+        let session = match session {
+            Some(session) if renew_session(&self.consul, session)? => session.to_string(),
+            _ => self.create_session(&format!("app_config {} leader on {}", self.key, host))?,
+        };
+        let leader = self.acquire(&format!("{}/leader", self.key), &session, &host)?;
+        Ok((session, leader))
+    }
+
+    /// A new session, whose locks are let go once it ends
+    fn create_session(&self, name: &str) -> Result<String> {
+        let url = format!("{}/v1/session/create", self.consul.address());
+        let body = json!({
+            "Name": name,
+            "TTL": format!("{}s", self.ttl.as_secs()),
+            "Behavior": "release",
+            // Free slots are taken again right away
+            "LockDelay": "0s",
+        });
+        let resp = with_token(http::put(&url), &self.consul).send_json(body);
+        let resp = checked(resp, "create a session")?;
+        let reply = resp.into_json().wrap_err("Consul sent an invalid session")?;
+        match reply["ID"].as_str() {
+            Some(id) => Ok(id.to_string()),
+            None => Err(eyre!("Consul sent a session without an ID")),
+        }
+    }
+
+    /// Lock <key> for <session>, returning whether it was free
+    fn acquire(&self, key: &str, session: &str, holder: &str) -> Result<bool> {
+        let url = format!("{}/v1/kv/{}?acquire={}", self.consul.address(), key, session);
+        let resp = with_token(http::put(&url), &self.consul).send_string(holder);
+        let resp = checked(resp, &format!("lock {}", key))?;
+        let reply = resp.into_string().wrap_err("Consul sent an invalid reply")?;
+        Ok(reply.trim() == "true")
+    }
+}
+
+/// Lease:
+/// A lock taken through a Coordinator, held until released.  Its session is
+/// renewed for as long as the Lease is kept, once dropped without being
+/// released the lock is let go when the ttl is over.
+#[derive(Debug)]
+pub struct Lease {
+    consul: Consul,
+    session: String,
+    key: String,
+    renewal: Renewal,
+}
+
+impl Lease {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Let others take the lock
+    pub fn release(self) -> Result<()> {
+        drop(self.renewal);
+        destroy_session(&self.consul, &self.session)
+    }
+}
+
+/// Renewal:
+/// Keeps a session alive, renewing it <every> so often on a thread of its
+/// own until dropped
+#[derive(Debug)]
+struct Renewal {
+    _stop: mpsc::Sender<()>,
+}
+
+impl Renewal {
+    fn start(consul: Consul, session: String, every: Duration) -> Renewal {
+        let (stop, stopped) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                match renew_session(&consul, &session) {
+                    Ok(true) => {}
+                    // Unless the lease was released meanwhile
+                    Ok(false) if stopped.try_recv() == Err(mpsc::TryRecvError::Empty) => {
+                        warning!("Consul session {} ended before its lease was let go", session);
+                        return;
+                    }
+                    Ok(false) => return,
+                    Err(e) => warning!("{:#}", e),
+                }
+            }
+        });
+        Renewal { _stop: stop }
+    }
+}
+
+/// Keep <session> alive for another ttl, returning whether it still was
+fn renew_session(consul: &Consul, session: &str) -> Result<bool> {
+    let url = format!("{}/v1/session/renew/{}", consul.address(), session);
+    let resp = with_token(http::put(&url), consul).call();
+    if resp.status() == 404 {
+        return Ok(false);
+    }
+    checked(resp, "renew a session").map(|_| true)
+}
+
+/// End <session>, letting go of its locks
+fn destroy_session(consul: &Consul, session: &str) -> Result<()> {
+    let url = format!("{}/v1/session/destroy/{}", consul.address(), session);
+    checked(with_token(http::put(&url), consul).call(), "end a session").map(|_| ())
+}
+
+/// <req> authenticated with the token of <consul>, if it has one
+fn with_token(mut req: ureq::Request, consul: &Consul) -> ureq::Request {
+    if let Some(token) = consul.token() {
+        req.set("X-Consul-Token", &token);
+    }
+    req
+}
+
+/// <resp>, if it is a success, the reply to our attempt to <what>
+fn checked(resp: ureq::Response, what: &str) -> Result<ureq::Response> {
+    if let Some(e) = resp.synthetic_error() {
+        return Err(eyre!("Unable to reach Consul to {}: {}", what, e));
+    }
+    if !resp.ok() {
+        return Err(eyre!("Consul refused to {}: {}", what, resp.status_line()));
+    }
+    Ok(resp)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    #[test]
+    fn test_coordination_conf() {
+        let conf: CoordinationConf = toml::from_str(
+            r#"
+            address = "consul.local:8500"
+            concurrency = 2
+            lease_ttl = "5m"
+            "#,
+        )
+        .unwrap();
+        let exp = Coordinator::new(
+            Consul::new(Some("consul.local:8500".to_string()), None),
+            "app_config/web",
+            Some(2),
+            Duration::from_secs(300),
+        );
+        assert_eq!(conf.convert("web"), exp);
+
+        let conf: CoordinationConf = toml::from_str("key = \"/fleet/web/\"").unwrap();
+        let res = conf.convert("web");
+        assert_eq!(res.key, "fleet/web");
+        assert_eq!(res.concurrency(), None);
+        assert_eq!(res.ttl, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_renewal() {
+        // Stands in for Consul, passing on the request line of each request
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let reply = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n[]";
+                stream.write_all(reply.as_bytes()).unwrap();
+                sender.send(line.trim().to_string()).unwrap();
+            }
+        });
+
+        let consul = Consul::new(Some(address), None);
+        let renewal = Renewal::start(consul, "abc".to_string(), Duration::from_millis(10));
+        let timeout = Duration::from_secs(10);
+        for _ in 0..2 {
+            let request = requests.recv_timeout(timeout).unwrap();
+            assert_eq!(request, "PUT /v1/session/renew/abc HTTP/1.1");
+        }
+
+        // Once dropped the session is left to end with its ttl
+        drop(renewal);
+        while requests.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        assert!(requests.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::imds;
+use eyre::{eyre, Result, WrapErr};
+use rusoto_core::credential::{
+    AwsCredentials, ChainProvider, ContainerProvider, EnvironmentProvider, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_sts::WebIdentityProvider;
+use serde_derive::Deserialize;
+use std::cell::RefCell;
+
+/// Where the AWS credentials come from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The default chain: environment, profile, ECS task role, instance role
+    Chain,
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN
+    Env,
+    /// A profile of the shared credentials file
+    Profile,
+    /// A role assumed with the token in AWS_WEB_IDENTITY_TOKEN_FILE, e.g. IRSA
+    WebIdentity,
+    /// The ECS task role
+    Ecs,
+    /// The EC2 instance role, fetched with IMDSv2 only
+    Imdsv2,
+}
+
+impl Source {
+    /// The source as named in error messages
+    fn describe(&self) -> &'static str {
+        match self {
+            Source::Chain => "the default credential chain",
+            Source::Env => "the environment (AWS_ACCESS_KEY_ID)",
+            Source::Profile => "the shared credentials file",
+            Source::WebIdentity => "web identity (AWS_WEB_IDENTITY_TOKEN_FILE, AWS_ROLE_ARN)",
+            Source::Ecs => "the ECS task role",
+            Source::Imdsv2 => "the instance role (IMDSv2)",
+        }
+    }
+}
+
+/// CredentialsConf:
+/// Which credentials calls to AWS are signed with.  Stored under
+/// [settings.aws_credentials] in the config file.
+/// - source: one of chain (default), env, profile, web_identity, ecs, imdsv2
+/// - profile: the profile to use with source = "profile", AWS_PROFILE or
+///   "default" otherwise
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename = "aws_credentials")]
+pub struct CredentialsConf {
+    pub source: Option<Source>,
+    pub profile: Option<String>,
+}
+
+impl CredentialsConf {
+    fn source(&self) -> Source {
+        self.source.unwrap_or(Source::Chain)
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<CredentialsConf> = RefCell::new(CredentialsConf::default());
+}
+
+/// Use <conf> for the calls to AWS made on this thread from now on, like
+/// http::configure() every pipeline can have its own.
+/// Will panic if a profile is given for another source.
+pub fn configure(conf: &CredentialsConf) {
+    if conf.profile.is_some() && conf.source() != Source::Profile {
+        eprintln!("Error, aws_credentials.profile requires source = \"profile\"");
+        std::process::exit(exitcode::CONFIG);
+    }
+    CURRENT.with(|current| *current.borrow_mut() = conf.clone());
+}
+
+/// Credentials for rusoto clients, from the source configured on this thread
+/// Errors name the source when it has none to give.
+pub async fn aws_credentials() -> Result<StaticProvider> {
+    let conf = CURRENT.with(|current| current.borrow().clone());
+    let credentials = match conf.source() {
+        Source::Chain => ChainProvider::new().credentials().await,
+        Source::Env => EnvironmentProvider::default().credentials().await,
+        Source::Profile => match ProfileProvider::new() {
+            Ok(mut provider) => {
+                if let Some(profile) = &conf.profile {
+                    provider.set_profile(profile.clone());
+                }
+                provider.credentials().await
+            }
+            Err(e) => Err(e),
+        },
+        Source::WebIdentity => WebIdentityProvider::from_k8s_env().credentials().await,
+        Source::Ecs => ContainerProvider::new().credentials().await,
+        Source::Imdsv2 => {
+            return imdsv2()
+                .wrap_err_with(|| format!("No AWS credentials from {}", conf.source().describe()))
+        }
+    };
+
+    let credentials: AwsCredentials = credentials
+        .map_err(|e| eyre!("No AWS credentials from {}: {}", conf.source().describe(), e))?;
+    Ok(StaticProvider::new(
+        credentials.aws_access_key_id().to_string(),
+        credentials.aws_secret_access_key().to_string(),
+        credentials.token().clone(),
+        None,
+    ))
+}
+
+/// Credentials of the EC2 instance role, fetched with IMDSv2
+fn imdsv2() -> Result<StaticProvider> {
+    let session = imds::Session::new()?;
+    let get = |role: &str| session.get(&format!("meta-data/iam/security-credentials/{}", role));
+    let role = get("")?;
+    let role = role.lines().next().ok_or_else(|| eyre!("The instance has no role"))?;
+    parse_imds_credentials(&get(role)?)
+}
+
+/// The credentials in the JSON document the metadata service returns for a role
+fn parse_imds_credentials(json: &str) -> Result<StaticProvider> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Document {
+        access_key_id: String,
+        secret_access_key: String,
+        token: Option<String>,
+    }
+
+    let doc: Document =
+        serde_json::from_str(json).wrap_err("Invalid credentials from the instance role")?;
+    Ok(StaticProvider::new(doc.access_key_id, doc.secret_access_key, doc.token, None))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conf() {
+        let conf: CredentialsConf = toml::from_str("").unwrap();
+        assert_eq!(conf.source(), Source::Chain);
+
+        let conf: CredentialsConf =
+            toml::from_str("source = \"profile\"\nprofile = \"prod\"").unwrap();
+        assert_eq!(conf.source(), Source::Profile);
+        assert_eq!(conf.profile, Some("prod".to_string()));
+
+        let conf: CredentialsConf = toml::from_str("source = \"web_identity\"").unwrap();
+        assert_eq!(conf.source(), Source::WebIdentity);
+
+        assert!(toml::from_str::<CredentialsConf>("source = \"imdsv1\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_imds_credentials() {
+        let json = r#"{
+            "Code": "Success",
+            "Type": "AWS-HMAC",
+            "AccessKeyId": "ASIA123",
+            "SecretAccessKey": "s3cr3t",
+            "Token": "session",
+            "Expiration": "2021-01-01T00:00:00Z"
+        }"#;
+        assert!(parse_imds_credentials(json).is_ok());
+
+        let res = parse_imds_credentials(r#"{"Code": "Failure"}"#).unwrap_err();
+        assert!(format!("{:#}", res).contains("Invalid credentials"));
+    }
+}
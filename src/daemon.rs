@@ -0,0 +1,365 @@
+use crate::duration;
+#[cfg(feature = "aws")]
+use crate::listen::Listener;
+use crate::settings::Settings;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often a pipeline is checked, unless settings.interval says otherwise
+const DEFAULT_INTERVAL: &str = "60s";
+/// How often config files are looked at for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before listening again after the queue failed us
+#[cfg(feature = "aws")]
+const LISTEN_RETRY: Duration = Duration::from_secs(30);
+
+/// Schedule:
+/// When the daemon checks a pipeline, from the [settings] of its config
+/// file: every <interval>, as long as it is <enabled>.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    pub enabled: bool,
+    pub interval: Duration,
+}
+
+impl Schedule {
+    /// The schedule in the config file <text>.  Only [settings] is read
+    fn parse(text: &str) -> Result<Schedule> {
+        let settings = settings(text)?;
+        let interval = settings.interval.as_deref().unwrap_or(DEFAULT_INTERVAL);
+        let interval = duration::parse(interval).wrap_err("Invalid settings.interval")?;
+        if interval == Duration::from_secs(0) {
+            return Err(eyre!("settings.interval must be above 0"));
+        }
+        Ok(Schedule {
+            enabled: settings.enabled.unwrap_or(true),
+            interval,
+        })
+    }
+}
+
+/// The [settings] of the config file <text>
+fn settings(text: &str) -> Result<Settings> {
+    let maps: toml::Value = toml::from_str(text)?;
+    match maps.get("settings") {
+        None => Ok(Settings::default()),
+        Some(settings) => Ok(settings.clone().try_into()?),
+    }
+}
+
+/// A pipeline's config as last loaded: its text, found valid, the schedule
+/// in it, and where to listen for checks in between, if anywhere
+#[derive(Clone, Debug)]
+struct Active {
+    contents: String,
+    schedule: Schedule,
+    #[cfg(feature = "aws")]
+    listener: Option<Listener>,
+}
+
+/// The config of one pipeline, shared by its worker and the main thread,
+/// which wakes the worker up when it changes.  <modified> is the time the
+/// file was changed when it was last looked at, valid or not.  <triggered>
+/// asks for a check now, rather than at the next interval.
+struct Slot {
+    active: Mutex<Active>,
+    modified: Mutex<Option<SystemTime>>,
+    triggered: AtomicBool,
+    changed: Condvar,
+}
+
+impl Slot {
+    /// Have the worker check the pipeline as soon as it can
+    fn trigger(&self) {
+        let _active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        self.triggered.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+}
+
+/// Run <check> on the contents of every pipeline config in <files>, each on
+/// its own schedule, until the process is stopped.  Each pipeline has a
+/// thread of its own, so one that is slow, fails or panics does not hold up
+/// the others.
+/// A config is loaded again on SIGHUP, or once its file changes.  It only
+/// takes the place of the one the pipeline has if it is valid, so a broken
+/// edit leaves the pipeline running as it was.  This is also how pipelines
+/// are disabled, enabled, or checked more or less often without a restart.
+/// A pipeline is checked right away on SIGUSR1, e.g. sent by an SSM
+/// command, or on a message to the queue in its [settings.listen].
+/// A check may ask for the next one to come sooner than the interval, after
+/// the Duration it returns, e.g. to apply a change it left waiting.
+/// What <check> works with is made by <prepare> on the worker's thread, as
+/// soon as a config is loaded, and kept until the next one is.
+pub fn run<T, P, F>(files: Vec<String>, prepare: P, check: F) -> Result<()>
+where
+    P: Fn(&str, &str) -> T + Send + Sync + 'static,
+    F: Fn(&str, &mut T) -> Result<Option<Duration>> + Send + Sync + 'static,
+{
+    let prepare = Arc::new(prepare);
+    let check = Arc::new(check);
+    let mut slots = Vec::new();
+    for file in &files {
+        let modified = modified(file);
+        let slot = Arc::new(Slot {
+            active: Mutex::new(load(file)?),
+            modified: Mutex::new(modified),
+            triggered: AtomicBool::new(false),
+            changed: Condvar::new(),
+        });
+        slots.push((file.clone(), slot.clone()));
+
+        #[cfg(feature = "aws")]
+        {
+            let (listened, listening) = (file.clone(), slot.clone());
+            std::thread::spawn(move || listen(&listened, &listening));
+        }
+
+        let file = file.clone();
+        let (prepare, check) = (prepare.clone(), check.clone());
+        std::thread::spawn(move || worker(&file, &slot, prepare.as_ref(), check.as_ref()));
+    }
+
+    watch(&slots)
+}
+
+/// Read the config in <file>, and validate it the way `app_config validate`
+/// does.  That runs in a process of its own, as a config error ends the
+/// process it is found in.
+fn load(file: &str) -> Result<Active> {
+    let read = || {
+        std::fs::read_to_string(tilde(file).to_string())
+            .wrap_err_with(|| format!("Could not open {}", file))
+    };
+    let contents = read()?;
+    let schedule =
+        Schedule::parse(&contents).wrap_err_with(|| format!("Invalid schedule in {}", file))?;
+
+    let exe = std::env::current_exe().wrap_err("Unable to find our own executable")?;
+    let output = std::process::Command::new(exe)
+        .arg("validate")
+        .arg("-f")
+        .arg(file)
+        .output()
+        .wrap_err_with(|| format!("Unable to validate {}", file))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{}", stderr.trim()));
+    }
+
+    // What was validated must be what we run
+    if read()? != contents {
+        return Err(eyre!("{} changed while it was loaded", file));
+    }
+    Ok(Active {
+        #[cfg(feature = "aws")]
+        listener: settings(&contents)?.listen.map(|listen| listen.convert()),
+        contents,
+        schedule,
+    })
+}
+
+/// When <file> was last changed, if that can be told
+fn modified(file: &str) -> Option<SystemTime> {
+    std::fs::metadata(tilde(file).to_string()).and_then(|m| m.modified()).ok()
+}
+
+/// Check the pipeline in <file> whenever it is due, forever.  The config
+/// loaded is prepared right away, not when it is next due.
+fn worker<T, P, F>(file: &str, slot: &Slot, prepare: &P, check: &F)
+where
+    P: Fn(&str, &str) -> T,
+    F: Fn(&str, &mut T) -> Result<Option<Duration>>,
+{
+    let mut last_run: Option<Instant> = None;
+    // When the last check asked for the next one, if sooner than the interval
+    let mut asked: Option<Instant> = None;
+    // The contents last prepared, and what was made of them
+    let mut prepared: Option<(String, Option<T>)> = None;
+    loop {
+        let (contents, due) = {
+            let mut active = slot.active.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if prepared.as_ref().map(|(contents, _)| contents) != Some(&active.contents) {
+                    break (active.contents.clone(), false);
+                }
+                let schedule = active.schedule;
+                let now = Instant::now();
+                let due = match (last_run, asked) {
+                    (Some(last_run), Some(asked)) => asked.min(last_run + schedule.interval),
+                    (Some(last_run), None) => last_run + schedule.interval,
+                    (None, _) => now,
+                };
+                let triggered = || slot.triggered.swap(false, Ordering::SeqCst);
+                if schedule.enabled && (triggered() || due <= now) {
+                    break (active.contents.clone(), true);
+                }
+                active = match schedule.enabled {
+                    true => match slot.changed.wait_timeout(active, due - now) {
+                        Ok((active, _)) => active,
+                        Err(e) => e.into_inner().0,
+                    },
+                    false => slot.changed.wait(active).unwrap_or_else(|e| e.into_inner()),
+                };
+            }
+        };
+
+        if !due {
+            let made = panic::catch_unwind(AssertUnwindSafe(|| prepare(file, &contents)));
+            if made.is_err() {
+                error!("pipeline {} panicked while it was loaded", file);
+            }
+            prepared = Some((contents, made.ok()));
+            continue;
+        }
+
+        last_run = Some(Instant::now());
+        asked = None;
+        let pipeline = match prepared.as_mut() {
+            Some((_, Some(pipeline))) => pipeline,
+            _ => {
+                error!("pipeline {} failed: its config could not be loaded", file);
+                continue;
+            }
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| check(file, pipeline))) {
+            Ok(Ok(next)) => asked = next.map(|next| Instant::now() + next),
+            Ok(Err(e)) => error!("pipeline {} failed: {:#}", file, e),
+            Err(_) => error!("pipeline {} panicked", file),
+        }
+    }
+}
+
+/// Trigger a check of the pipeline in <file> whenever a message comes to
+/// the queue it listens to, forever
+#[cfg(feature = "aws")]
+fn listen(file: &str, slot: &Slot) {
+    loop {
+        let listener = slot.active.lock().unwrap_or_else(|e| e.into_inner()).listener.clone();
+        let listener = match listener {
+            Some(listener) => listener,
+            // Until a reload gives it one
+            None => {
+                std::thread::sleep(WATCH_INTERVAL);
+                continue;
+            }
+        };
+
+        match listener.wait() {
+            Ok(true) => {
+                info!("Pipeline {} triggered", file);
+                slot.trigger();
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warning!("{} not listening, trying again in {:?}: {:#}", file, LISTEN_RETRY, e);
+                std::thread::sleep(LISTEN_RETRY);
+            }
+        }
+    }
+}
+
+/// Reload every config on SIGHUP, and any that changed every WATCH_INTERVAL.
+/// Trigger a check of every pipeline on SIGUSR1.
+#[tokio::main]
+async fn watch(slots: &[(String, Arc<Slot>)]) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup()).wrap_err("Unable to handle SIGHUP")?;
+    let mut triggers =
+        signal(SignalKind::user_defined1()).wrap_err("Unable to handle SIGUSR1")?;
+    loop {
+        let (hangup, trigger) = tokio::select! {
+            res = hangups.recv() => (res.is_some(), false),
+            res = triggers.recv() => (false, res.is_some()),
+            _ = tokio::time::delay_for(WATCH_INTERVAL) => (false, false),
+        };
+        for (file, slot) in slots {
+            reload(file, slot, hangup);
+        }
+        if trigger {
+            info!("Checking every pipeline now");
+            for (_, slot) in slots {
+                slot.trigger();
+            }
+        }
+    }
+}
+
+/// Load the config in <file> again if it changed, or in any case with
+/// <force>, and give it to its pipeline if it is valid
+fn reload(file: &str, slot: &Slot, force: bool) {
+    let modified = modified(file);
+    {
+        let mut seen = slot.modified.lock().unwrap_or_else(|e| e.into_inner());
+        if !force && *seen == modified {
+            return;
+        }
+        *seen = modified;
+    }
+
+    let new = match load(file) {
+        Ok(new) => new,
+        Err(e) => {
+            warning!("keeping the config {} had: {:#}", file, e);
+            return;
+        }
+    };
+
+    let mut active = slot.active.lock().unwrap_or_else(|e| e.into_inner());
+    if active.contents != new.contents {
+        info!("Reloaded {}", file);
+    }
+    if active.schedule != new.schedule {
+        match new.schedule.enabled {
+            true => info!("Pipeline {} enabled, every {:?}", file, new.schedule.interval),
+            false => info!("Pipeline {} disabled", file),
+        }
+    }
+    *active = new;
+    slot.changed.notify_all();
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "aws")]
+    use rusoto_core::Region;
+
+    #[test]
+    fn test_schedule() {
+        let exp = Schedule {
+            enabled: true,
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(Schedule::parse("[providers.mock]\ndata = \"\"").unwrap(), exp);
+
+        let exp = Schedule {
+            enabled: false,
+            interval: Duration::from_secs(300),
+        };
+        let res = Schedule::parse("[settings]\ninterval = \"5m\"\nenabled = false").unwrap();
+        assert_eq!(res, exp);
+
+        assert!(Schedule::parse("[settings]\ninterval = \"0s\"").is_err());
+        assert!(Schedule::parse("[settings]\ninterval = \"often\"").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "aws")]
+    fn test_listener() {
+        let queue_url = "https://sqs.eu-west-1.amazonaws.com/123456789012/app";
+        let text = format!(
+            "[settings.listen]\nqueue_url = \"{}\"\nregion = \"eu-west-1\"",
+            queue_url
+        );
+        let listen = settings(&text).unwrap().listen.unwrap();
+        assert_eq!(listen.convert(), Listener::new(queue_url, Region::EuWest1));
+
+        assert!(settings("[providers.mock]\ndata = \"\"").unwrap().listen.is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::hooks::formats;
+use crate::hooks::template::DataType;
+use crate::hooks::sha256;
+use crate::spool::{Payload, Spool};
+use eyre::{Result, WrapErr};
+
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use zeroize::Zeroize;
+
+/// ConfigData:
+/// One version of the configuration, as received from the provider and
+/// handed to every hook.  Besides the raw bytes it carries what hooks need to
+/// know about them: their hash, the provider's version and what it was
+/// labelled or described with, which provider it came from and when.  The
+/// data is only parsed when a hook asks for it, and only once per format,
+/// hooks then share what was parsed.
+/// Data taken out of a larger document, as with settings.for_each, is
+/// already parsed: its <value> is what hooks get whichever format they ask
+/// for.
+/// During a run it comes with the <workspace> of the run, where hooks keep
+/// what they make along the way, with the <traceparent> of the run's trace
+/// when it is traced, and with the data applied before it as <previous>,
+/// for hooks that want it.  It also comes with the <manifest> of the files
+/// the pipeline's hooks wrote before, for them to tell what is theirs, and
+/// the <outcomes> of the hooks run on it so far.
+/// Data that is <sensitive> is wiped from memory once dropped.  Debug never
+/// shows the data itself, only its size and hash.
+/// Large data may be spooled to a temp file rather than held on the heap.
+pub struct ConfigData {
+    raw: Payload,
+    sha256: String,
+    version: Option<String>,
+    version_info: VersionInfo,
+    provider: String,
+    received: DateTime<Utc>,
+    parsed: RefCell<BTreeMap<String, Arc<serde_yaml::Value>>>,
+    value: Option<Arc<serde_yaml::Value>>,
+    sensitive: bool,
+    workspace: Option<PathBuf>,
+    traceparent: Option<String>,
+    previous: Option<Rc<ConfigData>>,
+    manifest: Rc<Vec<String>>,
+    outcomes: Rc<RefCell<Vec<(String, String)>>>,
+}
+
+impl ConfigData {
+    /// Wrap <raw> received just now from <provider>
+    pub fn new<T: Into<Vec<u8>>>(raw: T, provider: &str, version: Option<String>) -> ConfigData {
+        let raw = raw.into();
+        ConfigData {
+            sha256: sha256(&raw),
+            raw: Payload::Memory(raw),
+            version,
+            version_info: VersionInfo::default(),
+            provider: provider.to_string(),
+            received: Utc::now(),
+            parsed: RefCell::new(BTreeMap::new()),
+            value: None,
+            sensitive: false,
+            workspace: None,
+            traceparent: None,
+            previous: None,
+            manifest: Rc::new(Vec::new()),
+            outcomes: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Flag the data as <sensitive>, or not
+    pub fn sensitive(mut self, sensitive: bool) -> ConfigData {
+        self.sensitive = sensitive;
+        self
+    }
+
+    /// Wrap what was written to <spool>, as ConfigData::new() does
+    pub fn spooled(spool: Spool, provider: &str, version: Option<String>) -> Result<ConfigData> {
+        let mut data = ConfigData::new(Vec::new(), provider, version);
+        data.raw = spool.finish()?;
+        data.sha256 = sha256(data.raw());
+        Ok(data)
+    }
+
+    /// Describe the provider's version of the data with <info>
+    pub fn with_version_info(mut self, info: VersionInfo) -> ConfigData {
+        self.version_info = info;
+        self
+    }
+
+    /// Hand the data to hooks along with the <workspace> of the run
+    pub fn in_workspace(mut self, workspace: &Path) -> ConfigData {
+        self.workspace = Some(workspace.to_path_buf());
+        self
+    }
+
+    /// Hand the data to hooks along with the <traceparent> of the run, for
+    /// the commands they run to continue its trace
+    pub fn with_traceparent(mut self, traceparent: String) -> ConfigData {
+        self.traceparent = Some(traceparent);
+        self
+    }
+
+    /// Hand the data to hooks along with the data applied before it
+    pub fn with_previous(mut self, previous: ConfigData) -> ConfigData {
+        self.previous = Some(Rc::new(previous));
+        self
+    }
+
+    /// Hand the data to hooks along with the <manifest> of the files the
+    /// pipeline's hooks wrote before, as absolute paths
+    pub fn with_manifest(mut self, manifest: Vec<String>) -> ConfigData {
+        self.manifest = Rc::new(manifest);
+        self
+    }
+
+    /// Wrap <value>, a part of <data>.  Hooks that want the raw data get
+    /// strings as they are and anything else as json.
+    pub fn from_value(value: serde_yaml::Value, data: &ConfigData) -> Result<ConfigData> {
+        let raw = match &value {
+            serde_yaml::Value::String(s) => s.clone().into_bytes(),
+            value => serde_json::to_vec(value)?,
+        };
+        Ok(ConfigData {
+            sha256: sha256(&raw),
+            raw: Payload::Memory(raw),
+            version: data.version.clone(),
+            version_info: data.version_info.clone(),
+            provider: data.provider.clone(),
+            received: data.received,
+            parsed: RefCell::new(BTreeMap::new()),
+            value: Some(Arc::new(value)),
+            sensitive: data.sensitive,
+            workspace: data.workspace.clone(),
+            traceparent: data.traceparent.clone(),
+            previous: data.previous.clone(),
+            manifest: Rc::clone(&data.manifest),
+            outcomes: Rc::clone(&data.outcomes),
+        })
+    }
+
+    /// The data exactly as received
+    pub fn raw(&self) -> &[u8] {
+        self.raw.as_slice()
+    }
+
+    /// The data as text, for hooks that can only deal with text
+    pub fn text(&self) -> Result<&str> {
+        std::str::from_utf8(self.raw()).wrap_err("Data is not valid UTF-8 text")
+    }
+
+    /// Hex encoded sha256 hash of the raw data
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// The provider's version of the data, if it has a notion of versions
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// The label of the provider's version of the data, if it has one
+    pub fn version_label(&self) -> Option<&str> {
+        self.version_info.label.as_deref()
+    }
+
+    /// What the provider's version of the data was described with
+    pub fn version_description(&self) -> Option<&str> {
+        self.version_info.description.as_deref()
+    }
+
+    /// The version of the data as people know it, e.g. "v42: 'raise pool
+    /// size'", by its label or else its number.  None without either.
+    pub fn describe_version(&self) -> Option<String> {
+        let version = match (self.version_label(), self.version()) {
+            (Some(label), _) => label.to_string(),
+            (None, Some(version)) => format!("v{}", version),
+            (None, None) => return None,
+        };
+        match self.version_description() {
+            Some(description) => Some(format!("{}: '{}'", version, description)),
+            None => Some(version),
+        }
+    }
+
+    /// Kind of the provider the data came from, e.g. "appconfig"
+    pub fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    /// The workspace of the run, if the data is handed to hooks by one
+    pub fn workspace(&self) -> Option<&Path> {
+        self.workspace.as_deref()
+    }
+
+    /// The variables commands run by hooks get from the run: the workspace,
+    /// and the traceparent for them to continue the run's trace
+    pub fn run_env(&self) -> Vec<(&'static str, OsString)> {
+        let mut env = Vec::new();
+        if let Some(dir) = &self.workspace {
+            env.push((crate::workspace::ENV_VAR, dir.clone().into_os_string()));
+        }
+        if let Some(traceparent) = &self.traceparent {
+            env.push(("TRACEPARENT", traceparent.into()));
+        }
+        env
+    }
+
+    /// The data applied before this data, if the run was given it
+    pub fn previous(&self) -> Option<&ConfigData> {
+        self.previous.as_deref()
+    }
+
+    /// Whether <path> is in the manifest, written by the pipeline before
+    pub fn in_manifest(&self, path: &str) -> bool {
+        let path = match std::env::current_dir() {
+            Ok(dir) => dir.join(path).to_string_lossy().to_string(),
+            Err(_) => path.to_string(),
+        };
+        self.manifest.contains(&path)
+    }
+
+    /// Record that the hook <name> ran on the data, or a part of it, with
+    /// <status>, e.g. "ok"
+    pub fn record_outcome(&self, name: &str, status: &str) {
+        self.outcomes.borrow_mut().push((name.to_string(), status.to_string()));
+    }
+
+    /// The names and statuses of the hooks run on the data so far, in the
+    /// order they ran.  Parts of the data share them with the whole.
+    pub fn outcomes(&self) -> Vec<(String, String)> {
+        self.outcomes.borrow().clone()
+    }
+
+    /// When the data was received
+    pub fn received(&self) -> DateTime<Utc> {
+        self.received
+    }
+
+    /// The data parsed as <format>
+    pub fn parsed(&self, format: &DataType) -> Result<Arc<serde_yaml::Value>> {
+        if let Some(value) = &self.value {
+            return Ok(Arc::clone(value));
+        }
+
+        let key = format!("{:?}", format);
+        if let Some(value) = self.parsed.borrow().get(&key) {
+            return Ok(Arc::clone(value));
+        }
+
+        let value = Arc::new(formats::parse(format, self.text()?)?);
+        self.parsed.borrow_mut().insert(key, Arc::clone(&value));
+        Ok(value)
+    }
+}
+
+/// VersionInfo:
+/// What a provider knows of a version of the data besides its number, for
+/// providers whose versions are labelled or described when published
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VersionInfo {
+    pub label: Option<String>,
+    pub description: Option<String>,
+}
+
+impl std::fmt::Debug for ConfigData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ConfigData")
+            .field("bytes", &self.raw().len())
+            .field("spooled", &matches!(self.raw, Payload::Spooled(_)))
+            .field("sha256", &self.sha256)
+            .field("version", &self.version)
+            .field("version_info", &self.version_info)
+            .field("provider", &self.provider)
+            .field("received", &self.received)
+            .field("sensitive", &self.sensitive)
+            .field("workspace", &self.workspace)
+            .field("traceparent", &self.traceparent)
+            .field("previous", &self.previous.as_ref().map(|previous| previous.sha256()))
+            .field("manifest", &self.manifest.len())
+            .field("outcomes", &self.outcomes.borrow())
+            .finish()
+    }
+}
+
+impl Drop for ConfigData {
+    fn drop(&mut self) {
+        if let (true, Payload::Memory(raw)) = (self.sensitive, &mut self.raw) {
+            raw.zeroize();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_config_data() {
+        let data = ConfigData::new("hosts: [web]", "mock", Some("3".to_string()));
+
+        assert_eq!(data.raw(), b"hosts: [web]");
+        assert_eq!(data.text().unwrap(), "hosts: [web]");
+        assert_eq!(data.sha256(), sha256(b"hosts: [web]"));
+        assert_eq!(data.version(), Some("3"));
+        assert_eq!(data.provider(), "mock");
+
+        let parsed = data.parsed(&DataType::YAML).unwrap();
+        assert_eq!(parsed["hosts"][0], serde_yaml::Value::from("web"));
+        assert_eq!(data.parsed.borrow().len(), 1);
+        // Hooks share what was parsed
+        assert!(Arc::ptr_eq(&parsed, &data.parsed(&DataType::YAML).unwrap()));
+        assert!(data.parsed(&DataType::TOML).is_err());
+    }
+
+    #[test]
+    fn test_from_value() {
+        let data = ConfigData::new("hosts: [web]", "mock", Some("3".to_string()));
+
+        let value: serde_yaml::Value = serde_yaml::from_str("{name: web, port: 80}").unwrap();
+        let element = ConfigData::from_value(value.clone(), &data).unwrap();
+        assert_eq!(element.text().unwrap(), r#"{"name":"web","port":80}"#);
+        assert_eq!(*element.parsed(&DataType::TOML).unwrap(), value);
+        assert_eq!(element.version(), Some("3"));
+        assert_eq!(element.received(), data.received());
+        assert_eq!(element.describe_version(), Some("v3".to_string()));
+
+        let element = ConfigData::from_value(serde_yaml::Value::from("web"), &data).unwrap();
+        assert_eq!(element.text().unwrap(), "web");
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let data = ConfigData::new("hosts: [web]", "mock", None);
+        data.record_outcome("template", "ok");
+        let element = ConfigData::from_value(serde_yaml::Value::from("web"), &data).unwrap();
+        element.record_outcome("command", "error");
+
+        let exp = vec![
+            ("template".to_string(), "ok".to_string()),
+            ("command".to_string(), "error".to_string()),
+        ];
+        assert_eq!(data.outcomes(), exp);
+    }
+
+    #[test]
+    fn test_run_env() {
+        assert!(ConfigData::new("", "mock", None).run_env().is_empty());
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let data = ConfigData::new("hosts: [web]", "mock", None)
+            .in_workspace(Path::new("/tmp/run"))
+            .with_traceparent(traceparent.to_string());
+        let element = ConfigData::from_value(serde_yaml::Value::from("web"), &data).unwrap();
+        let exp = vec![
+            ("APP_CONFIG_WORKSPACE", OsString::from("/tmp/run")),
+            ("TRACEPARENT", OsString::from(traceparent)),
+        ];
+        assert_eq!(element.run_env(), exp);
+        // Nothing of the trace is left in the environment of app_config
+        assert!(std::env::var("TRACEPARENT").is_err());
+    }
+
+    #[test]
+    fn test_describe_version() {
+        let info = |label: Option<&str>, description: Option<&str>| VersionInfo {
+            label: label.map(String::from),
+            description: description.map(String::from),
+        };
+        let data = ConfigData::new("hosts: [web]", "mock", Some("42".to_string()));
+        let data = data.with_version_info(info(None, Some("raise pool size")));
+        assert_eq!(data.describe_version(), Some("v42: 'raise pool size'".to_string()));
+        let element = ConfigData::from_value(serde_yaml::Value::from("web"), &data).unwrap();
+        assert_eq!(element.version_description(), Some("raise pool size"));
+
+        let data = data.with_version_info(info(Some("2024-06-rc1"), None));
+        assert_eq!(data.describe_version(), Some("2024-06-rc1".to_string()));
+        let data = ConfigData::new("hosts: [web]", "mock", None);
+        assert_eq!(data.describe_version(), None);
+    }
+
+    #[test]
+    fn test_spooled() {
+        let spooled = |threshold| {
+            let mut spool = Spool::new(threshold);
+            spool.write_all(b"hosts: [web]").unwrap();
+            ConfigData::spooled(spool, "mock", None).unwrap()
+        };
+        let data = spooled(Some(4));
+        assert!(matches!(data.raw, Payload::Spooled(_)));
+        assert_eq!(data.text().unwrap(), "hosts: [web]");
+        assert_eq!(data.sha256(), sha256(b"hosts: [web]"));
+
+        let data = spooled(Some(64));
+        assert!(matches!(data.raw, Payload::Memory(_)));
+        assert_eq!(data.sha256(), sha256(b"hosts: [web]"));
+    }
+
+    #[test]
+    fn test_debug() {
+        let data = ConfigData::new("password: hunter2", "mock", None).sensitive(true);
+        let debug = format!("{:?}", data);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(debug.contains(data.sha256()), "{}", debug);
+
+        // Parts of sensitive data are sensitive too
+        let value = serde_yaml::Value::from("hunter2");
+        assert!(ConfigData::from_value(value, &data).unwrap().sensitive);
+    }
+
+    #[test]
+    fn test_binary() {
+        let data = ConfigData::new(vec![0x1f, 0x8b, 0xff], "mock", None);
+        assert_eq!(data.raw().len(), 3);
+        assert!(data.text().is_err());
+    }
+}
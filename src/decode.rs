@@ -0,0 +1,196 @@
+use eyre::{eyre, Result, WrapErr};
+use serde_derive::Deserialize;
+
+use flate2::read::GzDecoder;
+use std::io::{Read, Write};
+
+/// Decoder:
+/// One stage of the decode chain set with `decode = [...]` on the provider.
+/// Stages run in the order given, so a payload that was gzipped and then
+/// base64 encoded is decoded with `decode = ["base64", "gzip"]`.
+/// - base64: standard alphabet, whitespace is ignored
+/// - gzip: a single gzip stream
+/// - json: a JSON document holding just a string, which is unwrapped
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decoder {
+    Base64,
+    Gzip,
+    Json,
+}
+
+impl Decoder {
+    /// Name of the stage as given in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Decoder::Base64 => "base64",
+            Decoder::Gzip => "gzip",
+            Decoder::Json => "json",
+        }
+    }
+
+    /// Run this stage over <data>, writing what it gives to <out>.  Fails
+    /// once that is more than <max> bytes, a gzip stream is only inflated
+    /// that far.
+    pub fn decode_to<W: Write>(&self, data: &[u8], max: Option<usize>, mut out: W) -> Result<()> {
+        let decoded = match self {
+            Decoder::Base64 => {
+                // Encoded payloads often come with a trailing newline
+                let trimmed: Vec<u8> =
+                    data.iter().filter(|b| !b.is_ascii_whitespace()).cloned().collect();
+                base64::decode(&trimmed).wrap_err("Data is not valid base64")?
+            }
+            Decoder::Gzip => {
+                let limit = max.map_or(u64::MAX, |max| max as u64 + 1);
+                let mut inflated = GzDecoder::new(data).take(limit);
+                let mut buf = vec![0; 64 * 1024];
+                let mut size = 0;
+                loop {
+                    let read = inflated.read(&mut buf).wrap_err("Data is not valid gzip")?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    size += read;
+                    within(size, max)?;
+                    out.write_all(&buf[..read]).wrap_err("Unable to write decoded data")?;
+                }
+            }
+            Decoder::Json => match serde_json::from_slice(data) {
+                Ok(serde_json::Value::String(s)) => s.into_bytes(),
+                Ok(_) => return Err(eyre!("Data is JSON, but not a string")),
+                Err(e) => return Err(e).wrap_err("Data is not valid JSON"),
+            },
+        };
+        within(decoded.len(), max)?;
+        out.write_all(&decoded).wrap_err("Unable to write decoded data")
+    }
+
+    /// Run this stage over <data>, see decode_to()
+    pub fn decode(&self, data: &[u8], max: Option<usize>) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.decode_to(data, max, &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Fail if data of <size> bytes is more than <max>
+fn within(size: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if size > max => {
+            Err(eyre!("Data is more than max_payload_size {} bytes once decoded", max))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Run <data> through every stage of <chain> in turn, none of which may
+/// give more than <max> bytes
+pub fn decode(chain: &[Decoder], data: Vec<u8>, max: Option<usize>) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decode_to(chain, data, max, &mut out)?;
+    Ok(out)
+}
+
+/// Run <data> through every stage of <chain> in turn, as decode() does.
+/// What the last one gives is written to <out> as it is decoded, rather
+/// than held whole on the heap first.
+pub fn decode_to<W: Write>(
+    chain: &[Decoder],
+    data: Vec<u8>,
+    max: Option<usize>,
+    mut out: W,
+) -> Result<()> {
+    let (last, stages) = match chain.split_last() {
+        Some(split) => split,
+        None => return out.write_all(&data).wrap_err("Unable to write decoded data"),
+    };
+    let failed = |i: usize, stage: &Decoder| {
+        format!("Decode stage {} of {} ({}) failed", i + 1, chain.len(), stage.name())
+    };
+    let mut data = data;
+    for (i, stage) in stages.iter().enumerate() {
+        data = stage.decode(&data, max).wrap_err_with(|| failed(i, stage))?;
+    }
+    last.decode_to(&data, max, out).wrap_err_with(|| failed(stages.len(), last))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let payload = base64::encode(gzip(b"hosts: [web]")) + "\n";
+        let chain = vec![Decoder::Base64, Decoder::Gzip];
+        let res = decode(&chain, payload.into_bytes(), None).unwrap();
+        assert_eq!(res, b"hosts: [web]");
+
+        let res = decode(&[], vec![0xff, 0x00], None).unwrap();
+        assert_eq!(res, vec![0xff, 0x00]);
+    }
+
+    #[test]
+    fn test_json() {
+        let payload = serde_json::to_vec(&base64::encode(gzip(b"hosts: [web]"))).unwrap();
+        let chain = vec![Decoder::Json, Decoder::Base64, Decoder::Gzip];
+        let res = decode(&chain, payload, None).unwrap();
+        assert_eq!(res, b"hosts: [web]");
+
+        let res = decode(&[Decoder::Json], br#""a\nb \"c\"""#.to_vec(), None).unwrap();
+        assert_eq!(res, b"a\nb \"c\"");
+
+        let res = decode(&[Decoder::Json], br#"{"data": "abc"}"#.to_vec(), None).unwrap_err();
+        assert!(format!("{:#}", res).contains("not a string"));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let res = decode(&[Decoder::Base64], b"not base64!".to_vec(), None).unwrap_err();
+        let res = format!("{:#}", res);
+        assert!(res.starts_with("Decode stage 1 of 1 (base64) failed: "), "{}", res);
+        assert!(res.contains("Data is not valid base64"), "{}", res);
+
+        let chain = vec![Decoder::Base64, Decoder::Gzip];
+        let res = decode(&chain, base64::encode("hosts: [web]").into_bytes(), None).unwrap_err();
+        let res = format!("{:#}", res);
+        assert!(res.starts_with("Decode stage 2 of 2 (gzip) failed: "), "{}", res);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let bomb = gzip(&vec![b'a'; 1 << 20]);
+        let res = decode(&[Decoder::Gzip], bomb.clone(), Some(1024)).unwrap_err();
+        let res = format!("{:#}", res);
+        assert!(res.contains("more than max_payload_size 1024 bytes"), "{}", res);
+        assert_eq!(decode(&[Decoder::Gzip], bomb, None).unwrap().len(), 1 << 20);
+
+        let payload = gzip(b"hosts: [web]");
+        assert_eq!(decode(&[Decoder::Gzip], payload.clone(), Some(12)).unwrap(), b"hosts: [web]");
+        assert!(decode(&[Decoder::Gzip], payload, Some(11)).is_err());
+        let payload = base64::encode("hosts: [web]").into_bytes();
+        assert!(decode(&[Decoder::Base64], payload, Some(11)).is_err());
+
+        let mut out = Vec::new();
+        let chain = vec![Decoder::Base64, Decoder::Gzip];
+        let payload = base64::encode(gzip(b"hosts: [web]")).into_bytes();
+        decode_to(&chain, payload, Some(64), &mut out).unwrap();
+        assert_eq!(out, b"hosts: [web]");
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let maps: toml::Value = toml::from_str(r#"decode = ["json", "base64", "gzip"]"#).unwrap();
+        let chain: Vec<Decoder> = maps["decode"].clone().try_into().unwrap();
+        assert_eq!(chain, vec![Decoder::Json, Decoder::Base64, Decoder::Gzip]);
+    }
+}
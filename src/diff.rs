@@ -0,0 +1,300 @@
+use crate::output;
+use difference::{Changeset, Difference};
+use serde_yaml::Value;
+
+// Rendering of the changes between two versions of a text, as check
+// --interactive shows them before a file is written.  Structured data can
+// be compared by what it holds rather than line by line, so keys moving
+// around or a change of formatting are not shown as changes.
+
+/// Lines of unchanged text shown around each change, unless told otherwise
+pub const CONTEXT: usize = 3;
+/// Widest the old text gets in a side by side diff, longer lines are cut
+const COLUMN_MAX: usize = 60;
+
+/// How the changes are laid out: one text after the other, as with
+/// `diff -u`, or the old text on the left and the new one on the right
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    Unified,
+    SideBySide,
+}
+
+/// One line of either text
+#[derive(Debug, PartialEq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines changed between <old> and <new>, with <context> unchanged
+/// lines around them, laid out as per <layout>
+pub fn render(old: &str, new: &str, layout: Layout, context: usize) -> String {
+    // Lines end in a newline, rather than being separated by one
+    let strip = |text: &str| text.strip_suffix('\n').unwrap_or(text).to_string();
+    let changeset = Changeset::new(&strip(old), &strip(new), "\n");
+    let lines = lines(&changeset);
+    let shown = shown(&lines, context);
+
+    match layout {
+        Layout::Unified => unified(&lines, &shown),
+        Layout::SideBySide => side_by_side(&lines, &shown),
+    }
+}
+
+/// Every line of the changeset, in order
+fn lines(changeset: &Changeset) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    for change in &changeset.diffs {
+        match change {
+            Difference::Same(text) => lines.extend(text.split('\n').map(Line::Same)),
+            Difference::Rem(text) => lines.extend(text.split('\n').map(Line::Removed)),
+            Difference::Add(text) => lines.extend(text.split('\n').map(Line::Added)),
+        }
+    }
+    lines
+}
+
+/// The indexes of the lines to show, the changed ones and up to <context>
+/// lines around them.  None stands for lines left out.
+fn shown(lines: &[Line], context: usize) -> Vec<Option<usize>> {
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|&i| !matches!(lines[i], Line::Same(_)))
+        .collect();
+    let near = |i: usize| changed.iter().any(|&c| c.max(i) - c.min(i) <= context);
+
+    let mut shown = Vec::new();
+    for i in 0..lines.len() {
+        if near(i) {
+            shown.push(Some(i));
+        } else if shown.last() != Some(&None) {
+            shown.push(None);
+        }
+    }
+    shown
+}
+
+fn unified(lines: &[Line], shown: &[Option<usize>]) -> String {
+    let mut out = String::new();
+    for i in shown {
+        match i.map(|i| &lines[i]) {
+            None => out.push_str("..."),
+            Some(Line::Same(line)) => out.push_str(&format!(" {}", line)),
+            Some(Line::Removed(line)) => {
+                out.push_str(&output::paint(&format!("-{}", line), output::RED))
+            }
+            Some(Line::Added(line)) => {
+                out.push_str(&output::paint(&format!("+{}", line), output::GREEN))
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn side_by_side(lines: &[Line], shown: &[Option<usize>]) -> String {
+    let width = lines
+        .iter()
+        .filter_map(|line| match line {
+            Line::Same(text) | Line::Removed(text) => Some(text.chars().count()),
+            Line::Added(_) => None,
+        })
+        .max()
+        .unwrap_or(0)
+        .min(COLUMN_MAX);
+    let column = |text: &str| {
+        let text: String = text.chars().take(width).collect();
+        format!("{:width$}", text, width = width)
+    };
+
+    let mut rows: Vec<String> = Vec::new();
+    let mut removed: Vec<&str> = Vec::new();
+    let mut added: Vec<&str> = Vec::new();
+    // Removed lines face the lines added in their place
+    let flush = |removed: &mut Vec<&str>, added: &mut Vec<&str>, rows: &mut Vec<String>| {
+        for i in 0..removed.len().max(added.len()) {
+            let left = match removed.get(i) {
+                Some(line) => output::paint(&column(line), output::RED),
+                None => column(""),
+            };
+            let (mark, right) = match (removed.get(i), added.get(i)) {
+                (_, Some(line)) if i < removed.len() => ('|', output::paint(line, output::GREEN)),
+                (_, Some(line)) => ('>', output::paint(line, output::GREEN)),
+                (_, None) => ('<', String::new()),
+            };
+            rows.push(format!("{} {} {}", left, mark, right).trim_end().to_string());
+        }
+        removed.clear();
+        added.clear();
+    };
+
+    for i in shown {
+        match i.map(|i| &lines[i]) {
+            Some(Line::Removed(line)) => removed.push(line),
+            Some(Line::Added(line)) => added.push(line),
+            other => {
+                flush(&mut removed, &mut added, &mut rows);
+                match other {
+                    Some(Line::Same(line)) => {
+                        rows.push(format!("{}   {}", column(line), line).trim_end().to_string())
+                    }
+                    _ => rows.push("...".to_string()),
+                }
+            }
+        }
+    }
+    flush(&mut removed, &mut added, &mut rows);
+
+    rows.iter().map(|row| format!("{}\n", row)).collect()
+}
+
+/// A change between two versions of structured data, at a jq style path
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(String, Value),
+    Removed(String, Value),
+    Changed(String, Value, Value),
+}
+
+/// The changes from <old> to <new>.  Maps are compared key by key, whatever
+/// their order, lists element by element.
+pub fn changes(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    compare(".", old, new, &mut changes);
+    changes
+}
+
+fn compare(path: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    // The path of a key or element below <path>, the root being "."
+    let below = |step: String| match path {
+        "." => step,
+        _ => format!("{}{}", path, step),
+    };
+    match (old, new) {
+        (Value::Mapping(old), Value::Mapping(new)) => {
+            for (key, value) in old {
+                let path = below(format!(".{}", key_name(key)));
+                match new.get(key) {
+                    Some(new_value) => compare(&path, value, new_value, changes),
+                    None => changes.push(Change::Removed(path, value.clone())),
+                }
+            }
+            for (key, value) in new {
+                if !old.contains_key(key) {
+                    let path = below(format!(".{}", key_name(key)));
+                    changes.push(Change::Added(path, value.clone()));
+                }
+            }
+        }
+        (Value::Sequence(old), Value::Sequence(new)) => {
+            for i in 0..old.len().max(new.len()) {
+                let path = below(format!("[{}]", i));
+                match (old.get(i), new.get(i)) {
+                    (Some(old), Some(new)) => compare(&path, old, new, changes),
+                    (Some(old), None) => changes.push(Change::Removed(path, old.clone())),
+                    (None, Some(new)) => changes.push(Change::Added(path, new.clone())),
+                    (None, None) => {}
+                }
+            }
+        }
+        (old, new) if old != new => {
+            changes.push(Change::Changed(path.to_string(), old.clone(), new.clone()))
+        }
+        _ => {}
+    }
+}
+
+/// How a key shows in a path, strings as they are
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        key => show(key),
+    }
+}
+
+/// A value on one line
+fn show(value: &Value) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| format!("{:?}", value))
+}
+
+/// One line per change: + for what was added, - for what was removed, and
+/// ~ for values that changed
+pub fn render_changes(changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return "same data, only its formatting changed\n".to_string();
+    }
+    let mut out = String::new();
+    for change in changes {
+        let line = match change {
+            Change::Added(path, value) => {
+                output::paint(&format!("+ {}: {}", path, show(value)), output::GREEN)
+            }
+            Change::Removed(path, value) => {
+                output::paint(&format!("- {}: {}", path, show(value)), output::RED)
+            }
+            Change::Changed(path, old, new) => output::paint(
+                &format!("~ {}: {} -> {}", path, show(old), show(new)),
+                output::YELLOW,
+            ),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\nport = 80\n";
+        let new = "a\nb\nc\nd\ne\nf\ng\nh\nport = 8080\n";
+        let res = render(old, new, Layout::Unified, CONTEXT);
+        assert_eq!(res, "...\n f\n g\n h\n-port = 80\n+port = 8080\n");
+        let res = render(old, new, Layout::Unified, 1);
+        assert_eq!(res, "...\n h\n-port = 80\n+port = 8080\n");
+
+        assert_eq!(render("", "port = 80\n", Layout::Unified, CONTEXT), "+port = 80\n");
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let old = "host = a\nport = 80\n";
+        let new = "host = a\nport = 8080\ntls = on\n";
+        let res = render(old, new, Layout::SideBySide, CONTEXT);
+        assert_eq!(
+            res,
+            "host = a    host = a\nport = 80 | port = 8080\n          > tls = on\n"
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let old: Value =
+            serde_yaml::from_str("port: 80\nhosts: [a, b]\ndb: {name: app, debug: true}").unwrap();
+        let new: Value =
+            serde_yaml::from_str("db: {name: app}\nhosts: [a, c, d]\nport: 8080").unwrap();
+        let exp = vec![
+            Change::Changed(".port".to_string(), Value::from(80), Value::from(8080)),
+            Change::Changed(".hosts[1]".to_string(), Value::from("b"), Value::from("c")),
+            Change::Added(".hosts[2]".to_string(), Value::from("d")),
+            Change::Removed(".db.debug".to_string(), Value::from(true)),
+        ];
+        assert_eq!(changes(&old, &new), exp);
+        assert_eq!(
+            render_changes(&exp),
+            "~ .port: 80 -> 8080\n~ .hosts[1]: \"b\" -> \"c\"\n+ .hosts[2]: \"d\"\n\
+             - .db.debug: true\n"
+        );
+
+        // Reordered keys are the same data
+        let reordered: Value = serde_yaml::from_str("{hosts: [a, b], port: 80}").unwrap();
+        let old: Value = serde_yaml::from_str("{port: 80, hosts: [a, b]}").unwrap();
+        assert!(changes(&old, &reordered).is_empty());
+        assert_eq!(render_changes(&[]), "same data, only its formatting changed\n");
+    }
+}
@@ -0,0 +1,44 @@
+use eyre::{eyre, Result};
+use std::time::Duration;
+
+/// Parse a duration as written in config files: a whole number followed by
+/// its unit, one of ms, s, m, h or d, e.g. "500ms", "10s" or "24h"
+pub fn parse(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| eyre!("Invalid duration {:?}, expected e.g. \"10s\"", text))?;
+    let millis = match unit.trim() {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        unit => return Err(eyre!("Invalid duration {:?}, unknown unit {:?}", text, unit)),
+    };
+    Ok(Duration::from_millis(number * millis))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse("24h").unwrap(), Duration::from_secs(24 * 3600));
+        assert_eq!(parse("1d").unwrap(), Duration::from_secs(24 * 3600));
+        assert_eq!(parse(" 5 s ").unwrap(), Duration::from_secs(5));
+
+        assert!(parse("10").is_err());
+        assert!(parse("s").is_err());
+        assert!(parse("10 years").is_err());
+        assert!(parse("-1s").is_err());
+    }
+}
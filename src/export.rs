@@ -0,0 +1,93 @@
+use crate::hooks::ssh::shell_quote;
+use eyre::{eyre, Result};
+use serde_yaml::Value;
+
+/// The `export NAME=value` lines of the data <value>, for a shell to eval.
+/// The data must be a map.  Maps and lists in it are flattened, their keys
+/// or indexes joined with `_`, so `{database: {host: a}}` gives
+/// `export <prefix>DATABASE_HOST='a'`.  Names are upper cased, and anything
+/// a shell does not allow in them becomes `_`.  Values are single quoted,
+/// nulls are empty.
+pub fn exports(value: &Value, prefix: &str) -> Result<Vec<String>> {
+    if !matches!(value, Value::Mapping(_)) {
+        return Err(eyre!("Only a map of keys and values can be exported"));
+    }
+
+    let mut vars = Vec::new();
+    flatten(value, prefix.to_string(), &mut vars)?;
+    Ok(vars
+        .into_iter()
+        .map(|(name, value)| format!("export {}={}", name, shell_quote(&value)))
+        .collect())
+}
+
+/// Add the variables in <value> to <vars>, each named <name> and its path
+fn flatten(value: &Value, name: String, vars: &mut Vec<(String, String)>) -> Result<()> {
+    let join = |key: &str| match name.is_empty() || name.ends_with('_') {
+        true => format!("{}{}", name, key),
+        false => format!("{}_{}", name, key),
+    };
+
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map {
+                let key = match key {
+                    Value::String(key) => key.clone(),
+                    key => serde_json::to_string(key)?,
+                };
+                flatten(value, join(&key), vars)?;
+            }
+        }
+        Value::Sequence(seq) => {
+            for (i, value) in seq.iter().enumerate() {
+                flatten(value, join(&i.to_string()), vars)?;
+            }
+        }
+        Value::Null => vars.push((var_name(&name), String::new())),
+        Value::String(s) => vars.push((var_name(&name), s.clone())),
+        value => vars.push((var_name(&name), serde_json::to_string(value)?)),
+    }
+    Ok(())
+}
+
+/// <name> made into a valid shell variable name
+fn var_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exports() {
+        let value: Value = serde_yaml::from_str(
+            "name: it's\nport: 80\ndatabase: {host: db.local, replica-set: null}\nhosts: [a, b]",
+        )
+        .unwrap();
+        let exp = vec![
+            "export APP_NAME='it'\\''s'",
+            "export APP_PORT='80'",
+            "export APP_DATABASE_HOST='db.local'",
+            "export APP_DATABASE_REPLICA_SET=''",
+            "export APP_HOSTS_0='a'",
+            "export APP_HOSTS_1='b'",
+        ];
+        assert_eq!(exports(&value, "APP_").unwrap(), exp);
+
+        assert_eq!(exports(&value, "").unwrap()[0], "export NAME='it'\\''s'");
+        assert_eq!(var_name("1st.key"), "_1ST_KEY");
+        assert!(exports(&Value::from("a"), "APP_").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::Config;
+use crate::data::ConfigData;
+use crate::diff::{self, Layout};
+use crate::hooks::Hook;
+use eyre::{eyre, Result, WrapErr};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// app_config test: the templates of a pipeline rendered with the data of
+// test cases, and compared to golden files.  A case is a directory holding
+// the data as `input`, in place of what the provider would give, and a
+// golden file for each output, named after the file the output is written
+// to, or `stdout` for templates printing theirs.  It may hold the data
+// applied before as `previous`, for templates rendering it.
+
+/// Name of the file of a case holding its data
+const INPUT: &str = "input";
+/// Name of the file of a case holding the data applied before, if any
+const PREVIOUS: &str = "previous";
+/// Name of the golden file of what templates print
+const STDOUT: &str = "stdout";
+
+/// The cases in <dir>: the directories in it holding an input, by name
+pub fn cases(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(dir).wrap_err_with(|| format!("Could not open {}", dir.display()))?;
+    let mut cases = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.join(INPUT).is_file() {
+            cases.push(path);
+        }
+    }
+    if cases.is_empty() {
+        return Err(eyre!("No test cases in {}, no directory holds an {}", dir.display(), INPUT));
+    }
+    cases.sort();
+    Ok(cases)
+}
+
+/// What the hooks of <config> render with the input of <case>, by the name
+/// of their golden file.  As check does, the pre_hooks and post_hooks get
+/// the data, the hooks each element of it with settings.for_each.
+pub fn render(config: &Config, case: &Path) -> Result<BTreeMap<String, String>> {
+    let input = case.join(INPUT);
+    let raw = fs::read(&input).wrap_err_with(|| format!("Could not read {}", input.display()))?;
+    let mut data = ConfigData::new(raw, "fixture", None).sensitive(config.sensitive());
+    let previous = case.join(PREVIOUS);
+    if previous.is_file() {
+        let raw = fs::read(&previous)
+            .wrap_err_with(|| format!("Could not read {}", previous.display()))?;
+        data = data.with_previous(ConfigData::new(raw, "fixture", None));
+    }
+    config.validate(&data).wrap_err("The input failed validation")?;
+    let for_each = config.for_each(&data)?;
+    let elements: Vec<&ConfigData> = match &for_each {
+        None => vec![&data],
+        Some(elements) => elements.iter().collect(),
+    };
+
+    let mut outputs = Outputs::default();
+    for hook in &config.pre_hooks {
+        outputs.add(hook.as_ref(), &data)?;
+    }
+    for element in elements {
+        for hook in &config.hooks {
+            outputs.add(hook.as_ref(), element)?;
+        }
+    }
+    for hook in &config.post_hooks {
+        outputs.add(hook.as_ref(), &data)?;
+    }
+    Ok(outputs.rendered.into_iter().map(|(name, (_, rendered))| (name, rendered)).collect())
+}
+
+/// What <rendered> differs in from the golden files of <case>
+pub fn compare(case: &Path, rendered: &BTreeMap<String, String>) -> Result<Vec<Mismatch>> {
+    let golden = golden_files(case)?;
+    let mut mismatches = Vec::new();
+    for (name, rendered) in rendered {
+        let expected = match golden.contains(name) {
+            true => Some(read(&case.join(name))?),
+            false => None,
+        };
+        if expected.as_ref() != Some(rendered) {
+            mismatches.push(Mismatch {
+                name: name.clone(),
+                expected,
+                rendered: Some(rendered.clone()),
+            });
+        }
+    }
+    for name in golden.iter().filter(|name| !rendered.contains_key(*name)) {
+        mismatches.push(Mismatch {
+            name: name.clone(),
+            expected: Some(read(&case.join(name))?),
+            rendered: None,
+        });
+    }
+    Ok(mismatches)
+}
+
+/// Write <rendered> to the golden files of <case>.  Golden files nothing
+/// renders any more are left for the user to remove.
+pub fn update(case: &Path, rendered: &BTreeMap<String, String>) -> Result<()> {
+    for (name, rendered) in rendered {
+        let path = case.join(name);
+        fs::write(&path, rendered).wrap_err_with(|| format!("Could not write {}", path.display()))?;
+    }
+    for name in golden_files(case)?.iter().filter(|name| !rendered.contains_key(*name)) {
+        warning!("{}: nothing renders {}", case.display(), name);
+    }
+    Ok(())
+}
+
+/// Mismatch:
+/// A golden file of a case and what was rendered for it, when they differ.
+/// Either is missing when there is no golden file for an output, or a
+/// golden file no output is rendered to.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    name: String,
+    expected: Option<String>,
+    rendered: Option<String>,
+}
+
+impl Mismatch {
+    pub fn summary(&self) -> String {
+        match (&self.expected, &self.rendered) {
+            (None, _) => format!("{} has no golden file", self.name),
+            (_, None) => format!("nothing renders {}", self.name),
+            _ => format!("{} differs from its golden file", self.name),
+        }
+    }
+
+    /// The changes from the golden file to what was rendered
+    pub fn diff(&self) -> String {
+        let expected = self.expected.as_deref().unwrap_or("");
+        let rendered = self.rendered.as_deref().unwrap_or("");
+        diff::render(expected, rendered, Layout::Unified, diff::CONTEXT)
+    }
+}
+
+/// What the hooks rendered so far, by the name of their golden file, along
+/// with the output it is for
+#[derive(Default)]
+struct Outputs {
+    rendered: BTreeMap<String, (String, String)>,
+}
+
+impl Outputs {
+    /// Add what <hook> renders for <data>.  Files rendered again replace
+    /// what they held, as they would be written over, and what is printed
+    /// follows what was printed before.
+    fn add(&mut self, hook: &dyn Hook, data: &ConfigData) -> Result<()> {
+        let rendered = hook
+            .rendered(data)
+            .wrap_err_with(|| format!("Unable to render {}", hook.name()))?;
+        for (output, rendered) in rendered {
+            if output == STDOUT {
+                let (_, printed) = self.rendered.entry(STDOUT.to_string()).or_default();
+                printed.push_str(&rendered);
+                continue;
+            }
+
+            let name = match Path::new(&output).file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => return Err(eyre!("{} renders to {}, not a file", hook.name(), output)),
+            };
+            match self.rendered.get(&name) {
+                Some((other, _)) if other != &output => {
+                    return Err(eyre!("{} and {} are both tested against {}", other, output, name))
+                }
+                _ => self.rendered.insert(name, (output, rendered)),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// The names of the golden files of <case>
+fn golden_files(case: &Path) -> Result<Vec<String>> {
+    let entries =
+        fs::read_dir(case).wrap_err_with(|| format!("Could not open {}", case.display()))?;
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name != INPUT && name != PREVIOUS && entry.file_type()?.is_file() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).wrap_err_with(|| format!("Could not read {}", path.display()))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let dir = std::env::temp_dir().join(format!("app_config-golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(INPUT), "hosts: []").unwrap();
+        fs::write(dir.join(PREVIOUS), "hosts: [a]").unwrap();
+        fs::write(dir.join("hosts.conf"), "a\nb\n").unwrap();
+        fs::write(dir.join("old.conf"), "gone\n").unwrap();
+
+        let mut rendered = BTreeMap::new();
+        rendered.insert("hosts.conf".to_string(), "a\nc\n".to_string());
+        rendered.insert(STDOUT.to_string(), "printed\n".to_string());
+        let mismatches = compare(&dir, &rendered).unwrap();
+        let summaries: Vec<String> = mismatches.iter().map(Mismatch::summary).collect();
+        assert_eq!(
+            summaries,
+            vec![
+                "hosts.conf differs from its golden file",
+                "stdout has no golden file",
+                "nothing renders old.conf",
+            ]
+        );
+
+        fs::remove_file(dir.join("old.conf")).unwrap();
+        update(&dir, &rendered).unwrap();
+        assert_eq!(compare(&dir, &rendered).unwrap(), Vec::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
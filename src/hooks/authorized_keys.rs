@@ -0,0 +1,324 @@
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{sha256, Hook, Registry};
+use crate::interactive;
+use crate::path;
+use crate::private_path::PrivatePath;
+use crate::sandbox::{lookup_user, Sandbox, SandboxConf, User};
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use eyre::{eyre, Result, WrapErr};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+#[cfg(test)]
+use std::any::Any;
+
+/// The types of key sshd takes
+const KEY_TYPES: [&str; 8] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// AuthorizedKeysConf will store the user's input from the configuration file
+// and then let us instantiate an AuthorizedKeys struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "authorized_keys")]
+pub struct AuthorizedKeysConf {
+    pub path: Option<String>,
+    pub select: Option<String>,
+    pub source_type: Option<DataType>,
+    pub reload: Option<String>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl AuthorizedKeysConf {
+    pub fn convert(&self) -> AuthorizedKeys {
+        let mut keys = AuthorizedKeys::new(
+            self.path.as_deref().unwrap_or("{home}/.ssh/authorized_keys"),
+            self.select.as_deref().unwrap_or("."),
+            self.source_type.clone().unwrap_or(DataType::YAML),
+        );
+        keys.reload = self.reload.clone();
+        keys.sandbox = self.sandbox.convert();
+        keys
+    }
+}
+
+/// Make authorized_keys hooks from [hooks.authorized_keys]
+pub fn register(registry: &mut Registry) {
+    registry.register("authorized_keys", |section| {
+        let conf: AuthorizedKeysConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The AuthorizedKeys Hook writes the authorized_keys file of each user of
+/// a map of user names to public keys, selected by the jq style path
+/// <select> in the data parsed as <source_type>.  A user has a key or a list
+/// of them, an empty list takes every key away.  The file of a user is at
+/// <path>, with `{user}` and `{home}` replaced by the user's name and home
+/// directory.  It is owned by the user and only readable by them, and so is
+/// the directory it is in when the hook creates it.  Every user is looked up
+/// in <passwd>, and every key checked, before any file is written.  As users
+/// own the directories their file is in, no symlink there is followed, the
+/// run fails on one in place of a file.  When a file changed, <reload> is run
+/// by bash in <sandbox>, e.g. to reload sshd.
+#[derive(Debug, PartialEq)]
+pub struct AuthorizedKeys {
+    path: String,
+    select: String,
+    source_type: DataType,
+    reload: Option<String>,
+    sandbox: Sandbox,
+    passwd: String,
+    output: RefCell<Option<String>>,
+}
+
+impl AuthorizedKeys {
+    /// Create a new AuthorizedKeys struct, looking users up in /etc/passwd
+    pub fn new(path: &str, select: &str, source_type: DataType) -> AuthorizedKeys {
+        AuthorizedKeys {
+            path: path.to_string(),
+            select: select.to_string(),
+            source_type,
+            reload: None,
+            sandbox: Sandbox::default(),
+            passwd: "/etc/passwd".to_string(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// Each user in <data>, with the file of their keys and what it holds
+    fn files_of(&self, data: &ConfigData) -> Result<Vec<(User, String, String)>> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+        let mut selected = path::select(&value, &self.select)?;
+        if selected.len() != 1 {
+            return Err(eyre!("{} selects {} values, not a map", self.select, selected.len()));
+        }
+        let map = match selected.remove(0) {
+            Value::Mapping(map) => map,
+            _ => return Err(eyre!("{} does not select a map of users", self.select)),
+        };
+        let passwd = fs::read_to_string(&self.passwd)
+            .wrap_err_with(|| format!("Could not read {}", self.passwd))?;
+
+        let mut users = BTreeMap::new();
+        for (name, keys) in map {
+            let name = match name {
+                Value::String(name) => name,
+                name => return Err(eyre!("{:?} is not a user name", name)),
+            };
+            let user = match lookup_user(&passwd, &name) {
+                Some(user) if user.name == name => user,
+                _ => return Err(eyre!("There is no user {}", name)),
+            };
+            let keys = match keys {
+                Value::Sequence(keys) => keys,
+                key => vec![key],
+            };
+            let mut contents = String::from("# Managed by app_config, do not edit\n");
+            for key in keys {
+                match key.as_str() {
+                    Some(key) if valid_key(key) => contents.push_str(&format!("{}\n", key)),
+                    _ => return Err(eyre!("{}: {:?} is not a valid public key", name, key)),
+                }
+            }
+            let file = self.path.replace("{user}", &user.name).replace("{home}", &user.home);
+            users.insert(name, (user, file, contents));
+        }
+        Ok(users.into_values().collect())
+    }
+}
+
+impl Hook for AuthorizedKeys {
+    fn kind(&self) -> &'static str {
+        "authorized_keys"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Write the files whose keys changed, owned by their user, and reload
+    /// if any did
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let files = self.files_of(data)?;
+        let list: Vec<String> =
+            files.iter().map(|(_, file, c)| format!("{}  {}\n", sha256(c), file)).collect();
+        *self.output.borrow_mut() = Some(sha256(list.concat()));
+
+        // A symlink or hard link in place of any file fails the run before
+        // one is written
+        for (user, file, _) in &files {
+            if Path::new(file).parent().is_some_and(|dir| dir.exists()) {
+                PrivatePath::new(file, Some(user))?.read()?;
+            }
+        }
+
+        let mut changed = false;
+        for (user, file, contents) in &files {
+            if let Some(dir) = Path::new(file).parent().filter(|dir| !dir.exists()) {
+                PrivatePath::new(&dir.to_string_lossy(), Some(user))?.create_dir()?;
+            }
+            let private = PrivatePath::new(file, Some(user))?;
+            let unchanged = private.read()?.as_ref() == Some(contents);
+            if !unchanged && interactive::confirm_write(file, contents.as_bytes())? {
+                private.write(contents)?;
+                info!("Wrote the keys of {} to {}", user.name, file);
+                changed = true;
+            }
+
+            // Files loosened by hand are made strict again, keys changed or not
+            private.restrict()?;
+        }
+
+        match (&self.reload, changed) {
+            (Some(command), true) => self.sandbox.run_checked(command, data.run_env()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The files of the users
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        Ok(self.files_of(data)?.into_iter().map(|(_, file, _)| file).collect())
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        Ok(self.files_of(data)?.into_iter().map(|(_, file, c)| (file, c)).collect())
+    }
+}
+
+/// Whether <line> is an authorized_keys line: optional options, a key type
+/// sshd takes, and a key of that type, all on one line
+fn valid_key(line: &str) -> bool {
+    if line.contains(['\n', '\r']) {
+        return false;
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let at = match words.iter().position(|word| KEY_TYPES.contains(word)) {
+        Some(at) => at,
+        None => return false,
+    };
+    // The key starts with its type, after the length of it
+    let key = match words.get(at + 1).map(base64::decode) {
+        Some(Ok(key)) => key,
+        _ => return false,
+    };
+    key.len() > 4 && key[4..].starts_with(words[at].as_bytes())
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    const KEY: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl alice@laptop";
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.authorized_keys]
+         path = "/etc/ssh/authorized_keys/{user}"
+         select = ".ssh_keys"
+        "#;
+        let path = "/etc/ssh/authorized_keys/{user}";
+        let exp = AuthorizedKeys::new(path, ".ssh_keys", DataType::YAML);
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: AuthorizedKeysConf = maps["hooks"]["authorized_keys"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_valid_key() {
+        assert!(valid_key(KEY));
+        assert!(valid_key(&format!("from=\"10.0.0.0/8\",no-pty {}", KEY)));
+        assert!(!valid_key("ssh-ed25519 AAAAB3NzaC1yc2EAAAADAQABAAABAQ== x"));
+        assert!(!valid_key("ssh-ed25519 not-base64"));
+        assert!(!valid_key("ssh-ed25519"));
+        assert!(!valid_key(&format!("{}\n{}", KEY, KEY)));
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("app_config_keys_{}", std::process::id()));
+        fs::create_dir_all(dir.join("alice")).unwrap();
+        let meta = fs::metadata(&dir).unwrap();
+        let passwd = format!(
+            "alice:x:{}:{}::{}/alice:/bin/sh\n",
+            meta.uid(),
+            meta.gid(),
+            dir.display()
+        );
+        fs::write(dir.join("passwd"), passwd).unwrap();
+
+        let mut hook = AuthorizedKeys::new("{home}/.ssh/authorized_keys", ".", DataType::YAML);
+        hook.passwd = dir.join("passwd").to_string_lossy().to_string();
+        hook.reload = Some(format!("echo >> {}", dir.join("reloads").display()));
+        let data = |yaml: &str| ConfigData::new(yaml, "mock", None);
+        hook.run(&data(&format!("alice: ['{}']\n", KEY))).unwrap();
+
+        let file = dir.join("alice/.ssh/authorized_keys");
+        let exp = format!("# Managed by app_config, do not edit\n{}\n", KEY);
+        assert_eq!(fs::read_to_string(&file).unwrap(), exp);
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(dir.join("alice/.ssh")).unwrap().mode() & 0o777, 0o700);
+
+        // Unchanged keys are not written again, nor reloaded, but their
+        // permissions are made strict again
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        hook.run(&data(&format!("alice: '{}'\n", KEY))).unwrap();
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o600);
+        hook.run(&data("alice: []\n")).unwrap();
+        let exp = "# Managed by app_config, do not edit\n";
+        assert_eq!(fs::read_to_string(&file).unwrap(), exp);
+        assert_eq!(fs::read_to_string(dir.join("reloads")).unwrap(), "\n\n");
+
+        assert!(hook.run(&data(&format!("bob: ['{}']\n", KEY))).is_err());
+        assert!(hook.run(&data("alice: ['ssh-rsa AAAA']\n")).is_err());
+
+        // A symlink the user planted in place of their file is not followed,
+        // nor is the file it points to written, chmoded or chowned
+        let target = dir.join("shadow");
+        fs::write(&target, "root:x:0:\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink(&target, &file).unwrap();
+        let res = hook.run(&data(&format!("alice: ['{}']\n", KEY)));
+        assert!(format!("{:#}", res.unwrap_err()).contains("symlink"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "root:x:0:\n");
+        assert_eq!(fs::metadata(&target).unwrap().mode() & 0o777, 0o640);
+
+        // Nor is one in place of its directory
+        fs::remove_dir_all(dir.join("alice/.ssh")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("alice/.ssh")).unwrap();
+        assert!(hook.run(&data(&format!("alice: ['{}']\n", KEY))).is_err());
+        assert!(!dir.join("authorized_keys").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
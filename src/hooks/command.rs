@@ -1,7 +1,14 @@
-use crate::hooks::Hook;
+use crate::data::ConfigData;
+use crate::hooks::{sha256, Hook, Registry};
+use crate::interactive;
+use crate::output;
+use crate::sandbox::{Sandbox, SandboxConf};
 use serde_derive::Deserialize;
+use std::cell::RefCell;
 use std::io::Write;
-use eyre::Result;
+#[cfg(test)]
+use std::any::Any;
+use eyre::{eyre, Result};
 
 
 // // // // // // // // // Handle Configuraion // // // // // // // //
@@ -13,6 +20,8 @@ use eyre::Result;
 pub struct CommandConf {
     pub command: String,
     pub pipe_data: Option<bool>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
 }
 
 impl CommandConf {
@@ -21,71 +30,107 @@ impl CommandConf {
             None => false,
             Some(x) => x,
         };
-        Command::new(&self.command, p)
+        Command::new(&self.command, p, self.sandbox.convert())
     }
 }
 
+/// Make command hooks from [hooks.command]
+pub fn register(registry: &mut Registry) {
+    registry.register("command", |section| {
+        let conf: CommandConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
 
 // // // // // // // // // // // Hook  // // // // // // // // // // //
 
 /// The Command Hook will fire off an external script whenever new data is received
 /// by the provider. Optionally, if pipe_data is true, it will pipe the data
-/// received from the provider into the stdin pipe on the script.
+/// received from the provider into the stdin pipe on the script.  The script
+/// runs in <sandbox>, as app_config runs unless configured otherwise.
 #[derive(Debug, PartialEq)]
 pub struct Command {
     command: String,
     pipe_data: bool,
+    sandbox: Sandbox,
+    output: RefCell<Option<String>>,
 }
 
 impl Command {
     /// Create a new Command struct
-    pub fn new(cmd: &str, pipe_data: bool) -> Command {
+    pub fn new(cmd: &str, pipe_data: bool, sandbox: Sandbox) -> Command {
         Command {
             command: cmd.to_string(),
             pipe_data,
+            sandbox,
+            output: RefCell::new(None),
         }
     }
 }
 
 impl Hook for Command {
+    fn kind(&self) -> &'static str {
+        "command"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     /// Execute the command
-    fn run(&self, data: &str) -> Result<()> {
-        match self.pipe_data {
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        if !interactive::confirm(&format!("Run {}", self.command))? {
+            return Ok(());
+        }
+        let mut cmd = std::process::Command::new("/bin/bash");
+        cmd.arg("-c").arg(self.command.clone());
+        self.sandbox.apply(&mut cmd);
+        cmd.envs(data.run_env());
+        let stdout = match self.pipe_data {
             // No data to pipe in.  Just run the command
             false => {
-                let out = std::process::Command::new("/bin/bash")
-                    .arg("-c")
-                    .arg(self.command.clone())
+                let out = cmd
+                    .stderr(std::process::Stdio::inherit())
                     .output()?;
                 if !out.status.success() {
-                    eprintln!("Failed to execute cmd: {}", self.command);
-                    std::process::exit(exitcode::SOFTWARE);
+                    return Err(eyre!("Failed to execute cmd: {}", self.command));
                 }
+                out.stdout
             }
             true => {
                 // We have data to pipe in.  Spawn a process, send it data
                 // Then check the return code
-                let mut child = std::process::Command::new("/bin/bash")
-                    .arg("-c")
-                    .arg(self.command.clone())
+                let mut child = cmd
                     .stdin(std::process::Stdio::piped())
                     .stdout(std::process::Stdio::piped())
                     .spawn()
                     .expect("Failed to spawn child process");
 
                 let stdin = child.stdin.as_mut().expect("Failed to open stdin");
-                stdin.write_all(data.as_bytes())?;
+                stdin.write_all(data.raw())?;
 
                 let output = child.wait_with_output()?;
 
                 if !output.status.success() {
-                    eprintln!("Failed to execute cmd: {}", self.command);
-                    std::process::exit(exitcode::SOFTWARE);
+                    return Err(eyre!("Failed to execute cmd: {}", self.command));
                 }
+                output.stdout
             }
         };
+        // What the command prints is for people, stdout is kept for data
+        if !output::quiet() {
+            std::io::stderr().write_all(&stdout)?;
+        }
+        *self.output.borrow_mut() = Some(sha256(&stdout));
         Ok(())
     }
+
+    /// sha256 of what the command printed
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
 }
 
 
@@ -93,19 +138,29 @@ impl Hook for Command {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::workspace;
 
     #[test]
     fn test_cmd() {
-        let c = Command::new(&"echo Booyeah", false);
+        let c = Command::new(&"echo Booyeah", false, Sandbox::default());
+
+        assert_eq!(c.run(&ConfigData::new("", "mock", None)).unwrap(), ());
+    }
 
-        assert_eq!(c.run(&"").unwrap(), ());
+    #[test]
+    fn test_workspace() {
+        let workspace = workspace::Workspace::create("test_command").unwrap();
+        let data = ConfigData::new("", "mock", None).in_workspace(workspace.path());
+        let c = Command::new(&"touch \"$APP_CONFIG_WORKSPACE/done\"", false, Sandbox::default());
+        c.run(&data).unwrap();
+        assert!(workspace.path().join("done").exists());
     }
 
     #[test]
     fn test_piped_cmd() {
-        let c = Command::new(&"echo", true);
+        let c = Command::new(&"echo", true, Sandbox::default());
 
-        let res = c.run(&"Booyeah").unwrap();
+        let res = c.run(&ConfigData::new("Booyeah", "mock", None)).unwrap();
         let expected = ();
 
         assert_eq!(res, expected);
@@ -122,12 +177,19 @@ mod tests {
 
     #[test]
     fn parse_config() {
-        let exp = Command::new(&"cat > booyeah.txt", true);
+        let exp = Command::new(&"cat > booyeah.txt", true, Sandbox::default());
 
         let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
         let conf: CommandConf = maps["hooks"]["command"].clone().try_into().unwrap();
         let res = conf.convert();
 
         assert_eq!(res, exp);
+
+        // The sandbox options sit next to the command
+        let config = format!("{}\n clean_env = true\n harden = true", gen_config());
+        let maps: toml::Value = toml::from_str(&config).unwrap();
+        let conf: CommandConf = maps["hooks"]["command"].clone().try_into().unwrap();
+        let exp = Command::new(&"cat > booyeah.txt", true, Sandbox::new(None, true, true));
+        assert_eq!(conf.convert(), exp);
     }
 }
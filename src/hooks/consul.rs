@@ -0,0 +1,159 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::redact::redacted;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// ConsulConf will store the user's input from the configuration file
+// and then let us instantiate a Consul struct
+#[derive(Deserialize)]
+#[serde(rename = "consul")]
+pub struct ConsulConf {
+    pub address: Option<String>,
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for ConsulConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ConsulConf")
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .finish()
+    }
+}
+
+impl ConsulConf {
+    pub fn convert(&self) -> Consul {
+        Consul::new(self.address.clone(), self.token.clone())
+    }
+}
+
+/// Make consul hooks from [hooks.consul]
+pub fn register(registry: &mut Registry) {
+    registry.register("consul", |section| {
+        let conf: ConsulConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Consul Hook asks the local Consul agent to reload its configuration,
+/// picking up any service definitions rendered by earlier hooks.
+/// If <address> or <token> are omitted, CONSUL_HTTP_ADDR and CONSUL_HTTP_TOKEN
+/// are used.
+#[derive(Clone, PartialEq)]
+pub struct Consul {
+    address: Option<String>,
+    token: Option<String>,
+}
+
+impl std::fmt::Debug for Consul {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Consul")
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .finish()
+    }
+}
+
+impl Consul {
+    /// Create a new Consul struct
+    pub fn new(address: Option<String>, token: Option<String>) -> Consul {
+        Consul { address, token }
+    }
+
+    /// Base url of the Consul HTTP API
+    pub fn address(&self) -> String {
+        let addr = match &self.address {
+            Some(addr) => addr.clone(),
+            None => std::env::var("CONSUL_HTTP_ADDR")
+                .unwrap_or_else(|_| "http://127.0.0.1:8500".to_string()),
+        };
+
+        // CONSUL_HTTP_ADDR is commonly set without a scheme
+        let addr = if addr.contains("://") {
+            addr
+        } else {
+            format!("http://{}", addr)
+        };
+        addr.trim_end_matches('/').to_string()
+    }
+
+    /// ACL token to authenticate with, if any
+    pub fn token(&self) -> Option<String> {
+        match &self.token {
+            Some(token) => Some(token.clone()),
+            None => std::env::var("CONSUL_HTTP_TOKEN").ok(),
+        }
+    }
+}
+
+impl Hook for Consul {
+    fn kind(&self) -> &'static str {
+        "consul"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Reload the agent via the Consul API
+    fn run(&self, _data: &ConfigData) -> Result<()> {
+        let url = format!("{}/v1/agent/reload", self.address());
+
+        let mut req = http::put(&url);
+        if let Some(token) = self.token() {
+            req.set("X-Consul-Token", &token);
+        }
+
+        let resp = req.call();
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach Consul at {}: {}", url, e));
+        }
+        if !resp.ok() {
+            return Err(eyre!("Consul failed to reload: {}", resp.status_line()));
+        }
+        Ok(())
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.consul]
+         address = "consul.local:8500"
+         token = "s3cr3t"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Consul::new(
+            Some("consul.local:8500".to_string()),
+            Some("s3cr3t".to_string()),
+        );
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: ConsulConf = maps["hooks"]["consul"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+        assert_eq!(res.address(), "http://consul.local:8500");
+        assert_eq!(res.token(), Some("s3cr3t".to_string()));
+    }
+}
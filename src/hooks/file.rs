@@ -1,11 +1,20 @@
-use crate::hooks::Hook;
+use crate::checksum;
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{Hook, Registry};
+use crate::interactive;
 use serde_derive::Deserialize;
 // use crate::config;
-use eyre::Result;
+use eyre::{eyre, Result, WrapErr};
 
+use handlebars::Handlebars;
 use shellexpand::tilde;
+use std::cell::RefCell;
 use std::fs;
 use std::io::prelude::*;
+use std::path::Path;
+#[cfg(test)]
+use std::any::Any;
 
 // FileConf will store the user's input from the configuration file
 // and then let us instantiate a File Object
@@ -15,48 +24,132 @@ use std::io::prelude::*;
 #[serde(rename = "File")]
 pub struct FileConf {
     pub outfile: String,
+    pub source_type: Option<DataType>,
+    pub checksum: Option<bool>,
 }
 
 impl FileConf {
     pub fn convert(&self) -> File {
-        File::new(&self.outfile)
+        let mut file = File::new(&self.outfile, self.source_type.clone().unwrap_or(DataType::YAML));
+        file.checksum = self.checksum.unwrap_or(false);
+        file
     }
 }
 
+/// Make file hooks from [hooks.file]
+pub fn register(registry: &mut Registry) {
+    registry.register("file", |section| {
+        let conf: FileConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
 /// File
 /// This hook allow us to take the raw data feed from a Provider and write it to
-/// a text file stored in <outfile>
+/// a text file stored in <outfile>.
+/// <outfile> may itself be a handlebars template, e.g.
+/// `/etc/wireguard/{{name}}.conf`, rendered against the data parsed as
+/// <source_type> (YAML unless configured).  The directories it names are
+/// created as needed.
+/// With <checksum> a `.sha256` sidecar is written next to the file, to tell
+/// when it is edited by hand.
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct File {
     outfile: String,
+    source_type: DataType,
+    checksum: bool,
+    #[serde(skip)]
+    output: RefCell<Option<String>>,
 }
 
 impl File {
     /// Create a new File struct
-    pub fn new(outfile: &str) -> File {
+    pub fn new(outfile: &str, source_type: DataType) -> File {
         // Read in the template from the provided file.
         let expanded_path = String::from(tilde(outfile));
 
         File {
             outfile: expanded_path,
+            source_type,
+            checksum: false,
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The file to write <data> to, rendering <outfile> if it is a template
+    fn file_name(&self, data: &ConfigData) -> Result<String> {
+        if !self.outfile.contains("{{") {
+            return Ok(self.outfile.clone());
         }
+
+        let context = data.parsed(&self.source_type).wrap_err_with(|| {
+            format!("Unable to parse {:?} data for outfile {}", self.source_type, self.outfile)
+        })?;
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(handlebars::no_escape);
+        hb.render_template(&self.outfile, &*context)
+            .map_err(|e| eyre!("Invalid outfile {}: {}", self.outfile, e))
     }
 }
 
 impl Hook for File {
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     /// Write the raw data to the output file
-    fn run(&self, data: &str) -> Result<()> {
+    fn run(&self, data: &ConfigData) -> Result<()> {
         // If the user configured 'outfile', write the template there
         // Else print the rendered templete to stdout
-        match fs::File::create(&self.outfile) {
-            Ok(mut file_handle) => file_handle.write_all(data.as_bytes())?,
+        let outfile = self.file_name(data)?;
+        // The directories a templated outfile names may be new
+        if self.outfile.contains("{{") {
+            if let Some(dir) = Path::new(&outfile).parent() {
+                fs::create_dir_all(dir)
+                    .wrap_err_with(|| format!("Unable to create {}", dir.display()))?;
+            }
+        }
+        if !interactive::confirm_write(&outfile, data.raw())? {
+            return Ok(());
+        }
+        match fs::File::create(&outfile) {
+            Ok(mut file_handle) => file_handle.write_all(data.raw())?,
             Err(e) => {
-                eprintln!("Could not open {}: {}", self.outfile, e);
+                eprintln!("Could not open {}: {}", outfile, e);
                 std::process::exit(exitcode::OSFILE);
             }
         };
+        if self.checksum {
+            checksum::write(&outfile, data.raw())?;
+        }
+        *self.output.borrow_mut() = Some(data.sha256().to_string());
         Ok(())
     }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        Ok(vec![self.file_name(data)?])
+    }
+
+    /// The output file, if it no longer matches its checksum
+    fn drifted(&self, data: &ConfigData) -> Result<Vec<String>> {
+        if !self.checksum {
+            return Ok(Vec::new());
+        }
+        let outfile = self.file_name(data)?;
+        match checksum::drifted(&outfile)? {
+            true => Ok(vec![outfile]),
+            false => Ok(Vec::new()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -72,7 +165,7 @@ mod tests {
 
     #[test]
     fn parse_config() {
-        let exp = File::new(&"somefile.txt");
+        let exp = File::new(&"somefile.txt", DataType::YAML);
 
         let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
         let conf: FileConf = maps["hooks"]["file"].clone().try_into().unwrap();
@@ -80,4 +173,22 @@ mod tests {
 
         assert_eq!(res, exp);
     }
+
+    #[test]
+    fn test_templated_outfile() {
+        let dir = std::env::temp_dir().join(format!("app_config_file_{}", std::process::id()));
+        let outfile = format!("{}/{{{{name}}}}/peer.conf", dir.display());
+        let data = ConfigData::new("name: wg0\n", "mock", None);
+
+        let hook = File::new(&outfile, DataType::YAML);
+        hook.run(&data).unwrap();
+        let written = fs::read_to_string(dir.join("wg0/peer.conf")).unwrap();
+        assert_eq!(written, "name: wg0\n");
+
+        let data = ConfigData::new("[not yaml", "mock", None);
+        let res = format!("{:#}", hook.run(&data).unwrap_err());
+        assert!(res.contains("Unable to parse"), "{}", res);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
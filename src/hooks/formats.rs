@@ -0,0 +1,538 @@
+use crate::hooks::template::DataType;
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+// Parsers for every source_type, turning each into the same serde_yaml::Value
+// that templates are rendered from and schemas are checked against.
+
+/// Source data from YAML, JSON (Lines), TOML, INI, properties, dotenv, HCL
+/// or XML and turn it all into a BTreeMap for use with Handlebars templates
+pub fn parse(source_type: &DataType, input_data: &str) -> Result<Value> {
+    let res = match source_type {
+        DataType::YAML => parse_yaml_stream(input_data)?,
+        DataType::JSON => serde_json::from_str(input_data)?,
+        DataType::JSONL => parse_json_lines(input_data)?,
+        DataType::TOML => toml::from_str(input_data)?,
+        DataType::INI => parse_ini(input_data)?,
+        DataType::PROPERTIES => parse_properties(input_data)?,
+        DataType::DOTENV => parse_dotenv(input_data)?,
+        DataType::HCL => parse_hcl(input_data)?,
+        DataType::XML => parse_xml(input_data)?,
+    };
+    Ok(res)
+}
+
+
+/// Write <value> out as <format>.  Only YAML, JSON and TOML can be written,
+/// and TOML only for a table.
+pub fn serialize(format: &DataType, value: &Value) -> Result<String> {
+    let res = match format {
+        DataType::YAML => serde_yaml::to_string(value)?,
+        DataType::JSON => serde_json::to_string_pretty(value)?,
+        DataType::TOML => match value {
+            Value::Mapping(_) => toml::to_string(&toml::Value::try_from(value)?)?,
+            _ => return Err(eyre!("Unable to write data as TOML, it is not a table")),
+        },
+        format => return Err(eyre!("Unable to write data as {:?}", format)),
+    };
+    Ok(res)
+}
+
+/// Deep merge <other> into <base>: maps are merged key by key, anything
+/// else in <other> takes the place of what <base> has
+pub fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Mapping(base), Value::Mapping(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+
+// // // // // // // // // // // Streams // // // // // // // // // // //
+
+/// A YAML stream.  A single document is returned as is, several documents
+/// separated by `---` become a list the template can #each over.
+pub fn parse_yaml_stream(input: &str) -> Result<Value> {
+    let mut docs = Vec::new();
+    for (n, doc) in serde_yaml::Deserializer::from_str(input).enumerate() {
+        let doc = Value::deserialize(doc)
+            .map_err(|e| eyre!("Invalid YAML data in document {}: {}", n + 1, e))?;
+        docs.push(doc);
+    }
+
+    match docs.len() {
+        0 => Ok(Value::Null),
+        1 => Ok(docs.pop().unwrap()),
+        _ => Ok(Value::Sequence(docs)),
+    }
+}
+
+/// JSON Lines, one JSON value per line, as a list.  Blank lines are skipped.
+pub fn parse_json_lines(input: &str) -> Result<Value> {
+    let mut docs = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let doc = serde_json::from_str(line)
+            .map_err(|e| eyre!("Invalid JSON data on line {}: {}", n + 1, e))?;
+        docs.push(doc);
+    }
+    Ok(Value::Sequence(docs))
+}
+
+
+// // // // // // // // // // // // INI // // // // // // // // // // // //
+
+/// Keys outside of any section end up at the top level, every section
+/// becomes a map of its own.  All values are strings.
+pub fn parse_ini(input: &str) -> Result<Value> {
+    let ini = ini::Ini::load_from_str(input).map_err(|e| eyre!("Invalid INI data: {}", e))?;
+
+    let mut root = Mapping::new();
+    for (section, props) in ini.iter() {
+        let mut map = Mapping::new();
+        for (k, v) in props.iter() {
+            map.insert(Value::from(k), Value::from(v));
+        }
+
+        match section {
+            None => root.extend(map),
+            Some(name) => {
+                root.insert(Value::from(name), Value::Mapping(map));
+            }
+        }
+    }
+    Ok(Value::Mapping(root))
+}
+
+
+// // // // // // // // // // // Properties // // // // // // // // // // //
+
+/// Java .properties files: a flat map of strings.  Dotted keys are kept as
+/// they are, so `db.host` is reached with `{{[db.host]}}`.
+pub fn parse_properties(input: &str) -> Result<Value> {
+    let mut root = Mapping::new();
+
+    let mut lines = input.lines();
+    while let Some(line) = lines.next() {
+        let mut logical = line.trim_start().to_string();
+        if logical.is_empty() || logical.starts_with('#') || logical.starts_with('!') {
+            continue;
+        }
+
+        // A line ending in an odd number of backslashes continues on the next
+        while ends_with_continuation(&logical) {
+            logical.pop();
+            match lines.next() {
+                Some(next) => logical.push_str(next.trim_start()),
+                None => break,
+            }
+        }
+
+        let (key, value) = split_property(&logical);
+        root.insert(Value::from(unescape(key)?), Value::from(unescape(value)?));
+    }
+    Ok(Value::Mapping(root))
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+/// Split at the first unescaped '=', ':' or whitespace
+fn split_property(line: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '=' | ':' => return (&line[..i], line[i + 1..].trim_start()),
+            c if c.is_whitespace() => {
+                let rest = line[i..].trim_start();
+                let rest = rest.strip_prefix(|c| c == '=' || c == ':').unwrap_or(rest);
+                return (&line[..i], rest.trim_start());
+            }
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+fn unescape(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('f') => out.push('\u{c}'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| eyre!("Invalid unicode escape in properties: \\u{}", hex))?;
+                out.push(c);
+            }
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    Ok(out)
+}
+
+
+// // // // // // // // // // // Dotenv // // // // // // // // // // //
+
+/// .env files: a flat map of strings.  Values are taken literally, $VAR
+/// references are not expanded.
+pub fn parse_dotenv(input: &str) -> Result<Value> {
+    let mut root = Mapping::new();
+
+    let mut lines = input.lines().enumerate();
+    while let Some((n, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let eq = line
+            .find('=')
+            .ok_or_else(|| eyre!("Invalid dotenv data on line {}: missing '='", n + 1))?;
+        let key = line[..eq].trim();
+        let raw = line[eq + 1..].trim_start();
+
+        let value = match raw.chars().next() {
+            Some(q) if q == '"' || q == '\'' => {
+                // Quoted values may span several lines
+                let mut quoted = raw[1..].to_string();
+                while closing_quote(&quoted, q).is_none() {
+                    match lines.next() {
+                        Some((_, next)) => {
+                            quoted.push('\n');
+                            quoted.push_str(next);
+                        }
+                        None => {
+                            return Err(eyre!("Unterminated quote for {} in dotenv data", key))
+                        }
+                    }
+                }
+                let end = closing_quote(&quoted, q).unwrap_or(quoted.len());
+                match q {
+                    '"' => unescape(&quoted[..end])?,
+                    _ => quoted[..end].to_string(),
+                }
+            }
+            // Unquoted values end at an inline comment
+            _ => match raw.find(" #") {
+                Some(i) => raw[..i].trim_end().to_string(),
+                None => raw.trim_end().to_string(),
+            },
+        };
+        root.insert(Value::from(key), Value::from(value));
+    }
+    Ok(Value::Mapping(root))
+}
+
+/// Index of the first unescaped <quote> in <s>
+fn closing_quote(s: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == '"' => escaped = true,
+            c if c == quote => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+
+// // // // // // // // // // // // HCL // // // // // // // // // // // //
+
+/// HCL documents, e.g. Terraform variable files.  Blocks become nested maps
+/// keyed by their type and labels.  Expressions are not evaluated.
+pub fn parse_hcl(input: &str) -> Result<Value> {
+    hcl::from_str(input).map_err(|e| eyre!("Invalid HCL data: {}", e))
+}
+
+
+// // // // // // // // // // // // XML // // // // // // // // // // // //
+
+/// XML documents, mapped the way xmltodict does.  Each element becomes a key
+/// of its parent, repeated elements become a list, attributes are prefixed
+/// with '@', and text next to attributes or children goes under "#text".  A
+/// leaf element is just its text.
+pub fn parse_xml(input: &str) -> Result<Value> {
+    use quick_xml::events::{BytesStart, Event};
+    use quick_xml::Reader;
+
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+
+    // Open elements, innermost last, with the document itself at the bottom
+    let mut stack: Vec<(String, Mapping, String)> =
+        vec![(String::new(), Mapping::new(), String::new())];
+    let xml_err =
+        |e: quick_xml::Error, pos: usize| eyre!("Invalid XML data at byte {}: {}", pos, e);
+
+    let open = |e: &BytesStart, reader: &Reader<&[u8]>| -> Result<(String, Mapping, String)> {
+        let name = reader.decode(e.name()).map_err(|e| xml_err(e, reader.buffer_position()))?;
+        let mut attrs = Mapping::new();
+        for attr in e.attributes() {
+            let attr = attr.map_err(|e| xml_err(e, reader.buffer_position()))?;
+            let key = reader.decode(attr.key).map_err(|e| xml_err(e, reader.buffer_position()))?;
+            let value = attr
+                .unescape_and_decode_value(reader)
+                .map_err(|e| xml_err(e, reader.buffer_position()))?;
+            attrs.insert(Value::from(format!("@{}", key)), Value::from(value));
+        }
+        Ok((name.to_string(), attrs, String::new()))
+    };
+
+    let mut buf = Vec::new();
+    loop {
+        let event = reader
+            .read_event(&mut buf)
+            .map_err(|e| xml_err(e, reader.buffer_position()))?;
+        match event {
+            Event::Start(e) => stack.push(open(&e, &reader)?),
+            Event::Empty(e) => {
+                let (name, map, text) = open(&e, &reader)?;
+                let parent = &mut stack.last_mut().unwrap().1;
+                add_child(parent, name, xml_node(map, text));
+            }
+            Event::Text(e) => {
+                let text = e
+                    .unescape_and_decode(&reader)
+                    .map_err(|e| xml_err(e, reader.buffer_position()))?;
+                stack.last_mut().unwrap().2.push_str(&text);
+            }
+            Event::CData(e) => {
+                let text = reader.decode(&e).map_err(|e| xml_err(e, reader.buffer_position()))?;
+                stack.last_mut().unwrap().2.push_str(text);
+            }
+            Event::End(_) => {
+                let (name, map, text) = stack.pop().unwrap();
+                let parent = &mut stack.last_mut().unwrap().1;
+                add_child(parent, name, xml_node(map, text));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    if stack.len() != 1 {
+        return Err(eyre!("Invalid XML data: unclosed element {}", stack.last().unwrap().0));
+    }
+    Ok(Value::Mapping(stack.pop().unwrap().1))
+}
+
+fn xml_node(mut map: Mapping, text: String) -> Value {
+    match (map.is_empty(), text.is_empty()) {
+        (true, true) => Value::Null,
+        (true, false) => Value::from(text),
+        (false, true) => Value::Mapping(map),
+        (false, false) => {
+            map.insert(Value::from("#text"), Value::from(text));
+            Value::Mapping(map)
+        }
+    }
+}
+
+/// Add <node> under <name>, turning repeated names into a list
+fn add_child(parent: &mut Mapping, name: String, node: Value) {
+    let key = Value::from(name);
+    match parent.get_mut(&key) {
+        None => {
+            parent.insert(key, node);
+        }
+        Some(Value::Sequence(list)) => list.push(node),
+        Some(existing) => {
+            let first = std::mem::replace(existing, Value::Null);
+            *existing = Value::Sequence(vec![first, node]);
+        }
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let value = parse(&DataType::YAML, "port: 80\ndb: {host: a}\n").unwrap();
+        let json = serialize(&DataType::JSON, &value).unwrap();
+        assert_eq!(json, "{\n  \"port\": 80,\n  \"db\": {\n    \"host\": \"a\"\n  }\n}");
+        let toml = serialize(&DataType::TOML, &value).unwrap();
+        assert_eq!(toml, "port = 80\n\n[db]\nhost = \"a\"\n");
+        let yaml = serialize(&DataType::YAML, &value).unwrap();
+        assert_eq!(parse(&DataType::YAML, &yaml).unwrap(), value);
+
+        assert!(serialize(&DataType::TOML, &Value::from("a")).is_err());
+        assert!(serialize(&DataType::INI, &value).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = parse(&DataType::YAML, "db: {host: a, port: 80}\nzones: [a, b]").unwrap();
+        let other = parse(&DataType::YAML, "db: {port: 8080}\nzones: [c]\nname: app").unwrap();
+        merge(&mut base, other);
+        let exp = parse(&DataType::YAML, "db: {host: a, port: 8080}\nzones: [c]\nname: app");
+        assert_eq!(base, exp.unwrap());
+    }
+
+    #[test]
+    fn test_yaml_stream() {
+        let res = parse_yaml_stream("---\nname: a\n---\nname: b\n").unwrap();
+        assert_eq!(res[0]["name"], Value::from("a"));
+        assert_eq!(res[1]["name"], Value::from("b"));
+
+        let res = parse_yaml_stream("---\nname: a\n").unwrap();
+        assert_eq!(res["name"], Value::from("a"));
+
+        assert!(parse_yaml_stream("a: b\n---\n- [\n").is_err());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let res = parse_json_lines("{\"name\": \"a\"}\n\n{\"name\": \"b\"}\n").unwrap();
+        assert_eq!(res[0]["name"], Value::from("a"));
+        assert_eq!(res[1]["name"], Value::from("b"));
+
+        assert!(parse_json_lines("{\"name\": \"a\"}\n{oops}\n").is_err());
+    }
+
+    #[test]
+    fn test_ini() {
+        let res = parse_ini(
+            "name = myApp
+
+[database]
+host = db.local
+port = 5432
+",
+        )
+        .unwrap();
+
+        assert_eq!(res["name"], Value::from("myApp"));
+        assert_eq!(res["database"]["host"], Value::from("db.local"));
+        assert_eq!(res["database"]["port"], Value::from("5432"));
+    }
+
+    #[test]
+    fn test_properties() {
+        let res = parse_properties(
+            "# comment
+! also a comment
+db.host=db.local
+db.port : 5432
+greeting Hello \\
+         World
+path=C:\\\\tmp
+snowman=\\u2603
+empty
+",
+        )
+        .unwrap();
+
+        assert_eq!(res["db.host"], Value::from("db.local"));
+        assert_eq!(res["db.port"], Value::from("5432"));
+        assert_eq!(res["greeting"], Value::from("Hello World"));
+        assert_eq!(res["path"], Value::from("C:\\tmp"));
+        assert_eq!(res["snowman"], Value::from("\u{2603}"));
+        assert_eq!(res["empty"], Value::from(""));
+    }
+
+    #[test]
+    fn test_dotenv() {
+        let res = parse_dotenv(
+            "# comment
+export DB_HOST=db.local # inline comment
+GREETING=\"Hello\\n\\\"World\\\"\"
+LITERAL='$HOME \\n'
+CERT=\"line1
+line2\"
+",
+        )
+        .unwrap();
+
+        assert_eq!(res["DB_HOST"], Value::from("db.local"));
+        assert_eq!(res["GREETING"], Value::from("Hello\n\"World\""));
+        assert_eq!(res["LITERAL"], Value::from("$HOME \\n"));
+        assert_eq!(res["CERT"], Value::from("line1\nline2"));
+
+        assert!(parse_dotenv("NOT A PAIR").is_err());
+        assert!(parse_dotenv("OPEN=\"forever").is_err());
+    }
+
+    #[test]
+    fn test_hcl() {
+        let res = parse_hcl(
+            r#"
+region = "us-east-1"
+instance_count = 3
+
+service "web" {
+  port = 80
+  tags = ["a", "b"]
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(res["region"], Value::from("us-east-1"));
+        assert_eq!(res["instance_count"], Value::from(3));
+        assert_eq!(res["service"]["web"]["port"], Value::from(80));
+        assert_eq!(res["service"]["web"]["tags"][1], Value::from("b"));
+    }
+
+    #[test]
+    fn test_xml() {
+        let res = parse_xml(
+            r#"<?xml version="1.0"?>
+<feed region="eu">
+  <title>Prices &amp; stock</title>
+  <item sku="a1">10</item>
+  <item sku="b2"><![CDATA[<20>]]></item>
+  <empty/>
+</feed>"#,
+        )
+        .unwrap();
+
+        let feed = &res["feed"];
+        assert_eq!(feed["@region"], Value::from("eu"));
+        assert_eq!(feed["title"], Value::from("Prices & stock"));
+        assert_eq!(feed["item"][0]["@sku"], Value::from("a1"));
+        assert_eq!(feed["item"][0]["#text"], Value::from("10"));
+        assert_eq!(feed["item"][1]["#text"], Value::from("<20>"));
+        assert_eq!(feed["empty"], Value::Null);
+
+        assert!(parse_xml("<a><b></a>").is_err());
+        assert!(parse_xml("<a>").is_err());
+    }
+}
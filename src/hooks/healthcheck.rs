@@ -0,0 +1,227 @@
+use crate::config::parse_duration;
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::sandbox::{Sandbox, SandboxConf};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use std::any::Any;
+
+/// How long the service has to become healthy, unless configured
+const DEFAULT_TIMEOUT: &str = "30s";
+/// How long to wait between checks, unless configured
+const DEFAULT_INTERVAL: &str = "1s";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// HealthcheckConf will store the user's input from the configuration file
+// and then let us instantiate a Healthcheck struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "healthcheck")]
+pub struct HealthcheckConf {
+    pub url: Option<String>,
+    pub command: Option<String>,
+    pub timeout: Option<String>,
+    pub interval: Option<String>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl HealthcheckConf {
+    /// Will panic unless exactly one of url or command is given, or if a
+    /// duration is invalid.
+    pub fn convert(&self) -> Healthcheck {
+        let check = match (&self.url, &self.command) {
+            (Some(url), None) => Check::Url(url.clone()),
+            (None, Some(command)) => Check::Command(command.clone()),
+            _ => {
+                eprintln!("Error, the healthcheck hook needs either a url or a command");
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let timeout = self.timeout.as_deref().unwrap_or(DEFAULT_TIMEOUT);
+        let interval = self.interval.as_deref().unwrap_or(DEFAULT_INTERVAL);
+
+        Healthcheck::new(
+            check,
+            parse_duration("healthcheck timeout", timeout),
+            parse_duration("healthcheck interval", interval),
+            self.sandbox.convert(),
+        )
+    }
+}
+
+/// Make healthcheck hooks from [hooks.healthcheck]
+pub fn register(registry: &mut Registry) {
+    registry.register("healthcheck", |section| {
+        let conf: HealthcheckConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// What tells us the service is healthy
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    /// A GET of the url answering with a 2xx status
+    Url(String),
+    /// The command, run by bash, exiting with 0
+    Command(String),
+}
+
+/// The Healthcheck Hook holds the run until the service the earlier hooks
+/// reloaded is healthy again, checking every <interval>.  If it is not
+/// within <timeout> the hook fails, and with it the run.  A command runs in
+/// <sandbox>.
+#[derive(Debug, PartialEq)]
+pub struct Healthcheck {
+    check: Check,
+    timeout: Duration,
+    interval: Duration,
+    sandbox: Sandbox,
+}
+
+impl Healthcheck {
+    /// Create a new Healthcheck struct
+    pub fn new(
+        check: Check,
+        timeout: Duration,
+        interval: Duration,
+        sandbox: Sandbox,
+    ) -> Healthcheck {
+        Healthcheck {
+            check,
+            timeout,
+            interval,
+            sandbox,
+        }
+    }
+
+    /// Check the service once, giving up at <deadline>
+    fn check(&self, deadline: Instant, data: &ConfigData) -> Result<()> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match &self.check {
+            Check::Url(url) => {
+                let resp = http::request("GET", url).timeout(remaining).call();
+                if let Some(e) = resp.synthetic_error() {
+                    return Err(eyre!("Unable to reach {}: {}", url, e));
+                }
+                if !resp.ok() {
+                    return Err(eyre!("{} answered {}", url, resp.status_line()));
+                }
+                Ok(())
+            }
+            Check::Command(command) => {
+                let mut cmd = std::process::Command::new("/bin/bash");
+                cmd.arg("-c").arg(command);
+                self.sandbox.apply(&mut cmd);
+                cmd.envs(data.run_env());
+                let mut child = cmd
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .spawn()?;
+                loop {
+                    if let Some(status) = child.try_wait()? {
+                        if !status.success() {
+                            return Err(eyre!("{} exited with {}", command, status));
+                        }
+                        return Ok(());
+                    }
+                    if Instant::now() >= deadline {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(eyre!("{} did not finish in time", command));
+                    }
+                    std::thread::sleep(Duration::from_millis(50).min(self.interval));
+                }
+            }
+        }
+    }
+}
+
+impl Hook for Healthcheck {
+    fn kind(&self) -> &'static str {
+        "healthcheck"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Check the service until it is healthy or the timeout is reached
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let res = self.check(deadline, data);
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() + self.interval >= deadline => {
+                    return Err(e.wrap_err(format!(
+                        "Service was not healthy within {:?}",
+                        self.timeout
+                    )));
+                }
+                Err(_) => std::thread::sleep(self.interval),
+            }
+        }
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> ConfigData {
+        ConfigData::new("", "mock", None)
+    }
+
+    fn command(command: &str, interval: Duration) -> Healthcheck {
+        let check = Check::Command(command.to_string());
+        Healthcheck::new(check, interval * 10, interval, Sandbox::default())
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.healthcheck]
+         url = "http://localhost:8080/health"
+         timeout = "10s"
+        "#;
+        let exp = Healthcheck::new(
+            Check::Url("http://localhost:8080/health".to_string()),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            Sandbox::default(),
+        );
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: HealthcheckConf = maps["hooks"]["healthcheck"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_command() {
+        let interval = Duration::from_millis(10);
+        let hook = command("true", interval);
+        assert!(hook.run(&data()).is_ok());
+
+        let hook = command("false", interval);
+        let res = format!("{:#}", hook.run(&data()).unwrap_err());
+        assert!(res.contains("Service was not healthy within"), "{}", res);
+
+        // A hanging check is cut short at the deadline
+        let started = Instant::now();
+        let hook = command("sleep 10", interval);
+        let res = format!("{:#}", hook.run(&data()).unwrap_err());
+        assert!(res.contains("did not finish in time"), "{}", res);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
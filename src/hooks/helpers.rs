@@ -0,0 +1,397 @@
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, Output,
+    RenderContext, RenderError, ScopedJson,
+};
+use eyre::WrapErr;
+use rhai::de::from_dynamic;
+use rhai::ser::to_dynamic;
+use rhai::{Dynamic, EvalAltResult, Scope, AST};
+use serde_json::Value;
+
+use crate::identity;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Register the bundled helper pack on <hb>.  Loosely modelled on Sprig, but
+/// the value being operated on always comes first, since handlebars has no
+/// pipelines:
+///
+///   dates   {{now}} {{now format="%Y"}} {{dateFormat "%d/%m/%Y" updated}}
+///   math    {{add a b}} {{sub a b}} {{mul a b}} {{div a b}} {{mod a b}}
+///   strings {{trim s}} {{upper s}} {{lower s}} {{replace s "-" "_"}}
+///           {{#each (split s ",")}} {{join list ","}}
+///   lists   {{first list}} {{last list}} {{#each (sortBy list "name")}}
+///   logic   {{ternary enabled "on" "off"}}
+///   host    {{hostname}} {{aws_region}} {{aws_account_id}} {{instance_id}}
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper("now", Box::new(now));
+    hb.register_helper("dateFormat", Box::new(date_format));
+
+    hb.register_helper("add", Box::new(add));
+    hb.register_helper("sub", Box::new(sub));
+    hb.register_helper("mul", Box::new(mul));
+    hb.register_helper("div", Box::new(div));
+    hb.register_helper("mod", Box::new(modulo));
+
+    hb.register_helper("trim", Box::new(trim));
+    hb.register_helper("upper", Box::new(upper));
+    hb.register_helper("lower", Box::new(lower));
+    hb.register_helper("replace", Box::new(replace));
+    hb.register_helper("split", Box::new(split));
+    hb.register_helper("join", Box::new(join));
+
+    hb.register_helper("first", Box::new(first));
+    hb.register_helper("last", Box::new(last));
+    hb.register_helper("sortBy", Box::new(sort_by));
+
+    hb.register_helper("ternary", Box::new(ternary));
+
+    for name in identity::NAMES {
+        hb.register_helper(
+            name,
+            Box::new(
+                move |_: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext,
+                      out: &mut dyn Output|
+                      -> HelperResult {
+                    let value = identity::lookup(name)
+                        .map_err(|e| RenderError::new(format!("{}: {:#}", name, e)))?;
+                    out.write(&value)?;
+                    Ok(())
+                },
+            ),
+        );
+    }
+}
+
+/// Register {{workspace}} on <hb>, the directory of the run hooks keep what
+/// they make along the way in, if there is one
+pub fn register_workspace(hb: &mut Handlebars, workspace: Option<String>) {
+    hb.register_helper(
+        "workspace",
+        Box::new(
+            move |_: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext,
+                  out: &mut dyn Output|
+                  -> HelperResult {
+                let workspace = workspace
+                    .as_ref()
+                    .ok_or_else(|| RenderError::new("workspace: only runs have one"))?;
+                out.write(workspace)?;
+                Ok(())
+            },
+        ),
+    );
+}
+
+/// Register {{file "path"}} and {{file_b64 "path"}} on <hb>, which inline
+/// the contents of a file as is, or base64 encoded.  Only files inside one
+/// of <dirs> can be read.
+pub fn register_files(hb: &mut Handlebars, dirs: &[PathBuf]) {
+    for (name, encode) in &[("file", false), ("file_b64", true)] {
+        let dirs = dirs.to_vec();
+        let encode = *encode;
+        hb.register_helper(
+            name,
+            Box::new(
+                move |h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext,
+                      out: &mut dyn Output|
+                      -> HelperResult {
+                    let path = match h.param(0) {
+                        Some(param) => param.value().render(),
+                        None => return Err(RenderError::new(format!("{} needs a path", name))),
+                    };
+                    let contents = read_file(&dirs, &path).map_err(RenderError::new)?;
+                    match encode {
+                        true => out.write(&base64::encode(contents))?,
+                        false => out.write(&String::from_utf8_lossy(&contents))?,
+                    }
+                    Ok(())
+                },
+            ),
+        );
+    }
+}
+
+
+// // // // // // // // // // // Scripts // // // // // // // // // // //
+
+/// Register each of <scripts>, rhai scripts, as a helper under its name.  A
+/// call of one may take at most <operations>, so that a script looping
+/// forever fails the render rather than keeping it busy.
+pub fn register_scripts(
+    hb: &mut Handlebars,
+    scripts: &BTreeMap<String, String>,
+    operations: u64,
+) -> eyre::Result<()> {
+    let mut engine = rhai::Engine::new();
+    engine.set_max_operations(operations);
+    let engine = Arc::new(engine);
+    for (name, script) in scripts {
+        let script = engine
+            .compile(script)
+            .wrap_err_with(|| format!("Unable to compile template helper {}", name))?;
+        let helper = ScriptHelper { name: name.clone(), engine: Arc::clone(&engine), script };
+        hb.register_helper(name, Box::new(helper));
+    }
+    Ok(())
+}
+
+/// ScriptHelper:
+/// The compiled <script> of the helper <name>, run by <engine>.  The
+/// helper's arguments are available in the script as the array `params`
+/// and the map `hash`.
+struct ScriptHelper {
+    name: String,
+    engine: Arc<rhai::Engine>,
+    script: AST,
+}
+
+impl HelperDef for ScriptHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let failed = |e: Box<EvalAltResult>| RenderError::new(format!("{}: {}", self.name, e));
+        let params: Vec<&Value> = h.params().iter().map(|p| p.value()).collect();
+        let hash: BTreeMap<&str, &Value> = h.hash().iter().map(|(k, v)| (*k, v.value())).collect();
+
+        let mut scope = Scope::new();
+        scope.push_dynamic("params", to_dynamic(params).map_err(failed)?);
+        scope.push_dynamic("hash", to_dynamic(hash).map_err(failed)?);
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.script)
+            .map_err(failed)?;
+        Ok(Some(ScopedJson::Derived(from_dynamic(&result).map_err(failed)?)))
+    }
+}
+
+
+// // // // // // // // // // // Dates // // // // // // // // // // //
+
+// Current UTC time, RFC 3339 unless a strftime style format is given
+handlebars_helper!(now: |{format: str = ""}| {
+    let time = chrono::Utc::now();
+    match format {
+        "" => time.to_rfc3339(),
+        f => time.format(f).to_string(),
+    }
+});
+
+// Reformat an RFC 3339 timestamp
+handlebars_helper!(date_format: |format: str, date: str| {
+    chrono::DateTime::parse_from_rfc3339(date)
+        .map_err(|e| RenderError::new(format!("dateFormat: bad date {}: {}", date, e)))?
+        .format(format)
+        .to_string()
+});
+
+
+// // // // // // // // // // // Math // // // // // // // // // // //
+
+handlebars_helper!(add: |x: i64, y: i64| {
+    x.checked_add(y).ok_or_else(|| RenderError::new("add: overflow"))?
+});
+handlebars_helper!(sub: |x: i64, y: i64| {
+    x.checked_sub(y).ok_or_else(|| RenderError::new("sub: overflow"))?
+});
+handlebars_helper!(mul: |x: i64, y: i64| {
+    x.checked_mul(y).ok_or_else(|| RenderError::new("mul: overflow"))?
+});
+handlebars_helper!(div: |x: i64, y: i64| {
+    x.checked_div(y).ok_or_else(|| divide_error("div", y))?
+});
+handlebars_helper!(modulo: |x: i64, y: i64| {
+    x.checked_rem(y).ok_or_else(|| divide_error("mod", y))?
+});
+
+/// Why dividing by <y> failed, as it fails for a zero <y> and for the
+/// smallest i64 divided by -1
+fn divide_error(helper: &str, y: i64) -> RenderError {
+    match y {
+        0 => RenderError::new(format!("{}: division by zero", helper)),
+        _ => RenderError::new(format!("{}: overflow", helper)),
+    }
+}
+
+
+// // // // // // // // // // // Strings // // // // // // // // // // //
+
+handlebars_helper!(trim: |s: str| s.trim());
+handlebars_helper!(upper: |s: str| s.to_uppercase());
+handlebars_helper!(lower: |s: str| s.to_lowercase());
+handlebars_helper!(replace: |s: str, from: str, to: str| s.replace(from, to));
+handlebars_helper!(split: |s: str, sep: str| s.split(sep).collect::<Vec<&str>>());
+handlebars_helper!(join: |list: array, sep: str| {
+    list.iter()
+        .map(|v| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(sep)
+});
+
+
+// // // // // // // // // // // Lists // // // // // // // // // // //
+
+handlebars_helper!(first: |list: array| list.first().cloned().unwrap_or(Value::Null));
+handlebars_helper!(last: |list: array| list.last().cloned().unwrap_or(Value::Null));
+
+// Sort a list of objects by the field <key>
+handlebars_helper!(sort_by: |list: array, key: str| {
+    let mut list = list.clone();
+    list.sort_by(|a, b| compare(&a[key], &b[key]));
+    list
+});
+
+/// Order numbers numerically and everything else by its text
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            let (x, y) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+            x.partial_cmp(&y).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (x, y) => x.to_string().cmp(&y.to_string()),
+    }
+}
+
+
+// // // // // // // // // // // Logic // // // // // // // // // // //
+
+handlebars_helper!(ternary: |cond: Json, yes: Json, no: Json| {
+    let truthy = match cond {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(_) => true,
+    };
+    if truthy { yes.clone() } else { no.clone() }
+});
+
+
+// // // // // // // // // // // Files // // // // // // // // // // //
+
+/// Read the file at <path>, as long as it is inside one of <dirs>.  Links
+/// and `..` are resolved first, so they can not be used to get out.
+pub fn read_file(dirs: &[PathBuf], path: &str) -> Result<Vec<u8>, String> {
+    let expanded_path = shellexpand::tilde(path).to_string();
+    let resolved = Path::new(&expanded_path)
+        .canonicalize()
+        .map_err(|e| format!("Could not open {}: {}", path, e))?;
+
+    if !dirs.iter().any(|dir| resolved.starts_with(dir)) {
+        return Err(format!("{} is outside of the template's file_dirs", path));
+    }
+    std::fs::read(&resolved).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(tpl: &str) -> String {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let data = serde_json::json!({
+            "name": " web-1 ",
+            "updated": "2020-11-05T10:30:00+00:00",
+            "ports": [80, 443],
+            "hosts": [{"name": "b", "weight": 10}, {"name": "a", "weight": 2}],
+            "enabled": true,
+        });
+        hb.render_template(tpl, &data).unwrap()
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(render(r#"{{dateFormat "%d/%m/%Y" updated}}"#), "05/11/2020");
+        assert_eq!(render(r#"{{now format="%Y"}}"#).len(), 4);
+    }
+
+    #[test]
+    fn test_math() {
+        assert_eq!(
+            render("{{add 2 3}} {{sub 2 3}} {{mul 2 3}} {{div 7 2}} {{mod 7 2}}"),
+            "5 -1 6 3 1"
+        );
+        assert_eq!(render("{{add (first ports) 1}}"), "81");
+
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        for tpl in &[
+            "{{div 1 0}}",
+            "{{add 9223372036854775807 1}}",
+            "{{sub -9223372036854775807 2}}",
+            "{{mul 4611686018427387904 2}}",
+        ] {
+            assert!(hb.render_template(tpl, &Value::Null).is_err(), "{}", tpl);
+        }
+
+        for (tpl, exp) in &[
+            ("{{div 1 0}}", "div: division by zero"),
+            ("{{mod 1 0}}", "mod: division by zero"),
+            ("{{div -9223372036854775808 -1}}", "div: overflow"),
+            ("{{mod -9223372036854775808 -1}}", "mod: overflow"),
+        ] {
+            let err = hb.render_template(tpl, &Value::Null).unwrap_err();
+            assert!(err.to_string().contains(exp), "{}: {}", tpl, err);
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(render("{{upper (trim name)}}"), "WEB-1");
+        assert_eq!(render(r#"{{replace (trim name) "-" "_"}}"#), "web_1");
+        assert_eq!(render(r#"{{#each (split "a,b" ",")}}[{{this}}]{{/each}}"#), "[a][b]");
+        assert_eq!(render(r#"{{join ports ", "}}"#), "80, 443");
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(render("{{first ports}} {{last ports}}"), "80 443");
+        assert_eq!(render(r#"{{#each (sortBy hosts "name")}}{{this.name}}{{/each}}"#), "ab");
+        assert_eq!(render(r#"{{#each (sortBy hosts "weight")}}{{this.weight}} {{/each}}"#), "2 10 ");
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(render("{{hostname}}"), identity::lookup("hostname").unwrap());
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = Path::new("./tests").canonicalize().unwrap();
+        let mut hb = Handlebars::new();
+        register_files(&mut hb, &[dir]);
+
+        let res = hb.render_template(r#"{{file "./tests/test_template.tmpl"}}"#, &Value::Null);
+        assert!(res.unwrap().starts_with("{{#each hosts}}"));
+
+        let res = hb.render_template(r#"{{file_b64 "./tests/exec_plugin.sh"}}"#, &Value::Null);
+        let res = base64::decode(res.unwrap()).unwrap();
+        assert!(res.starts_with(b"#!"));
+
+        let res = hb.render_template(r#"{{file "./tests/../Cargo.toml"}}"#, &Value::Null);
+        assert!(format!("{}", res.unwrap_err()).contains("outside of the template's file_dirs"));
+
+        assert!(hb.render_template(r#"{{file "./tests/nope"}}"#, &Value::Null).is_err());
+        assert!(hb.render_template("{{file}}", &Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_ternary() {
+        assert_eq!(render(r#"{{ternary enabled "on" "off"}}"#), "on");
+        assert_eq!(render(r#"{{ternary missing "on" "off"}}"#), "off");
+    }
+}
@@ -0,0 +1,341 @@
+use crate::data::ConfigData;
+use crate::hooks::template::{splice, DataType};
+use crate::hooks::{replace, sha256, Hook, Registry};
+use crate::interactive;
+use crate::path;
+use crate::sandbox::{Sandbox, SandboxConf};
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// HostsConf will store the user's input from the configuration file
+// and then let us instantiate a Hosts struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "hosts")]
+pub struct HostsConf {
+    pub format: Option<Format>,
+    pub file: Option<String>,
+    pub select: Option<String>,
+    pub source_type: Option<DataType>,
+    pub marker: Option<String>,
+    pub reload: Option<String>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl HostsConf {
+    /// Will panic if a marker is given for a dnsmasq file
+    pub fn convert(&self) -> Hosts {
+        let format = self.format.clone().unwrap_or(Format::Hosts);
+        let marker = match (&format, &self.marker) {
+            (Format::Dnsmasq, Some(_)) => {
+                eprintln!("Error, a hosts marker requires format = \"hosts\"");
+                std::process::exit(exitcode::CONFIG);
+            }
+            (Format::Dnsmasq, None) => None,
+            (Format::Hosts, marker) => {
+                Some(marker.clone().unwrap_or_else(|| "app_config".to_string()))
+            }
+        };
+        let file = match (&self.file, &format) {
+            (Some(file), _) => file.clone(),
+            (None, Format::Hosts) => "/etc/hosts".to_string(),
+            (None, Format::Dnsmasq) => "/etc/dnsmasq.d/app_config.conf".to_string(),
+        };
+
+        let mut hosts = Hosts::new(
+            format,
+            &file,
+            self.select.as_deref().unwrap_or("."),
+            self.source_type.clone().unwrap_or(DataType::YAML),
+        );
+        hosts.marker = marker;
+        hosts.reload = self.reload.clone();
+        hosts.sandbox = self.sandbox.convert();
+        hosts
+    }
+}
+
+/// Make hosts hooks from [hooks.hosts]
+pub fn register(registry: &mut Registry) {
+    registry.register("hosts", |section| {
+        let conf: HostsConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The file the entries are written to
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// /etc/hosts, or a file like it, of which only a managed block is ours
+    Hosts,
+    /// A dnsmasq conf.d file of host-record lines, ours as a whole
+    Dnsmasq,
+}
+
+/// The Hosts Hook merges a map of host names to IPs, selected by the jq
+/// style path <select> in the data parsed as <source_type>, into <file>.
+/// A name maps to one IP or a list of them.  In the hosts format the
+/// entries replace the block between `# BEGIN <marker>` and `# END <marker>`,
+/// the rest of the file is left as it is.  Names and IPs are checked before
+/// anything is written, one bad entry fails the hook.  When the file
+/// changed, <reload> is run by bash in <sandbox>, e.g. to have dnsmasq
+/// read it again.
+#[derive(Debug, PartialEq)]
+pub struct Hosts {
+    format: Format,
+    file: String,
+    select: String,
+    source_type: DataType,
+    marker: Option<String>,
+    reload: Option<String>,
+    sandbox: Sandbox,
+    output: RefCell<Option<String>>,
+}
+
+impl Hosts {
+    /// Create a new Hosts struct, without a marker or a reload command
+    pub fn new(format: Format, file: &str, select: &str, source_type: DataType) -> Hosts {
+        Hosts {
+            format,
+            file: file.to_string(),
+            select: select.to_string(),
+            source_type,
+            marker: None,
+            reload: None,
+            sandbox: Sandbox::default(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The IPs of each host name in <data>
+    fn entries(&self, data: &ConfigData) -> Result<BTreeMap<String, Vec<IpAddr>>> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+        let mut selected = path::select(&value, &self.select)?;
+        if selected.len() != 1 {
+            return Err(eyre!("{} selects {} values, not a map", self.select, selected.len()));
+        }
+        let map = match selected.remove(0) {
+            Value::Mapping(map) => map,
+            _ => return Err(eyre!("{} does not select a map of host names", self.select)),
+        };
+
+        let mut entries = BTreeMap::new();
+        for (name, ips) in map {
+            let name = match name {
+                Value::String(name) if valid_name(&name) => name,
+                name => return Err(eyre!("{:?} is not a valid host name", name)),
+            };
+            let ips = match ips {
+                Value::Sequence(ips) => ips,
+                ip => vec![ip],
+            };
+            if ips.is_empty() {
+                return Err(eyre!("{} has no IP", name));
+            }
+            let ips = ips
+                .iter()
+                .map(|ip| match ip.as_str().map(str::parse::<IpAddr>) {
+                    Some(Ok(ip)) => Ok(ip),
+                    _ => Err(eyre!("{}: {:?} is not a valid IP", name, ip)),
+                })
+                .collect::<Result<Vec<IpAddr>>>()?;
+            entries.insert(name, ips);
+        }
+        Ok(entries)
+    }
+
+    /// The lines of the entries in <data>, as the format writes them
+    fn render(&self, data: &ConfigData) -> Result<String> {
+        let mut rendered = String::new();
+        if self.format == Format::Dnsmasq {
+            rendered.push_str("# Managed by app_config, do not edit\n");
+        }
+        for (name, ips) in self.entries(data)? {
+            match self.format {
+                Format::Hosts => {
+                    for ip in ips {
+                        rendered.push_str(&format!("{}\t{}\n", ip, name));
+                    }
+                }
+                Format::Dnsmasq => {
+                    let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+                    rendered.push_str(&format!("host-record={},{}\n", name, ips.join(",")));
+                }
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+impl Hook for Hosts {
+    fn kind(&self) -> &'static str {
+        "hosts"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Write the entries to the file, and reload if it changed
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let rendered = self.render(data)?;
+        let file = tilde(&self.file).to_string();
+        let existing = match fs::read_to_string(&file) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", file)),
+        };
+        let contents = match &self.marker {
+            Some(marker) => {
+                let begin = format!("# BEGIN {}", marker);
+                let end = format!("# END {}", marker);
+                splice(&existing, &begin, &end, &rendered)
+                    .wrap_err_with(|| format!("Could not update {}", file))?
+            }
+            None => rendered.clone(),
+        };
+        *self.output.borrow_mut() = Some(sha256(&rendered));
+
+        if contents == existing {
+            return Ok(());
+        }
+        if !interactive::confirm_write(&file, contents.as_bytes())? {
+            return Ok(());
+        }
+        replace(&file, &contents)?;
+        match &self.reload {
+            Some(command) => self.sandbox.run_checked(command, data.run_env()),
+            None => Ok(()),
+        }
+    }
+
+    /// The dnsmasq file, a hosts file is only ours in part
+    fn files(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        match self.format {
+            Format::Hosts => Ok(Vec::new()),
+            Format::Dnsmasq => Ok(vec![tilde(&self.file).to_string()]),
+        }
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    /// The entries run writes, leaving out what a managed block is spliced
+    /// into
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        Ok(vec![(self.file.clone(), self.render(data)?)])
+    }
+}
+
+/// Whether <name> is a host name as RFC 1123 has them: dot separated labels
+/// of letters, digits and hyphens, neither starting nor ending with one
+fn valid_name(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(format: Format, file: &str) -> Hosts {
+        Hosts::new(format, file, ".hosts", DataType::YAML)
+    }
+
+    fn data(yaml: &str) -> ConfigData {
+        ConfigData::new(yaml, "mock", None)
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.hosts]
+         select = ".hosts"
+         reload = "pkill -HUP dnsmasq"
+        "#;
+        let mut exp = hosts(Format::Hosts, "/etc/hosts");
+        exp.marker = Some("app_config".to_string());
+        exp.reload = Some("pkill -HUP dnsmasq".to_string());
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: HostsConf = maps["hooks"]["hosts"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_render() {
+        let d = data("hosts:\n  web.local: 10.0.0.2\n  db.local: [10.0.0.3, 'fd00::3']\n");
+        let res = hosts(Format::Hosts, "hosts").render(&d).unwrap();
+        assert_eq!(res, "10.0.0.3\tdb.local\nfd00::3\tdb.local\n10.0.0.2\tweb.local\n");
+
+        let res = hosts(Format::Dnsmasq, "dnsmasq.conf").render(&d).unwrap();
+        let exp = "# Managed by app_config, do not edit\n\
+                   host-record=db.local,10.0.0.3,fd00::3\n\
+                   host-record=web.local,10.0.0.2\n";
+        assert_eq!(res, exp);
+
+        for bad in &[
+            "hosts:\n  -web: 10.0.0.2\n",
+            "hosts:\n  'web local': 10.0.0.2\n",
+            "hosts:\n  web: 10.0.0.256\n",
+            "hosts:\n  web: '10.0.0.2\\n10.0.0.3 evil'\n",
+            "hosts:\n  web: []\n",
+            "hosts: [web]\n",
+        ] {
+            assert!(hosts(Format::Hosts, "hosts").render(&data(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("app_config_hosts_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("hosts");
+        let reloads = dir.join("reloads");
+        fs::write(&file, "127.0.0.1\tlocalhost\n").unwrap();
+
+        let mut hook = hosts(Format::Hosts, file.to_str().unwrap());
+        hook.marker = Some("app_config".to_string());
+        hook.reload = Some(format!("echo >> {}", reloads.display()));
+        hook.run(&data("hosts:\n  web.local: 10.0.0.2\n")).unwrap();
+        let exp = "127.0.0.1\tlocalhost\n\
+                   # BEGIN app_config\n10.0.0.2\tweb.local\n# END app_config\n";
+        assert_eq!(fs::read_to_string(&file).unwrap(), exp);
+
+        // The same entries leave the file as it is, without a reload
+        hook.run(&data("hosts:\n  web.local: 10.0.0.2\n")).unwrap();
+        hook.run(&data("hosts:\n  web.local: 10.0.0.4\n")).unwrap();
+        let exp = "127.0.0.1\tlocalhost\n\
+                   # BEGIN app_config\n10.0.0.4\tweb.local\n# END app_config\n";
+        assert_eq!(fs::read_to_string(&file).unwrap(), exp);
+        assert_eq!(fs::read_to_string(&reloads).unwrap(), "\n\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
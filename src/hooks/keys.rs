@@ -0,0 +1,227 @@
+#[cfg(feature = "aws")]
+use crate::providers::param_store::get_params;
+use crate::state::{self, Db};
+#[cfg(not(feature = "aws"))]
+use eyre::eyre;
+use eyre::{Result, WrapErr};
+
+#[cfg(feature = "state-sqlite")]
+use rusqlite::{params, OptionalExtension};
+#[cfg(not(feature = "state-sqlite"))]
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a value is used before it is fetched again, unless configured
+pub const DEFAULT_TTL: u64 = 300;
+
+/// KeyCache:
+/// Parameter Store values looked up by the `key` template helper.  Values
+/// are kept in memory for <ttl>, so a template using the same key many
+/// times, or rendered many times, only fetches it once.  Lookups give up
+/// after <timeout>, if there is one.  With a state file the last value
+/// fetched of every key is also stored there, decrypted, and used when
+/// Parameter Store can not be reached.  Debug only shows how many keys are
+/// in memory, never their values.
+pub struct KeyCache {
+    ttl: Duration,
+    timeout: Option<Duration>,
+    memory: Mutex<HashMap<String, (Instant, String)>>,
+    db_conn: Option<Mutex<Db>>,
+}
+
+impl std::fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("KeyCache")
+            .field("ttl", &self.ttl)
+            .field("timeout", &self.timeout)
+            .field("keys", &self.memory.try_lock().map(|memory| memory.len()).ok())
+            .field("db_conn", &self.db_conn)
+            .finish()
+    }
+}
+
+impl Default for KeyCache {
+    fn default() -> KeyCache {
+        KeyCache {
+            ttl: Duration::from_secs(DEFAULT_TTL),
+            timeout: None,
+            memory: Mutex::new(HashMap::new()),
+            db_conn: None,
+        }
+    }
+}
+
+impl KeyCache {
+    /// Create a new cache, persisted in <state_file> if there is one
+    /// Will panic if the state file can not be opened or initialized.
+    pub fn new(ttl: u64, state_file: &Option<String>) -> KeyCache {
+        let db_conn = state_file.as_ref().map(|file_name| {
+            let conn = state::open_db(&Some(file_name.to_string()), "");
+            if let Err(e) = KeyCache::create_cache(&conn) {
+                eprintln!("Error, unable to create cache: {:?}", e);
+                std::process::exit(exitcode::SOFTWARE);
+            }
+            Mutex::new(conn)
+        });
+
+        KeyCache {
+            ttl: Duration::from_secs(ttl),
+            timeout: None,
+            memory: Mutex::new(HashMap::new()),
+            db_conn,
+        }
+    }
+
+    /// Give up on looking a key up after <timeout>
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Setup the table of last known values if it does not already exist
+    #[cfg(feature = "state-sqlite")]
+    fn create_cache(db_conn: &Db) -> state::Result<()> {
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS template_keys (
+                key     TEXT PRIMARY KEY,
+                value   TEXT NOT NULL,
+                fetched TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        Ok(())
+    }
+
+    /// Without sqlite, values are kept in the state file as they are fetched
+    #[cfg(not(feature = "state-sqlite"))]
+    fn create_cache(_db_conn: &Db) -> state::Result<()> {
+        Ok(())
+    }
+
+    /// The value of <key>, from memory if it is fresh enough, else from
+    /// Parameter Store, else the last known value
+    pub fn get(&self, key: &str) -> Result<String> {
+        if let Some((fetched, value)) = self.memory.lock().unwrap().get(key) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(value.clone());
+            }
+        }
+
+        match get_params(key, self.timeout) {
+            Ok(value) => {
+                self.store(key, &value)?;
+                Ok(value)
+            }
+            Err(e) => match self.last_known(key)? {
+                Some(value) => {
+                    warning!("using the last known value of {}: {:#}", key, e);
+                    Ok(value)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Remember the freshly fetched <value> of <key>
+    pub fn store(&self, key: &str, value: &str) -> Result<()> {
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (Instant::now(), value.to_string()));
+
+        if let Some(db_conn) = &self.db_conn {
+            save(&db_conn.lock().unwrap(), key, value).wrap_err("Unable to cache template key")?;
+        }
+        Ok(())
+    }
+
+    /// The value of <key> last stored in the state file
+    fn last_known(&self, key: &str) -> Result<Option<String>> {
+        let db_conn = match &self.db_conn {
+            Some(db_conn) => db_conn.lock().unwrap(),
+            None => return Ok(None),
+        };
+        Ok(load(&db_conn, key)?)
+    }
+}
+
+/// Store the <value> of <key> just fetched in the state file
+#[cfg(feature = "state-sqlite")]
+fn save(db_conn: &Db, key: &str, value: &str) -> state::Result<()> {
+    db_conn.execute(
+        "INSERT OR REPLACE INTO template_keys (key, value, fetched)
+            VALUES (?1, ?2, ?3)",
+        params![key, value, chrono::Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// The value of <key> last stored in the state file, if any
+#[cfg(feature = "state-sqlite")]
+fn load(db_conn: &Db, key: &str) -> state::Result<Option<String>> {
+    db_conn
+        .query_row("SELECT value FROM template_keys WHERE key=?1", params![key], |row| row.get(0))
+        .optional()
+}
+
+/// A value as kept in the state file, and when it was fetched
+#[cfg(not(feature = "state-sqlite"))]
+#[derive(Deserialize, Serialize)]
+struct LastKnown {
+    value: String,
+    fetched: String,
+}
+
+#[cfg(not(feature = "state-sqlite"))]
+fn save(db_conn: &Db, key: &str, value: &str) -> state::Result<()> {
+    let last_known = LastKnown {
+        value: value.to_string(),
+        fetched: chrono::Utc::now().to_rfc3339(),
+    };
+    db_conn.put("template_keys", key, &last_known)
+}
+
+#[cfg(not(feature = "state-sqlite"))]
+fn load(db_conn: &Db, key: &str) -> state::Result<Option<String>> {
+    let last_known: Option<LastKnown> = db_conn.get("template_keys", key)?;
+    Ok(last_known.map(|last_known| last_known.value))
+}
+
+/// Without the aws feature there is no Parameter Store to look keys up in,
+/// only the last known values are left
+#[cfg(not(feature = "aws"))]
+fn get_params(key: &str, _timeout: Option<Duration>) -> Result<String> {
+    Err(eyre!("Unable to look up {}, app_config was built without the aws feature", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        let cache = KeyCache::new(60, &None);
+        cache.store("Hello", "World").unwrap();
+        assert_eq!(cache.get("Hello").unwrap(), "World");
+        assert_eq!(cache.last_known("Hello").unwrap(), None);
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("app_config_keys_{}.db", std::process::id()));
+        let state_file = Some(path.to_str().unwrap().to_string());
+
+        let cache = KeyCache::new(60, &state_file);
+        cache.store("Hello", "World").unwrap();
+        cache.store("Hello", "Again").unwrap();
+
+        // A new run only has the state file to go on
+        let cache = KeyCache::new(0, &state_file);
+        let res = cache.last_known("Hello").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+
+        assert_eq!(res, Some("Again".to_string()));
+    }
+}
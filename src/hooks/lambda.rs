@@ -0,0 +1,226 @@
+use crate::credentials;
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::providers::parse_region;
+use rusoto_core::Region;
+use rusoto_lambda::{InvocationRequest, Lambda as LambdaApi, LambdaClient};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result, WrapErr};
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// LambdaConf will store the user's input from the configuration file
+// and then let us instantiate a Lambda struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "lambda")]
+pub struct LambdaConf {
+    pub function: String,
+    pub qualifier: Option<String>,
+    #[serde(rename = "async")]
+    pub asynchronous: Option<bool>,
+    pub payload: Option<String>,
+    pub source_type: Option<DataType>,
+    pub region: Option<String>,
+}
+
+impl LambdaConf {
+    /// Will panic if payload is neither "data" nor "event", or if the region
+    /// is invalid
+    pub fn convert(&self) -> Lambda {
+        let payload = match self.payload.as_deref().unwrap_or("data") {
+            "data" => Payload::Data,
+            "event" => Payload::Event,
+            other => {
+                eprintln!("Error, the lambda hook's payload must be data or event, not {}", other);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let mut lambda = Lambda::new(&self.function, payload);
+        lambda.qualifier = self.qualifier.clone();
+        lambda.asynchronous = self.asynchronous.unwrap_or(false);
+        lambda.source_type = self.source_type.clone().unwrap_or(DataType::YAML);
+        lambda.region = self.region.as_deref().map(parse_region).unwrap_or_default();
+        lambda
+    }
+}
+
+/// Make lambda hooks from [hooks.lambda]
+pub fn register(registry: &mut Registry) {
+    registry.register("lambda", |section| {
+        let conf: LambdaConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// What the function is invoked with
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    /// The data, parsed as <source_type>, as JSON
+    Data,
+    /// Where the data came from and its checksum, but not the data itself
+    Event,
+}
+
+/// The Lambda Hook invokes <function>, at <qualifier> if given, so that
+/// serverless automation reacts to the same change the host applied.
+/// It is invoked with the data or with a change event, as per <payload>.
+/// Unless <async>, the hook waits for the function and fails if it does;
+/// async invocations are only queued.
+#[derive(Debug, PartialEq)]
+pub struct Lambda {
+    function: String,
+    qualifier: Option<String>,
+    asynchronous: bool,
+    payload: Payload,
+    source_type: DataType,
+    region: Region,
+}
+
+impl Lambda {
+    /// Create a new Lambda struct, invoking <function> synchronously
+    pub fn new(function: &str, payload: Payload) -> Lambda {
+        Lambda {
+            function: function.to_string(),
+            qualifier: None,
+            asynchronous: false,
+            payload,
+            source_type: DataType::YAML,
+            region: Region::default(),
+        }
+    }
+
+    /// The JSON the function is invoked with
+    fn payload(&self, data: &ConfigData) -> Result<serde_json::Value> {
+        match self.payload {
+            Payload::Data => {
+                let value = data
+                    .parsed(&self.source_type)
+                    .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+                Ok(serde_json::to_value(&*value)?)
+            }
+            Payload::Event => Ok(serde_json::json!({
+                "provider": data.provider(),
+                "version": data.version(),
+                "version_label": data.version_label(),
+                "version_description": data.version_description(),
+                "sha256": data.sha256(),
+                "received": data.received().to_rfc3339(),
+            })),
+        }
+    }
+
+    /// How Lambda is asked to run the function
+    fn invocation_type(&self) -> &'static str {
+        if self.asynchronous {
+            "Event"
+        } else {
+            "RequestResponse"
+        }
+    }
+}
+
+impl Hook for Lambda {
+    fn kind(&self) -> &'static str {
+        "lambda"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Invoke the function with the payload
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let payload = serde_json::to_vec(&self.payload(data)?)?;
+        invoke(self, payload)
+    }
+}
+
+/// invoke()
+/// Make the call to Lambda and, for synchronous invocations, check that
+/// the function succeeded
+#[tokio::main]
+async fn invoke(lambda: &Lambda, payload: Vec<u8>) -> Result<()> {
+    let client = LambdaClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        lambda.region.clone(),
+    );
+
+    let request = InvocationRequest {
+        function_name: lambda.function.clone(),
+        qualifier: lambda.qualifier.clone(),
+        invocation_type: Some(lambda.invocation_type().to_string()),
+        payload: Some(payload.into()),
+        ..Default::default()
+    };
+
+    let resp = match client.invoke(request).await {
+        Ok(resp) => resp,
+        Err(e) => return Err(eyre!("Unable to invoke {}: {:?}", lambda.function, e)),
+    };
+    if let Some(error) = resp.function_error {
+        let output = resp.payload.as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+        return Err(eyre!("{} failed, {}: {}", lambda.function, error, output));
+    }
+    Ok(())
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.lambda]
+         function = "reindex"
+         qualifier = "live"
+         async = true
+         payload = "event"
+         region = "eu-west-1"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let mut exp = Lambda::new("reindex", Payload::Event);
+        exp.qualifier = Some("live".to_string());
+        exp.asynchronous = true;
+        exp.region = Region::EuWest1;
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: LambdaConf = maps["hooks"]["lambda"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+        assert_eq!(res.invocation_type(), "Event");
+    }
+
+    #[test]
+    fn test_payload() {
+        let data = ConfigData::new("db: {port: 5432}", "mock", Some("3".to_string()));
+
+        let lambda = Lambda::new("reindex", Payload::Data);
+        assert_eq!(lambda.payload(&data).unwrap(), serde_json::json!({"db": {"port": 5432}}));
+        assert_eq!(lambda.invocation_type(), "RequestResponse");
+
+        let lambda = Lambda::new("reindex", Payload::Event);
+        let event = lambda.payload(&data).unwrap();
+        assert_eq!(event["provider"], "mock");
+        assert_eq!(event["version"], "3");
+        assert_eq!(event["version_description"], serde_json::Value::Null);
+        assert_eq!(event["sha256"], data.sha256());
+        assert!(event.get("db").is_none());
+    }
+}
@@ -1,19 +1,383 @@
 pub mod template;
-pub use crate::hooks::template::{Template, TemplateConf};
+pub mod helpers;
+pub mod formats;
+pub mod keys;
 pub mod file;
-pub use crate::hooks::file::{File, FileConf};
 pub mod raw;
-pub use crate::hooks::raw::{Raw, RawConf};
 pub mod command;
-pub use crate::hooks::command::{Command, CommandConf};
+#[cfg(feature = "nomad")]
+pub mod nomad;
+pub mod consul;
+pub mod ssh;
+pub mod syslog;
+pub mod pagerduty;
+pub mod opsgenie;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod healthcheck;
+pub mod hosts;
+pub mod schedule;
+pub mod nftables;
+pub mod sysctl;
+pub mod authorized_keys;
+pub mod tls;
+#[cfg(feature = "vault")]
+pub mod vault;
+#[cfg(feature = "aws")]
+pub mod lambda;
+#[cfg(feature = "aws")]
+pub mod ssm_command;
 
 /*
 use std::error::Error;
 type BoxResult<T> = Result<T, Box<dyn Error>>;
 */
-use eyre::Result;
+use crate::data::ConfigData;
+use eyre::{Result, WrapErr};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+#[cfg(test)]
+use std::any::Any;
+
+/// Hooks left out of builds without the feature they are behind
+const FEATURES: [(&str, &str); 5] = [
+    ("nomad", "nomad"),
+    ("wasm", "wasm"),
+    ("vault", "vault"),
+    ("lambda", "aws"),
+    ("ssm_command", "aws"),
+];
 
 pub trait Hook: std::fmt::Debug {
-    fn run(&self, data: &str) -> Result<()>;
+    /// The config file section this hook is configured by, e.g. "template"
+    fn kind(&self) -> &'static str;
+
+    /// The hook as Any, so the tests can downcast it to the struct of its
+    /// kind.  Named and leader_only hooks give the hook they wrap.
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any;
+
+    /// The name check --only and --skip select this hook by, its kind
+    /// unless it was given one
+    fn name(&self) -> &str {
+        self.kind()
+    }
+
+    /// Apply <data>.  Hooks have to be idempotent: running one again on the
+    /// same data, as app_config replay does with the hook a run failed on,
+    /// leaves things as running it once would.  A hook that fails part way
+    /// leaves nothing a run from the start could not get past.
+    fn run(&self, data: &ConfigData) -> Result<()>;
     // fn run(&self, data: &str) -> BoxResult<()>;
+
+    /// Called on the [on_error] hooks once a run succeeds after they have
+    /// been notified of a failure.  Most hooks have nothing to clear.
+    fn resolve(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The files this hook wrote for <data> that were changed or removed
+    /// since, going by their checksum sidecars.  Most hooks write none.
+    fn drifted(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// The files this hook writes for <data>, for app_config cleanup to tell
+    /// those no hook writes any more.  Files it only keeps a managed block
+    /// of are not its own.  Most hooks write none.
+    fn files(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// sha256 of what the last run produced, e.g. the file it wrote, for the
+    /// run report.  Hooks that produce nothing of their own have none.
+    fn output_sha256(&self) -> Option<String> {
+        None
+    }
+
+    /// Take the [vars] of the config file, for hooks that render them along
+    /// with the data
+    fn set_vars(&mut self, _vars: &serde_yaml::Mapping) {}
+
+    /// Take the path of the <config> file and the name of the <pipeline>,
+    /// for hooks that tell the pipelines apart, e.g. in the alerts they open
+    fn set_pipeline(&mut self, _config: &str, _pipeline: &str) {}
+
+    /// Give up on calls to AWS after <timeout>, settings.timeout, for hooks
+    /// that make them along the way, e.g. to look keys up
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    /// Whether only the host leading the replicas of the pipeline runs this
+    /// hook, e.g. one writing the data back upstream
+    fn leader_only(&self) -> bool {
+        false
+    }
+
+    /// Whether this hook wants the data applied before, see
+    /// ConfigData::previous.  It is only kept for pipelines that do.
+    fn uses_previous(&self) -> bool {
+        false
+    }
+
+    /// What this hook renders for <data>, as the names of its outputs and
+    /// what they would hold, without writing anything.  For app_config test
+    /// to compare with golden files, most hooks render nothing.
+    fn rendered(&self, _data: &ConfigData) -> Result<Vec<(String, String)>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Named:
+/// A hook given a name in the config file, e.g. `name = "render-nginx"`
+#[derive(Debug)]
+pub struct Named {
+    name: String,
+    hook: Box<dyn Hook>,
+}
+
+impl Named {
+    pub fn new(name: &str, hook: Box<dyn Hook>) -> Named {
+        Named {
+            name: name.to_string(),
+            hook,
+        }
+    }
+}
+
+impl Hook for Named {
+    fn kind(&self) -> &'static str {
+        self.hook.kind()
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self.hook.as_any()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        self.hook.run(data)
+    }
+
+    fn resolve(&self) -> Result<()> {
+        self.hook.resolve()
+    }
+
+    fn drifted(&self, data: &ConfigData) -> Result<Vec<String>> {
+        self.hook.drifted(data)
+    }
+
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        self.hook.files(data)
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.hook.output_sha256()
+    }
+
+    fn set_vars(&mut self, vars: &serde_yaml::Mapping) {
+        self.hook.set_vars(vars)
+    }
+
+    fn set_pipeline(&mut self, config: &str, pipeline: &str) {
+        self.hook.set_pipeline(config, pipeline)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.hook.set_timeout(timeout)
+    }
+
+    fn leader_only(&self) -> bool {
+        self.hook.leader_only()
+    }
+
+    fn uses_previous(&self) -> bool {
+        self.hook.uses_previous()
+    }
+
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        self.hook.rendered(data)
+    }
+}
+
+/// LeaderOnly:
+/// A hook only the leading replica runs, set with `leader_only = true`
+#[derive(Debug)]
+pub struct LeaderOnly {
+    hook: Box<dyn Hook>,
 }
+
+impl LeaderOnly {
+    pub fn new(hook: Box<dyn Hook>) -> LeaderOnly {
+        LeaderOnly { hook }
+    }
+}
+
+impl Hook for LeaderOnly {
+    fn kind(&self) -> &'static str {
+        self.hook.kind()
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self.hook.as_any()
+    }
+
+    fn name(&self) -> &str {
+        self.hook.name()
+    }
+
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        self.hook.run(data)
+    }
+
+    fn resolve(&self) -> Result<()> {
+        self.hook.resolve()
+    }
+
+    fn drifted(&self, data: &ConfigData) -> Result<Vec<String>> {
+        self.hook.drifted(data)
+    }
+
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        self.hook.files(data)
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.hook.output_sha256()
+    }
+
+    fn set_vars(&mut self, vars: &serde_yaml::Mapping) {
+        self.hook.set_vars(vars)
+    }
+
+    fn set_pipeline(&mut self, config: &str, pipeline: &str) {
+        self.hook.set_pipeline(config, pipeline)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.hook.set_timeout(timeout)
+    }
+
+    fn leader_only(&self) -> bool {
+        true
+    }
+
+    fn uses_previous(&self) -> bool {
+        self.hook.uses_previous()
+    }
+
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        self.hook.rendered(data)
+    }
+}
+
+/// HookFailed:
+/// A hook of the pipeline returned an error.  The run fails like on any
+/// other error, but can be told apart from the provider failing, e.g. to
+/// exit with a different code.
+#[derive(Debug)]
+pub struct HookFailed;
+
+impl std::fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error running hook")
+    }
+}
+
+/// Build:
+/// Makes a hook from its section of the config file
+pub type Build = Box<dyn Fn(toml::Value) -> std::result::Result<Box<dyn Hook>, toml::de::Error>>;
+
+/// Registry:
+/// The kinds of hooks known, by the config file section each is configured
+/// by, along with how to make them.  Every hook module registers its own
+/// kind.
+pub struct Registry {
+    kinds: Vec<(String, Build)>,
+}
+
+impl Registry {
+    /// Make hooks configured by a <kind> section with <build>, in place of
+    /// any registered before
+    pub fn register<F>(&mut self, kind: &str, build: F)
+    where
+        F: Fn(toml::Value) -> std::result::Result<Box<dyn Hook>, toml::de::Error> + 'static,
+    {
+        self.kinds.retain(|(k, _)| k != kind);
+        self.kinds.push((kind.to_string(), Box::new(build)));
+    }
+
+    /// How hooks of <kind> are made, if they are known
+    pub fn get(&self, kind: &str) -> Option<&Build> {
+        self.kinds.iter().find(|(k, _)| k == kind).map(|(_, build)| build)
+    }
+
+    /// The feature <kind> is behind, when this build was made without it
+    pub fn missing_feature(&self, kind: &str) -> Option<&'static str> {
+        match self.get(kind) {
+            Some(_) => None,
+            None => FEATURES.iter().find(|(k, _)| *k == kind).map(|(_, feature)| *feature),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry { kinds: Vec::new() };
+        template::register(&mut registry);
+        file::register(&mut registry);
+        raw::register(&mut registry);
+        command::register(&mut registry);
+        #[cfg(feature = "nomad")]
+        nomad::register(&mut registry);
+        consul::register(&mut registry);
+        ssh::register(&mut registry);
+        syslog::register(&mut registry);
+        pagerduty::register(&mut registry);
+        opsgenie::register(&mut registry);
+        #[cfg(feature = "wasm")]
+        wasm::register(&mut registry);
+        healthcheck::register(&mut registry);
+        hosts::register(&mut registry);
+        schedule::register(&mut registry);
+        nftables::register(&mut registry);
+        sysctl::register(&mut registry);
+        authorized_keys::register(&mut registry);
+        tls::register(&mut registry);
+        #[cfg(feature = "vault")]
+        vault::register(&mut registry);
+        #[cfg(feature = "aws")]
+        lambda::register(&mut registry);
+        #[cfg(feature = "aws")]
+        ssm_command::register(&mut registry);
+        registry
+    }
+}
+
+/// Hex encoded sha256 of <data>, used to identify a version of the data
+/// without having to log or store the data itself
+pub fn sha256<T: AsRef<[u8]>>(data: T) -> String {
+    format!("{:x}", Sha256::digest(data.as_ref()))
+}
+
+/// Replace <file> with <contents>, keeping its permissions.  The contents
+/// are written next to it and renamed over it, so that nothing reading the
+/// file sees it half written.  Files that can not be renamed over, as an
+/// /etc/hosts bind mounted into a container, are written in place.
+pub fn replace(file: &str, contents: &str) -> Result<()> {
+    let tmp = format!("{}.app_config.tmp", file);
+    std::fs::write(&tmp, contents).wrap_err_with(|| format!("Could not write {}", tmp))?;
+    if let Ok(meta) = std::fs::metadata(file) {
+        std::fs::set_permissions(&tmp, meta.permissions())?;
+    }
+    if std::fs::rename(&tmp, file).is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        std::fs::write(file, contents).wrap_err_with(|| format!("Could not write {}", file))?;
+    }
+    Ok(())
+}
+
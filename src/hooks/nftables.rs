@@ -0,0 +1,320 @@
+use crate::data::ConfigData;
+use crate::hooks::template::{DataType, Engine, Template};
+use crate::hooks::{replace, sha256, Hook, Registry};
+use crate::interactive;
+use crate::sandbox::{Sandbox, SandboxConf};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// NftablesConf will store the user's input from the configuration file
+// and then let us instantiate a Nftables struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "nftables")]
+pub struct NftablesConf {
+    pub file: String,
+    pub source_type: Option<DataType>,
+    pub engine: Option<Engine>,
+    pub nft: Option<String>,
+    pub flush: Option<bool>,
+    pub verify: Option<String>,
+    pub save: Option<String>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl NftablesConf {
+    /// Will panic if the template file can not be read
+    pub fn convert(&self) -> Nftables {
+        let tpl = match fs::read_to_string(tilde(&self.file).to_string()) {
+            Ok(tpl) => tpl,
+            Err(e) => {
+                eprintln!("Could not open {}: {}", &self.file, e);
+                std::process::exit(exitcode::OSFILE);
+            }
+        };
+        let template = Template::new(
+            &self.file,
+            &tpl,
+            self.source_type.clone().unwrap_or(DataType::YAML),
+            Vec::new(),
+            self.engine.clone().unwrap_or(Engine::Handlebars),
+            BTreeMap::new(),
+            None,
+        );
+
+        let mut nftables = Nftables::new(template);
+        if let Some(nft) = &self.nft {
+            nftables.nft = nft.clone();
+        }
+        nftables.flush = self.flush.unwrap_or(true);
+        nftables.verify = self.verify.clone();
+        nftables.save = self.save.clone();
+        nftables.sandbox = self.sandbox.convert();
+        nftables
+    }
+}
+
+/// Make nftables hooks from [hooks.nftables]
+pub fn register(registry: &mut Registry) {
+    registry.register("nftables", |section| {
+        let conf: NftablesConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Nftables Hook renders the data into an nftables ruleset with
+/// <template>, and loads it with <nft>.  With <flush> the ruleset starts by
+/// flushing the one loaded, so that it replaces it as a whole, in a single
+/// transaction.  The ruleset is checked by nft before it is loaded, one that
+/// fails the check is never loaded.  The ruleset is then written to <save>,
+/// e.g. /etc/nftables.conf for it to be loaded at boot.  The ruleset loaded
+/// before is kept, and loaded again should the <verify> command, run by bash
+/// in <sandbox>, fail once the new one is, or should it fail to be saved.
+#[derive(Debug)]
+pub struct Nftables {
+    template: Template,
+    nft: String,
+    flush: bool,
+    verify: Option<String>,
+    save: Option<String>,
+    sandbox: Sandbox,
+    output: RefCell<Option<String>>,
+}
+
+impl Nftables {
+    /// Create a new Nftables struct, flushing the ruleset loaded before,
+    /// without a verify command or a file to save to
+    pub fn new(template: Template) -> Nftables {
+        Nftables {
+            template,
+            nft: "nft".to_string(),
+            flush: true,
+            verify: None,
+            save: None,
+            sandbox: Sandbox::default(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The ruleset of <data>
+    fn ruleset(&self, data: &ConfigData) -> Result<String> {
+        let rendered = self.template.render(data)?;
+        match self.flush {
+            true => Ok(format!("flush ruleset\n{}", rendered)),
+            false => Ok(rendered),
+        }
+    }
+
+    /// Run nft with <args>, and <input> on its stdin, its output if it
+    /// succeeds
+    fn nft(&self, args: &[&str], input: &str) -> Result<String> {
+        let mut child = std::process::Command::new(&self.nft)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Unable to run {}", self.nft))?;
+        // nft failing may not read all of it, its status tells why
+        let written = child.stdin.take().map(|mut stdin| stdin.write_all(input.as_bytes()));
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(eyre!("{} {} failed: {}", self.nft, args.join(" "), stderr.trim()));
+        }
+        if let Some(Err(e)) = written {
+            return Err(e).wrap_err_with(|| format!("Unable to write to {}", self.nft));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+
+    /// Check <ruleset> with nft, and load it if it passes.  It goes to nft
+    /// through stdin, as a file could be changed between the check and the
+    /// load.
+    fn load(&self, ruleset: &str, check: bool) -> Result<()> {
+        if check {
+            self.nft(&["-c", "-f", "-"], ruleset)?;
+        }
+        self.nft(&["-f", "-"], ruleset).map(|_| ())
+    }
+}
+
+impl Hook for Nftables {
+    fn kind(&self) -> &'static str {
+        "nftables"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Check and load the ruleset, loading the one before again should it
+    /// fail verification
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let ruleset = self.ruleset(data)?;
+        if !interactive::confirm(&format!("Load the ruleset rendered from {}", self.name()))? {
+            return Ok(());
+        }
+        let previous = self.nft(&["list", "ruleset"], "")?;
+        self.load(&ruleset, true).wrap_err("The ruleset was not loaded")?;
+        *self.output.borrow_mut() = Some(sha256(&ruleset));
+
+        // The ruleset before is loaded again should the new one fail
+        // verification, or fail to be saved, for the ruleset loaded to be
+        // the one loaded at boot
+        let verified = match &self.verify {
+            Some(command) => self
+                .sandbox
+                .run_checked(command, data.run_env())
+                .wrap_err("The ruleset failed verification"),
+            None => Ok(()),
+        };
+        let saved = verified.and_then(|_| match &self.save {
+            Some(save) => replace(&tilde(save), &ruleset).wrap_err("The ruleset failed to be saved"),
+            None => Ok(()),
+        });
+        if let Err(e) = saved {
+            self.load(&format!("flush ruleset\n{}", previous), false)
+                .wrap_err_with(|| format!("{:#}, and the ruleset before failed to load", e))?;
+            return Err(e.wrap_err("The ruleset was rolled back"));
+        }
+        Ok(())
+    }
+
+    /// The file the ruleset is saved to
+    fn files(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        Ok(self.save.iter().map(|save| tilde(save).to_string()).collect())
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn set_vars(&mut self, vars: &serde_yaml::Mapping) {
+        self.template.set_vars(vars);
+    }
+
+    /// The ruleset saved, if it is
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        match &self.save {
+            Some(save) => Ok(vec![(save.clone(), self.ruleset(data)?)]),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Stands in for nft: logs its first argument, lists a ruleset, fails
+    // the check of rulesets holding "bad", and keeps what it loads
+    const FAKE_NFT: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "$1" >> "$dir/calls"
+case "$1" in
+  list) echo 'table inet old {'; echo '}' ;;
+  -c) if grep -q bad; then echo 'syntax error' >&2; exit 1; fi ;;
+  -f) cat > "$dir/loaded" ;;
+esac
+"#;
+
+    fn nftables(dir: &std::path::Path, tpl: &str) -> Nftables {
+        let nft = dir.join("nft");
+        if !nft.exists() {
+            fs::write(&nft, FAKE_NFT).unwrap();
+            fs::set_permissions(&nft, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let template = Template::new(
+            "rules.nft.hbs",
+            tpl,
+            DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        let mut hook = Nftables::new(template);
+        hook.nft = nft.to_string_lossy().to_string();
+        hook
+    }
+
+    fn read(dir: &std::path::Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap_or_default()
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.nftables]
+         file = "./tests/test_template.tmpl"
+         verify = "curl -sf http://localhost/"
+         save = "/etc/nftables.conf"
+        "#;
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: NftablesConf = maps["hooks"]["nftables"].clone().try_into().unwrap();
+        let hook = conf.convert();
+        assert_eq!(hook.nft, "nft");
+        assert!(hook.flush);
+        assert_eq!(hook.verify.as_deref(), Some("curl -sf http://localhost/"));
+        assert_eq!(hook.save.as_deref(), Some("/etc/nftables.conf"));
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("app_config_nft_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tpl = "table inet filter {\n  chain input { tcp dport {{port}} accept }\n}\n";
+        let data = ConfigData::new("port: 22", "mock", None);
+
+        let mut hook = nftables(&dir, tpl);
+        hook.save = Some(dir.join("nftables.conf").to_string_lossy().to_string());
+        hook.run(&data).unwrap();
+        let exp = "flush ruleset\ntable inet filter {\n  chain input { tcp dport 22 accept }\n}\n";
+        assert_eq!(read(&dir, "loaded"), exp);
+        assert_eq!(read(&dir, "nftables.conf"), exp);
+        assert_eq!(read(&dir, "calls"), "list\n-c\n-f\n");
+
+        // A ruleset failing verification is rolled back, and not saved
+        fs::remove_file(dir.join("calls")).unwrap();
+        hook.verify = Some("false".to_string());
+        let data_80 = ConfigData::new("port: 80", "mock", None);
+        let res = format!("{:#}", hook.run(&data_80).unwrap_err());
+        assert!(res.contains("rolled back"), "{}", res);
+        assert_eq!(read(&dir, "loaded"), "flush ruleset\ntable inet old {\n}\n");
+        assert_eq!(read(&dir, "nftables.conf"), exp);
+        assert_eq!(read(&dir, "calls"), "list\n-c\n-f\n-f\n");
+
+        // So is one that fails to be saved
+        hook.verify = None;
+        hook.save = Some(dir.join("missing/nftables.conf").to_string_lossy().to_string());
+        let res = format!("{:#}", hook.run(&data_80).unwrap_err());
+        assert!(res.contains("failed to be saved"), "{}", res);
+        assert_eq!(read(&dir, "loaded"), "flush ruleset\ntable inet old {\n}\n");
+
+        // One failing the check is never loaded
+        fs::remove_file(dir.join("calls")).unwrap();
+        let hook = nftables(&dir, "bad {{port}}\n");
+        let res = format!("{:#}", hook.run(&data).unwrap_err());
+        assert!(res.contains("syntax error"), "{}", res);
+        assert_eq!(read(&dir, "calls"), "list\n-c\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::redact::redacted;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// NomadConf will store the user's input from the configuration file
+// and then let us instantiate a Nomad struct
+#[derive(Deserialize)]
+#[serde(rename = "nomad")]
+pub struct NomadConf {
+    pub alloc_id: String,
+    pub task: Option<String>,
+    pub address: Option<String>,
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for NomadConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("NomadConf")
+            .field("alloc_id", &self.alloc_id)
+            .field("task", &self.task)
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .finish()
+    }
+}
+
+impl NomadConf {
+    pub fn convert(&self) -> Nomad {
+        Nomad::new(
+            &self.alloc_id,
+            self.task.clone(),
+            self.address.clone(),
+            self.token.clone(),
+        )
+    }
+}
+
+/// Make nomad hooks from [hooks.nomad]
+pub fn register(registry: &mut Registry) {
+    registry.register("nomad", |section| {
+        let conf: NomadConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Nomad Hook asks the Nomad agent to restart an allocation (or a single
+/// task within it) whenever new data is received by the provider.
+/// If <address> or <token> are omitted, NOMAD_ADDR and NOMAD_TOKEN are used.
+#[derive(PartialEq)]
+pub struct Nomad {
+    alloc_id: String,
+    task: Option<String>,
+    address: Option<String>,
+    token: Option<String>,
+}
+
+impl std::fmt::Debug for Nomad {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Nomad")
+            .field("alloc_id", &self.alloc_id)
+            .field("task", &self.task)
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .finish()
+    }
+}
+
+impl Nomad {
+    /// Create a new Nomad struct
+    pub fn new(
+        alloc_id: &str,
+        task: Option<String>,
+        address: Option<String>,
+        token: Option<String>,
+    ) -> Nomad {
+        Nomad {
+            alloc_id: alloc_id.to_string(),
+            task,
+            address,
+            token,
+        }
+    }
+
+    /// Base url of the Nomad HTTP API
+    fn address(&self) -> String {
+        let addr = match &self.address {
+            Some(addr) => addr.clone(),
+            None => std::env::var("NOMAD_ADDR")
+                .unwrap_or_else(|_| "http://127.0.0.1:4646".to_string()),
+        };
+        addr.trim_end_matches('/').to_string()
+    }
+
+    /// ACL token to authenticate with, if any
+    fn token(&self) -> Option<String> {
+        match &self.token {
+            Some(token) => Some(token.clone()),
+            None => std::env::var("NOMAD_TOKEN").ok(),
+        }
+    }
+}
+
+impl Hook for Nomad {
+    fn kind(&self) -> &'static str {
+        "nomad"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Restart the allocation via the Nomad API
+    fn run(&self, _data: &ConfigData) -> Result<()> {
+        let url = format!(
+            "{}/v1/client/allocation/{}/restart",
+            self.address(),
+            self.alloc_id
+        );
+
+        let mut req = http::post(&url);
+        if let Some(token) = self.token() {
+            req.set("X-Nomad-Token", &token);
+        }
+
+        let body = match &self.task {
+            Some(task) => serde_json::json!({ "TaskName": task }),
+            None => serde_json::json!({}),
+        };
+
+        let resp = req.send_json(body);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach Nomad at {}: {}", url, e));
+        }
+        if !resp.ok() {
+            return Err(eyre!(
+                "Nomad failed to restart allocation {}: {}",
+                self.alloc_id,
+                resp.status_line()
+            ));
+        }
+        Ok(())
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.nomad]
+         alloc_id = "5456bd7a-9fc0-c0dd-6131-cbee77f57577"
+         task = "web"
+         address = "http://nomad.local:4646/"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Nomad::new(
+            &"5456bd7a-9fc0-c0dd-6131-cbee77f57577",
+            Some("web".to_string()),
+            Some("http://nomad.local:4646/".to_string()),
+            None,
+        );
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: NomadConf = maps["hooks"]["nomad"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+        assert_eq!(res.address(), "http://nomad.local:4646");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::redact::redacted;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// OpsgenieConf will store the user's input from the configuration file
+// and then let us instantiate an Opsgenie struct
+#[derive(Deserialize)]
+#[serde(rename = "opsgenie")]
+pub struct OpsgenieConf {
+    pub api_key: String,
+    pub alias: String,
+    pub priority: Option<String>,
+    pub api_url: Option<String>,
+}
+
+impl std::fmt::Debug for OpsgenieConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("OpsgenieConf")
+            .field("api_key", &redacted(&self.api_key))
+            .field("alias", &self.alias)
+            .field("priority", &self.priority)
+            .field("api_url", &self.api_url)
+            .finish()
+    }
+}
+
+impl OpsgenieConf {
+    pub fn convert(&self) -> Opsgenie {
+        let priority = self.priority.clone().unwrap_or_else(|| "P3".to_string());
+        match priority.as_str() {
+            "P1" | "P2" | "P3" | "P4" | "P5" => {}
+            _ => {
+                eprintln!("Error, unknown opsgenie priority: {}", priority);
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+
+        Opsgenie::new(
+            &self.api_key,
+            &self.alias,
+            &priority,
+            self.api_url.as_deref().unwrap_or("https://api.opsgenie.com"),
+        )
+    }
+}
+
+/// Make opsgenie hooks from [hooks.opsgenie]
+pub fn register(registry: &mut Registry) {
+    registry.register("opsgenie", |section| {
+        let conf: OpsgenieConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Opsgenie Hook opens an alert using the data it receives as the
+/// description.  It is meant to be used under [on_error], where the data is the
+/// error that failed the run.  Alerts share <alias>, so Opsgenie de-duplicates
+/// repeated failures, and the alert is closed once a run succeeds again.
+/// Set <api_url> to https://api.eu.opsgenie.com for EU accounts.
+#[derive(PartialEq)]
+pub struct Opsgenie {
+    api_key: String,
+    alias: String,
+    priority: String,
+    api_url: String,
+}
+
+impl std::fmt::Debug for Opsgenie {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Opsgenie")
+            .field("api_key", &redacted(&self.api_key))
+            .field("alias", &self.alias)
+            .field("priority", &self.priority)
+            .field("api_url", &self.api_url)
+            .finish()
+    }
+}
+
+impl Opsgenie {
+    /// Create a new Opsgenie struct
+    pub fn new(api_key: &str, alias: &str, priority: &str, api_url: &str) -> Opsgenie {
+        Opsgenie {
+            api_key: api_key.to_string(),
+            alias: alias.to_string(),
+            priority: priority.to_string(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Body of a create alert request
+    fn alert(&self, data: &str) -> serde_json::Value {
+        // Opsgenie limits the message to 130 characters, and the
+        // description to 15000.
+        let message: String = data.lines().next().unwrap_or("").chars().take(130).collect();
+        let description: String = data.chars().take(15000).collect();
+
+        serde_json::json!({
+            "message": message,
+            "alias": self.alias,
+            "description": description,
+            "priority": self.priority,
+            "source": "app_config",
+        })
+    }
+
+    fn send(&self, url: &str, body: serde_json::Value) -> Result<()> {
+        let resp = http::post(url)
+            .set("Authorization", &format!("GenieKey {}", self.api_key))
+            .send_json(body);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach Opsgenie: {}", e));
+        }
+        if !resp.ok() {
+            return Err(eyre!("Opsgenie rejected request: {}", resp.status_line()));
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Opsgenie {
+    fn kind(&self) -> &'static str {
+        "opsgenie"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Open the alert
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let url = format!("{}/v2/alerts", self.api_url);
+        self.send(&url, self.alert(data.text()?))
+    }
+
+    /// Close the alert
+    fn resolve(&self) -> Result<()> {
+        let url = format!(
+            "{}/v2/alerts/{}/close?identifierType=alias",
+            self.api_url, self.alias
+        );
+        self.send(&url, serde_json::json!({ "source": "app_config" }))
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [on_error.opsgenie]
+         api_key = "K3Y"
+         alias = "myApp-dev"
+         priority = "P2"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Opsgenie::new(&"K3Y", &"myApp-dev", &"P2", &"https://api.opsgenie.com");
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: OpsgenieConf = maps["on_error"]["opsgenie"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_alert() {
+        let o = Opsgenie::new(&"K3Y", &"myApp-dev", &"P2", &"https://api.opsgenie.com");
+
+        let res = o.alert(&"Error running hook\nFailed to execute cmd: false");
+        assert_eq!(res["message"], "Error running hook");
+        assert_eq!(res["alias"], "myApp-dev");
+        assert_eq!(res["priority"], "P2");
+    }
+}
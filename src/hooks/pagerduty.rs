@@ -0,0 +1,243 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::identity;
+use crate::redact::redacted;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+#[cfg(test)]
+use std::any::Any;
+
+const EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// PagerDutyConf will store the user's input from the configuration file
+// and then let us instantiate a PagerDuty struct
+#[derive(Deserialize)]
+#[serde(rename = "pagerduty")]
+pub struct PagerDutyConf {
+    pub routing_key: String,
+    pub dedup_key: Option<String>,
+    pub severity: Option<String>,
+    pub source: Option<String>,
+}
+
+impl std::fmt::Debug for PagerDutyConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PagerDutyConf")
+            .field("routing_key", &redacted(&self.routing_key))
+            .field("dedup_key", &self.dedup_key)
+            .field("severity", &self.severity)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl PagerDutyConf {
+    pub fn convert(&self) -> PagerDuty {
+        let severity = self.severity.clone().unwrap_or_else(|| "error".to_string());
+        match severity.as_str() {
+            "critical" | "error" | "warning" | "info" => {}
+            _ => {
+                eprintln!("Error, unknown pagerduty severity: {}", severity);
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+
+        PagerDuty::new(
+            &self.routing_key,
+            self.dedup_key.as_deref(),
+            &severity,
+            &self.source.clone().unwrap_or_else(|| "app_config".to_string()),
+        )
+    }
+}
+
+/// Make pagerduty hooks from [hooks.pagerduty]
+pub fn register(registry: &mut Registry) {
+    registry.register("pagerduty", |section| {
+        let conf: PagerDutyConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The PagerDuty Hook triggers an incident through the Events API v2, using
+/// the data it receives as the summary.  It is meant to be used under
+/// [on_error], where the data is the error that failed the run.  All events
+/// share <dedup_key>, so repeated failures update one incident, and the
+/// incident is resolved once a run succeeds again.  Unless configured, the
+/// key is derived from the host, the config file and the pipeline, so that
+/// each pipeline on each host has an incident of its own.
+#[derive(PartialEq)]
+pub struct PagerDuty {
+    routing_key: String,
+    dedup_key: Option<String>,
+    severity: String,
+    source: String,
+}
+
+impl std::fmt::Debug for PagerDuty {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PagerDuty")
+            .field("routing_key", &redacted(&self.routing_key))
+            .field("dedup_key", &self.dedup_key)
+            .field("severity", &self.severity)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl PagerDuty {
+    /// Create a new PagerDuty struct
+    pub fn new(
+        routing_key: &str,
+        dedup_key: Option<&str>,
+        severity: &str,
+        source: &str,
+    ) -> PagerDuty {
+        PagerDuty {
+            routing_key: routing_key.to_string(),
+            dedup_key: dedup_key.map(String::from),
+            severity: severity.to_string(),
+            source: source.to_string(),
+        }
+    }
+
+    /// The key the events of the incident share, the source if the pipeline
+    /// is not known
+    fn dedup_key(&self) -> &str {
+        self.dedup_key.as_deref().unwrap_or(&self.source)
+    }
+
+    /// Body of a trigger event
+    fn trigger_event(&self, data: &str) -> serde_json::Value {
+        // PagerDuty rejects summaries over 1024 characters
+        let summary: String = data.chars().take(1024).collect();
+
+        serde_json::json!({
+            "routing_key": self.routing_key,
+            "event_action": "trigger",
+            "dedup_key": self.dedup_key(),
+            "payload": {
+                "summary": summary,
+                "source": self.source,
+                "severity": self.severity,
+            },
+        })
+    }
+
+    /// Body of a resolve event
+    fn resolve_event(&self) -> serde_json::Value {
+        serde_json::json!({
+            "routing_key": self.routing_key,
+            "event_action": "resolve",
+            "dedup_key": self.dedup_key(),
+        })
+    }
+
+    fn send(&self, event: serde_json::Value) -> Result<()> {
+        let resp = http::post(EVENTS_URL).send_json(event);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach PagerDuty: {}", e));
+        }
+        if !resp.ok() {
+            return Err(eyre!("PagerDuty rejected event: {}", resp.status_line()));
+        }
+        Ok(())
+    }
+}
+
+impl Hook for PagerDuty {
+    fn kind(&self) -> &'static str {
+        "pagerduty"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Trigger (or update) the incident
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        self.send(self.trigger_event(data.text()?))
+    }
+
+    /// Resolve the incident
+    fn resolve(&self) -> Result<()> {
+        self.send(self.resolve_event())
+    }
+
+    /// Derive the dedup_key from the host, <config> and <pipeline>, unless
+    /// it is configured
+    fn set_pipeline(&mut self, config: &str, pipeline: &str) {
+        if self.dedup_key.is_none() {
+            let host = identity::lookup("hostname").unwrap_or_default();
+            self.dedup_key = Some(format!("app_config:{}:{}:{}", host, config, pipeline));
+        }
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [on_error.pagerduty]
+         routing_key = "R0UT1NG"
+         dedup_key = "myApp-dev"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = PagerDuty::new(&"R0UT1NG", Some("myApp-dev"), &"error", &"app_config");
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: PagerDutyConf = maps["on_error"]["pagerduty"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_events() {
+        let p = PagerDuty::new(&"R0UT1NG", Some("myApp-dev"), &"error", &"host1");
+
+        let res = p.trigger_event(&"x".repeat(2000));
+        assert_eq!(res["event_action"], "trigger");
+        assert_eq!(res["dedup_key"], "myApp-dev");
+        assert_eq!(res["payload"]["summary"].as_str().unwrap().len(), 1024);
+
+        let res = p.resolve_event();
+        assert_eq!(res["event_action"], "resolve");
+        assert_eq!(res["dedup_key"], "myApp-dev");
+    }
+
+    #[test]
+    fn test_dedup_key() {
+        // A configured key is kept
+        let mut p = PagerDuty::new("R0UT1NG", Some("myApp-dev"), "error", "app_config");
+        p.set_pipeline("/etc/app_config/web.toml", "web");
+        assert_eq!(p.dedup_key(), "myApp-dev");
+
+        // Otherwise every pipeline on every host gets its own
+        let host = identity::lookup("hostname").unwrap();
+        let key = |config: &str, pipeline: &str| {
+            let mut p = PagerDuty::new("R0UT1NG", None, "error", "app_config");
+            p.set_pipeline(config, pipeline);
+            p.resolve_event()["dedup_key"].as_str().unwrap().to_string()
+        };
+        let web = key("/etc/app_config/web.toml", "web");
+        assert_eq!(web, format!("app_config:{}:/etc/app_config/web.toml:web", host));
+        assert_ne!(web, key("/etc/app_config/db.toml", "db"));
+    }
+}
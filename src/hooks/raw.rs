@@ -1,6 +1,9 @@
-use crate::hooks::Hook;
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
 use serde_derive::Deserialize;
 use eyre::Result;
+#[cfg(test)]
+use std::any::Any;
 
 // RawConf will let the config file parser instantiate a Raw Hook struct
 // Overkill for this simpel module, but some other hooks are more complex and
@@ -15,15 +18,32 @@ impl RawConf {
     }
 }
 
+/// Make raw hooks from [hooks.raw]
+pub fn register(registry: &mut Registry) {
+    registry.register("raw", |section| {
+        let conf: RawConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 /// Raw allows us to output the data received from the provider directly
 /// to stdout
 pub struct Raw {}
 
 impl Hook for Raw {
+    fn kind(&self) -> &'static str {
+        "raw"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     /// Write the raw data to stdout
-    fn run(&self, data: &str) -> Result<()> {
-        println!("{}", data);
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        println!("{}", data.text()?);
         Ok(())
     }
 }
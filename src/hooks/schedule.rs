@@ -0,0 +1,543 @@
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{replace, sha256, Hook, Registry};
+use crate::interactive;
+use crate::path;
+use crate::sandbox::{Sandbox, SandboxConf};
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+#[cfg(test)]
+use std::any::Any;
+
+/// The first line of the files the hook writes, and of the units it removes
+const HEADER: &str = "# Managed by app_config, do not edit\n";
+
+/// Shorthands cron takes in place of the five time fields
+const CRON_SHORTHANDS: [&str; 8] = [
+    "@reboot", "@yearly", "@annually", "@monthly", "@weekly", "@daily", "@midnight", "@hourly",
+];
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// ScheduleConf will store the user's input from the configuration file
+// and then let us instantiate a Schedule struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "schedule")]
+pub struct ScheduleConf {
+    pub format: Option<Format>,
+    pub file: Option<String>,
+    pub unit_dir: Option<String>,
+    pub prefix: Option<String>,
+    pub systemctl: Option<String>,
+    pub select: Option<String>,
+    pub source_type: Option<DataType>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl ScheduleConf {
+    /// Will panic if file is given for systemd timers, or unit_dir,
+    /// prefix or systemctl for cron
+    pub fn convert(&self) -> Schedule {
+        let format = self.format.clone().unwrap_or(Format::Cron);
+        let target = match format {
+            Format::Cron => {
+                if self.unit_dir.is_some() || self.prefix.is_some() || self.systemctl.is_some() {
+                    eprintln!("Error, unit_dir, prefix and systemctl require format = \"systemd\"");
+                    std::process::exit(exitcode::CONFIG);
+                }
+                Target::Cron {
+                    file: self.file.clone().unwrap_or_else(|| "/etc/cron.d/app_config".into()),
+                }
+            }
+            Format::Systemd => {
+                if self.file.is_some() {
+                    eprintln!("Error, a schedule file requires format = \"cron\"");
+                    std::process::exit(exitcode::CONFIG);
+                }
+                let prefix = self.prefix.clone().unwrap_or_else(|| "app_config".into());
+                if !valid_name(&prefix) {
+                    eprintln!("Error, the schedule prefix {} is not a valid unit name", prefix);
+                    std::process::exit(exitcode::CONFIG);
+                }
+                Target::Systemd {
+                    unit_dir: self.unit_dir.clone().unwrap_or_else(|| "/etc/systemd/system".into()),
+                    prefix,
+                    systemctl: self.systemctl.clone().unwrap_or_else(|| "systemctl".into()),
+                }
+            }
+        };
+
+        let mut schedule = Schedule::new(
+            target,
+            self.select.as_deref().unwrap_or("."),
+            self.source_type.clone().unwrap_or(DataType::YAML),
+        );
+        schedule.sandbox = self.sandbox.convert();
+        schedule
+    }
+}
+
+/// Make schedule hooks from [hooks.schedule]
+pub fn register(registry: &mut Registry) {
+    registry.register("schedule", |section| {
+        let conf: ScheduleConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// What runs the jobs
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Cron,
+    Systemd,
+}
+
+/// Where the jobs are written to
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    /// A cron.d file, ours as a whole
+    Cron { file: String },
+    /// A timer and a service unit in <unit_dir> for each job, named
+    /// <prefix>-<job>, enabled and disabled through <systemctl>
+    Systemd {
+        unit_dir: String,
+        prefix: String,
+        systemctl: String,
+    },
+}
+
+/// A job of the data: when it runs, in the syntax of cron or of systemd
+/// OnCalendar, what it runs and as whom
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    schedule: String,
+    command: String,
+    user: Option<String>,
+}
+
+/// The Schedule Hook installs the jobs of a map of job names to jobs,
+/// selected by the jq style path <select> in the data parsed as
+/// <source_type>, e.g. backups scheduled centrally.  Each job has a
+/// `schedule`, a `command` and optionally the `user` it runs as, root
+/// otherwise.  Every job is checked before anything is written, one bad job
+/// fails the hook.  Jobs the data no longer holds are removed: the cron file
+/// is written as a whole, and the units of <prefix> without a job are
+/// disabled and removed, if the pipeline wrote them, going by the manifest,
+/// and they still carry the header marking them as managed.  systemctl is
+/// run by bash in <sandbox>.
+#[derive(Debug, PartialEq)]
+pub struct Schedule {
+    target: Target,
+    select: String,
+    source_type: DataType,
+    sandbox: Sandbox,
+    output: RefCell<Option<String>>,
+}
+
+impl Schedule {
+    /// Create a new Schedule struct
+    pub fn new(target: Target, select: &str, source_type: DataType) -> Schedule {
+        Schedule {
+            target,
+            select: select.to_string(),
+            source_type,
+            sandbox: Sandbox::default(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The jobs in <data>, by name
+    fn jobs(&self, data: &ConfigData) -> Result<BTreeMap<String, Job>> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+        let mut selected = path::select(&value, &self.select)?;
+        if selected.len() != 1 {
+            return Err(eyre!("{} selects {} values, not a map", self.select, selected.len()));
+        }
+        let map = match selected.remove(0) {
+            Value::Mapping(map) => map,
+            _ => return Err(eyre!("{} does not select a map of jobs", self.select)),
+        };
+
+        let mut jobs = BTreeMap::new();
+        for (name, job) in map {
+            let name = match name {
+                Value::String(name) if valid_name(&name) => name,
+                name => return Err(eyre!("{:?} is not a valid job name", name)),
+            };
+            let job: Job = serde_yaml::from_value(job)
+                .wrap_err_with(|| format!("Job {} is not a schedule and a command", name))?;
+            let valid_schedule = match self.target {
+                Target::Cron { .. } => valid_cron(&job.schedule),
+                Target::Systemd { .. } => valid_calendar(&job.schedule),
+            };
+            if !valid_schedule {
+                return Err(eyre!("Job {}: {:?} is not a valid schedule", name, job.schedule));
+            }
+            if job.command.trim().is_empty() || job.command.contains(['\n', '\r']) {
+                return Err(eyre!("Job {}: the command must be a single line", name));
+            }
+            if let Some(user) = &job.user {
+                if !valid_user(user) {
+                    return Err(eyre!("Job {}: {:?} is not a valid user", name, user));
+                }
+            }
+            jobs.insert(name, job);
+        }
+        Ok(jobs)
+    }
+
+    /// The files the jobs in <data> are written to, and what each holds
+    fn render(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        let jobs = self.jobs(data)?;
+        match &self.target {
+            Target::Cron { file } => {
+                let mut rendered = String::from(HEADER);
+                for (name, job) in &jobs {
+                    rendered.push_str(&format!(
+                        "# {}\n{} {} {}\n",
+                        name,
+                        job.schedule,
+                        job.user.as_deref().unwrap_or("root"),
+                        // An unescaped % ends the command in cron
+                        job.command.replace('%', "\\%")
+                    ));
+                }
+                Ok(vec![(tilde(file).to_string(), rendered)])
+            }
+            Target::Systemd { unit_dir, prefix, .. } => {
+                let dir = tilde(unit_dir).to_string();
+                let mut rendered = Vec::new();
+                for (name, job) in &jobs {
+                    let unit = format!("{}/{}-{}", dir, prefix, name);
+                    rendered.push((format!("{}.service", unit), service(name, job)));
+                    rendered.push((format!("{}.timer", unit), timer(name, job)));
+                }
+                Ok(rendered)
+            }
+        }
+    }
+
+    /// Run <args> of systemctl, failing unless it exits with 0
+    fn systemctl(&self, systemctl: &str, args: &str) -> Result<()> {
+        let command = format!("{} {}", systemctl, args);
+        self.sandbox.run_checked(&command, Vec::new())
+    }
+}
+
+impl Hook for Schedule {
+    fn kind(&self) -> &'static str {
+        "schedule"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Write the jobs, remove those the data no longer holds, and have
+    /// systemd pick up the timers that changed
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let rendered = self.render(data)?;
+        let list: Vec<String> =
+            rendered.iter().map(|(file, r)| format!("{}  {}\n", sha256(r), file)).collect();
+        *self.output.borrow_mut() = Some(sha256(list.concat()));
+
+        let mut changed = Vec::new();
+        for (file, contents) in &rendered {
+            let existing = match fs::read_to_string(file) {
+                Ok(existing) => Some(existing),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", file)),
+            };
+            if existing.as_ref() == Some(contents) {
+                continue;
+            }
+            if !interactive::confirm_write(file, contents.as_bytes())? {
+                continue;
+            }
+            replace(file, contents)?;
+            changed.push(file.clone());
+        }
+
+        let (unit_dir, prefix, systemctl) = match &self.target {
+            Target::Cron { .. } => return Ok(()),
+            Target::Systemd { unit_dir, prefix, systemctl } => (unit_dir, prefix, systemctl),
+        };
+
+        // Timers of ours the data no longer holds a job for: named after a
+        // job, written by the pipeline before and still marked as managed
+        let dir = tilde(unit_dir).to_string();
+        let entries =
+            fs::read_dir(&dir).wrap_err_with(|| format!("Could not open {}", dir))?;
+        let mut removed = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            let job = name
+                .strip_prefix(&format!("{}-", prefix))
+                .and_then(|rest| rest.strip_suffix(".timer"));
+            let timer = format!("{}/{}", dir, name);
+            if job.is_some_and(valid_name)
+                && !rendered.iter().any(|(file, _)| file == &timer)
+                && managed(&timer, data)?
+            {
+                removed.push(name.trim_end_matches(".timer").to_string());
+            }
+        }
+        removed.sort();
+        for unit in &removed {
+            if !interactive::confirm(&format!("Remove {}", unit))? {
+                continue;
+            }
+            self.systemctl(systemctl, &format!("disable --now {}.timer", unit))?;
+            for suffix in &["timer", "service"] {
+                let file = format!("{}/{}.{}", dir, unit, suffix);
+                // A service someone else put in its place is left alone
+                if *suffix == "service" && !managed(&file, data)? {
+                    continue;
+                }
+                match fs::remove_file(&file) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(e).wrap_err_with(|| format!("Could not remove {}", file));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        self.systemctl(systemctl, "daemon-reload")?;
+        let timers = changed.iter().filter(|file| file.ends_with(".timer"));
+        for timer in timers {
+            let unit = Path::new(timer).file_name().unwrap_or_default().to_string_lossy();
+            self.systemctl(systemctl, &format!("enable {}", unit))?;
+            self.systemctl(systemctl, &format!("restart {}", unit))?;
+        }
+        Ok(())
+    }
+
+    /// The cron file, or the units of the jobs
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        Ok(self.render(data)?.into_iter().map(|(file, _)| file).collect())
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        self.render(data)
+    }
+}
+
+/// The service unit running <job>
+fn service(name: &str, job: &Job) -> String {
+    let user = match &job.user {
+        Some(user) => format!("User={}\n", user),
+        None => String::new(),
+    };
+    // systemd would expand % specifiers and $ variables, and unquote
+    let command = job
+        .command
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!(
+        "{}[Unit]\nDescription=app_config job {}\n\n\
+         [Service]\nType=oneshot\n{}ExecStart=/bin/sh -c \"{}\"\n",
+        HEADER, name, user, command
+    )
+}
+
+/// The timer unit starting the service of <job> on its schedule
+fn timer(name: &str, job: &Job) -> String {
+    format!(
+        "{}[Unit]\nDescription=app_config timer {}\n\n\
+         [Timer]\nOnCalendar={}\nPersistent=true\n\n\
+         [Install]\nWantedBy=timers.target\n",
+        HEADER, name, job.schedule
+    )
+}
+
+/// Whether the unit <file> is one the pipeline wrote, going by the manifest
+/// of <data>, and it still starts with the header marking it as managed
+fn managed(file: &str, data: &ConfigData) -> Result<bool> {
+    if !data.in_manifest(file) {
+        return Ok(false);
+    }
+    match fs::read_to_string(file) {
+        Ok(contents) => Ok(contents.starts_with(HEADER)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).wrap_err_with(|| format!("Could not read {}", file)),
+    }
+}
+
+/// Whether <name> can name a job, and so a unit or a cron.d file: letters,
+/// digits, hyphens and underscores
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether <user> is a user name as useradd takes them
+fn valid_user(user: &str) -> bool {
+    !user.is_empty()
+        && user.len() <= 32
+        && !user.starts_with('-')
+        && user.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+/// Whether <schedule> is a cron shorthand, or five time fields of digits,
+/// names and `*,/-`
+fn valid_cron(schedule: &str) -> bool {
+    if CRON_SHORTHANDS.contains(&schedule) {
+        return true;
+    }
+    let fields: Vec<&str> = schedule.split(' ').filter(|f| !f.is_empty()).collect();
+    fields.len() == 5
+        && !schedule.contains(['\t', '\n'])
+        && fields.iter().all(|f| f.chars().all(|c| c.is_ascii_alphanumeric() || "*,/-".contains(c)))
+}
+
+/// Whether <schedule> only holds what OnCalendar expressions are made of.
+/// systemd parses it, a typo fails to enable the timer.
+fn valid_calendar(schedule: &str) -> bool {
+    !schedule.trim().is_empty()
+        && schedule.chars().all(|c| c.is_ascii_alphanumeric() || " *-:,./~".contains(c))
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(yaml: &str) -> ConfigData {
+        ConfigData::new(yaml, "mock", None)
+    }
+
+    const JOBS: &str = "\
+jobs:
+  backup:
+    schedule: 30 2 * * *
+    command: tar czf /backup/$(date +%F).tgz /srv
+  rotate:
+    schedule: '@daily'
+    command: logrotate /etc/logrotate.conf
+    user: ops
+";
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.schedule]
+         format = "systemd"
+         select = ".jobs"
+        "#;
+        let target = Target::Systemd {
+            unit_dir: "/etc/systemd/system".to_string(),
+            prefix: "app_config".to_string(),
+            systemctl: "systemctl".to_string(),
+        };
+        let exp = Schedule::new(target, ".jobs", DataType::YAML);
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: ScheduleConf = maps["hooks"]["schedule"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_cron() {
+        let target = Target::Cron { file: "/etc/cron.d/app_config".to_string() };
+        let hook = Schedule::new(target, ".jobs", DataType::YAML);
+        let exp = "# Managed by app_config, do not edit\n\
+                   # backup\n30 2 * * * root tar czf /backup/$(date +\\%F).tgz /srv\n\
+                   # rotate\n@daily ops logrotate /etc/logrotate.conf\n";
+        let res = hook.rendered(&data(JOBS)).unwrap();
+        assert_eq!(res, vec![("/etc/cron.d/app_config".to_string(), exp.to_string())]);
+
+        for bad in &[
+            "jobs:\n  a.b: {schedule: '@daily', command: x}\n",
+            "jobs:\n  a: {schedule: '* * * *', command: x}\n",
+            "jobs:\n  a: {schedule: '@often', command: x}\n",
+            "jobs:\n  a: {schedule: '* * * * * root', command: x}\n",
+            "jobs:\n  a: {schedule: '@daily', command: \"x\\n* * * * * root y\"}\n",
+            "jobs:\n  a: {schedule: '@daily', command: x, user: 'r t'}\n",
+            "jobs:\n  a: {schedule: '@daily', command: x, when: now}\n",
+            "jobs:\n  a: {command: x}\n",
+        ] {
+            assert!(hook.rendered(&data(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_systemd() {
+        let dir = std::env::temp_dir().join(format!("app_config_schedule_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let calls = dir.join("calls");
+        let target = Target::Systemd {
+            unit_dir: dir.to_string_lossy().to_string(),
+            prefix: "app_config".to_string(),
+            systemctl: format!("echo >> {}", calls.display()),
+        };
+        let hook = Schedule::new(target, ".jobs", DataType::YAML);
+        let jobs = "jobs:\n  backup:\n    schedule: '*-*-* 02:30:00'\n    \
+                    command: tar czf /backup/$(date +%F).tgz /srv\n    user: ops\n";
+        hook.run(&data(jobs)).unwrap();
+
+        let service = fs::read_to_string(dir.join("app_config-backup.service")).unwrap();
+        assert!(service.contains("User=ops\n"), "{}", service);
+        assert!(
+            service.contains("ExecStart=/bin/sh -c \"tar czf /backup/$$(date +%%F).tgz /srv\"\n"),
+            "{}",
+            service
+        );
+        let timer = fs::read_to_string(dir.join("app_config-backup.timer")).unwrap();
+        assert!(timer.contains("OnCalendar=*-*-* 02:30:00\n"), "{}", timer);
+
+        // Units the pipeline did not write, or no longer marked as managed,
+        // are left alone
+        let unit = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let kept = ["app_config-other.timer", "app_config-edited.timer", "app_config-a.b.timer"];
+        for name in &kept {
+            let contents = if *name == kept[1] { "[Timer]\n" } else { HEADER };
+            fs::write(unit(name), contents).unwrap();
+        }
+        let manifest = vec![
+            unit("app_config-backup.timer"),
+            unit("app_config-backup.service"),
+            unit(kept[1]),
+            unit(kept[2]),
+        ];
+
+        // Unchanged jobs are left alone, removed ones are disabled
+        hook.run(&data(jobs)).unwrap();
+        hook.run(&data("jobs: {}\n").with_manifest(manifest)).unwrap();
+        for name in &kept {
+            assert!(dir.join(name).exists(), "{}", name);
+        }
+        assert!(!dir.join("app_config-backup.timer").exists());
+        assert!(!dir.join("app_config-backup.service").exists());
+        let exp = "daemon-reload\nenable app_config-backup.timer\n\
+                   restart app_config-backup.timer\n\
+                   disable --now app_config-backup.timer\ndaemon-reload\n";
+        assert_eq!(fs::read_to_string(&calls).unwrap(), exp);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
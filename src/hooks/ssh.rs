@@ -0,0 +1,266 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::interactive;
+use serde_derive::Deserialize;
+use std::io::Write;
+use std::process::Stdio;
+#[cfg(test)]
+use std::any::Any;
+use eyre::{eyre, Result, WrapErr};
+
+use shellexpand::tilde;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// SshConf will store the user's input from the configuration file
+// and then let us instantiate a Ssh struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "ssh")]
+pub struct SshConf {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<String>,
+    pub local_file: Option<String>,
+    pub remote_file: Option<String>,
+    pub command: Option<String>,
+}
+
+impl SshConf {
+    pub fn convert(&self) -> Ssh {
+        if self.remote_file.is_none() && self.command.is_none() {
+            eprintln!("Error, ssh hook needs a remote_file, a command, or both");
+            std::process::exit(exitcode::CONFIG);
+        }
+        if self.local_file.is_some() && self.remote_file.is_none() {
+            eprintln!("Error, ssh hook local_file requires a remote_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        Ssh::new(
+            &self.host,
+            self.user.clone(),
+            self.port.unwrap_or(22),
+            self.identity_file.as_ref().map(|f| tilde(f).to_string()),
+            self.local_file.as_ref().map(|f| tilde(f).to_string()),
+            self.remote_file.clone(),
+            self.command.clone(),
+        )
+    }
+}
+
+/// Make ssh hooks from [hooks.ssh]
+pub fn register(registry: &mut Registry) {
+    registry.register("ssh", |section| {
+        let conf: SshConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Ssh Hook pushes changes to a remote host that can not run app_config
+/// itself. If <remote_file> is set, either <local_file> (e.g. the out_file of
+/// a template hook) or the raw provider data is copied there. If <command> is
+/// set it is then run on the remote host. Only key based auth is supported.
+#[derive(Debug, PartialEq)]
+pub struct Ssh {
+    host: String,
+    user: Option<String>,
+    port: u16,
+    identity_file: Option<String>,
+    local_file: Option<String>,
+    remote_file: Option<String>,
+    command: Option<String>,
+}
+
+impl Ssh {
+    /// Create a new Ssh struct
+    pub fn new(
+        host: &str,
+        user: Option<String>,
+        port: u16,
+        identity_file: Option<String>,
+        local_file: Option<String>,
+        remote_file: Option<String>,
+        command: Option<String>,
+    ) -> Ssh {
+        Ssh {
+            host: host.to_string(),
+            user,
+            port,
+            identity_file,
+            local_file,
+            remote_file,
+            command,
+        }
+    }
+
+    /// user@host, or just host
+    fn target(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Options shared by ssh and scp. BatchMode stops either from hanging on
+    /// a password prompt when the key is not accepted.
+    fn common_args(&self) -> Vec<String> {
+        let mut args = vec!["-o".to_string(), "BatchMode=yes".to_string()];
+        if let Some(key) = &self.identity_file {
+            args.push("-i".to_string());
+            args.push(key.clone());
+        }
+        args
+    }
+
+    /// Build the ssh invocation for <remote_cmd>
+    fn ssh_cmd(&self, remote_cmd: &str) -> std::process::Command {
+        let mut cmd = std::process::Command::new("ssh");
+        cmd.args(self.common_args())
+            .arg("-p")
+            .arg(self.port.to_string())
+            .arg(self.target())
+            .arg(remote_cmd);
+        cmd
+    }
+
+    /// Copy <local_file> to <remote_file> with scp
+    fn copy_file(&self, local_file: &str, remote_file: &str) -> Result<()> {
+        let out = std::process::Command::new("scp")
+            .args(self.common_args())
+            .arg("-P")
+            .arg(self.port.to_string())
+            .arg(local_file)
+            .arg(format!("{}:{}", self.target(), remote_file))
+            .output()
+            .wrap_err("Failed to spawn scp")?;
+        if !out.status.success() {
+            return Err(eyre!(
+                "Failed to copy {} to {}:{}: {}",
+                local_file,
+                self.host,
+                remote_file,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Stream <data> into <remote_file> over an ssh session
+    fn copy_data(&self, data: &[u8], remote_file: &str) -> Result<()> {
+        let remote_cmd = format!("cat > {}", shell_quote(remote_file));
+        let mut child = self
+            .ssh_cmd(&remote_cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .wrap_err("Failed to spawn ssh")?;
+
+        let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+        stdin.write_all(data)?;
+
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(eyre!(
+                "Failed to write {}:{}: {}",
+                self.host,
+                remote_file,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Ssh {
+    fn kind(&self) -> &'static str {
+        "ssh"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Copy the data across, then run the remote command
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        if let Some(remote_file) = &self.remote_file {
+            let action = format!("Copy to {}:{}", self.host, remote_file);
+            if interactive::confirm(&action)? {
+                match &self.local_file {
+                    Some(local_file) => self.copy_file(local_file, remote_file)?,
+                    None => self.copy_data(data.raw(), remote_file)?,
+                }
+            }
+        }
+
+        if let Some(command) = &self.command {
+            if !interactive::confirm(&format!("Run {} on {}", command, self.host))? {
+                return Ok(());
+            }
+            let out = self.ssh_cmd(command).output().wrap_err("Failed to spawn ssh")?;
+            if !out.status.success() {
+                return Err(eyre!(
+                    "Failed to execute remote cmd on {}: {}",
+                    self.host,
+                    command
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wrap <s> in single quotes so the (remote) shell takes it literally
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.ssh]
+         host = "appliance.local"
+         user = "admin"
+         identity_file = "/keys/id_ed25519"
+         remote_file = "/etc/app/app.conf"
+         command = "systemctl reload app"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Ssh::new(
+            &"appliance.local",
+            Some("admin".to_string()),
+            22,
+            Some("/keys/id_ed25519".to_string()),
+            None,
+            Some("/etc/app/app.conf".to_string()),
+            Some("systemctl reload app".to_string()),
+        );
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: SshConf = maps["hooks"]["ssh"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+        assert_eq!(res.target(), "admin@appliance.local");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/etc/it's.conf"), "'/etc/it'\\''s.conf'");
+    }
+}
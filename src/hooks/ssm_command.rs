@@ -0,0 +1,257 @@
+use crate::credentials;
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::providers::parse_region;
+use crate::redact::{redacted, Redacted};
+use rusoto_core::Region;
+use rusoto_ssm::{SendCommandRequest, Ssm, SsmClient, Target};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use std::any::Any;
+
+/// The document run when none is configured
+const DEFAULT_DOCUMENT: &str = "AWS-RunShellScript";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// SsmCommandConf will store the user's input from the configuration file
+// and then let us instantiate a SsmCommand struct
+#[derive(Deserialize)]
+#[serde(rename = "ssm_command")]
+pub struct SsmCommandConf {
+    pub document: Option<String>,
+    pub commands: Option<Vec<String>>,
+    pub parameters: Option<BTreeMap<String, Vec<String>>>,
+    pub tags: BTreeMap<String, Vec<String>>,
+    pub max_concurrency: Option<String>,
+    pub max_errors: Option<String>,
+    pub region: Option<String>,
+}
+
+impl std::fmt::Debug for SsmCommandConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SsmCommandConf")
+            .field("document", &self.document)
+            .field("commands", &self.commands.as_ref().map(|c| redacted(c.join("\n"))))
+            .field("parameters", &self.parameters.as_ref().map(redacted_parameters))
+            .field("tags", &self.tags)
+            .field("max_concurrency", &self.max_concurrency)
+            .field("max_errors", &self.max_errors)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl SsmCommandConf {
+    /// Will panic if no tag selects the instances, or if the region is
+    /// invalid
+    pub fn convert(&self) -> SsmCommand {
+        if self.tags.is_empty() || self.tags.values().any(|values| values.is_empty()) {
+            eprintln!("Error, the ssm_command hook needs tags, each with at least one value");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        let mut parameters = self.parameters.clone().unwrap_or_default();
+        if let Some(commands) = &self.commands {
+            parameters.insert("commands".to_string(), commands.clone());
+        }
+
+        let mut ssm = SsmCommand::new(
+            self.document.as_deref().unwrap_or(DEFAULT_DOCUMENT),
+            parameters,
+            self.tags.clone(),
+        );
+        ssm.max_concurrency = self.max_concurrency.clone();
+        ssm.max_errors = self.max_errors.clone();
+        ssm.region = self.region.as_deref().map(parse_region).unwrap_or_default();
+        ssm
+    }
+}
+
+/// Make ssm_command hooks from [hooks.ssm_command]
+pub fn register(registry: &mut Registry) {
+    registry.register("ssm_command", |section| {
+        let conf: SsmCommandConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The SsmCommand Hook sends an SSM document, by default a shell script of
+/// <commands>, to every instance carrying the given <tags>, e.g.
+/// `tags = { Role = ["web"] }`.  One node watching the data can so tell a
+/// whole fleet to check now, rather than each instance polling often.
+/// SSM spreads the run as per <max_concurrency> and stops it after
+/// <max_errors>; the hook only waits for the command to be accepted.
+#[derive(PartialEq)]
+pub struct SsmCommand {
+    document: String,
+    parameters: BTreeMap<String, Vec<String>>,
+    tags: BTreeMap<String, Vec<String>>,
+    max_concurrency: Option<String>,
+    max_errors: Option<String>,
+    region: Region,
+}
+
+impl std::fmt::Debug for SsmCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SsmCommand")
+            .field("document", &self.document)
+            .field("parameters", &redacted_parameters(&self.parameters))
+            .field("tags", &self.tags)
+            .field("max_concurrency", &self.max_concurrency)
+            .field("max_errors", &self.max_errors)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl SsmCommand {
+    /// Create a new SsmCommand struct
+    pub fn new(
+        document: &str,
+        parameters: BTreeMap<String, Vec<String>>,
+        tags: BTreeMap<String, Vec<String>>,
+    ) -> SsmCommand {
+        SsmCommand {
+            document: document.to_string(),
+            parameters,
+            tags,
+            max_concurrency: None,
+            max_errors: None,
+            region: Region::default(),
+        }
+    }
+
+    /// The SendCommand call for the change to <data>
+    fn request(&self, data: &ConfigData) -> SendCommandRequest {
+        let targets = self
+            .tags
+            .iter()
+            .map(|(tag, values)| Target {
+                key: Some(format!("tag:{}", tag)),
+                values: Some(values.clone()),
+            })
+            .collect();
+        let parameters: HashMap<String, Vec<String>> = self
+            .parameters
+            .iter()
+            .map(|(name, values)| (name.clone(), values.clone()))
+            .collect();
+
+        SendCommandRequest {
+            document_name: self.document.clone(),
+            parameters: Some(parameters).filter(|parameters| !parameters.is_empty()),
+            targets: Some(targets),
+            max_concurrency: self.max_concurrency.clone(),
+            max_errors: self.max_errors.clone(),
+            // SSM keeps comments to 100 characters
+            comment: Some(format!("app_config: {} data {:.12}", data.provider(), data.sha256())),
+            ..Default::default()
+        }
+    }
+}
+
+impl Hook for SsmCommand {
+    fn kind(&self) -> &'static str {
+        "ssm_command"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Send the command to the tagged instances
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let id = send_command(&self.region, self.request(data))?;
+        info!("Sent {} as SSM command {}", self.document, id);
+        Ok(())
+    }
+}
+
+/// send_command()
+/// Make the call to SSM and return the id of the command
+#[tokio::main]
+async fn send_command(region: &Region, request: SendCommandRequest) -> Result<String> {
+    let client = SsmClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        region.clone(),
+    );
+
+    let document = request.document_name.clone();
+    match client.send_command(request).await {
+        Ok(result) => Ok(result
+            .command
+            .and_then(|command| command.command_id)
+            .unwrap_or_default()),
+        Err(e) => Err(eyre!("Unable to send {}: {:?}", document, e)),
+    }
+}
+
+/// <parameters> as Debug may show them, the values are often scripts that
+/// hold secrets
+fn redacted_parameters(parameters: &BTreeMap<String, Vec<String>>) -> BTreeMap<&str, Redacted> {
+    parameters.iter().map(|(name, values)| (name.as_str(), redacted(values.join("\n")))).collect()
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.ssm_command]
+         commands = ["app_config check"]
+         tags = { Role = ["web", "api"] }
+         max_concurrency = "10%"
+        "#
+        .to_string()
+    }
+
+    fn gen_hook() -> SsmCommand {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("commands".to_string(), vec!["app_config check".to_string()]);
+        let mut tags = BTreeMap::new();
+        tags.insert("Role".to_string(), vec!["web".to_string(), "api".to_string()]);
+        SsmCommand::new(DEFAULT_DOCUMENT, parameters, tags)
+    }
+
+    #[test]
+    fn parse_config() {
+        let mut exp = gen_hook();
+        exp.max_concurrency = Some("10%".to_string());
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: SsmCommandConf = maps["hooks"]["ssm_command"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_request() {
+        let data = ConfigData::new("port: 80", "mock", None);
+        let req = gen_hook().request(&data);
+
+        assert_eq!(req.document_name, "AWS-RunShellScript");
+        assert_eq!(
+            req.targets,
+            Some(vec![Target {
+                key: Some("tag:Role".to_string()),
+                values: Some(vec!["web".to_string(), "api".to_string()]),
+            }])
+        );
+        assert_eq!(req.parameters.unwrap()["commands"], vec!["app_config check"]);
+        assert_eq!(req.comment.unwrap(), format!("app_config: mock data {:.12}", data.sha256()));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{replace, sha256, Hook, Registry};
+use crate::interactive;
+use crate::path;
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// SysctlConf will store the user's input from the configuration file
+// and then let us instantiate a Sysctl struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "sysctl")]
+pub struct SysctlConf {
+    pub file: Option<String>,
+    pub select: Option<String>,
+    pub source_type: Option<DataType>,
+    pub proc_dir: Option<String>,
+}
+
+impl SysctlConf {
+    pub fn convert(&self) -> Sysctl {
+        let mut sysctl = Sysctl::new(
+            self.file.as_deref().unwrap_or("/etc/sysctl.d/90-app_config.conf"),
+            self.select.as_deref().unwrap_or("."),
+            self.source_type.clone().unwrap_or(DataType::YAML),
+        );
+        if let Some(proc_dir) = &self.proc_dir {
+            sysctl.proc_dir = proc_dir.clone();
+        }
+        sysctl
+    }
+}
+
+/// Make sysctl hooks from [hooks.sysctl]
+pub fn register(registry: &mut Registry) {
+    registry.register("sysctl", |section| {
+        let conf: SysctlConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Sysctl Hook sets the kernel parameters of a map of sysctl keys to
+/// values, selected by the jq style path <select> in the data parsed as
+/// <source_type>, e.g. `net.core.somaxconn: 1024`.  They are set through
+/// <proc_dir>, and written to the drop-in <file> to be set again at boot.
+/// Every key is checked to be a parameter of the running kernel before any
+/// is set.  The parameters whose value changed are logged.  Keys the data
+/// no longer holds are left out of the drop-in, but keep the value they
+/// were set to until the next boot.
+#[derive(Debug, PartialEq)]
+pub struct Sysctl {
+    file: String,
+    select: String,
+    source_type: DataType,
+    proc_dir: String,
+    output: RefCell<Option<String>>,
+}
+
+impl Sysctl {
+    /// Create a new Sysctl struct, setting parameters through /proc/sys
+    pub fn new(file: &str, select: &str, source_type: DataType) -> Sysctl {
+        Sysctl {
+            file: file.to_string(),
+            select: select.to_string(),
+            source_type,
+            proc_dir: "/proc/sys".to_string(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The value of each key in <data>
+    fn params(&self, data: &ConfigData) -> Result<BTreeMap<String, String>> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+        let mut selected = path::select(&value, &self.select)?;
+        if selected.len() != 1 {
+            return Err(eyre!("{} selects {} values, not a map", self.select, selected.len()));
+        }
+        let map = match selected.remove(0) {
+            Value::Mapping(map) => map,
+            _ => return Err(eyre!("{} does not select a map of sysctl keys", self.select)),
+        };
+
+        let mut params = BTreeMap::new();
+        for (key, value) in map {
+            let key = match key {
+                Value::String(key) if valid_key(&key) => key,
+                key => return Err(eyre!("{:?} is not a valid sysctl key", key)),
+            };
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => (value as u8).to_string(),
+                value => return Err(eyre!("{}: {:?} is not a sysctl value", key, value)),
+            };
+            if value.trim().is_empty() || value.contains(['\n', '\r']) {
+                return Err(eyre!("{}: the value must be a single line", key));
+            }
+            params.insert(key, value);
+        }
+        Ok(params)
+    }
+
+    /// The drop-in setting <params>
+    fn drop_in(params: &BTreeMap<String, String>) -> String {
+        let mut drop_in = String::from("# Managed by app_config, do not edit\n");
+        for (key, value) in params {
+            drop_in.push_str(&format!("{} = {}\n", key, value));
+        }
+        drop_in
+    }
+
+    /// The file of the parameter <key>
+    fn proc_file(&self, key: &str) -> String {
+        format!("{}/{}", self.proc_dir, key.replace('.', "/"))
+    }
+}
+
+impl Hook for Sysctl {
+    fn kind(&self) -> &'static str {
+        "sysctl"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Set the parameters whose value differs, and write the drop-in
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let params = self.params(data)?;
+
+        // Every parameter is read before any is set, to fail on keys the
+        // kernel does not have without setting the others
+        let mut changed = Vec::new();
+        for (key, value) in &params {
+            let file = self.proc_file(key);
+            let current = fs::read_to_string(&file)
+                .wrap_err_with(|| format!("{} is not a parameter of this kernel", key))?;
+            if normalized(&current) != normalized(value) {
+                changed.push((key, normalized(&current), value));
+            }
+        }
+
+        if !changed.is_empty() {
+            let action = format!("Set {} kernel parameters", changed.len());
+            if interactive::confirm(&action)? {
+                for (key, current, value) in &changed {
+                    fs::write(self.proc_file(key), value)
+                        .wrap_err_with(|| format!("Unable to set {}", key))?;
+                    info!("Set {} from {} to {}", key, current, value);
+                }
+            }
+        }
+        info!("{} of {} kernel parameters changed", changed.len(), params.len());
+
+        let drop_in = Sysctl::drop_in(&params);
+        *self.output.borrow_mut() = Some(sha256(&drop_in));
+        let file = tilde(&self.file).to_string();
+        if fs::read_to_string(&file).ok().as_ref() == Some(&drop_in) {
+            return Ok(());
+        }
+        if interactive::confirm_write(&file, drop_in.as_bytes())? {
+            replace(&file, &drop_in)?;
+        }
+        Ok(())
+    }
+
+    /// The drop-in
+    fn files(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        Ok(vec![tilde(&self.file).to_string()])
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        Ok(vec![(self.file.clone(), Sysctl::drop_in(&self.params(data)?))])
+    }
+}
+
+/// Whether <key> names a kernel parameter: dot separated parts of letters,
+/// digits, hyphens and underscores, so that it can not lead out of
+/// /proc/sys
+fn valid_key(key: &str) -> bool {
+    key.split('.').all(|part| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// <value> with runs of whitespace made a single space, as the kernel
+/// separates the numbers of a value by tabs
+fn normalized(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.sysctl]
+         select = ".kernel"
+        "#;
+        let exp = Sysctl::new("/etc/sysctl.d/90-app_config.conf", ".kernel", DataType::YAML);
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: SysctlConf = maps["hooks"]["sysctl"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("app_config_sysctl_{}", std::process::id()));
+        fs::create_dir_all(dir.join("proc/net/core")).unwrap();
+        fs::create_dir_all(dir.join("proc/net/ipv4")).unwrap();
+        fs::write(dir.join("proc/net/core/somaxconn"), "128\n").unwrap();
+        fs::write(dir.join("proc/net/ipv4/tcp_rmem"), "4096\t87380\t6291456\n").unwrap();
+        fs::write(dir.join("proc/net/ipv4/ip_forward"), "1\n").unwrap();
+
+        let file = dir.join("90-app_config.conf");
+        let mut hook = Sysctl::new(file.to_str().unwrap(), ".kernel", DataType::YAML);
+        hook.proc_dir = dir.join("proc").to_string_lossy().to_string();
+        let data = |yaml: &str| ConfigData::new(yaml, "mock", None);
+        hook.run(&data(
+            "kernel:\n  net.core.somaxconn: 1024\n  net.ipv4.tcp_rmem: 4096 87380 6291456\n",
+        ))
+        .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("proc/net/core/somaxconn")).unwrap(), "1024");
+        // Values that only differ in whitespace are left as they are
+        let tcp_rmem = fs::read_to_string(dir.join("proc/net/ipv4/tcp_rmem")).unwrap();
+        assert_eq!(tcp_rmem, "4096\t87380\t6291456\n");
+        let exp = "# Managed by app_config, do not edit\n\
+                   net.core.somaxconn = 1024\nnet.ipv4.tcp_rmem = 4096 87380 6291456\n";
+        assert_eq!(fs::read_to_string(&file).unwrap(), exp);
+
+        // One unknown key fails the hook before any parameter is set
+        let res = hook.run(&data("kernel:\n  net.ipv4.ip_forward: 0\n  net.nope: 1\n"));
+        assert!(format!("{:#}", res.unwrap_err()).contains("net.nope is not a parameter"));
+        assert_eq!(fs::read_to_string(dir.join("proc/net/ipv4/ip_forward")).unwrap(), "1\n");
+
+        for bad in &["kernel:\n  ../x: 1\n", "kernel:\n  a..b: 1\n", "kernel:\n  a: [1]\n"] {
+            assert!(hook.run(&data(bad)).is_err(), "{}", bad);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
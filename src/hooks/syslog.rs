@@ -0,0 +1,205 @@
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result, WrapErr};
+
+use std::os::unix::net::UnixDatagram;
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// SyslogConf will store the user's input from the configuration file
+// and then let us instantiate a Syslog struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "syslog")]
+pub struct SyslogConf {
+    pub identifier: Option<String>,
+    pub facility: Option<String>,
+    pub socket: Option<String>,
+}
+
+impl SyslogConf {
+    pub fn convert(&self) -> Syslog {
+        let facility = self.facility.clone().unwrap_or_else(|| "user".to_string());
+        let code = match facility_code(&facility) {
+            Some(code) => code,
+            None => {
+                eprintln!("Error, unknown syslog facility: {}", facility);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+
+        Syslog::new(
+            self.identifier.as_deref().unwrap_or("app_config"),
+            code,
+            self.socket.as_deref().unwrap_or("/dev/log"),
+        )
+    }
+}
+
+/// Make syslog hooks from [hooks.syslog]
+pub fn register(registry: &mut Registry) {
+    registry.register("syslog", |section| {
+        let conf: SyslogConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Syslog Hook writes a structured record of each applied change to the
+/// local syslog socket (journald listens there as well), so that changes end
+/// up in the central log pipeline.  The data itself is never logged, only its
+/// version, sha256 hash and size, along with how the hooks run on it before
+/// went.  As a post_hook it has the outcome of every other hook of the run.
+#[derive(Debug, PartialEq)]
+pub struct Syslog {
+    identifier: String,
+    facility: u8,
+    socket: String,
+}
+
+impl Syslog {
+    /// Create a new Syslog struct
+    pub fn new(identifier: &str, facility: u8, socket: &str) -> Syslog {
+        Syslog {
+            identifier: identifier.to_string(),
+            facility,
+            socket: socket.to_string(),
+        }
+    }
+
+    /// Format an RFC 3164 style message at severity "info".  The hooks are
+    /// listed as <name>:<status>, separated by commas.
+    fn format(&self, data: &ConfigData) -> String {
+        let pri = self.facility * 8 + 6;
+        let hooks: Vec<String> = data
+            .outcomes()
+            .iter()
+            .map(|(name, status)| format!("{}:{}", field(name), status))
+            .collect();
+        format!(
+            "<{}>{}[{}]: event=config_applied version={} sha256={} bytes={} hooks={}",
+            pri,
+            self.identifier,
+            std::process::id(),
+            field(data.version().unwrap_or("-")),
+            data.sha256(),
+            data.raw().len(),
+            if hooks.is_empty() { "-".to_string() } else { hooks.join(",") }
+        )
+    }
+}
+
+impl Hook for Syslog {
+    fn kind(&self) -> &'static str {
+        "syslog"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Send the record to the syslog socket
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let sock = UnixDatagram::unbound().wrap_err("Unable to create syslog socket")?;
+        sock.connect(&self.socket)
+            .wrap_err_with(|| format!("Unable to connect to syslog at {}", self.socket))?;
+
+        let msg = self.format(data);
+        let sent = sock.send(msg.as_bytes())?;
+        if sent != msg.len() {
+            return Err(eyre!("Syslog message truncated"));
+        }
+        Ok(())
+    }
+}
+
+/// <value> as a single field of the record, with no whitespace, or the
+/// characters separating the hooks, to break it up
+fn field(value: &str) -> String {
+    value.replace(|c: char| c.is_whitespace() || c == ',' || c == ':', "_")
+}
+
+/// Map a syslog facility name to its numeric code
+fn facility_code(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::sha256;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.syslog]
+         identifier = "myApp"
+         facility = "local3"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Syslog::new(&"myApp", 19, &"/dev/log");
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: SyslogConf = maps["hooks"]["syslog"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_send() {
+        let path = std::env::temp_dir().join(format!("app_config_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let s = Syslog::new(&"myApp", 16, path.to_str().unwrap());
+        let data = ConfigData::new("Booyeah", "mock", Some("v 3".to_string()));
+        data.record_outcome("template", "ok");
+        data.record_outcome("reload nginx", "error");
+        s.run(&data).unwrap();
+
+        let mut buf = [0; 1024];
+        let n = listener.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..n]).to_string();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(msg.starts_with("<134>myApp["));
+        assert!(msg.contains(&format!("sha256={}", sha256(&"Booyeah"))));
+        assert!(msg.contains(" version=v_3 "), "{}", msg);
+        assert!(msg.ends_with("bytes=7 hooks=template:ok,reload_nginx:error"), "{}", msg);
+    }
+}
@@ -1,14 +1,45 @@
-use crate::hooks::Hook;
+use crate::checksum;
+use crate::config;
+use crate::data::ConfigData;
+use crate::identity;
+use crate::interactive;
+use crate::hooks::keys::{self, KeyCache};
+use crate::hooks::{formats, helpers, sha256, Hook, Registry};
 use serde_derive::Deserialize;
-use eyre::Result;
+use eyre::{eyre, Result, WrapErr};
 
 use shellexpand::tilde;
 use std::fs;
 use std::io::prelude::*;
 
 use handlebars::{Handlebars, RenderContext, Helper, Context, JsonRender, 
-                 HelperResult, Output };
+                 HelperResult };
+#[cfg(feature = "aws")]
 use crate::providers::param_store::get_params;
+use crate::providers::Provider;
+use crate::path;
+#[cfg(feature = "aws")]
+use crate::s3;
+use crate::schema::Schema;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(test)]
+use std::any::Any;
+
+/// How long a template may take to render, unless configured
+const DEFAULT_RENDER_TIMEOUT: &str = "30s";
+/// How large the output of a template may be, unless configured
+const DEFAULT_MAX_OUTPUT_SIZE: usize = 64 * 1024 * 1024;
+/// How deeply the data of a template may nest, unless configured
+const DEFAULT_MAX_DEPTH: usize = 64;
+/// How many operations a call of a helper script may take, unless configured
+const DEFAULT_MAX_OPERATIONS: u64 = 1_000_000;
 
 
 // // // // // // // // // Handle Configuraion // // // // // // // //
@@ -21,109 +52,983 @@ pub struct TemplateConf {
     file: String,
     source_type: DataType,
     out_file: Option<String>,
+    outputs: Option<Vec<Output>>,
+    engine: Option<Engine>,
+    helpers: Option<BTreeMap<String, String>>,
+    validate: Option<DataType>,
+    schema: Option<String>,
+    file_dirs: Option<Vec<String>>,
+    key_ttl: Option<u64>,
+    keep_keys: Option<bool>,
+    state_file: Option<String>,
+    mode: Option<Mode>,
+    marker: Option<String>,
+    comment: Option<String>,
+    banner: Option<bool>,
+    checksum: Option<bool>,
+    extra_data: Option<Vec<toml::Value>>,
+    render_timeout: Option<String>,
+    max_output_size: Option<usize>,
+    max_depth: Option<usize>,
+    max_operations: Option<u64>,
+    previous: Option<bool>,
 }
 
 impl TemplateConf {
     pub fn convert(&self) -> Template {
-        // Read in the template from the provided file.
-        let expanded_path = String::from(tilde(&self.file));
-
-        let file_contents: String = match fs::read_to_string(expanded_path) {
-            Ok(file_contents) => file_contents,
+        // Remote templates are fetched as they are needed, local ones are
+        // read in from the provided file now.
+        let remote = match Remote::parse(&self.file) {
+            Ok(remote) => remote,
             Err(e) => {
-                eprintln!("Could not open {}: {}", &self.file, e);
-                std::process::exit(exitcode::OSFILE);
+                eprintln!("Error, {}", e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let file_contents: String = match remote {
+            Some(_) => String::new(),
+            None => {
+                let expanded_path = String::from(tilde(&self.file));
+                match fs::read_to_string(expanded_path) {
+                    Ok(file_contents) => file_contents,
+                    Err(e) => {
+                        eprintln!("Could not open {}: {}", &self.file, e);
+                        std::process::exit(exitcode::OSFILE);
+                    }
+                }
             }
         };
 
-        Template::new(
+        let engine = self.engine.clone().unwrap_or(Engine::Handlebars);
+        let helpers = self.helpers.clone().unwrap_or_default();
+        if engine != Engine::Handlebars && !helpers.is_empty() {
+            eprintln!("Error, template helpers are only supported by the handlebars engine");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        // Compile the helper scripts now, so mistakes show up at startup
+        let compiler = rhai::Engine::new();
+        for (name, script) in &helpers {
+            if let Err(e) = compiler.compile(script) {
+                eprintln!("Error, unable to compile template helper {}: {}", name, e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+
+        // out_file is shorthand for a single output, without a context
+        let outputs = match (&self.out_file, &self.outputs) {
+            (Some(_), Some(_)) => {
+                eprintln!("Error, a template can have either out_file or outputs, not both");
+                std::process::exit(exitcode::CONFIG);
+            }
+            (Some(out_file), None) => vec![Output {
+                out_file: out_file.clone(),
+                context: None,
+            }],
+            (None, outputs) => outputs.clone().unwrap_or_default(),
+        };
+
+        // A schema implies the output is json, unless told otherwise
+        let validation = match (&self.validate, &self.schema) {
+            (None, None) => None,
+            (format, schema) => Some(Validation {
+                format: format.clone().unwrap_or(DataType::JSON),
+                schema: schema.as_ref().map(|path| Schema::from_file(path)),
+            }),
+        };
+
+        // The file helpers may only read inside these directories
+        let mut file_dirs = Vec::new();
+        for dir in self.file_dirs.clone().unwrap_or_default() {
+            match Path::new(&tilde(&dir).to_string()).canonicalize() {
+                Ok(dir) => file_dirs.push(dir),
+                Err(e) => {
+                    eprintln!("Error, template file_dirs {}: {}", dir, e);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            }
+        }
+
+        // Managed blocks live in files, between markers of their own
+        let mode = self.mode.clone().unwrap_or(Mode::Overwrite);
+        if mode == Mode::Managed && outputs.is_empty() {
+            eprintln!("Error, a template in managed mode needs an out_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+        if mode != Mode::Managed && self.marker.is_some() {
+            eprintln!("Error, a template marker requires mode = \"managed\"");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        let mut template = Template::new(
+            &self.file,
             &file_contents,
             self.source_type.clone(),
-            self.out_file.clone(),
-        )
+            outputs,
+            engine,
+            helpers,
+            validation,
+        );
+        template.remote = remote;
+        template.file_dirs = file_dirs;
+        let marker = self.marker.clone().unwrap_or_else(|| "app_config".to_string());
+        template.managed = match mode {
+            Mode::Overwrite => None,
+            Mode::Managed => Some(marker),
+        };
+        template.comment = self.comment.clone().unwrap_or_else(|| "#".to_string());
+        template.banner = self.banner.unwrap_or(false);
+        template.checksum = self.checksum.unwrap_or(false);
+        template.previous = self.previous.unwrap_or(false);
+        template.extra_data = self
+            .extra_data
+            .iter()
+            .flatten()
+            .map(|conf| ExtraData::parse(conf, &self.source_type))
+            .collect();
+        template.limits = Limits {
+            timeout: config::parse_duration(
+                "render_timeout",
+                self.render_timeout.as_deref().unwrap_or(DEFAULT_RENDER_TIMEOUT),
+            ),
+            output_size: self.max_output_size.unwrap_or(DEFAULT_MAX_OUTPUT_SIZE),
+            depth: self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+            operations: self.max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS),
+        };
+
+        // Values looked up are secrets, they are only written to the state
+        // file when asked to
+        let keep_keys = self.keep_keys.unwrap_or(false);
+        if keep_keys && self.state_file.is_none() {
+            eprintln!("Error, keep_keys requires a state_file");
+            std::process::exit(exitcode::CONFIG);
+        }
+        template.keys = Arc::new(KeyCache::new(
+            self.key_ttl.unwrap_or(keys::DEFAULT_TTL),
+            &self.state_file.clone().filter(|_| keep_keys),
+        ));
+        template
     }
 }
 
+/// Make template hooks from [hooks.template]
+pub fn register(registry: &mut Registry) {
+    registry.register("template", |section| {
+        let conf: TemplateConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum DataType {
     YAML,
     JSON,
+    JSONL,
     TOML,
+    INI,
+    PROPERTIES,
+    DOTENV,
+    HCL,
+    XML,
+}
+
+/// Checks the rendered output has to pass before it is written: it must
+/// parse as <format>, and match <schema> if there is one.
+#[derive(Debug)]
+pub struct Validation {
+    format: DataType,
+    schema: Option<Schema>,
+}
+
+impl Validation {
+    fn check(&self, rendered: &str) -> Result<()> {
+        let value = formats::parse(&self.format, rendered)
+            .wrap_err_with(|| format!("Output is not valid {:?}", self.format))?;
+
+        if let Some(schema) = &self.schema {
+            schema.validate(&serde_json::to_value(&value)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Limits:
+/// What rendering a template may take: at most <timeout>, <output_size>
+/// bytes of output, data nested at most <depth> levels deep, and at most
+/// <operations> for each call of a helper script.  Broken or hostile data
+/// can so neither wedge the daemon nor fill the disk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub timeout: Duration,
+    pub output_size: usize,
+    pub depth: usize,
+    pub operations: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            timeout: config::parse_duration("render_timeout", DEFAULT_RENDER_TIMEOUT),
+            output_size: DEFAULT_MAX_OUTPUT_SIZE,
+            depth: DEFAULT_MAX_DEPTH,
+            operations: DEFAULT_MAX_OPERATIONS,
+        }
+    }
+}
+
+/// TemplateResourceLimit:
+/// Rendering a template went over one of its Limits.  The run fails like
+/// with any other error, but the cause can be told apart.
+#[derive(Debug)]
+pub enum TemplateResourceLimit {
+    Timeout { template: String, timeout: Duration },
+    OutputSize { template: String, size: usize },
+    Depth { template: String, depth: usize },
+}
+
+impl std::fmt::Display for TemplateResourceLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateResourceLimit::Timeout { template, timeout } => {
+                write!(f, "Template {} did not render within {:?}", template, timeout)
+            }
+            TemplateResourceLimit::OutputSize { template, size } => {
+                write!(f, "Template {} rendered more than {} bytes", template, size)
+            }
+            TemplateResourceLimit::Depth { template, depth } => {
+                write!(f, "Data for template {} nests deeper than {} levels", template, depth)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateResourceLimit {}
+
+/// Output:
+/// One of the files a template is rendered to.  With a <context>, a path
+/// such as `.tenants[]`, the template is rendered once for every value the
+/// path selects, with that value as its data.  <out_file> may then refer to
+/// the value, e.g. `/etc/app/{{name}}.conf`, so that each gets its own file.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Output {
+    pub out_file: String,
+    pub context: Option<String>,
+}
+
+impl Output {
+    /// Name of the file to write the template rendered with <context> to
+    fn file_name(&self, context: &serde_yaml::Value) -> Result<String> {
+        if !self.out_file.contains("{{") {
+            return Ok(self.out_file.clone());
+        }
+
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(handlebars::no_escape);
+        hb.render_template(&self.out_file, context)
+            .map_err(|e| eyre!("Invalid out_file {}: {}", self.out_file, e))
+    }
+}
+
+/// Remote:
+/// A template kept remotely rather than on the host, set by giving <file> as
+/// `s3://bucket/key` for an S3 object or as `ssm:name` for a Parameter Store
+/// parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Remote {
+    S3 { bucket: String, key: String },
+    Ssm(String),
+}
+
+impl Remote {
+    /// The remote <file> refers to, if it is not a local file
+    fn parse(file: &str) -> Result<Option<Remote>> {
+        if let Some(path) = file.strip_prefix("s3://") {
+            return match path.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+                    Ok(Some(Remote::S3 {
+                        bucket: bucket.to_string(),
+                        key: key.to_string(),
+                    }))
+                }
+                _ => Err(eyre!("Invalid template location {}, expected s3://bucket/key", file)),
+            };
+        }
+        match file.strip_prefix("ssm:") {
+            Some("") => Err(eyre!("Invalid template location {}, expected ssm:name", file)),
+            Some(name) => Ok(Some(Remote::Ssm(name.to_string()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetch the template's text, from SSM within <timeout> if there is one
+    #[cfg(feature = "aws")]
+    fn fetch(&self, timeout: Option<Duration>) -> Result<String> {
+        match self {
+            Remote::S3 { bucket, key } => {
+                let data = s3::get_object(bucket, key)?;
+                String::from_utf8(data)
+                    .map_err(|_| eyre!("s3://{}/{} is not valid UTF-8 text", bucket, key))
+            }
+            Remote::Ssm(name) => get_params(name, timeout),
+        }
+    }
+
+    #[cfg(not(feature = "aws"))]
+    fn fetch(&self, _timeout: Option<Duration>) -> Result<String> {
+        Err(eyre!("Remote templates need app_config built with the aws feature"))
+    }
+}
+
+/// ExtraData:
+/// More data for a template, from a provider of its own given inline, e.g.
+/// `{provider = "param_store", key = "/shared/app"}`.  It is parsed as
+/// <source_type>, that of the template unless the entry has its own.
+#[derive(Debug)]
+pub struct ExtraData {
+    provider: Box<dyn Provider>,
+    source_type: DataType,
+}
+
+impl ExtraData {
+    /// Will panic if the entry does not name a valid provider
+    fn parse(conf: &toml::Value, source_type: &DataType) -> ExtraData {
+        let mut section = match conf.as_table() {
+            Some(section) => section.clone(),
+            None => {
+                eprintln!("Error, template extra_data entries must be tables");
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let kind = match section.remove("provider") {
+            Some(toml::Value::String(kind)) => kind,
+            _ => {
+                eprintln!("Error, template extra_data entries need a provider");
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let source_type = match section.remove("source_type").map(|t| t.try_into()) {
+            None => source_type.clone(),
+            Some(Ok(source_type)) => source_type,
+            Some(Err(e)) => {
+                eprintln!("Error, invalid source_type in template extra_data: {}", e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+
+        ExtraData {
+            provider: config::parse_provider(&kind, &toml::Value::Table(section), "extra_data"),
+            source_type,
+        }
+    }
+
+    /// The provider's current data
+    fn fetch(&self) -> Result<serde_yaml::Value> {
+        let kind = self.provider.kind();
+        let raw = self
+            .provider
+            .query()
+            .wrap_err_with(|| format!("Unable to get extra data from {}", kind))?;
+        let value = ConfigData::new(raw, kind, None).parsed(&self.source_type).wrap_err_with(|| {
+            format!("Unable to parse {:?} extra data from {}", self.source_type, kind)
+        })?;
+        Ok(owned(value))
+    }
+}
+
+/// How a rendered template gets into its file: by overwriting the file, or
+/// as a managed block of a file whose rest is owned by something else
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Overwrite,
+    Managed,
+}
+
+/// Template language the template file is written in
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    Handlebars,
+    Tera,
 }
 
 
 // // // // // // // // // // // Hook // // // // // // // // // // //
 
 /// The Template hook will take formatted data (yaml, toml, json) from the provider
-/// and render it using a Handlebars template stored in <tpl>. If <out_file> is
-/// ommited the template will be rendered to stdout. Else it will be saved to a file.
+/// and render it using a Handlebars template stored in <tpl>. If there are no
+/// <outputs> the template will be rendered to stdout. Else it will be saved to
+/// each of them, every output is rendered before any file is written.
+/// With <engine> set to tera, the template is written in Tera's Jinja2 style
+/// syntax instead, with its filters, conditions and macros.
+/// Each of <helpers> is a rhai script registered as a handlebars helper under
+/// its name.  The helper's arguments are available in the script as the
+/// array `params` and the map `hash`.
+/// Bad data or template mistakes fail the hook with an error naming the
+/// template file <name>, and where in it rendering went wrong.
+/// With a <validation>, output that fails it is never written.
+/// A <remote> template is fetched anew every time the hook runs, and takes
+/// the place of <tpl>.
+/// The file and file_b64 helpers can read files inside <file_dirs> only.
+/// Values the key helper looks up are cached in <keys>.
+/// With <managed> the output only replaces the block between the lines
+/// `<comment> BEGIN <managed>` and `<comment> END <managed>` of its file,
+/// which is appended to the file if it has none yet.  With <banner> the
+/// output starts with a <comment> line saying it is managed, and from what.
+/// With <checksum> a `.sha256` sidecar is written next to every output, to
+/// tell when it is edited by hand.
+/// The data of each of <extra_data> is fetched every time the hook runs,
+/// and deep merged in order, with the provider's own data last to have the
+/// last word.
+/// The [vars] of the config file are rendered as `vars`, e.g.
+/// `{{vars.datacenter}}`, in place of any `vars` the data has.  With
+/// <previous>, so is the data applied before as `old`, e.g. to keep a value
+/// unless it changed: `{{#if old}}`.  It is null on the first run.
+/// Rendering is held to <limits>.
 #[derive(Debug)]
 pub struct Template {
+    name: String,
     tpl: String,
+    remote: Option<Remote>,
+    timeout: Option<Duration>,
+    file_dirs: Vec<PathBuf>,
+    keys: Arc<KeyCache>,
+    managed: Option<String>,
+    comment: String,
+    banner: bool,
+    checksum: bool,
+    extra_data: Vec<ExtraData>,
+    vars: serde_yaml::Mapping,
+    previous: bool,
+    output: RefCell<Option<String>>,
     source_type: DataType,
-    out_file: Option<String>,
+    outputs: Vec<Output>,
+    engine: Engine,
+    helpers: BTreeMap<String, String>,
+    validation: Option<Validation>,
+    limits: Limits,
+    rendering: Arc<AtomicBool>,
 }
 
 impl Template {
     /// Create a new Template struct
-    pub fn new(tpl: &str, source_type: DataType, out_file: Option<String>) -> Template {
+    pub fn new(
+        name: &str,
+        tpl: &str,
+        source_type: DataType,
+        outputs: Vec<Output>,
+        engine: Engine,
+        helpers: BTreeMap<String, String>,
+        validation: Option<Validation>,
+    ) -> Template {
         Template {
+            name: name.to_string(),
             tpl: tpl.to_string(),
+            remote: None,
+            timeout: None,
+            file_dirs: Vec::new(),
+            keys: Default::default(),
+            managed: None,
+            comment: "#".to_string(),
+            banner: false,
+            checksum: false,
+            extra_data: Vec::new(),
+            vars: serde_yaml::Mapping::new(),
+            previous: false,
+            output: RefCell::new(None),
             source_type,
-            out_file,
+            outputs,
+            engine,
+            helpers,
+            validation,
+            limits: Limits::default(),
+            rendering: Default::default(),
         }
     }
 
     /// Render the template
-    fn render(&self, data: &str) -> String {
-        let transformed_data = Template::transform(&self.source_type, data);
+    pub fn render(&self, data: &ConfigData) -> Result<String> {
+        let context = self.with_vars(self.parse(data)?, data)?;
+        self.render_value(&self.load()?, context, data.workspace())
+    }
+
+    /// The text of the template
+    fn load(&self) -> Result<Cow<'_, str>> {
+        match &self.remote {
+            None => Ok(Cow::Borrowed(&self.tpl)),
+            Some(remote) => {
+                let tpl = remote
+                    .fetch(self.timeout)
+                    .wrap_err_with(|| format!("Unable to fetch template {}", self.name))?;
+                Ok(Cow::Owned(tpl))
+            }
+        }
+    }
+
+    /// The data, shared with the other hooks unless extra data is merged in
+    fn parse(&self, data: &ConfigData) -> Result<Arc<serde_yaml::Value>> {
+        let value = data.parsed(&self.source_type).wrap_err_with(|| {
+            format!("Unable to parse {:?} data for template {}", self.source_type, self.name)
+        })?;
+        if self.extra_data.is_empty() {
+            return Ok(value);
+        }
+
+        let mut merged = serde_yaml::Value::Null;
+        for extra in &self.extra_data {
+            let extra = extra
+                .fetch()
+                .wrap_err_with(|| format!("No extra data for template {}", self.name))?;
+            formats::merge(&mut merged, extra);
+        }
+        formats::merge(&mut merged, owned(value));
+        Ok(Arc::new(merged))
+    }
+
+    /// <context> with the [vars] of the config file added, if there are any,
+    /// and with <previous> the data applied before <data> as `old`
+    fn with_vars(
+        &self,
+        context: Arc<serde_yaml::Value>,
+        data: &ConfigData,
+    ) -> Result<Arc<serde_yaml::Value>> {
+        if self.vars.is_empty() && !self.previous {
+            return Ok(context);
+        }
+        let mut context = match owned(context) {
+            serde_yaml::Value::Mapping(context) => context,
+            serde_yaml::Value::Null => serde_yaml::Mapping::new(),
+            _ => return Err(eyre!("Template {} needs data that is a map to add vars", self.name)),
+        };
+        if !self.vars.is_empty() {
+            context.insert("vars".into(), serde_yaml::Value::Mapping(self.vars.clone()));
+        }
+        if self.previous {
+            let old = match data.previous() {
+                None => serde_yaml::Value::Null,
+                Some(previous) => owned(
+                    previous.parsed(&self.source_type).wrap_err_with(|| {
+                        format!("Unable to parse the previous data for template {}", self.name)
+                    })?,
+                ),
+            };
+            context.insert("old".into(), old);
+        }
+        Ok(Arc::new(serde_yaml::Value::Mapping(context)))
+    }
+
+    /// Render the template with <transformed_data>, and check the result
+    fn render_value(
+        &self,
+        tpl: &str,
+        transformed_data: Arc<serde_yaml::Value>,
+        workspace: Option<&Path>,
+    ) -> Result<String> {
+        let template = self.name.clone();
+        if depth(&transformed_data) > self.limits.depth {
+            return Err(TemplateResourceLimit::Depth { template, depth: self.limits.depth }.into());
+        }
+        let rendered = self.render_engine(tpl, Arc::clone(&transformed_data), workspace)?;
+
+        if let Some(validation) = &self.validation {
+            validation
+                .check(&rendered)
+                .wrap_err_with(|| format!("Rendered template {} failed validation", self.name))?;
+        }
+        Ok(rendered)
+    }
+
+    /// Render the template for every output, as a list of file names and
+    /// their contents
+    fn render_outputs(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        let tpl = self.load()?;
+        let mut rendered = Vec::new();
+        for (file, context) in self.output_files(data)? {
+            rendered.push((file, self.render_value(&tpl, context, data.workspace())?));
+        }
+        Ok(rendered)
+    }
+
+    /// The files the template is rendered to for <data>, each with the
+    /// context it is rendered with
+    fn output_files(&self, data: &ConfigData) -> Result<Vec<(String, Arc<serde_yaml::Value>)>> {
+        let transformed_data = self.parse(data)?;
+
+        let mut files: Vec<(String, Arc<serde_yaml::Value>)> = Vec::new();
+        for output in &self.outputs {
+            let contexts = match &output.context {
+                None => vec![Arc::clone(&transformed_data)],
+                Some(context) => path::select(&transformed_data, context)
+                    .wrap_err_with(|| format!("Bad context for template {}", self.name))?
+                    .into_iter()
+                    .map(Arc::new)
+                    .collect(),
+            };
+
+            for context in contexts {
+                let context = self.with_vars(context, data)?;
+                let file = output.file_name(&context)?;
+                if files.iter().any(|(f, _)| f == &file) {
+                    return Err(eyre!("Template {} renders to {} twice", self.name, file));
+                }
+                files.push((file, context));
+            }
+        }
+        Ok(files)
+    }
 
+    /// Render with the engine on a thread of its own, so a render that
+    /// takes too long can be given up on.  It is left to finish on its own,
+    /// and until it does the template is not rendered again, so renders
+    /// given up on never pile up.
+    /// The thread shares <transformed_data>, rather than a copy of it.
+    fn render_engine(
+        &self,
+        tpl: &str,
+        transformed_data: Arc<serde_yaml::Value>,
+        workspace: Option<&Path>,
+    ) -> Result<String> {
+        if self.rendering.swap(true, Ordering::SeqCst) {
+            return Err(eyre!(
+                "Template {} is still rendering since it ran out of time before",
+                self.name
+            ));
+        }
+        let rendering = Rendering(self.rendering.clone());
+        let renderer = Renderer {
+            name: self.name.clone(),
+            engine: self.engine.clone(),
+            keys: self.keys.clone(),
+            file_dirs: self.file_dirs.clone(),
+            helpers: self.helpers.clone(),
+            workspace: workspace.map(|dir| dir.to_string_lossy().to_string()),
+            limits: self.limits.clone(),
+        };
+        let tpl = tpl.to_string();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let rendered = renderer.render(&tpl, &transformed_data);
+            // Free before the render is seen to be over
+            drop(rendering);
+            let _ = sender.send(rendered);
+        });
+
+        match receiver.recv_timeout(self.limits.timeout) {
+            Ok(rendered) => rendered,
+            Err(RecvTimeoutError::Timeout) => Err(TemplateResourceLimit::Timeout {
+                template: self.name.clone(),
+                timeout: self.limits.timeout,
+            }
+            .into()),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(eyre!("Rendering template {} panicked", self.name))
+            }
+        }
+    }
+}
+
+/// Rendering:
+/// Held by the thread rendering a template, which is free to render again
+/// once it is dropped, panics included
+struct Rendering(Arc<AtomicBool>);
+
+impl Drop for Rendering {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// <value> itself when nothing else holds it, else a copy of it
+fn owned(value: Arc<serde_yaml::Value>) -> serde_yaml::Value {
+    Arc::try_unwrap(value).unwrap_or_else(|value| (*value).clone())
+}
+
+/// How many levels deep <value> nests, a scalar does not
+fn depth(value: &serde_yaml::Value) -> usize {
+    match value {
+        serde_yaml::Value::Mapping(map) => 1 + map.iter().map(|(_, v)| depth(v)).max().unwrap_or(0),
+        serde_yaml::Value::Sequence(seq) => 1 + seq.iter().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Renderer:
+/// What a template engine needs of a Template, to render on another thread
+struct Renderer {
+    name: String,
+    engine: Engine,
+    keys: Arc<KeyCache>,
+    file_dirs: Vec<PathBuf>,
+    helpers: BTreeMap<String, String>,
+    workspace: Option<String>,
+    limits: Limits,
+}
+
+impl Renderer {
+    /// Render into a buffer that stops the engine as soon as the output
+    /// grows larger than allowed, rather than once it is all there
+    fn render(&self, tpl: &str, transformed_data: &serde_yaml::Value) -> Result<String> {
+        let mut output = CappedOutput::new(self.limits.output_size);
+        let res = match self.engine {
+            Engine::Handlebars => self.render_handlebars(tpl, transformed_data, &mut output),
+            Engine::Tera => self.render_tera(tpl, transformed_data, &mut output),
+        };
+        if output.exceeded {
+            let (template, size) = (self.name.clone(), self.limits.output_size);
+            return Err(TemplateResourceLimit::OutputSize { template, size }.into());
+        }
+        res?;
+        String::from_utf8(output.buf)
+            .wrap_err_with(|| format!("Template {} rendered invalid UTF-8", self.name))
+    }
+
+    // Templates are registered under their file name, so that handlebars
+    // and tera both mention it next to the line and column of any error
+    fn render_handlebars(
+        &self,
+        tpl: &str,
+        transformed_data: &serde_yaml::Value,
+        output: &mut CappedOutput,
+    ) -> Result<()> {
         let mut hb = Handlebars::new();
-        hb.register_helper("key", Box::new(key_helper));
+        hb.register_helper("key", Box::new(key_helper(self.keys.clone())));
+        helpers::register(&mut hb);
+        helpers::register_files(&mut hb, &self.file_dirs);
+        helpers::register_workspace(&mut hb, self.workspace.clone());
+        helpers::register_scripts(&mut hb, &self.helpers, self.limits.operations)?;
+
+        hb.register_template_string(&self.name, tpl)
+            .map_err(|e| eyre!("Invalid template {}: {}", self.name, e.to_string().trim_end()))?;
+
+        hb.render_to_write(&self.name, &transformed_data, output)?;
+        Ok(())
+    }
+
+    fn render_tera(
+        &self,
+        tpl: &str,
+        transformed_data: &serde_yaml::Value,
+        output: &mut CappedOutput,
+    ) -> Result<()> {
+        let mut tera = tera::Tera::default();
+        tera.register_function("key", key_function(self.keys.clone()));
+        tera.register_function("file", file_function(&self.file_dirs, false));
+        tera.register_function("file_b64", file_function(&self.file_dirs, true));
+        tera.register_function("workspace", workspace_function(self.workspace.clone()));
+        for name in identity::NAMES {
+            tera.register_function(name, identity_function(name));
+        }
+
+        tera.add_raw_template(&self.name, tpl)
+            .wrap_err_with(|| format!("Invalid template {}", self.name))?;
+
+        let context = tera::Context::from_serialize(transformed_data)
+            .wrap_err("Template data must be a map")?;
+        tera.render_to(&self.name, &context, output)?;
+        Ok(())
+    }
+}
 
-        assert!(hb.register_template_string("tpl", self.tpl.clone()).is_ok());
+/// CappedOutput:
+/// What a template engine renders to, taking at most <limit> bytes.  Once it
+/// is <exceeded> every write fails, which ends the render.
+struct CappedOutput {
+    buf: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
 
-        hb.render("tpl", &transformed_data).unwrap()
+impl CappedOutput {
+    fn new(limit: usize) -> CappedOutput {
+        CappedOutput { buf: Vec::new(), limit, exceeded: false }
     }
+}
 
-    /// Source data from YAML, JSON or TOML and turn it all into a BTreeMap
-    /// for use with Handlebars templates
-    fn transform(source_type: &DataType, input_data: &str) -> serde_yaml::Value {
-        match source_type {
-            DataType::YAML => serde_yaml::from_str(input_data).unwrap(),
-            DataType::JSON => serde_json::from_str(input_data).unwrap(),
-            DataType::TOML => toml::from_str(input_data).unwrap(),
+impl Write for CappedOutput {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.exceeded || self.buf.len() + data.len() > self.limit {
+            self.exceeded = true;
+            return Err(std::io::Error::other("output too large"));
         }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
 impl Hook for Template {
+    fn kind(&self) -> &'static str {
+        "template"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     /// Render the data and either print to stdout,
     /// or save the output to a file
-    fn run(&self, data: &str) -> Result<()> {
-        let rendered_data = &self.render(data);
-
-        // If the user configured 'out_file', write the template there
-        // Else print the rendered templete to stdout
-        match &self.out_file {
-            Some(file) => {
-                let expanded_path = tilde(&file).to_string();
-
-                match fs::File::create(expanded_path) {
-                    Ok(mut file_handle) => 
-                        file_handle.write_all(rendered_data.as_bytes())?,
-                    Err(e) => {
-                        eprintln!("Could not open {}: {}", file, e);
-                        std::process::exit(exitcode::OSFILE);
-                    }
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        // Without outputs print the rendered templete to stdout
+        if self.outputs.is_empty() {
+            let rendered = format!("{}{}", self.banner(data), self.render(data)?);
+            print!("{}", rendered);
+            *self.output.borrow_mut() = Some(sha256(rendered));
+            return Ok(());
+        }
+
+        // The checksum of each file written
+        let mut written = Vec::new();
+        for (file, rendered_data) in self.render_outputs(data)? {
+            let expanded_path = tilde(&file).to_string();
+            let mut contents = format!("{}{}", self.banner(data), rendered_data);
+
+            if let Some(marker) = &self.managed {
+                let existing = match fs::read_to_string(&expanded_path) {
+                    Ok(existing) => existing,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", file)),
                 };
+                let begin = format!("{} BEGIN {}", self.comment, marker);
+                let end = format!("{} END {}", self.comment, marker);
+                contents = splice(&existing, &begin, &end, &contents)
+                    .wrap_err_with(|| format!("Could not update {}", file))?;
+            }
+
+            if !interactive::confirm_write(&expanded_path, contents.as_bytes())? {
+                continue;
+            }
+            let mut file_handle = fs::File::create(&expanded_path)
+                .wrap_err_with(|| format!("Could not open {}", file))?;
+            file_handle.write_all(contents.as_bytes())?;
+            if self.checksum {
+                checksum::write(&expanded_path, contents.as_bytes())?;
+            }
+            written.push((sha256(&contents), file));
+        }
+
+        // A single file is known by its own checksum, several by that of
+        // the list of theirs
+        *self.output.borrow_mut() = match written.as_slice() {
+            [(sha, _)] => Some(sha.clone()),
+            written => {
+                let list: Vec<String> =
+                    written.iter().map(|(sha, file)| format!("{}  {}\n", sha, file)).collect();
+                Some(sha256(list.concat()))
             }
-            None => print!("{}", rendered_data),
         };
         Ok(())
     }
+
+    /// The files of the outputs, unless only a managed block of each is
+    /// written
+    fn files(&self, data: &ConfigData) -> Result<Vec<String>> {
+        if self.managed.is_some() {
+            return Ok(Vec::new());
+        }
+        let files = self.output_files(data)?.into_iter();
+        Ok(files.map(|(file, _)| tilde(&file).to_string()).collect())
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+
+    fn set_vars(&mut self, vars: &serde_yaml::Mapping) {
+        self.vars = vars.clone();
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+        // Nothing else holds the cache until the template is rendered
+        if let Some(keys) = Arc::get_mut(&mut self.keys) {
+            keys.set_timeout(timeout);
+        }
+    }
+
+    fn uses_previous(&self) -> bool {
+        self.previous
+    }
+
+    /// What run writes, or prints to "stdout", leaving out what a managed
+    /// block is spliced into
+    fn rendered(&self, data: &ConfigData) -> Result<Vec<(String, String)>> {
+        let banner = self.banner(data);
+        if self.outputs.is_empty() {
+            return Ok(vec![("stdout".to_string(), format!("{}{}", banner, self.render(data)?))]);
+        }
+        let rendered = self.render_outputs(data)?;
+        Ok(rendered.into_iter().map(|(file, r)| (file, format!("{}{}", banner, r))).collect())
+    }
+
+    /// The outputs for <data> that no longer match their checksum
+    fn drifted(&self, data: &ConfigData) -> Result<Vec<String>> {
+        if !self.checksum {
+            return Ok(Vec::new());
+        }
+        let mut drifted = Vec::new();
+        for (file, _) in self.output_files(data)? {
+            if checksum::drifted(&tilde(&file))? {
+                drifted.push(file);
+            }
+        }
+        Ok(drifted)
+    }
+}
+
+impl Template {
+    /// The banner line output starts with, if it has one
+    fn banner(&self, data: &ConfigData) -> String {
+        if !self.banner {
+            return String::new();
+        }
+        let version = match data.version() {
+            Some(version) => format!(" version {}", version),
+            None => String::new(),
+        };
+        format!(
+            "{} Managed by app_config, do not edit. Rendered from {}{} data, sha256 {}\n",
+            self.comment,
+            data.provider(),
+            version,
+            data.sha256()
+        )
+    }
+}
+
+/// <existing> with the lines from <begin> to <end> replaced by <block>, or
+/// with them appended if there is no <begin> line yet
+pub fn splice(existing: &str, begin: &str, end: &str, block: &str) -> Result<String> {
+    let mut block = block.to_string();
+    if !block.is_empty() && !block.ends_with('\n') {
+        block.push('\n');
+    }
+    let managed = format!("{}\n{}{}\n", begin, block, end);
+
+    let lines: Vec<&str> = existing.split_inclusive('\n').collect();
+    let start = lines.iter().position(|l| l.trim_end() == begin);
+    let stop = start.and_then(|start| {
+        lines[start..].iter().position(|l| l.trim_end() == end).map(|i| start + i)
+    });
+
+    match (start, stop) {
+        (Some(start), Some(stop)) => Ok(format!(
+            "{}{}{}",
+            lines[..start].concat(),
+            managed,
+            lines[stop + 1..].concat()
+        )),
+        (Some(_), None) => Err(eyre!("Found \"{}\" without \"{}\"", begin, end)),
+        (None, _) if existing.is_empty() || existing.ends_with('\n') => {
+            Ok(format!("{}{}", existing, managed))
+        }
+        (None, _) => Ok(format!("{}\n{}", existing, managed)),
+    }
 }
 
 
@@ -131,19 +1036,83 @@ impl Hook for Template {
 /// Return the result.   Assume in AWS Paramstore there is a key called "Hello"
 /// with a value "World".  In the template we can write 
 /// `Greetings: {{key "Hello"}}` and when rendered we see: `Greetings: World`
-fn key_helper (
-    h: &Helper, _: &Handlebars, _: &Context, _rc: &mut RenderContext, 
-                                    out: &mut dyn Output) -> HelperResult {
+/// With `{{key "Hello" default="Stranger"}}` the default is used if there is
+/// no way to get the value, rather than failing the render.
+fn key_helper(keys: Arc<KeyCache>) -> impl handlebars::HelperDef + Send + Sync {
+    move |h: &Helper, _: &Handlebars, _: &Context, _rc: &mut RenderContext,
+          out: &mut dyn handlebars::Output|
+          -> HelperResult {
+        let ssm_key: String = match h.param(0) {
+            Some(param) => param.value().render(),
+            None => return Err(handlebars::RenderError::new("key helper needs a parameter")),
+        };
+        let default = h.hash_get("default").map(|v| v.value().render());
+
+        let value = lookup_key(&keys, &ssm_key, default).map_err(handlebars::RenderError::new)?;
+        out.write(&value)?;
+        Ok(())
+    }
+}
+
+/// Tera counterpart of key_helper: `Greetings: {{ key(name="Hello") }}`
+fn key_function(keys: Arc<KeyCache>) -> impl tera::Function {
+    move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        let ssm_key = match args.get("name").and_then(|v| v.as_str()) {
+            Some(ssm_key) => ssm_key,
+            None => return Err("key() needs a name argument".into()),
+        };
+        let default = args.get("default").and_then(|v| v.as_str()).map(String::from);
+
+        Ok(tera::Value::String(lookup_key(&keys, ssm_key, default)?))
+    }
+}
+
+/// Look <ssm_key> up, falling back to <default> if there is one
+fn lookup_key(keys: &KeyCache, ssm_key: &str, default: Option<String>) -> Result<String, String> {
+    match (keys.get(ssm_key), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(e), Some(default)) => {
+            warning!("using the default for {}: {:#}", ssm_key, e);
+            Ok(default)
+        }
+        (Err(e), None) => Err(format!("{:#}", e)),
+    }
+}
 
-    let ssm_key: String = h.param(0).unwrap().value().render();
-    let value = match get_params(&ssm_key) {
-        Ok(value) => value,
-        Err(e) => return Err(handlebars::RenderError::new(format!("{:#?}", e))),
-    };
+/// Tera counterparts of the file helpers: `{{ file(path="ca.pem") }}`
+fn file_function(dirs: &[PathBuf], encode: bool) -> impl tera::Function {
+    let dirs = dirs.to_vec();
+    move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        let path = match args.get("path").and_then(|v| v.as_str()) {
+            Some(path) => path,
+            None => return Err("file() needs a path argument".into()),
+        };
 
-    out.write(&value)?;
-    Ok(())
+        let contents = helpers::read_file(&dirs, path)?;
+        match encode {
+            true => Ok(tera::Value::String(base64::encode(contents))),
+            false => Ok(tera::Value::String(String::from_utf8_lossy(&contents).to_string())),
+        }
+    }
+}
+
+/// Tera function giving the workspace of the run, `{{ workspace() }}`
+fn workspace_function(workspace: Option<String>) -> impl tera::Function {
+    move |_: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        match &workspace {
+            Some(workspace) => Ok(tera::Value::String(workspace.clone())),
+            None => Err("workspace: only runs have one".into()),
+        }
+    }
+}
 
+/// Tera function returning the identity value <name> of the host, e.g.
+/// `{{ hostname() }}`
+fn identity_function(name: &'static str) -> impl tera::Function {
+    move |_: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        let value = identity::lookup(name).map_err(|e| format!("{}: {:#}", name, e))?;
+        Ok(tera::Value::String(value))
+    }
 }
     
 
@@ -153,6 +1122,10 @@ fn key_helper (
 mod tests {
     use super::*;
 
+    fn gen_data(data: &str) -> ConfigData {
+        ConfigData::new(data, "mock", None)
+    }
+
     fn gen_yml_data() -> &'static str {
         "---
 hosts:
@@ -209,12 +1182,30 @@ PublicKey = {{this.public_key}}
     fn test_yaml_template() {
         let expected = gen_expected();
         let tpl = Template {
+            name: "test.tpl".to_string(),
             tpl: gen_template().to_string(),
+            remote: None,
+            timeout: None,
+            file_dirs: Vec::new(),
+            keys: Default::default(),
+            managed: None,
+            comment: "#".to_string(),
+            banner: false,
+            checksum: false,
+            extra_data: Vec::new(),
+            vars: serde_yaml::Mapping::new(),
+            previous: false,
+            output: RefCell::new(None),
             // data: gen_yml_data().to_string(),
             source_type: DataType::YAML,
-            out_file: None,
+            outputs: Vec::new(),
+            engine: Engine::Handlebars,
+            helpers: BTreeMap::new(),
+            validation: None,
+            limits: Limits::default(),
+            rendering: Default::default(),
         };
-        let res = tpl.render(gen_yml_data());
+        let res = tpl.render(&gen_data(gen_yml_data())).unwrap();
 
         assert_eq!(expected, res);
     }
@@ -223,12 +1214,30 @@ PublicKey = {{this.public_key}}
     fn test_json_template() {
         let expected = gen_expected();
         let tpl = Template {
+            name: "test.tpl".to_string(),
             tpl: gen_template().to_string(),
+            remote: None,
+            timeout: None,
+            file_dirs: Vec::new(),
+            keys: Default::default(),
+            managed: None,
+            comment: "#".to_string(),
+            banner: false,
+            checksum: false,
+            extra_data: Vec::new(),
+            vars: serde_yaml::Mapping::new(),
+            previous: false,
+            output: RefCell::new(None),
             // data: gen_json_data().to_string(),
             source_type: DataType::JSON,
-            out_file: None,
+            outputs: Vec::new(),
+            engine: Engine::Handlebars,
+            helpers: BTreeMap::new(),
+            validation: None,
+            limits: Limits::default(),
+            rendering: Default::default(),
         };
-        let res = tpl.render(gen_json_data());
+        let res = tpl.render(&gen_data(gen_json_data())).unwrap();
 
         assert_eq!(expected, res);
     }
@@ -237,13 +1246,517 @@ PublicKey = {{this.public_key}}
     fn test_toml_template() {
         let expected = gen_expected();
         let tpl = Template {
+            name: "test.tpl".to_string(),
             tpl: gen_template().to_string(),
+            remote: None,
+            timeout: None,
+            file_dirs: Vec::new(),
+            keys: Default::default(),
+            managed: None,
+            comment: "#".to_string(),
+            banner: false,
+            checksum: false,
+            extra_data: Vec::new(),
+            vars: serde_yaml::Mapping::new(),
+            previous: false,
+            output: RefCell::new(None),
             // data: gen_toml_data().to_string(),
             source_type: DataType::TOML,
-            out_file: None,
+            outputs: Vec::new(),
+            engine: Engine::Handlebars,
+            helpers: BTreeMap::new(),
+            validation: None,
+            limits: Limits::default(),
+            rendering: Default::default(),
         };
-        let res = tpl.render(gen_toml_data());
+        let res = tpl.render(&gen_data(gen_toml_data())).unwrap();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_tera_template() {
+        let expected = "
+[Peer]
+EndPoint = HOST1
+PublicKey = xyz
+";
+        let tpl = Template::new(
+            "test.tpl",
+            "{% for host in hosts %}{% if host.public_key != \"abc\" %}
+[Peer]
+EndPoint = {{ host.name | upper }}
+PublicKey = {{ host.public_key }}
+{% endif %}{% endfor %}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Tera,
+            BTreeMap::new(),
+            None,
+        );
+        let res = tpl.render(&gen_data(gen_yml_data())).unwrap();
 
         assert_eq!(expected, res);
     }
+
+    #[test]
+    fn test_limits() {
+        let tera = |tpl: &str| {
+            let (engine, helpers) = (Engine::Tera, BTreeMap::new());
+            Template::new("test.tpl", tpl, DataType::YAML, Vec::new(), engine, helpers, None)
+        };
+        let limit = |tpl: &Template, data: &str| {
+            let err = tpl.render(&gen_data(data)).unwrap_err();
+            let limit = err.downcast_ref::<TemplateResourceLimit>().map(|l| l.to_string());
+            limit.unwrap_or_else(|| panic!("{:#}", err))
+        };
+
+        let mut tpl = tera("{{ a }}");
+        tpl.limits.depth = 2;
+        assert_eq!(tpl.render(&gen_data("a: {b: 1}")).unwrap(), "[object]");
+        let res = limit(&tpl, "a: {b: [1]}");
+        assert_eq!(res, "Data for template test.tpl nests deeper than 2 levels");
+
+        let mut tpl = tera("{% for i in range(end=10) %}{{ a }}{% endfor %}");
+        tpl.limits.output_size = 20;
+        assert_eq!(tpl.render(&gen_data("a: ab")).unwrap().len(), 20);
+        assert_eq!(limit(&tpl, "a: abc"), "Template test.tpl rendered more than 20 bytes");
+
+        // The render is given up on as soon as it passes the limit, before
+        // it would run out of time
+        let mut tpl = tera(
+            "{% for i in range(end=10) %}{{ a }}{% endfor %}\
+             {% for i in range(end=10000000) %}{% endfor %}",
+        );
+        tpl.limits.output_size = 20;
+        tpl.limits.timeout = Duration::from_millis(20);
+        assert_eq!(limit(&tpl, "a: abc"), "Template test.tpl rendered more than 20 bytes");
+
+        let mut tpl = tera("{% for i in range(end=10000000) %}{% endfor %}");
+        tpl.limits.timeout = Duration::from_millis(20);
+        assert_eq!(limit(&tpl, "a: 1"), "Template test.tpl did not render within 20ms");
+
+        // Not again until the render given up on is over
+        let res = format!("{:#}", tpl.render(&gen_data("a: 1")).unwrap_err());
+        assert!(res.contains("still rendering"), "{}", res);
+        while tpl.rendering.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn parse_engine() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [hooks.template]
+            file = "./tests/mock.toml"
+            source_type = "yaml"
+            engine = "tera"
+            "#,
+        )
+        .unwrap();
+        let conf: TemplateConf = maps["hooks"]["template"].clone().try_into().unwrap();
+
+        assert_eq!(conf.convert().engine, Engine::Tera);
+    }
+
+    #[test]
+    fn test_script_helpers() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [hooks.template]
+            file = "./tests/mock.toml"
+            source_type = "yaml"
+
+            [hooks.template.helpers]
+            percent = '''
+                let value = params[0];
+                (value * 100).to_string() + hash["label"]
+            '''
+            "#,
+        )
+        .unwrap();
+        let conf: TemplateConf = maps["hooks"]["template"].clone().try_into().unwrap();
+        let mut tpl = conf.convert();
+        tpl.tpl = "{{percent ratio label=\"%\"}}".to_string();
+
+        assert_eq!(tpl.render(&gen_data("ratio: 42")).unwrap(), "4200%");
+    }
+
+    #[test]
+    fn test_set_timeout() {
+        let mut tpl = Template::new(
+            "test.tpl",
+            "{{key \"/app/token\"}}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        tpl.set_timeout(Duration::from_secs(5));
+        assert_eq!(tpl.timeout, Some(Duration::from_secs(5)));
+        assert!(format!("{:?}", tpl.keys).contains("timeout: Some(5s)"), "{:?}", tpl.keys);
+    }
+
+    #[test]
+    fn test_script_limits() {
+        let helpers: BTreeMap<String, String> =
+            vec![("spin".to_string(), "loop { }".to_string())].into_iter().collect();
+        let mut tpl = Template::new(
+            "test.tpl",
+            "{{spin}}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            helpers,
+            None,
+        );
+        tpl.limits.operations = 1000;
+        tpl.limits.timeout = Duration::from_secs(10);
+
+        // A script looping forever runs out of operations, not of time
+        let err = tpl.render(&gen_data("a: 1")).unwrap_err();
+        assert!(err.downcast_ref::<TemplateResourceLimit>().is_none(), "{:#}", err);
+    }
+
+    #[test]
+    fn test_validation() {
+        let schema = Schema::compile(
+            "peers.json",
+            r#"{"type": "array", "items": {"required": ["endpoint"]}}"#,
+        )
+        .unwrap();
+        let tpl = Template::new(
+            "test.tpl",
+            "[{{#each hosts}}{\"{{this.public_key}}\": 1}{{#unless @last}},{{/unless}}{{/each}}]",
+            DataType::YAML,
+            vec![Output {
+                out_file: "/nonexistent/out".to_string(),
+                context: None,
+            }],
+            Engine::Handlebars,
+            BTreeMap::new(),
+            Some(Validation {
+                format: DataType::JSON,
+                schema: Some(schema),
+            }),
+        );
+        let res = format!("{:#}", tpl.run(&gen_data(gen_yml_data())).unwrap_err());
+        assert!(res.contains("Rendered template test.tpl failed validation"), "{}", res);
+        assert!(res.contains("at /0:"), "{}", res);
+
+        let v = Validation {
+            format: DataType::TOML,
+            schema: None,
+        };
+        assert!(v.check("a = 1").is_ok());
+        assert!(v.check("a = ").is_err());
+    }
+
+    #[test]
+    fn test_outputs() {
+        let dir = std::env::temp_dir().join(format!("app_config_outputs_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let out_file = format!("{}/{{{{name}}}}.conf", dir.display());
+
+        let tpl = Template::new(
+            "test.tpl",
+            "{{name}}:{{#each hosts}} {{this}}{{/each}}",
+            DataType::YAML,
+            vec![Output {
+                out_file: out_file.clone(),
+                context: Some(".tenants[]".to_string()),
+            }],
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        let data = "tenants: [{name: acme, hosts: [a1, a2]}, {name: umbrella, hosts: [u1]}]";
+        tpl.run(&gen_data(data)).unwrap();
+
+        let acme = fs::read_to_string(dir.join("acme.conf")).unwrap();
+        let umbrella = fs::read_to_string(dir.join("umbrella.conf")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(acme, "acme: a1 a2");
+        assert_eq!(umbrella, "umbrella: u1");
+
+        // Nothing is written if two values end up in the same file
+        let data = "tenants: [{name: acme, hosts: []}, {name: acme, hosts: []}]";
+        let res = format!("{:#}", tpl.run(&gen_data(data)).unwrap_err());
+        assert!(res.contains("acme.conf twice"), "{}", res);
+        assert!(!dir.exists());
+
+        let data = "clusters: []";
+        let res = format!("{:#}", tpl.run(&gen_data(data)).unwrap_err());
+        assert!(res.contains("Bad context for template test.tpl"), "{}", res);
+    }
+
+    #[test]
+    fn test_splice() {
+        let (begin, end) = ("# BEGIN app_config", "# END app_config");
+
+        let res = splice("", begin, end, "a = 1").unwrap();
+        assert_eq!(res, "# BEGIN app_config\na = 1\n# END app_config\n");
+
+        // The rest of the file is left as it is
+        let existing = "top\n# BEGIN app_config\nold\n# END app_config\nbottom";
+        let res = splice(existing, begin, end, "a = 1\n").unwrap();
+        assert_eq!(res, "top\n# BEGIN app_config\na = 1\n# END app_config\nbottom");
+
+        let res = splice("top", begin, end, "a = 1").unwrap();
+        assert_eq!(res, "top\n# BEGIN app_config\na = 1\n# END app_config\n");
+
+        assert!(splice("# BEGIN app_config\nold\n", begin, end, "a = 1").is_err());
+    }
+
+    #[test]
+    fn test_managed() {
+        let out_file =
+            std::env::temp_dir().join(format!("app_config_managed_{}", std::process::id()));
+        fs::write(&out_file, "127.0.0.1 localhost\n").unwrap();
+
+        let mut tpl = Template::new(
+            "test.tpl",
+            "{{#each hosts}}{{this.name}}\n{{/each}}",
+            DataType::YAML,
+            vec![Output {
+                out_file: out_file.to_str().unwrap().to_string(),
+                context: None,
+            }],
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        tpl.managed = Some("hosts".to_string());
+        tpl.banner = true;
+
+        tpl.run(&gen_data(gen_yml_data())).unwrap();
+        tpl.run(&gen_data(gen_yml_data())).unwrap();
+        let res = fs::read_to_string(&out_file).unwrap();
+        fs::remove_file(&out_file).unwrap();
+
+        let sha = gen_data(gen_yml_data()).sha256().to_string();
+        assert_eq!(
+            res,
+            format!(
+                "127.0.0.1 localhost\n# BEGIN hosts\n\
+                 # Managed by app_config, do not edit. Rendered from mock data, sha256 {}\n\
+                 host1\nhost2\n# END hosts\n",
+                sha
+            )
+        );
+    }
+
+    #[test]
+    fn parse_outputs() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [hooks.template]
+            file = "./tests/test_template.tmpl"
+            source_type = "yaml"
+
+            [[hooks.template.outputs]]
+            out_file = "/etc/app/{{name}}.conf"
+            context = ".tenants[]"
+
+            [[hooks.template.outputs]]
+            out_file = "/etc/app/all.conf"
+            "#,
+        )
+        .unwrap();
+        let conf: TemplateConf = maps["hooks"]["template"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(
+            res.outputs,
+            vec![
+                Output {
+                    out_file: "/etc/app/{{name}}.conf".to_string(),
+                    context: Some(".tenants[]".to_string()),
+                },
+                Output {
+                    out_file: "/etc/app/all.conf".to_string(),
+                    context: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_helpers() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [hooks.template]
+            file = "./tests/test_template.tmpl"
+            source_type = "yaml"
+            engine = "tera"
+            file_dirs = ["./tests"]
+            "#,
+        )
+        .unwrap();
+        let conf: TemplateConf = maps["hooks"]["template"].clone().try_into().unwrap();
+        let mut tpl = conf.convert();
+        tpl.tpl = "{{ file(path=\"./tests/test_template.tmpl\") | length > 0 }}".to_string();
+        assert_eq!(tpl.render(&gen_data("a: 1")).unwrap(), "true");
+
+        tpl.tpl = "{{ file_b64(path=\"./Cargo.toml\") }}".to_string();
+        let res = format!("{:#}", tpl.render(&gen_data("a: 1")).unwrap_err());
+        assert!(res.contains("outside of the template's file_dirs"), "{}", res);
+    }
+
+    #[test]
+    fn test_key_default() {
+        let mut tpl = Template::new(
+            "test.tpl",
+            "{{key \"/app/missing\" default=\"none\"}}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        tpl.keys.store("/app/name", "web").unwrap();
+        assert_eq!(tpl.render(&gen_data("a: 1")).unwrap(), "none");
+
+        tpl.tpl = "{{key \"/app/name\"}}".to_string();
+        assert_eq!(tpl.render(&gen_data("a: 1")).unwrap(), "web");
+
+        tpl.engine = Engine::Tera;
+        tpl.tpl = "{{ key(name=\"/app/name\") }}-{{ key(name=\"/x\", default=\"\") }}".to_string();
+        assert_eq!(tpl.render(&gen_data("a: 1")).unwrap(), "web-");
+    }
+
+    #[test]
+    fn test_vars() {
+        let mut tpl = Template::new(
+            "test.tpl",
+            "{{vars.datacenter}}/{{name}}",
+            DataType::YAML,
+            vec![Output {
+                out_file: "/etc/{{vars.role}}/{{name}}.conf".to_string(),
+                context: Some(".apps[]".to_string()),
+            }],
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        let mut vars = serde_yaml::Mapping::new();
+        vars.insert("datacenter".into(), "dc1".into());
+        vars.insert("role".into(), "web".into());
+        tpl.set_vars(&vars);
+
+        let res = tpl.render_outputs(&gen_data("apps: [{name: a}]")).unwrap();
+        assert_eq!(res, vec![("/etc/web/a.conf".to_string(), "dc1/a".to_string())]);
+        assert!(tpl.render(&gen_data("[a, b]")).is_err());
+    }
+
+    #[test]
+    fn test_previous() {
+        let tpl = "{{#if old}}{{old.port}} -> {{/if}}{{port}}";
+        let (format, engine, helpers) = (DataType::YAML, Engine::Handlebars, BTreeMap::new());
+        let mut tpl = Template::new("test.tpl", tpl, format, Vec::new(), engine, helpers, None);
+        tpl.previous = true;
+
+        assert_eq!(tpl.render(&gen_data("port: 80")).unwrap(), "80");
+        let data = gen_data("port: 8080").with_previous(gen_data("port: 80"));
+        assert_eq!(tpl.render(&data).unwrap(), "80 -> 8080");
+        assert!(tpl.uses_previous());
+    }
+
+    #[test]
+    fn test_workspace() {
+        let data = gen_data("a: 1").in_workspace(Path::new("/tmp/run"));
+        let templates = [(Engine::Handlebars, "{{workspace}}"), (Engine::Tera, "{{workspace()}}")];
+        for (engine, tpl) in templates {
+            let (format, helpers) = (DataType::YAML, BTreeMap::new());
+            let tpl = Template::new("test.tpl", tpl, format, Vec::new(), engine, helpers, None);
+            assert_eq!(tpl.render(&data).unwrap(), "/tmp/run");
+            assert!(tpl.render(&gen_data("a: 1")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_extra_data() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [hooks.template]
+            file = "./tests/test_template.tmpl"
+            source_type = "yaml"
+            extra_data = [
+                {provider = "mock", data = "db: {host: shared, port: 5432}\nzone: a"},
+                {provider = "mock", data = "{\"zone\": \"b\"}", source_type = "json"},
+            ]
+            "#,
+        )
+        .unwrap();
+        let conf: TemplateConf = maps["hooks"]["template"].clone().try_into().unwrap();
+        let mut tpl = conf.convert();
+        tpl.tpl = "{{db.host}}:{{db.port}} {{zone}}".to_string();
+        assert_eq!(tpl.render(&gen_data("db: {port: 6432}")).unwrap(), "shared:6432 b");
+    }
+
+    #[test]
+    fn test_remote() {
+        assert_eq!(Remote::parse("./tests/test_template.tmpl").unwrap(), None);
+        assert_eq!(
+            Remote::parse("s3://configs/templates/app.tmpl").unwrap(),
+            Some(Remote::S3 {
+                bucket: "configs".to_string(),
+                key: "templates/app.tmpl".to_string(),
+            })
+        );
+        assert_eq!(
+            Remote::parse("ssm:/templates/app").unwrap(),
+            Some(Remote::Ssm("/templates/app".to_string()))
+        );
+        assert!(Remote::parse("s3://configs").is_err());
+        assert!(Remote::parse("s3:///app.tmpl").is_err());
+        assert!(Remote::parse("ssm:").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let tpl = Template::new(
+            "test.tpl",
+            "{{#each hosts}}\n{{this.name}\n{{/each}}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        let res = format!("{:#}", tpl.render(&gen_data(gen_yml_data())).unwrap_err());
+        assert!(res.contains("Invalid template test.tpl"), "{}", res);
+        assert!(res.contains("test.tpl\":2:"), "{}", res);
+
+        let tpl = Template::new(
+            "test.tpl",
+            "{{add this.name 1}}",
+            DataType::JSON,
+            Vec::new(),
+            Engine::Handlebars,
+            BTreeMap::new(),
+            None,
+        );
+        let res = format!("{:#}", tpl.render(&gen_data("{\"hosts\": [")).unwrap_err());
+        assert!(res.contains("Unable to parse JSON data for template test.tpl"), "{}", res);
+        assert!(res.contains("line 1"), "{}", res);
+
+        let res = format!("{:#}", tpl.render(&gen_data("{\"name\": \"web\"}")).unwrap_err());
+        assert!(res.contains("Error rendering \"test.tpl\" line 1"), "{}", res);
+
+        let tpl = Template::new(
+            "test.tpl",
+            "{{ hosts | first | get(key=\"nope\") }}",
+            DataType::YAML,
+            Vec::new(),
+            Engine::Tera,
+            BTreeMap::new(),
+            None,
+        );
+        let res = format!("{:#}", tpl.render(&gen_data(gen_yml_data())).unwrap_err());
+        assert!(res.contains("test.tpl"), "{}", res);
+        assert!(res.contains("nope"), "{}", res);
+    }
 }
@@ -0,0 +1,470 @@
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{sha256, Hook, Registry};
+use crate::interactive;
+use crate::path;
+use crate::private_path::PrivatePath;
+use crate::sandbox::{lookup_user, Sandbox, SandboxConf, User};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use rustls::internal::pemfile;
+use rustls::{PrivateKey, SignatureScheme};
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use eyre::{eyre, Result, WrapErr};
+use shellexpand::tilde;
+use std::cell::RefCell;
+use std::fs;
+use std::io::BufReader;
+#[cfg(test)]
+use std::any::Any;
+
+/// What the key signs to show it is that of the certificate
+const PROOF: &[u8] = b"app_config checks the key matches the certificate";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// TlsConf will store the user's input from the configuration file
+// and then let us instantiate a Tls struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "tls")]
+pub struct TlsConf {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub chain: Option<String>,
+    pub cert_file: String,
+    pub key_file: String,
+    pub chain_file: Option<String>,
+    pub source_type: Option<DataType>,
+    pub owner: Option<String>,
+    pub allow_earlier_expiry: Option<bool>,
+    pub reload: Option<String>,
+    #[serde(flatten)]
+    pub sandbox: SandboxConf,
+}
+
+impl TlsConf {
+    /// Will panic unless chain and chain_file are given together, or if the
+    /// owner does not exist
+    pub fn convert(&self) -> Tls {
+        let chain = match (&self.chain, &self.chain_file) {
+            (Some(chain), Some(file)) => Some((chain.clone(), file.clone())),
+            (None, None) => None,
+            _ => {
+                eprintln!("Error, the tls hook needs both chain and chain_file, or neither");
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let owner = self.owner.as_ref().map(|name| {
+            let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+            match lookup_user(&passwd, name) {
+                Some(user) => user,
+                None => {
+                    eprintln!("Error, no user {} to own the tls files", name);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            }
+        });
+
+        let mut tls = Tls::new(
+            (self.cert.as_deref().unwrap_or(".cert"), &self.cert_file),
+            (self.key.as_deref().unwrap_or(".key"), &self.key_file),
+            self.source_type.clone().unwrap_or(DataType::YAML),
+        );
+        tls.chain = chain;
+        tls.owner = owner;
+        tls.allow_earlier_expiry = self.allow_earlier_expiry.unwrap_or(false);
+        tls.reload = self.reload.clone();
+        tls.sandbox = self.sandbox.convert();
+        tls
+    }
+}
+
+/// Make tls hooks from [hooks.tls]
+pub fn register(registry: &mut Registry) {
+    registry.register("tls", |section| {
+        let conf: TlsConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Tls Hook deploys a certificate, its key and optionally the chain of
+/// certificates issuing it.  Each is a PEM, or the base64 of one, at a jq
+/// style path of the data parsed as <source_type>, and is written to its
+/// file, given along with the path, e.g. `(".tls.cert", "/etc/nginx/cert.pem")`.
+/// Nothing is written unless the key is a PKCS#8 or RSA key matching the
+/// certificate, and the certificate has not expired.  Nor, unless
+/// <allow_earlier_expiry>, if it expires before the certificate deployed.
+/// The files only get mode 0600, owned by <owner> if given, from the moment
+/// they are created.  A symlink in place of one fails the run.  When any of
+/// them changed, <reload> is run by bash in <sandbox>, once every file is
+/// written, e.g. to reload the services using them.
+#[derive(Debug, PartialEq)]
+pub struct Tls {
+    cert: (String, String),
+    key: (String, String),
+    chain: Option<(String, String)>,
+    source_type: DataType,
+    owner: Option<User>,
+    allow_earlier_expiry: bool,
+    reload: Option<String>,
+    sandbox: Sandbox,
+    output: RefCell<Option<String>>,
+}
+
+/// The PEMs taken from the data, checked
+struct Material {
+    cert: String,
+    key: String,
+    chain: Option<String>,
+    not_after: DateTime<Utc>,
+}
+
+impl Tls {
+    /// Create a new Tls struct, from the paths in the data and the files of
+    /// the certificate and key
+    pub fn new(cert: (&str, &str), key: (&str, &str), source_type: DataType) -> Tls {
+        Tls {
+            cert: (cert.0.to_string(), cert.1.to_string()),
+            key: (key.0.to_string(), key.1.to_string()),
+            chain: None,
+            source_type,
+            owner: None,
+            allow_earlier_expiry: false,
+            reload: None,
+            sandbox: Sandbox::default(),
+            output: RefCell::new(None),
+        }
+    }
+
+    /// The certificate, key and chain in <data>, once checked
+    fn material(&self, data: &ConfigData) -> Result<Material> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+        let cert = pem_at(&value, &self.cert.0)?;
+        let key = pem_at(&value, &self.key.0)?;
+        let chain = match &self.chain {
+            Some((chain, _)) => Some(pem_at(&value, chain)?),
+            None => None,
+        };
+
+        let cert_der = certs(&cert).wrap_err("Invalid certificate")?.remove(0).0;
+        check_key(&cert_der, &key)?;
+        if let Some(chain) = &chain {
+            certs(chain).wrap_err("Invalid chain")?;
+        }
+        let not_after = not_after(&cert_der)?;
+        if not_after <= Utc::now() {
+            return Err(eyre!("The certificate expired on {}", not_after));
+        }
+        Ok(Material {
+            cert,
+            key,
+            chain,
+            not_after,
+        })
+    }
+
+    /// The files and what each is to hold
+    fn files_of(&self, material: &Material) -> Vec<(String, String)> {
+        let mut files = vec![
+            (tilde(&self.cert.1).to_string(), material.cert.clone()),
+            (tilde(&self.key.1).to_string(), material.key.clone()),
+        ];
+        if let (Some((_, file)), Some(chain)) = (&self.chain, &material.chain) {
+            files.push((tilde(file).to_string(), chain.clone()));
+        }
+        files
+    }
+}
+
+impl Hook for Tls {
+    fn kind(&self) -> &'static str {
+        "tls"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Check the certificate and key, write the files that changed, and
+    /// reload if any did
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let material = self.material(data)?;
+        *self.output.borrow_mut() = Some(sha256(&material.cert));
+
+        // A certificate renewed elsewhere is not replaced by an older one
+        let deployed = tilde(&self.cert.1).to_string();
+        if let Ok(pem) = fs::read_to_string(&deployed) {
+            let deployed = certs(&pem).and_then(|certs| not_after(&certs[0].0));
+            if let Ok(deployed) = deployed {
+                if material.not_after < deployed && !self.allow_earlier_expiry {
+                    return Err(eyre!(
+                        "The certificate expires on {}, before the one deployed on {}",
+                        material.not_after,
+                        deployed
+                    ));
+                }
+            }
+        }
+
+        // Every file that changed is staged, created only readable by its
+        // owner, before any replaces the one deployed, so that a failed write
+        // leaves the key and certificate deployed before as they were
+        let mut staged = Vec::new();
+        let res = self.files_of(&material).into_iter().try_for_each(|(file, contents)| {
+            let private = PrivatePath::new(&file, self.owner.as_ref())?;
+            let unchanged = private.read()?.as_ref() == Some(&contents);
+            // The key is not shown, only the file it goes to
+            let confirmed = match file == tilde(&self.key.1) {
+                true => unchanged || interactive::confirm(&format!("Write the key to {}", file))?,
+                false => interactive::confirm_write(&file, contents.as_bytes())?,
+            };
+            match !unchanged && confirmed {
+                true => {
+                    private.stage(&contents)?;
+                    staged.push(private);
+                }
+                false => private.restrict()?,
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            staged.iter().for_each(PrivatePath::discard);
+            return Err(e);
+        }
+        for private in &staged {
+            private.commit()?;
+        }
+        let changed = !staged.is_empty();
+        if changed {
+            info!("Deployed the certificate expiring on {}", material.not_after);
+        }
+
+        match (&self.reload, changed) {
+            (Some(command), true) => self.sandbox.run_checked(command, data.run_env()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The certificate, key and chain files
+    fn files(&self, _data: &ConfigData) -> Result<Vec<String>> {
+        let mut files = vec![tilde(&self.cert.1).to_string(), tilde(&self.key.1).to_string()];
+        files.extend(self.chain.iter().map(|(_, file)| tilde(file).to_string()));
+        Ok(files)
+    }
+
+    fn output_sha256(&self) -> Option<String> {
+        self.output.borrow().clone()
+    }
+}
+
+/// The PEM at <path> in <value>, decoded if it is base64
+fn pem_at(value: &Value, path: &str) -> Result<String> {
+    let mut selected = path::select(value, path)?;
+    let text = match (selected.len(), selected.pop()) {
+        (1, Some(Value::String(text))) => text,
+        _ => return Err(eyre!("{} does not select a PEM", path)),
+    };
+    if text.trim_start().starts_with("-----BEGIN") {
+        return Ok(text);
+    }
+    let compact: String = text.split_whitespace().collect();
+    let decoded = base64::decode(compact).map_err(|_| eyre!("{} is neither PEM nor base64", path))?;
+    String::from_utf8(decoded).map_err(|_| eyre!("{} is not the base64 of a PEM", path))
+}
+
+/// The certificates of <pem>, at least one
+fn certs(pem: &str) -> Result<Vec<rustls::Certificate>> {
+    match pemfile::certs(&mut BufReader::new(pem.as_bytes())) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(eyre!("No certificate in the PEM")),
+    }
+}
+
+/// Check the key in <pem> is that of the certificate <cert_der>, by having
+/// it sign and the certificate verify the signature
+fn check_key(cert_der: &[u8], pem: &str) -> Result<()> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(pem.as_bytes()))
+        .map_err(|_| eyre!("Invalid key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(pem.as_bytes()))
+            .map_err(|_| eyre!("Invalid key"))?;
+    }
+    let key: PrivateKey = keys.pop().ok_or_else(|| eyre!("No PKCS#8 or RSA key in the PEM"))?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| eyre!("The key is not of a supported type"))?;
+
+    let schemes = [
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256,
+    ];
+    let signer = key.choose_scheme(&schemes).ok_or_else(|| eyre!("The key can not sign"))?;
+    let signature = signer.sign(PROOF).map_err(|e| eyre!("The key failed to sign: {}", e))?;
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+    let cert = webpki::EndEntityCert::from(cert_der)
+        .map_err(|e| eyre!("Invalid certificate: {}", e))?;
+    cert.verify_signature(algorithm, PROOF, &signature)
+        .map_err(|_| eyre!("The key does not match the certificate"))
+}
+
+/// When the certificate <der> expires: the notAfter of its validity, the
+/// fifth field of the tbsCertificate, or the fourth without a version
+fn not_after(der: &[u8]) -> Result<DateTime<Utc>> {
+    let invalid = || eyre!("Invalid certificate, no validity found");
+    let (_, cert, _) = der_item(der).ok_or_else(invalid)?;
+    let (_, mut tbs, _) = der_item(cert).ok_or_else(invalid)?;
+    // The version is explicitly tagged [0]
+    let mut fields = Vec::new();
+    while !tbs.is_empty() && fields.len() < 5 {
+        let (tag, content, rest) = der_item(tbs).ok_or_else(invalid)?;
+        fields.push((tag, content));
+        tbs = rest;
+    }
+    let validity = match fields.first() {
+        Some((0xa0, _)) => fields.get(4),
+        _ => fields.get(3),
+    };
+    let (_, validity) = validity.ok_or_else(invalid)?;
+    let (_, _, rest) = der_item(validity).ok_or_else(invalid)?;
+    let (tag, time, _) = der_item(rest).ok_or_else(invalid)?;
+    let time = std::str::from_utf8(time).map_err(|_| invalid())?;
+    // UTCTime has two digits of the year, 1950 to 2049
+    let time = match (tag, time.get(..2).and_then(|yy| yy.parse::<u32>().ok())) {
+        (0x17, Some(yy)) if yy >= 50 => format!("19{}", time),
+        (0x17, Some(_)) => format!("20{}", time),
+        (0x18, _) => time.to_string(),
+        _ => return Err(invalid()),
+    };
+    let time = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").map_err(|_| invalid())?;
+    Ok(Utc.from_utc_datetime(&time))
+}
+
+/// The tag, content and what follows of the DER item <der> starts with
+fn der_item(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *der.first()?;
+    let first = *der.get(1)? as usize;
+    let (len, start) = match first {
+        0..=0x7f => (first, 2),
+        0x81..=0x84 => {
+            let n = first - 0x80;
+            let len = der.get(2..2 + n)?.iter().fold(0, |len, b| len << 8 | *b as usize);
+            (len, 2 + n)
+        }
+        _ => return None,
+    };
+    let end = start.checked_add(len)?;
+    Some((tag, der.get(start..end)?, der.get(end..)?))
+}
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> String {
+        fs::read_to_string(format!("./tests/tls/{}", name)).unwrap()
+    }
+
+    /// Data holding the certificate <cert> and key <key> fixtures
+    fn data(cert: &str, key: &str) -> ConfigData {
+        let mut map = serde_yaml::Mapping::new();
+        map.insert("cert".into(), fixture(cert).into());
+        map.insert("key".into(), base64::encode(fixture(key)).into());
+        ConfigData::new(serde_yaml::to_string(&map).unwrap(), "mock", None)
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = r#"
+        [hooks.tls]
+         cert = ".tls.cert"
+         key = ".tls.key"
+         cert_file = "/etc/nginx/cert.pem"
+         key_file = "/etc/nginx/key.pem"
+         reload = "systemctl reload nginx"
+        "#;
+        let mut exp = Tls::new(
+            (".tls.cert", "/etc/nginx/cert.pem"),
+            (".tls.key", "/etc/nginx/key.pem"),
+            DataType::YAML,
+        );
+        exp.reload = Some("systemctl reload nginx".to_string());
+
+        let maps: toml::Value = toml::from_str(config).unwrap();
+        let conf: TlsConf = maps["hooks"]["tls"].clone().try_into().unwrap();
+        assert_eq!(conf.convert(), exp);
+    }
+
+    #[test]
+    fn test_check() {
+        let cert = |name| certs(&fixture(name)).unwrap().remove(0).0;
+        assert!(check_key(&cert("cert.pem"), &fixture("key.pem")).is_ok());
+        assert!(check_key(&cert("ec_cert.pem"), &fixture("ec_key.pem")).is_ok());
+        let res = check_key(&cert("cert.pem"), &fixture("other_key.pem"));
+        assert_eq!(format!("{}", res.unwrap_err()), "The key does not match the certificate");
+        assert!(check_key(&cert("cert.pem"), &fixture("ec_key.pem")).is_err());
+
+        let exp = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(not_after(&cert("expired.pem")).unwrap(), exp);
+        assert!(not_after(&cert("older.pem")).unwrap() < not_after(&cert("cert.pem")).unwrap());
+        assert!(not_after(b"\x30\x03\x02\x01\x01").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("app_config_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let mut hook =
+            Tls::new((".cert", &file("cert.pem")), (".key", &file("key.pem")), DataType::YAML);
+        hook.reload = Some(format!("echo >> {}", file("reloads")));
+
+        hook.run(&data("older.pem", "key.pem")).unwrap();
+        assert_eq!(fs::read_to_string(file("cert.pem")).unwrap(), fixture("older.pem"));
+        assert_eq!(fs::read_to_string(file("key.pem")).unwrap(), fixture("key.pem"));
+        for name in &["cert.pem", "key.pem"] {
+            assert_eq!(fs::metadata(file(name)).unwrap().mode() & 0o777, 0o600);
+        }
+        hook.run(&data("older.pem", "key.pem")).unwrap();
+        hook.run(&data("cert.pem", "key.pem")).unwrap();
+        assert_eq!(fs::read_to_string(file("reloads")).unwrap(), "\n\n");
+
+        // Nothing is written for a mismatched key, an expired certificate,
+        // or one expiring before the one deployed
+        for (cert, key) in &[
+            ("cert.pem", "other_key.pem"),
+            ("expired.pem", "key.pem"),
+            ("older.pem", "key.pem"),
+        ] {
+            assert!(hook.run(&data(cert, key)).is_err(), "{} {}", cert, key);
+        }
+        assert_eq!(fs::read_to_string(file("cert.pem")).unwrap(), fixture("cert.pem"));
+        assert_eq!(fs::read_to_string(file("reloads")).unwrap(), "\n\n");
+
+        // A symlink planted as the temp file is removed, not written through
+        let target = file("stolen");
+        fs::write(&target, "").unwrap();
+        std::os::unix::fs::symlink(&target, file("cert.pem.app_config.tmp")).unwrap();
+        hook.allow_earlier_expiry = true;
+        hook.run(&data("older.pem", "key.pem")).unwrap();
+        assert_eq!(fs::read_to_string(file("cert.pem")).unwrap(), fixture("older.pem"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "");
+        assert!(!Path::new(&file("cert.pem.app_config.tmp")).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use crate::data::ConfigData;
+use crate::hooks::template::DataType;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use crate::redact::redacted;
+use crate::path;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result, WrapErr};
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::any::Any;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// VaultConf will store the user's input from the configuration file
+// and then let us instantiate a Vault struct
+#[derive(Deserialize)]
+#[serde(rename = "vault")]
+pub struct VaultConf {
+    pub address: Option<String>,
+    pub token: Option<String>,
+    pub namespace: Option<String>,
+    pub mount: Option<String>,
+    pub path: String,
+    pub kv_version: Option<u8>,
+    pub source_type: Option<DataType>,
+    pub fields: Option<BTreeMap<String, String>>,
+}
+
+impl std::fmt::Debug for VaultConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("VaultConf")
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .field("namespace", &self.namespace)
+            .field("mount", &self.mount)
+            .field("path", &self.path)
+            .field("kv_version", &self.kv_version)
+            .field("source_type", &self.source_type)
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+impl VaultConf {
+    /// Will panic if kv_version is neither 1 nor 2
+    pub fn convert(&self) -> Vault {
+        let kv_version = self.kv_version.unwrap_or(2);
+        if kv_version != 1 && kv_version != 2 {
+            eprintln!("Error, the vault hook's kv_version must be 1 or 2");
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        let mut vault = Vault::new(
+            self.mount.as_deref().unwrap_or("secret"),
+            &self.path,
+            self.source_type.clone().unwrap_or(DataType::YAML),
+            self.fields.clone().unwrap_or_default(),
+        );
+        vault.address = self.address.clone();
+        vault.token = self.token.clone();
+        vault.namespace = self.namespace.clone();
+        vault.kv_version = kv_version;
+        vault
+    }
+}
+
+/// Make vault hooks from [hooks.vault]
+pub fn register(registry: &mut Registry) {
+    registry.register("vault", |section| {
+        let conf: VaultConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Vault Hook writes values taken from the data into a secret of a
+/// Vault KV engine mounted at <mount>, so that what arrived from one system
+/// of record reaches the consumers that read it from Vault.
+/// Each of <fields> names a key of the secret and the jq style path of its
+/// value in the data, parsed as <source_type>, e.g. `password = ".db.password"`.
+/// Without fields, the whole data is written, it must then be a map.
+/// If <address> or <token> are omitted, VAULT_ADDR and VAULT_TOKEN are used,
+/// and <namespace> defaults to VAULT_NAMESPACE.
+#[derive(PartialEq)]
+pub struct Vault {
+    address: Option<String>,
+    token: Option<String>,
+    namespace: Option<String>,
+    mount: String,
+    path: String,
+    kv_version: u8,
+    source_type: DataType,
+    fields: BTreeMap<String, String>,
+}
+
+impl std::fmt::Debug for Vault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Vault")
+            .field("address", &self.address)
+            .field("token", &self.token.as_ref().map(redacted))
+            .field("namespace", &self.namespace)
+            .field("mount", &self.mount)
+            .field("path", &self.path)
+            .field("kv_version", &self.kv_version)
+            .field("source_type", &self.source_type)
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+impl Vault {
+    /// Create a new Vault struct, writing to a KV v2 engine
+    pub fn new(
+        mount: &str,
+        path: &str,
+        source_type: DataType,
+        fields: BTreeMap<String, String>,
+    ) -> Vault {
+        Vault {
+            address: None,
+            token: None,
+            namespace: None,
+            mount: mount.trim_matches('/').to_string(),
+            path: path.trim_matches('/').to_string(),
+            kv_version: 2,
+            source_type,
+            fields,
+        }
+    }
+
+    /// Base url of the Vault HTTP API
+    fn address(&self) -> String {
+        let addr = match &self.address {
+            Some(addr) => addr.clone(),
+            None => std::env::var("VAULT_ADDR")
+                .unwrap_or_else(|_| "https://127.0.0.1:8200".to_string()),
+        };
+        addr.trim_end_matches('/').to_string()
+    }
+
+    /// Url of the secret, KV v2 keeps the data of secrets under data/
+    fn url(&self) -> String {
+        match self.kv_version {
+            1 => format!("{}/v1/{}/{}", self.address(), self.mount, self.path),
+            _ => format!("{}/v1/{}/data/{}", self.address(), self.mount, self.path),
+        }
+    }
+
+    /// The request body writing the secret taken from <data>
+    fn payload(&self, data: &ConfigData) -> Result<serde_json::Value> {
+        let value = data
+            .parsed(&self.source_type)
+            .wrap_err_with(|| format!("Unable to parse {:?} data", self.source_type))?;
+
+        let secret = if self.fields.is_empty() {
+            match serde_json::to_value(&*value)? {
+                secret @ serde_json::Value::Object(_) => secret,
+                _ => return Err(eyre!("Only a map can be written to Vault, give fields")),
+            }
+        } else {
+            let mut secret = serde_json::Map::new();
+            for (key, field) in &self.fields {
+                let mut values = path::select(&value, field)?;
+                if values.len() != 1 {
+                    return Err(eyre!("Field {}: {} selects {} values", key, field, values.len()));
+                }
+                secret.insert(key.clone(), serde_json::to_value(values.remove(0))?);
+            }
+            serde_json::Value::Object(secret)
+        };
+
+        match self.kv_version {
+            1 => Ok(secret),
+            _ => Ok(serde_json::json!({ "data": secret })),
+        }
+    }
+}
+
+impl Hook for Vault {
+    fn kind(&self) -> &'static str {
+        "vault"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Write the secret via the Vault API
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let body = self.payload(data)?;
+        let url = self.url();
+
+        let mut req = http::post(&url);
+        let token = self.token.clone().or_else(|| std::env::var("VAULT_TOKEN").ok());
+        if let Some(token) = token {
+            req.set("X-Vault-Token", &token);
+        }
+        let namespace = self.namespace.clone().or_else(|| std::env::var("VAULT_NAMESPACE").ok());
+        if let Some(namespace) = namespace {
+            req.set("X-Vault-Namespace", &namespace);
+        }
+
+        let resp = req.send_json(body);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach Vault at {}: {}", url, e));
+        }
+        if !resp.ok() {
+            return Err(eyre!(
+                "Vault failed to write {}/{}: {}",
+                self.mount,
+                self.path,
+                resp.status_line()
+            ));
+        }
+        Ok(())
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.vault]
+         address = "https://vault.local:8200/"
+         path = "myApp/db"
+         fields = { password = ".db.password", port = ".db.port" }
+        "#
+        .to_string()
+    }
+
+    fn gen_fields() -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        fields.insert("password".to_string(), ".db.password".to_string());
+        fields.insert("port".to_string(), ".db.port".to_string());
+        fields
+    }
+
+    #[test]
+    fn parse_config() {
+        let mut exp = Vault::new("secret", "myApp/db", DataType::YAML, gen_fields());
+        exp.address = Some("https://vault.local:8200/".to_string());
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: VaultConf = maps["hooks"]["vault"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+        assert_eq!(res.url(), "https://vault.local:8200/v1/secret/data/myApp/db");
+    }
+
+    #[test]
+    fn test_payload() {
+        let data = ConfigData::new("db: {password: s3cr3t, port: 5432}", "mock", None);
+
+        let mut vault = Vault::new("secret", "myApp/db", DataType::YAML, gen_fields());
+        let exp = serde_json::json!({"data": {"password": "s3cr3t", "port": 5432}});
+        assert_eq!(vault.payload(&data).unwrap(), exp);
+
+        vault.kv_version = 1;
+        vault.fields = BTreeMap::new();
+        let exp = serde_json::json!({"db": {"password": "s3cr3t", "port": 5432}});
+        assert_eq!(vault.payload(&data).unwrap(), exp);
+
+        vault.fields.insert("host".to_string(), ".db.host".to_string());
+        assert!(vault.payload(&data).is_err());
+    }
+}
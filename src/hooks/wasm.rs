@@ -0,0 +1,348 @@
+use crate::config::parse_duration;
+use crate::data::ConfigData;
+use crate::hooks::{Hook, Registry};
+use crate::http;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+use wasmtime::{Caller, Config, Engine, InterruptHandle, Linker, Memory, Module, Store, Trap};
+use wasmtime_wasi::{Wasi, WasiCtxBuilder};
+#[cfg(test)]
+use std::any::Any;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// How long the module may run, unless configured
+const DEFAULT_TIMEOUT: &str = "30s";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// WasmConf will store the user's input from the configuration file
+// and then let us instantiate a Wasm struct
+#[derive(Debug, Deserialize)]
+#[serde(rename = "wasm")]
+pub struct WasmConf {
+    pub module: String,
+    pub dirs: Option<Vec<String>>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub timeout: Option<String>,
+}
+
+impl WasmConf {
+    /// Will panic if the timeout is invalid.
+    pub fn convert(&self) -> Wasm {
+        let timeout = self.timeout.as_deref().unwrap_or(DEFAULT_TIMEOUT);
+        Wasm::new(
+            &self.module,
+            self.dirs.clone().unwrap_or_default(),
+            self.allowed_hosts.clone().unwrap_or_default(),
+            parse_duration("wasm timeout", timeout),
+        )
+    }
+}
+
+/// Make wasm hooks from [hooks.wasm]
+pub fn register(registry: &mut Registry) {
+    registry.register("wasm", |section| {
+        let conf: WasmConf = section.try_into()?;
+        Ok(Box::new(conf.convert()))
+    });
+}
+
+
+// // // // // // // // // // // Hook  // // // // // // // // // // //
+
+/// The Wasm Hook runs a WebAssembly module in a wasmtime sandbox.  The module
+/// can only see the directories listed in <dirs>, and can only reach the
+/// network through the app_config.http_post import, limited to
+/// <allowed_hosts>.  A module still running after <timeout> is interrupted,
+/// failing the hook.
+///
+/// The module must export:
+///   memory
+///   alloc(len: i32) -> i32         buffer for the data
+///   run(ptr: i32, len: i32) -> i32 0 on success
+///
+/// and may import:
+///   app_config.http_post(url_ptr, url_len, body_ptr, body_len) -> i32
+///     HTTP status, -1 if the host is not allowed, -2 if unreachable.
+///     Redirects are not followed, the module gets their status.
+#[derive(Debug, PartialEq)]
+pub struct Wasm {
+    module: String,
+    dirs: Vec<String>,
+    allowed_hosts: Vec<String>,
+    timeout: Duration,
+}
+
+impl Wasm {
+    /// Create a new Wasm struct
+    pub fn new(
+        module: &str,
+        dirs: Vec<String>,
+        allowed_hosts: Vec<String>,
+        timeout: Duration,
+    ) -> Wasm {
+        Wasm {
+            module: module.to_string(),
+            dirs,
+            allowed_hosts,
+            timeout,
+        }
+    }
+
+    /// Link WASI, with only <dirs> preopened, and our network import
+    fn linker(&self, store: &Store) -> Result<Linker> {
+        let mut linker = Linker::new(store);
+
+        let mut ctx = WasiCtxBuilder::new();
+        ctx.inherit_stdout().inherit_stderr();
+        for dir in &self.dirs {
+            let f = std::fs::File::open(dir).map_err(|e| eyre!("Unable to open {}: {}", dir, e))?;
+            ctx.preopened_dir(f, dir);
+        }
+        let ctx = ctx.build().map_err(|e| eyre!("Unable to build WASI context: {}", e))?;
+        Wasi::new(store, ctx)
+            .add_to_linker(&mut linker)
+            .map_err(|e| eyre!("Unable to link WASI: {}", e))?;
+
+        let allowed_hosts = self.allowed_hosts.clone();
+        linker
+            .func(
+                "app_config",
+                "http_post",
+                move |caller: Caller<'_>, url_ptr: i32, url_len: i32, body_ptr: i32, body_len: i32| {
+                    let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                        Some(m) => m,
+                        None => return Err(Trap::new("module does not export memory")),
+                    };
+                    let url = read_string(&memory, url_ptr, url_len).map_err(Trap::new)?;
+                    let body = read_string(&memory, body_ptr, body_len).map_err(Trap::new)?;
+
+                    if !host_allowed(&allowed_hosts, &url) {
+                        return Ok(-1);
+                    }
+                    // A redirect could lead to any host
+                    let resp = http::post(&url).redirects(0).send_string(&body);
+                    if resp.synthetic_error().is_some() {
+                        return Ok(-2);
+                    }
+                    Ok(resp.status() as i32)
+                },
+            )
+            .map_err(|e| eyre!("Unable to link app_config.http_post: {}", e))?;
+
+        Ok(linker)
+    }
+
+    /// Instantiate <module> and call its run with <data>
+    fn call(&self, linker: &Linker, module: &Module, data: &ConfigData) -> Result<()> {
+        let instance = linker
+            .instantiate(module)
+            .map_err(|e| eyre!("Unable to instantiate {}: {}", self.module, e))?;
+
+        let memory = instance
+            .get_memory("memory")
+            .ok_or_else(|| eyre!("{} does not export memory", self.module))?;
+        let alloc = instance
+            .get_func("alloc")
+            .ok_or_else(|| eyre!("{} does not export alloc", self.module))?
+            .get1::<i32, i32>()
+            .map_err(|e| eyre!("Bad alloc export in {}: {}", self.module, e))?;
+        let run = instance
+            .get_func("run")
+            .ok_or_else(|| eyre!("{} does not export run", self.module))?
+            .get2::<i32, i32, i32>()
+            .map_err(|e| eyre!("Bad run export in {}: {}", self.module, e))?;
+
+        let len = data.raw().len() as i32;
+        let ptr = alloc(len).map_err(|e| eyre!("alloc trapped: {}", e))?;
+        write_bytes(&memory, ptr, data.raw()).map_err(|e| eyre!(e))?;
+
+        let code = run(ptr, len).map_err(|e| eyre!("{} trapped: {}", self.module, e))?;
+        if code != 0 {
+            return Err(eyre!("{} failed with code {}", self.module, code));
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Wasm {
+    fn kind(&self) -> &'static str {
+        "wasm"
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Instantiate the module, copy the data into it and call run, all
+    /// within the timeout
+    fn run(&self, data: &ConfigData) -> Result<()> {
+        let mut config = Config::new();
+        config.interruptable(true);
+        let engine = Engine::new(&config);
+        let store = Store::new(&engine);
+        let linker = self.linker(&store)?;
+
+        let module = Module::from_file(&engine, &self.module)
+            .map_err(|e| eyre!("Unable to load {}: {}", self.module, e))?;
+        let interrupt = store
+            .interrupt_handle()
+            .map_err(|e| eyre!("Unable to interrupt {}: {}", self.module, e))?;
+        let watchdog = Watchdog::start(interrupt, self.timeout);
+        self.call(&linker, &module, data).map_err(|e| {
+            if watchdog.fired() {
+                return eyre!("{} ran out of time after {:?}", self.module, self.timeout);
+            }
+            e
+        })
+    }
+}
+
+/// Watchdog:
+/// Interrupts a module once <timeout> passed, unless dropped before then.
+/// The module traps at its next function call or loop iteration.
+struct Watchdog {
+    _stop: mpsc::Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn start(interrupt: InterruptHandle, timeout: Duration) -> Watchdog {
+        let (stop, stopped) = mpsc::channel();
+        let fired = Arc::new(AtomicBool::new(false));
+        let fire = fired.clone();
+        std::thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout) {
+                fire.store(true, Ordering::SeqCst);
+                interrupt.interrupt();
+            }
+        });
+        Watchdog { _stop: stop, fired }
+    }
+
+    /// Did the module run out of time?
+    fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Is the host of <url> in <allowed>?  The url is parsed as it is for the
+/// request, so that the host checked is the one the request goes to.
+fn host_allowed(allowed: &[String], url: &str) -> bool {
+    let url = match url::Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return false,
+    };
+    match url.host_str() {
+        Some(host) => allowed.iter().any(|h| h.eq_ignore_ascii_case(host)),
+        None => false,
+    }
+}
+
+/// The range of <len> bytes at <ptr> in the module's memory, None if either
+/// is negative, as the module may pass anything
+fn range(ptr: i32, len: i32) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    Some(start..end)
+}
+
+/// Copy <len> bytes at <ptr> out of the module's memory
+fn read_string(memory: &Memory, ptr: i32, len: i32) -> std::result::Result<String, String> {
+    let range = range(ptr, len).ok_or_else(|| "pointer out of bounds".to_string())?;
+    // Safe as long as nothing else touches the memory while we copy
+    let bytes = unsafe { memory.data_unchecked().get(range).map(|b| b.to_vec()) };
+    let bytes = bytes.ok_or_else(|| "pointer out of bounds".to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+/// Copy <bytes> into the module's memory at <ptr>
+fn write_bytes(memory: &Memory, ptr: i32, bytes: &[u8]) -> std::result::Result<(), String> {
+    let out_of_bounds = || "alloc returned a pointer out of bounds".to_string();
+    let range = range(ptr, bytes.len() as i32).ok_or_else(out_of_bounds)?;
+    // Safe as long as nothing else touches the memory while we copy
+    let dest = unsafe { memory.data_unchecked_mut().get_mut(range) };
+    match dest {
+        Some(dest) => {
+            dest.copy_from_slice(bytes);
+            Ok(())
+        }
+        None => Err(out_of_bounds()),
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [hooks.wasm]
+         module = "./hooks/notify.wasm"
+         dirs = ["/etc/myApp"]
+         allowed_hosts = ["hooks.example.com"]
+         timeout = "5s"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Wasm::new(
+            &"./hooks/notify.wasm",
+            vec!["/etc/myApp".to_string()],
+            vec!["hooks.example.com".to_string()],
+            Duration::from_secs(5),
+        );
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: WasmConf = maps["hooks"]["wasm"].clone().try_into().unwrap();
+        let res = conf.convert();
+
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut config = Config::new();
+        config.interruptable(true);
+        let store = Store::new(&Engine::new(&config));
+
+        let watchdog = Watchdog::start(store.interrupt_handle().unwrap(), Duration::from_secs(60));
+        assert!(!watchdog.fired());
+
+        let watchdog = Watchdog::start(store.interrupt_handle().unwrap(), Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(watchdog.fired());
+    }
+
+    #[test]
+    fn test_host_allowed() {
+        let allowed = vec!["hooks.example.com".to_string()];
+
+        assert!(host_allowed(&allowed, "https://hooks.example.com/x?y=1"));
+        assert!(host_allowed(&allowed, "http://user@HOOKS.example.com:8080"));
+        assert!(!host_allowed(&allowed, "https://hooks.example.com.evil.io/"));
+        assert!(!host_allowed(&allowed, "https://evil.io/?h=hooks.example.com"));
+        assert!(!host_allowed(&allowed, "hooks.example.com"));
+        assert!(!host_allowed(&[], "https://hooks.example.com/"));
+        // The request would go to evil.io, a backslash ends the host
+        assert!(!host_allowed(&allowed, "https://evil.io\\@hooks.example.com/"));
+        assert!(!host_allowed(&allowed, "file://hooks.example.com/etc/passwd"));
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(range(8, 4), Some(8..12));
+        assert_eq!(range(-1, 4), None);
+        assert_eq!(range(8, -4), None);
+        assert_eq!(range(i32::MAX, i32::MAX), Some(i32::MAX as usize..2 * i32::MAX as usize));
+    }
+}
@@ -0,0 +1,289 @@
+use eyre::{eyre, Result, WrapErr};
+use serde_derive::Deserialize;
+
+use hyper::Uri;
+use std::cell::RefCell;
+use std::io::BufReader;
+use std::sync::Arc;
+
+// Calls to AWS go through rusoto, over connections of its own
+#[cfg(feature = "aws")]
+mod aws;
+#[cfg(feature = "aws")]
+pub use aws::aws_client;
+
+/// HttpConf:
+/// How outgoing HTTP requests, to AWS and to the services hooks talk to, are
+/// made.  Stored under [settings.http] in the config file.
+/// - proxy: HTTP proxy tunneled through with CONNECT, HTTPS_PROXY by default
+/// - no_proxy: hosts, and domains with a leading '.', reached directly.
+///   NO_PROXY by default, "*" matches every host.
+/// - ca_bundle: PEM file of CAs trusted on top of the system ones, e.g. for a
+///   TLS inspecting proxy
+/// - client_cert, client_key: PEM files of a TLS client certificate
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename = "http")]
+pub struct HttpConf {
+    pub proxy: Option<String>,
+    pub no_proxy: Option<Vec<String>>,
+    pub ca_bundle: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// HTTP settings of the pipeline running on this thread, ready to use
+#[derive(Clone, Default)]
+struct Http {
+    proxy: Option<String>,
+    no_proxy: Vec<String>,
+    rustls: Option<Arc<rustls::ClientConfig>>,
+    #[cfg(feature = "aws")]
+    native_tls: Option<native_tls::TlsConnector>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Http>> = RefCell::new(None);
+}
+
+/// Use <conf> for the HTTP requests made on this thread from now on.  Every
+/// pipeline runs on a thread of its own, so they can each have their own.
+/// Will panic if the proxy, or any of the certificates, are invalid.
+pub fn configure(conf: &HttpConf) {
+    match Http::new(conf) {
+        Ok(http) => CURRENT.with(|current| *current.borrow_mut() = Some(http)),
+        Err(e) => {
+            eprintln!("Error, invalid http settings: {:#}", e);
+            std::process::exit(exitcode::CONFIG);
+        }
+    }
+}
+
+/// The HTTP settings of this thread, from the environment if none were set
+fn current() -> Http {
+    CURRENT.with(|current| {
+        current
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                Http::new(&HttpConf::default()).unwrap_or_else(|e| {
+                    warning!("ignoring the proxy environment: {:#}", e);
+                    Http::default()
+                })
+            })
+            .clone()
+    })
+}
+
+impl Http {
+    fn new(conf: &HttpConf) -> Result<Http> {
+        let proxy = conf.proxy.clone().or_else(|| env_var(&["HTTPS_PROXY", "https_proxy"]));
+        if let Some(proxy) = &proxy {
+            ureq::Proxy::new(proxy).map_err(|e| eyre!("Invalid proxy {}: {}", proxy, e))?;
+            proxy_uri(proxy)?;
+        }
+
+        let no_proxy = match &conf.no_proxy {
+            Some(no_proxy) => no_proxy.clone(),
+            None => env_var(&["NO_PROXY", "no_proxy"])
+                .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).collect())
+                .unwrap_or_default(),
+        };
+
+        // Without any, the default TLS configurations are used
+        let custom_tls = conf.ca_bundle.is_some() || conf.client_cert.is_some();
+
+        Ok(Http {
+            proxy,
+            no_proxy,
+            rustls: match custom_tls {
+                true => Some(Arc::new(rustls_config(conf)?)),
+                false => None,
+            },
+            #[cfg(feature = "aws")]
+            native_tls: match custom_tls {
+                true => Some(native_tls_connector(conf)?),
+                false => None,
+            },
+        })
+    }
+
+    /// The proxy to reach <host> through, if any
+    fn proxy_for(&self, host: &str) -> Option<&str> {
+        if bypass(&self.no_proxy, host) {
+            None
+        } else {
+            self.proxy.as_deref()
+        }
+    }
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Whether <host> is to be reached directly according to <no_proxy>
+fn bypass(no_proxy: &[String], host: &str) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*"
+            || host == entry
+            || (host.ends_with(entry) && host[..host.len() - entry.len()].ends_with('.'))
+    })
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).wrap_err_with(|| format!("Unable to read {}", path))
+}
+
+fn client_pems(conf: &HttpConf) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match (&conf.client_cert, &conf.client_key) {
+        (None, None) => Ok(None),
+        (Some(cert), Some(key)) => Ok(Some((read_pem(cert)?, read_pem(key)?))),
+        _ => Err(eyre!("client_cert and client_key go together")),
+    }
+}
+
+/// TLS configuration of requests made with ureq
+fn rustls_config(conf: &HttpConf) -> Result<rustls::ClientConfig> {
+    use rustls::internal::pemfile;
+
+    let mut config = rustls::ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if let Some(ca_bundle) = &conf.ca_bundle {
+        let pem = read_pem(ca_bundle)?;
+        config
+            .root_store
+            .add_pem_file(&mut BufReader::new(&pem[..]))
+            .map_err(|_| eyre!("{} is not a valid PEM file", ca_bundle))?;
+    }
+
+    if let Some((cert, key)) = client_pems(conf)? {
+        let certs = pemfile::certs(&mut BufReader::new(&cert[..]))
+            .map_err(|_| eyre!("Invalid client certificate"))?;
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(&key[..]))
+            .map_err(|_| eyre!("Invalid client key"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(&key[..]))
+                .map_err(|_| eyre!("Invalid client key"))?;
+        }
+        let key = keys.pop().ok_or_else(|| eyre!("No private key in the client key"))?;
+        config.set_single_client_cert(certs, key)?;
+    }
+    Ok(config)
+}
+
+/// TLS configuration of requests made to AWS
+#[cfg(feature = "aws")]
+fn native_tls_connector(conf: &HttpConf) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_bundle) = &conf.ca_bundle {
+        let pem = String::from_utf8(read_pem(ca_bundle)?)?;
+        // A bundle holds many certificates, native-tls reads one at a time
+        let marker = "-----BEGIN CERTIFICATE-----";
+        for cert in pem.split(marker).skip(1) {
+            let cert = native_tls::Certificate::from_pem(format!("{}{}", marker, cert).as_bytes())
+                .wrap_err_with(|| format!("Invalid certificate in {}", ca_bundle))?;
+            builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some((cert, key)) = client_pems(conf)? {
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .wrap_err("Invalid client certificate or key")?;
+        builder.identity(identity);
+    }
+    Ok(builder.build()?)
+}
+
+fn proxy_uri(proxy: &str) -> Result<Uri> {
+    let proxy = if proxy.contains("://") {
+        proxy.to_string()
+    } else {
+        format!("http://{}", proxy)
+    };
+    proxy.parse().map_err(|e| eyre!("Invalid proxy {}: {}", proxy, e))
+}
+
+
+// // // // // // // // // // ureq // // // // // // // // // //
+
+/// Start a <method> request to <url> with the HTTP settings of this thread
+pub fn request(method: &str, url: &str) -> ureq::Request {
+    let http = current();
+    let mut req = ureq::request(method, url);
+
+    let host = url.parse::<Uri>().ok().and_then(|u| u.host().map(String::from));
+    if let Some(proxy) = host.and_then(|host| http.proxy_for(&host).map(String::from)) {
+        // Checked when the settings were read
+        if let Ok(proxy) = ureq::Proxy::new(proxy) {
+            req.set_proxy(proxy);
+        }
+    }
+    if let Some(rustls) = http.rustls {
+        req.set_tls_config(rustls);
+    }
+    req
+}
+
+pub fn post(url: &str) -> ureq::Request {
+    request("POST", url)
+}
+
+pub fn put(url: &str) -> ureq::Request {
+    request("PUT", url)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bypass() {
+        let no_proxy = vec![
+            "localhost".to_string(),
+            ".internal".to_string(),
+            "amazonaws.com".to_string(),
+        ];
+
+        assert!(bypass(&no_proxy, "localhost"));
+        assert!(bypass(&no_proxy, "consul.internal"));
+        assert!(bypass(&no_proxy, "ssm.us-east-1.amazonaws.com"));
+        assert!(!bypass(&no_proxy, "notamazonaws.com"));
+        assert!(!bypass(&no_proxy, "events.pagerduty.com"));
+        assert!(!bypass(&[], "localhost"));
+        assert!(bypass(&["*".to_string()], "events.pagerduty.com"));
+    }
+
+    #[test]
+    fn test_proxy_for() {
+        let conf = HttpConf {
+            proxy: Some("proxy.corp:3128".to_string()),
+            no_proxy: Some(vec![".corp".to_string()]),
+            ..Default::default()
+        };
+        let http = Http::new(&conf).unwrap();
+        assert_eq!(http.proxy_for("ssm.us-east-1.amazonaws.com"), Some("proxy.corp:3128"));
+        assert_eq!(http.proxy_for("consul.corp"), None);
+        assert_eq!(proxy_uri("proxy.corp:3128").unwrap().port_u16(), Some(3128));
+    }
+
+    #[test]
+    fn test_invalid_conf() {
+        let conf = HttpConf {
+            ca_bundle: Some("./tests/no_such_bundle.pem".to_string()),
+            ..Default::default()
+        };
+        let res = format!("{:#}", Http::new(&conf).err().unwrap());
+        assert!(res.contains("Unable to read ./tests/no_such_bundle.pem"), "{}", res);
+
+        let conf = HttpConf {
+            client_cert: Some("./tests/cert.pem".to_string()),
+            ..Default::default()
+        };
+        let res = format!("{:#}", Http::new(&conf).err().unwrap());
+        assert!(res.contains("client_cert and client_key go together"), "{}", res);
+    }
+}
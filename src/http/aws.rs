@@ -0,0 +1,109 @@
+use super::{bypass, current, proxy_uri};
+use eyre::Result;
+use hyper::client::connect::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_tls::HttpsConnector;
+use rusoto_core::HttpClient;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Dispatcher for rusoto clients with the HTTP settings of this thread
+pub fn aws_client() -> Result<HttpClient<HttpsConnector<Connector>>> {
+    let http = current();
+    let connector = Connector {
+        http: {
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            http
+        },
+        proxy: http.proxy.as_deref().map(proxy_uri).transpose()?,
+        no_proxy: http.no_proxy.clone(),
+    };
+
+    let tls = match http.native_tls {
+        Some(tls) => tls,
+        None => native_tls::TlsConnector::new()?,
+    };
+    Ok(HttpClient::from_connector(HttpsConnector::from((connector, tls.into()))))
+}
+
+/// Connector:
+/// Opens the connections rusoto makes requests on, either straight to AWS
+/// or tunneled through the proxy.  TLS is added on top by HttpsConnector.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    proxy: Option<Uri>,
+    no_proxy: Vec<String>,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let host = dst.host().unwrap_or_default().to_string();
+        let proxy = match &self.proxy {
+            Some(proxy) if !bypass(&self.no_proxy, &host) => proxy.clone(),
+            _ => {
+                let connecting = self.http.call(dst);
+                return Box::pin(async move { Ok(connecting.await?) });
+            }
+        };
+
+        let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        let connecting = self.http.call(proxy.clone());
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            tunnel(&mut stream, &proxy, &host, port).await?;
+            Ok(stream)
+        })
+    }
+}
+
+/// Ask the <proxy> at the other end of <stream> to tunnel it to <host>
+async fn tunnel(stream: &mut TcpStream, proxy: &Uri, host: &str, port: u16) -> Result<(), BoxError> {
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some(authority) = proxy.authority() {
+        if let Some(at) = authority.as_str().rfind('@') {
+            let credentials = base64::encode(&authority.as_str()[..at]);
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response a byte at a time, what follows it belongs to TLS
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err("Proxy response too long".into());
+        }
+        let mut byte = [0; 1];
+        if stream.read(&mut byte).await? == 0 {
+            return Err("Proxy closed the connection".into());
+        }
+        response.push(byte[0]);
+    }
+
+    let status = String::from_utf8_lossy(&response);
+    let status = status.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(format!("Proxy refused to connect to {}: {}", host, status).into()),
+    }
+}
@@ -0,0 +1,99 @@
+use crate::imds;
+use eyre::{eyre, Result, WrapErr};
+use serde_derive::Deserialize;
+use std::sync::Mutex;
+
+/// The identity values templates can embed, see lookup()
+pub const NAMES: &[&str] = &["hostname", "aws_region", "aws_account_id", "instance_id"];
+
+/// The parts of the EC2 instance identity document we use
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    account_id: String,
+    region: String,
+    instance_id: String,
+}
+
+// Fetched once, then kept for the rest of the run, failures included so a
+// host off EC2 does not wait on the metadata service for every lookup
+static DOCUMENT: Mutex<Option<Result<Document, String>>> = Mutex::new(None);
+
+/// The identity value <name> of the host we run on.  The hostname and, when
+/// AWS_REGION or AWS_DEFAULT_REGION is set, the region are known offline,
+/// the rest comes from the instance identity document over IMDSv2.
+pub fn lookup(name: &str) -> Result<String> {
+    match name {
+        "hostname" => hostname(),
+        "aws_region" => match region_from_env() {
+            Some(region) => Ok(region),
+            None => Ok(document()?.region),
+        },
+        "aws_account_id" => Ok(document()?.account_id),
+        "instance_id" => Ok(document()?.instance_id),
+        _ => Err(eyre!("Unknown identity value {}", name)),
+    }
+}
+
+fn hostname() -> Result<String> {
+    if let Ok(name) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return Ok(name.trim().to_string());
+    }
+    let output = std::process::Command::new("hostname")
+        .output()
+        .wrap_err("Unable to get the hostname")?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn region_from_env() -> Option<String> {
+    ["AWS_REGION", "AWS_DEFAULT_REGION"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|region| !region.is_empty())
+}
+
+fn document() -> Result<Document> {
+    let mut cached = DOCUMENT.lock().unwrap_or_else(|e| e.into_inner());
+    let document = cached.get_or_insert_with(|| {
+        imds::Session::new()
+            .and_then(|session| session.get("dynamic/instance-identity/document"))
+            .and_then(|json| parse_document(&json))
+            .map_err(|e| format!("{:#}", e))
+    });
+    document.clone().map_err(|e| eyre!("No instance identity: {}", e))
+}
+
+fn parse_document(json: &str) -> Result<Document> {
+    serde_json::from_str(json).wrap_err("Invalid instance identity document")
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_document() {
+        let json = r#"{
+            "accountId": "123456789012",
+            "architecture": "x86_64",
+            "availabilityZone": "us-east-1a",
+            "instanceId": "i-0123456789abcdef0",
+            "instanceType": "t3.micro",
+            "region": "us-east-1"
+        }"#;
+        let exp = Document {
+            account_id: "123456789012".to_string(),
+            region: "us-east-1".to_string(),
+            instance_id: "i-0123456789abcdef0".to_string(),
+        };
+        assert_eq!(parse_document(json).unwrap(), exp);
+        assert!(parse_document("{}").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert!(!lookup("hostname").unwrap().is_empty());
+        assert!(lookup("ami_id").is_err());
+    }
+}
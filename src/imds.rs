@@ -0,0 +1,45 @@
+use eyre::{eyre, Result, WrapErr};
+
+/// The EC2 instance metadata service
+const IMDS: &str = "http://169.254.169.254/latest";
+/// Milliseconds to wait for the instance metadata service
+const IMDS_TIMEOUT: u64 = 1000;
+
+/// Session:
+/// A session with the instance metadata service.  It is always asked for a
+/// session token first, and never called without one, so this works where
+/// IMDSv1 is disabled.  It is reached directly, never through the proxy.
+pub struct Session {
+    token: String,
+}
+
+impl Session {
+    /// Start a session, failing quickly off EC2
+    pub fn new() -> Result<Session> {
+        let resp = ureq::put(&format!("{}/api/token", IMDS))
+            .set("X-aws-ec2-metadata-token-ttl-seconds", "300")
+            .timeout_connect(IMDS_TIMEOUT)
+            .call();
+        let token = body(resp).wrap_err("Unable to get an IMDSv2 token")?;
+        Ok(Session { token })
+    }
+
+    /// The document at <path>, e.g. "meta-data/instance-id"
+    pub fn get(&self, path: &str) -> Result<String> {
+        let resp = ureq::get(&format!("{}/{}", IMDS, path))
+            .set("X-aws-ec2-metadata-token", &self.token)
+            .timeout_connect(IMDS_TIMEOUT)
+            .call();
+        body(resp)
+    }
+}
+
+fn body(resp: ureq::Response) -> Result<String> {
+    if let Some(e) = resp.synthetic_error() {
+        return Err(eyre!("Unable to reach the instance metadata service: {}", e));
+    }
+    if !resp.ok() {
+        return Err(eyre!("Instance metadata service answered {}", resp.status_line()));
+    }
+    Ok(resp.into_string()?)
+}
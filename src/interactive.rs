@@ -0,0 +1,118 @@
+use crate::diff::{self, Layout};
+use crate::hooks::formats;
+use crate::hooks::template::DataType;
+use eyre::{Result, WrapErr};
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// With check --interactive, hooks ask before they change anything: files
+// are only written once their diff is accepted, and commands only run once
+// confirmed.  What is declined is skipped, the run goes on.
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SIDE_BY_SIDE: AtomicBool = AtomicBool::new(false);
+static CONTEXT: AtomicUsize = AtomicUsize::new(diff::CONTEXT);
+static SEMANTIC: AtomicBool = AtomicBool::new(false);
+// One question at a time, pipelines may be checked on several threads
+static PROMPT: Mutex<()> = Mutex::new(());
+
+/// Ask before changes for the rest of the process, showing them laid out
+/// as per <layout> with <context> unchanged lines around them.  With
+/// <semantic>, JSON, YAML and TOML files show the keys that changed instead.
+pub fn enable(layout: Layout, context: usize, semantic: bool) {
+    SIDE_BY_SIDE.store(layout == Layout::SideBySide, Ordering::Relaxed);
+    CONTEXT.store(context, Ordering::Relaxed);
+    SEMANTIC.store(semantic, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether <contents> may be written to <path>.  The change to what the file
+/// holds now is shown first.  Writing what is already there is not asked.
+pub fn confirm_write(path: &str, contents: &[u8]) -> Result<bool> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+
+    let existing = match std::fs::read(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", path)),
+    };
+    if existing == contents {
+        return Ok(true);
+    }
+
+    let change = match (std::str::from_utf8(&existing), std::str::from_utf8(contents)) {
+        (Ok(old), Ok(new)) => match semantic_diff(path, old, new) {
+            Some(changes) => changes,
+            None => {
+                let layout = match SIDE_BY_SIDE.load(Ordering::Relaxed) {
+                    true => Layout::SideBySide,
+                    false => Layout::Unified,
+                };
+                diff::render(old, new, layout, CONTEXT.load(Ordering::Relaxed))
+            }
+        },
+        _ => format!("binary data, {} bytes now, {} after\n", existing.len(), contents.len()),
+    };
+    ask(&format!("--- {}\n+++ {}\n{}Write {}?", path, path, change, path))
+}
+
+/// The keys changed between <old> and <new>, if asked for and the file at
+/// <path> holds JSON, YAML or TOML, going by its extension.  None when
+/// either does not parse, the line diff shows what is wrong then.
+fn semantic_diff(path: &str, old: &str, new: &str) -> Option<String> {
+    if !SEMANTIC.load(Ordering::Relaxed) {
+        return None;
+    }
+    let format = match std::path::Path::new(path).extension()?.to_str()? {
+        "json" => DataType::JSON,
+        "yaml" | "yml" => DataType::YAML,
+        "toml" => DataType::TOML,
+        _ => return None,
+    };
+    // A new file has nothing to compare with
+    if old.is_empty() {
+        return None;
+    }
+    let old = formats::parse(&format, old).ok()?;
+    let new = formats::parse(&format, new).ok()?;
+    Some(diff::render_changes(&diff::changes(&old, &new)))
+}
+
+/// Whether to go ahead with <action>, e.g. "Run systemctl reload nginx"
+pub fn confirm(action: &str) -> Result<bool> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+    ask(&format!("{}?", action))
+}
+
+/// Print <question> to stderr and read the answer from stdin, only a yes
+/// is taken as one
+fn ask(question: &str) -> Result<bool> {
+    let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).wrap_err("Unable to read the answer")?;
+    let yes = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !yes {
+        info!("Skipped");
+    }
+    Ok(yes)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disabled() {
+        assert!(confirm_write("/nonexistent/file", b"data").unwrap());
+        assert!(confirm("Run rm -rf /").unwrap());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+// app_config lint: warnings about configs that are valid, but likely not
+// what was meant.  Hard mistakes are left to Config, which is parsed first.
+
+/// Hook sections run one after the other on new data
+const RUN_SECTIONS: [&str; 3] = ["pre_hooks", "hooks", "post_hooks"];
+
+/// The warnings for the config file <maps>, each naming what it is about
+pub fn lint(maps: &toml::Value) -> Vec<String> {
+    let mut warnings = Vec::new();
+    warnings.extend(in_memory_state(maps));
+
+    let mut hooks = Vec::new();
+    for section in RUN_SECTIONS.iter().chain(&["on_error"]) {
+        if let Some(table) = maps.get(section).and_then(|t| t.as_table()) {
+            for (kind, hook) in table {
+                hooks.push((format!("{}.{}", section, kind), kind.as_str(), hook));
+            }
+        }
+    }
+    for (name, kind, hook) in &hooks {
+        warnings.extend(unpiped_command(name, kind, hook));
+    }
+
+    let run: Vec<_> = hooks.iter().filter(|(name, _, _)| !name.starts_with("on_error.")).collect();
+    warnings.extend(shared_outputs(&run));
+    warnings.extend(unreachable(&run));
+    warnings
+}
+
+/// A provider billed per call, with nowhere to remember what it got.
+/// Every run then downloads and applies the data again.
+fn in_memory_state(maps: &toml::Value) -> Option<String> {
+    let provider = maps.get("providers")?.get("appconfig")?;
+    // settings.state_dir gives it one
+    let state_dir = maps.get("settings").and_then(|s| s.get("state_dir"));
+    if state_dir.is_some() && state_dir.and_then(|d| d.as_bool()) != Some(false) {
+        return None;
+    }
+    match provider.get("state_file") {
+        Some(_) => None,
+        None => Some(
+            "providers.appconfig has no state_file, every run pays for a download and \
+             applies the data again"
+                .to_string(),
+        ),
+    }
+}
+
+/// A command reading its stdin, which it is only given with pipe_data
+fn unpiped_command(name: &str, kind: &str, hook: &toml::Value) -> Option<String> {
+    if kind != "command" || hook.get("pipe_data").and_then(|p| p.as_bool()) == Some(true) {
+        return None;
+    }
+    let command = hook.get("command")?.as_str()?;
+    let reads_stdin = command.contains("/dev/stdin")
+        || command.contains("$(cat)")
+        || command.split_whitespace().any(|word| word == "-");
+    match reads_stdin {
+        true => Some(format!("{} reads stdin, but has no pipe_data = true", name)),
+        false => None,
+    }
+}
+
+/// Where a hook writes the data to: a file, or stdout
+fn outputs(kind: &str, hook: &toml::Value) -> Vec<String> {
+    let out_file = |hook: &toml::Value, key: &str| {
+        hook.get(key).and_then(|f| f.as_str()).map(|f| f.to_string())
+    };
+    match kind {
+        "raw" => vec!["stdout".to_string()],
+        "file" => out_file(hook, "outfile").into_iter().collect(),
+        "template" => match (out_file(hook, "out_file"), hook.get("outputs")) {
+            (Some(file), _) => vec![file],
+            (None, Some(outputs)) => outputs
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|output| out_file(output, "out_file"))
+                .collect(),
+            (None, None) => vec!["stdout".to_string()],
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Several hooks writing to the same file, or all printing to stdout
+fn shared_outputs(hooks: &[&(String, &str, &toml::Value)]) -> Vec<String> {
+    let mut writers: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (name, kind, hook) in hooks {
+        for output in outputs(kind, hook) {
+            writers.entry(output).or_default().push(name);
+        }
+    }
+    writers
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(output, names)| format!("{} all write to {}", names.join(", "), output))
+        .collect()
+}
+
+/// Hooks after one that always fails.  The first hook to fail ends the
+/// run, so those after it never run.
+fn unreachable(hooks: &[&(String, &str, &toml::Value)]) -> Option<String> {
+    let failing = hooks.iter().position(|(_, kind, hook)| {
+        let command = hook.get("command").and_then(|c| c.as_str()).map(str::trim);
+        *kind == "command" && matches!(command, Some("false") | Some("exit 1"))
+    })?;
+    let after: Vec<&str> = hooks[failing + 1..].iter().map(|(name, _, _)| name.as_str()).collect();
+    match after.is_empty() {
+        true => None,
+        false => Some(format!(
+            "{} always fails, {} never run",
+            hooks[failing].0,
+            after.join(", ")
+        )),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [providers.appconfig]
+            application = "myApp"
+            environment = "dev"
+            configuration = "myConf"
+            client_id = "42"
+
+            [pre_hooks.command]
+            command = "false"
+
+            [hooks.template]
+            file = "./tests/test_template.tmpl"
+            source_type = "yaml"
+
+            [hooks.raw]
+
+            [hooks.command]
+            command = "jq .port /dev/stdin"
+            "#,
+        )
+        .unwrap();
+        let exp = vec![
+            "providers.appconfig has no state_file, every run pays for a download and applies \
+             the data again",
+            "hooks.command reads stdin, but has no pipe_data = true",
+            "hooks.template, hooks.raw all write to stdout",
+            "pre_hooks.command always fails, hooks.template, hooks.raw, hooks.command never run",
+        ];
+        assert_eq!(lint(&maps), exp);
+
+        let maps: toml::Value = toml::from_str(
+            r#"
+            [providers.mock]
+            data = "port: 80"
+
+            [hooks.file]
+            outfile = "out.txt"
+
+            [hooks.command]
+            command = "cat - > out.txt"
+            pipe_data = true
+            "#,
+        )
+        .unwrap();
+        assert!(lint(&maps).is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::credentials;
+use crate::http;
+use crate::providers::parse_region;
+use rusoto_core::Region;
+use rusoto_sqs::{DeleteMessageRequest, ReceiveMessageRequest, Sqs, SqsClient};
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+
+/// How long one receive waits for a message, the most SQS allows
+const WAIT_SECONDS: i64 = 20;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// ListenConf holds the [settings.listen] section of the config file
+#[derive(Debug, Deserialize)]
+#[serde(rename = "listen")]
+pub struct ListenConf {
+    pub queue_url: String,
+    pub region: Option<String>,
+}
+
+impl ListenConf {
+    /// Will panic if the region is invalid
+    pub fn convert(&self) -> Listener {
+        let region = self.region.as_deref().map(parse_region).unwrap_or_default();
+        Listener::new(&self.queue_url, region)
+    }
+}
+
+
+// // // // // // // // // // Listener // // // // // // // // // //
+
+/// Listener:
+/// Where the daemon hears that a pipeline should be checked now rather
+/// than at its next interval: any message on the SQS queue at <queue_url>,
+/// e.g. one an EventBridge rule sends when the data changes.  Messages are
+/// deleted once received, what they say does not matter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+    queue_url: String,
+    region: Region,
+}
+
+impl Listener {
+    pub fn new(queue_url: &str, region: Region) -> Listener {
+        Listener {
+            queue_url: queue_url.to_string(),
+            region,
+        }
+    }
+
+    /// Wait up to WAIT_SECONDS for messages.  Returns whether any came.
+    pub fn wait(&self) -> Result<bool> {
+        receive(self)
+    }
+}
+
+/// receive()
+/// Long poll the queue, and delete what it gave us
+#[tokio::main]
+async fn receive(listener: &Listener) -> Result<bool> {
+    let client = SqsClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        listener.region.clone(),
+    );
+
+    let request = ReceiveMessageRequest {
+        queue_url: listener.queue_url.clone(),
+        max_number_of_messages: Some(10),
+        wait_time_seconds: Some(WAIT_SECONDS),
+        ..Default::default()
+    };
+    let messages = match client.receive_message(request).await {
+        Ok(result) => result.messages.unwrap_or_default(),
+        Err(e) => return Err(eyre!("Unable to receive from {}: {:?}", listener.queue_url, e)),
+    };
+
+    for message in &messages {
+        let receipt_handle = match &message.receipt_handle {
+            Some(receipt_handle) => receipt_handle.clone(),
+            None => continue,
+        };
+        let request = DeleteMessageRequest {
+            queue_url: listener.queue_url.clone(),
+            receipt_handle,
+        };
+        // Left on the queue, the message comes back once it is visible
+        // again and only triggers one more check
+        if let Err(e) = client.delete_message(request).await {
+            warning!("unable to delete a message from {}: {:?}", listener.queue_url, e);
+        }
+    }
+    Ok(!messages.is_empty())
+}
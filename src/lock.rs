@@ -0,0 +1,79 @@
+use eyre::{Result, WrapErr};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+
+/// RunLock:
+/// Held while a pipeline runs, so processes sharing its state file take
+/// turns with it, e.g. an init container applying the first data and the
+/// sidecar that keeps it up to date.  The lock is a file next to the state
+/// file, it is released when the RunLock is dropped, or its process ends.
+/// Without a state file nothing is shared and there is nothing to lock.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: Option<File>,
+}
+
+impl RunLock {
+    /// Wait for the lock of <pipeline> on <state_file>
+    pub fn acquire(state_file: &Option<String>, pipeline: &str) -> Result<RunLock> {
+        let path = match state_file {
+            Some(state_file) => format!("{}.{}.lock", state_file, pipeline),
+            None => return Ok(RunLock { _file: None }),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("Unable to open {}", path))?;
+
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                info!("Waiting for another process running {}", pipeline);
+                flock(&file, libc::LOCK_EX).wrap_err_with(|| format!("Unable to lock {}", path))?;
+            }
+            Err(e) => return Err(e).wrap_err_with(|| format!("Unable to lock {}", path)),
+        }
+        Ok(RunLock { _file: Some(file) })
+    }
+}
+
+/// flock(2) <file>, retrying when interrupted by a signal
+pub fn flock(file: &File, operation: libc::c_int) -> std::io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_lock() {
+        let dir = std::env::temp_dir();
+        let state_file = dir.join(format!("app_config_lock_{}.db", std::process::id()));
+        let state_file = Some(state_file.to_string_lossy().to_string());
+        let path = format!("{}.web.lock", state_file.as_ref().unwrap());
+
+        let lock = RunLock::acquire(&state_file, "web").unwrap();
+        let other = File::open(&path).unwrap();
+        let res = flock(&other, libc::LOCK_EX | libc::LOCK_NB);
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+
+        drop(lock);
+        assert!(flock(&other, libc::LOCK_EX | libc::LOCK_NB).is_ok());
+        drop(other);
+
+        assert!(RunLock::acquire(&None, "web").unwrap()._file.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,52 +1,1291 @@
-#[macro_use]
-extern crate clap;
-use clap::ArgMatches;
-
+use clap::Parser;
 use simple_eyre::eyre::{WrapErr, Report};
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::sync::{mpsc, Arc, Mutex};
+use chrono::Utc;
+use std::time::{Duration, Instant, SystemTime};
 
+#[macro_use]
+mod output;
 mod cli;
 mod hooks;
 mod providers;
-use cli::build_cli;
+use cli::{
+    ApproveArgs, AuditArgs, CheckArgs, CleanupArgs, Cli, Cmd, ExportArgs, FreezeArgs, QueryArgs,
+    ReportArgs, BenchArgs, TestArgs,
+};
 mod config;
 use config::Config;
+mod settings;
+mod state;
+mod checksum;
+#[cfg(feature = "state-sqlite")]
+mod migrate;
+use state::{AuditEntry, State};
+use checksum::OnDrift;
+use report::Run;
+#[cfg(feature = "aws")]
+mod cloudwatch;
+#[cfg(feature = "aws")]
+use cloudwatch::RunOutcome;
+mod telemetry;
+use telemetry::Tracer;
+mod reporting;
+mod schema;
+mod path;
+#[cfg(feature = "aws")]
+mod s3;
+mod data;
+mod decode;
+mod duration;
+mod http;
+#[cfg(feature = "aws")]
+mod credentials;
+mod imds;
+mod report;
+mod daemon;
+mod identity;
+mod export;
+mod interactive;
+mod coordination;
+mod window;
+mod normalize;
+mod diff;
+mod sandbox;
+mod redact;
+mod spool;
+mod private_path;
+mod workspace;
+mod lock;
+#[cfg(feature = "aws")]
+mod listen;
+mod lint;
+mod golden;
+mod bench;
+use lock::RunLock;
+use spool::Spool;
+use data::ConfigData;
+use hooks::formats;
+use hooks::template::DataType;
+use hooks::{Hook, HookFailed};
+use providers::{PayloadTooLarge, ProviderTimeout};
+use window::Window;
+use workspace::Workspace;
+
+/// How long check --wait-for-initial waits after a failed run, at first and
+/// at most
+const WAIT_MIN: Duration = Duration::from_secs(1);
+const WAIT_MAX: Duration = Duration::from_secs(60);
 
 
 fn main() -> Result<(), Report> {
     simple_eyre::install()?;
 
-    run()?;
+    // A provider timing out is worth retrying soon, tell the caller so, and
+    // a hook failing is not
+    if let Err(e) = run() {
+        if e.downcast_ref::<ProviderTimeout>().is_some() {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exitcode::TEMPFAIL);
+        }
+        if e.downcast_ref::<HookFailed>().is_some() {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exitcode::SOFTWARE);
+        }
+        return Err(e);
+    }
 
     Ok(())
 }
 
 
 fn run() -> eyre::Result<()> {
-    let matches = build_cli().get_matches();
+    let cli = Cli::parse();
+    output::configure(cli.quiet, cli.no_color);
 
     // Handle CLI subcommands
-    let res = match matches.subcommand() {
-        ("check", Some(matches)) => check_for_updates(matches),
-        ("query", Some(matches)) => query_data(matches),
-        // ("params", Some(matches)) => params(matches),
-        _ => std::process::exit(1),
+    match &cli.command {
+        Cmd::Check(args) => check_for_updates(cli.files(), args),
+        Cmd::Daemon => run_daemon(cli.files()),
+        Cmd::Validate => validate_configs(cli.files()),
+        Cmd::Lint => lint_configs(cli.files()),
+        Cmd::Test(args) => test_templates(cli.file(), args),
+        Cmd::Bench(args) => bench_pipeline(cli.file(), args),
+        Cmd::Query(args) => query_data(cli.file(), args),
+        Cmd::Export(args) => export_data(cli.file(), args),
+        Cmd::Audit(args) => print_audit_log(cli.file(), args),
+        Cmd::Report(args) => print_report(cli.file(), args),
+        Cmd::Freeze(args) => freeze_pipelines(cli.files(), args),
+        Cmd::Thaw => thaw_pipelines(cli.files()),
+        Cmd::Approve(args) => approve_change(cli.file(), args),
+        Cmd::Replay => replay_run(cli.file()),
+        Cmd::Cleanup(args) => cleanup_files(cli.files(), args),
+        Cmd::Bash => {
+            cli::bash_completion();
+            Ok(())
+        }
+    }
+}
+
+
+/// Check upstream providers for updates
+/// Every config file given is a pipeline of its own.  With more than one,
+/// up to <jobs> of them are checked at once, each on its own thread.
+fn check_for_updates(files: &[String], args: &CheckArgs) -> eyre::Result<()> {
+    if args.interactive {
+        let layout = match args.side_by_side {
+            true => diff::Layout::SideBySide,
+            false => diff::Layout::Unified,
+        };
+        interactive::enable(layout, args.context, args.semantic);
+    }
+    let opts = CheckOptions {
+        offline: args.offline,
+        replay: false,
+        approve: false,
+        bootstrap: args.bootstrap || args.wait_for_initial,
+        wait_for_initial: args.wait_for_initial,
+        only: args.only.clone(),
+        skip: args.skip.clone(),
+        strict: args.strict,
     };
+    if files.len() == 1 {
+        return check_pipeline(&files[0], &opts);
+    }
 
-    res
+    // Questions are asked one pipeline at a time
+    let jobs = if args.interactive { 1 } else { args.jobs as usize };
+    check_pipelines(files.to_vec(), jobs, opts)
 }
 
 
-/// Check upstream provider for updates
-/// If there are updates run all associated hooks, else just end
-fn check_for_updates(matches: &ArgMatches) -> eyre::Result<()> {
-    let file = matches.value_of("FILE").unwrap();
+/// Check every pipeline in <files>, <jobs> at a time.  A pipeline failing
+/// does not stop the others, the run fails once they have all ended.
+fn check_pipelines(files: Vec<String>, jobs: usize, opts: CheckOptions) -> eyre::Result<()> {
+    // Config and state file errors end the process.  They are found before
+    // any pipeline starts, rather than while others are half way through
+    // their hooks.
+    for file in &files {
+        pipeline_state(&Config::from_file(file));
+    }
+
+    let total = files.len();
+    let opts = Arc::new(opts);
+    let queue = Arc::new(Mutex::new(files.into_iter().collect::<VecDeque<_>>()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..jobs.min(total))
+        .map(|_| {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let opts = opts.clone();
+            std::thread::spawn(move || loop {
+                let file = match queue.lock().unwrap().pop_front() {
+                    Some(file) => file,
+                    None => break,
+                };
+                let res = check_pipeline(&file, &opts);
+                tx.send((file, res)).unwrap();
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut failed = Vec::new();
+    for (file, res) in rx {
+        if let Err(e) = res {
+            error!("pipeline {} failed: {:#}", file, e);
+            failed.push(file);
+        }
+    }
+    for worker in workers {
+        if worker.join().is_err() {
+            failed.push("<panicked>".to_string());
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre::eyre!(
+            "{} of {} pipelines failed: {}",
+            failed.len(),
+            total,
+            failed.join(", ")
+        ))
+    }
+}
+
+
+/// Check the pipelines given, each every settings.interval, until stopped.
+/// A config is parsed, and its state file opened, once it is loaded, right
+/// after it was validated.  Each check then runs with them as they are, so
+/// a template or state file going missing fails the check, not the daemon.
+fn run_daemon(files: &[String]) -> eyre::Result<()> {
+    daemon::run(
+        files.to_vec(),
+        |file, contents| {
+            let config = Config::parse(file, contents);
+            let state = pipeline_state(&config);
+            (config, state)
+        },
+        |file, (config, state)| {
+            check_config(file, config, state, &CheckOptions::default())?;
+
+            // A change left waiting is applied as soon as it may be, rather
+            // than at the next interval, unless it waits for an approval
+            let approval = config.settings.require_approval.unwrap_or(false);
+            match state.applies()? {
+                (_, true) if !approval => {
+                    apply_due(config.min_apply_interval, &config.windows, state)
+                }
+                _ => Ok(None),
+            }
+        },
+    )
+}
+
+
+/// Parse the config files given, exiting with CONFIG at the first invalid one
+fn validate_configs(files: &[String]) -> eyre::Result<()> {
+    for file in files {
+        Config::from_file(file);
+        info!("{} is valid", file);
+    }
+    Ok(())
+}
+
+
+/// Lint the config files given, after validating them.  Fails if any
+/// gives a warning, so it can gate changes to them.
+fn lint_configs(files: &[String]) -> eyre::Result<()> {
+    let mut count = 0;
+    for file in files {
+        Config::from_file(file);
+        let contents = std::fs::read_to_string(shellexpand::tilde(file).to_string())
+            .wrap_err_with(|| format!("Could not open {}", file))?;
+        let maps: toml::Value = toml::from_str(&contents)?;
+
+        let warnings = lint::lint(&maps);
+        for warning in &warnings {
+            warning!("{}: {}", file, warning);
+        }
+        if warnings.is_empty() {
+            info!("{} looks good", file);
+        }
+        count += warnings.len();
+    }
+
+    if count == 0 {
+        Ok(())
+    } else {
+        Err(eyre::eyre!("{} warnings", count))
+    }
+}
+
+
+/// Render the templates of the pipeline in <file> with the data of each
+/// test case, and compare what they render to the golden files of the case.
+/// Fails if any case does not match, so template changes can be gated on it.
+fn test_templates(file: &str, args: &TestArgs) -> eyre::Result<()> {
+    let config = Config::from_file(file);
+    let cases = golden::cases(&args.cases)?;
+
+    let mut failed = Vec::new();
+    for case in &cases {
+        let res = golden::render(&config, case).and_then(|rendered| match args.update {
+            true => golden::update(case, &rendered).map(|_| Vec::new()),
+            false => golden::compare(case, &rendered),
+        });
+        let name = case.display();
+        match res {
+            Ok(mismatches) if mismatches.is_empty() => info!("{}: ok", name),
+            Ok(mismatches) => {
+                for mismatch in mismatches {
+                    error!("{}: {}", name, mismatch.summary());
+                    print!("{}", mismatch.diff());
+                }
+                failed.push(name.to_string());
+            }
+            Err(e) => {
+                error!("{}: {:#}", name, e);
+                failed.push(name.to_string());
+            }
+        }
+    }
+
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(eyre::eyre!(
+            "{} of {} cases failed: {}",
+            failed.len(),
+            cases.len(),
+            failed.join(", ")
+        )),
+    }
+}
+
+
+/// Measure how long the steps of a run of the pipeline in <file> take, over
+/// args.iterations: querying the provider, rendering each hook, and with
+/// --run-hooks running it.  With settings.for_each a hook's time is that of
+/// all the elements.  Nothing is recorded in the state file.
+fn bench_pipeline(file: &str, args: &BenchArgs) -> eyre::Result<()> {
     let config = Config::from_file(file);
+    http::configure(&config.settings.http.clone().unwrap_or_default());
+    #[cfg(feature = "aws")]
+    credentials::configure(&config.settings.aws_credentials.clone().unwrap_or_default());
+    if config.provider.pays_every_run() {
+        warning!("{} is billed per download, and downloads the data on every iteration", file);
+    }
+
+    let mut timings = bench::Timings::default();
+    for _ in 0..args.iterations {
+        let started = Instant::now();
+        let raw = config.provider.query().wrap_err("Unable to poll the provider")?;
+        timings.record("poll", started.elapsed());
+        let data = ConfigData::new(raw, config.provider.kind(), config.provider.version())
+            .sensitive(config.sensitive());
 
-    if let Some(data) = config.provider.poll()? {
-        // We have data, let's run each of the hooks in order
-        // If there is no data, just exit the program with nothing more to do.
-        for hook in config.hooks {
-            hook.run(&data).wrap_err("Error running hook")?;
+        let for_each = config.for_each(&data)?;
+        let elements: Vec<&ConfigData> = match &for_each {
+            None => vec![&data],
+            Some(elements) => elements.iter().collect(),
+        };
+        let hooks = config.pre_hooks.iter().map(|hook| (hook, vec![&data]));
+        let hooks = hooks
+            .chain(config.hooks.iter().map(|hook| (hook, elements.clone())))
+            .chain(config.post_hooks.iter().map(|hook| (hook, vec![&data])));
+        for (hook, data) in hooks {
+            let started = Instant::now();
+            let mut rendered = false;
+            for data in &data {
+                let outputs = hook
+                    .rendered(data)
+                    .wrap_err_with(|| format!("Unable to render {}", hook.name()))?;
+                rendered |= !outputs.is_empty();
+            }
+            if rendered {
+                timings.record(&format!("render {}", hook.name()), started.elapsed());
+            }
+            if !args.run_hooks {
+                continue;
+            }
+
+            let started = Instant::now();
+            for data in &data {
+                hook.run(data).wrap_err_with(|| format!("Error running {}", hook.name()))?;
+            }
+            timings.record(&format!("hook {}", hook.name()), started.elapsed());
+        }
+    }
+    print!("{}", timings.summary());
+    Ok(())
+}
+
+
+/// Options of the check subcommand that apply to every pipeline
+/// - offline: the provider is not polled, its cached data is applied instead
+/// - bootstrap: the data is applied even if it did not change, and on the
+///   first poll whatever settings.bootstrap says
+/// - wait_for_initial: failed runs are tried again until one applies the
+///   data, see wait_for_initial()
+/// - only, skip: names of the hooks to run, or not to run, see Hook::name
+/// - strict: pipelines whose provider pays for the data on every run fail
+///   rather than only warn, see Provider::pays_every_run
+/// - replay: offline, the hooks that completed in the run that failed on
+///   the data are not run again, see replay_run()
+/// - approve: offline, the change waiting for approval is applied, see
+///   approve_change()
+#[derive(Clone, Debug, Default)]
+struct CheckOptions {
+    offline: bool,
+    replay: bool,
+    approve: bool,
+    bootstrap: bool,
+    wait_for_initial: bool,
+    only: Vec<String>,
+    skip: Vec<String>,
+    strict: bool,
+}
+
+impl CheckOptions {
+    /// Leave out the hooks of <config> that --only and --skip do not select.
+    /// The on_error hooks are kept, failures are reported whatever ran.
+    fn select_hooks(&self, config: &mut Config) {
+        if self.only.is_empty() && self.skip.is_empty() {
+            return;
+        }
+
+        let selected = |name: &str| {
+            (self.only.is_empty() || self.only.iter().any(|n| n == name))
+                && !self.skip.iter().any(|n| n == name)
+        };
+        for name in self.only.iter().chain(&self.skip) {
+            let mut hooks = config.hooks.iter().chain(&config.pre_hooks).chain(&config.post_hooks);
+            if !hooks.any(|hook| hook.name() == name) {
+                warning!("{} has no hook named {}", config.name(), name);
+            }
+        }
+        config.hooks.retain(|hook| selected(hook.name()));
+        config.pre_hooks.retain(|hook| selected(hook.name()));
+        config.post_hooks.retain(|hook| selected(hook.name()));
+    }
+}
+
+
+/// Check the upstream provider of the pipeline in <file> for updates
+/// If there are updates run all associated hooks, else just end
+fn check_pipeline(file: &str, opts: &CheckOptions) -> eyre::Result<()> {
+    if opts.wait_for_initial {
+        wait_for_initial(file, opts);
+        return Ok(());
+    }
+    let mut config = Config::from_file(file);
+    let state = pipeline_state(&config);
+    check_config(file, &mut config, &state, opts)
+}
+
+
+/// Check the pipeline in <file> until a run succeeds, waiting longer after
+/// each failed one, from WAIT_MIN up to WAIT_MAX.  Meant for containers, to
+/// hold their main process back until it has its config.  With --bootstrap
+/// implied, a run that succeeds is one that applied the data.
+fn wait_for_initial(file: &str, opts: &CheckOptions) {
+    let mut delay = WAIT_MIN;
+    loop {
+        let mut config = Config::from_file(file);
+        let state = pipeline_state(&config);
+        let e = match check_config(file, &mut config, &state, opts) {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        warning!("{} is not applied yet, trying again in {:?}: {:#}", file, delay, e);
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(WAIT_MAX);
+    }
+}
+
+
+/// Check the pipeline <config>, read from <file>, with its <state>, see
+/// check_pipeline().  The config is as it was once the check is over, so
+/// the daemon keeps it for the next one.
+fn check_config(
+    file: &str,
+    config: &mut Config,
+    state: &State,
+    opts: &CheckOptions,
+) -> eyre::Result<()> {
+    // Only the hooks picked with --only and --skip run
+    opts.select_hooks(config);
+
+    // Without a state file, a provider billed per download pays for the
+    // data and applies it again on every run
+    if config.provider.pays_every_run() && !opts.offline {
+        let problem = format!(
+            "{} has no state_file, every run pays for the data and applies it again",
+            config.name()
+        );
+        if opts.strict {
+            return Err(eyre::eyre!(problem));
+        }
+        warning!("{}", problem);
+    }
+
+    // Every request this pipeline makes goes through its proxy, if any
+    http::configure(&config.settings.http.clone().unwrap_or_default());
+    // And calls to AWS are signed with its credentials
+    #[cfg(feature = "aws")]
+    credentials::configure(&config.settings.aws_credentials.clone().unwrap_or_default());
+
+    // Panics are reported as they happen, failed runs once they end
+    let reporter = config
+        .settings
+        .error_reporting
+        .as_ref()
+        .map(|r| r.convert(file, &config.name()));
+    reporting::report_panics(reporter.as_ref());
+
+    // Processes sharing the state file take turns with the pipeline
+    let _lock = RunLock::acquire(&config.settings.state_file, &config.name())?;
+
+    // Hooks writing back upstream only run on the leading replica
+    let left_out = elect(config, state, opts.offline);
+    let res = apply(file, config, state, opts, reporter);
+    left_out.put_back(config);
+    res
+}
+
+
+/// Poll the provider of <config> and run its hooks on any new data, the
+/// part of check_config() past the election
+fn apply(
+    file: &str,
+    config: &Config,
+    state: &State,
+    opts: &CheckOptions,
+    reporter: Option<reporting::Reporter>,
+) -> eyre::Result<()> {
+
+    // Every run leaves a report of what it did
+    let run = Run::new(&config.name(), config.provider.kind());
+
+    let tracer = Tracer::new(config.settings.otlp.clone());
+
+    // If there is no data, there is nothing more to do.
+    let start_time = SystemTime::now();
+    let started = Instant::now();
+    let provider = &config.provider;
+    let first_poll = state.last_contact()?.is_none();
+    let mut fallback = None;
+    let refused = if opts.offline { None } else { cost_guard(config, state)? };
+    let polled = if opts.offline {
+        fallback = Some(if opts.replay { "replay" } else { "offline" });
+        // An approved change is the data polled, which providers keeping no
+        // data in the state file fetch again
+        match opts.approve {
+            true => cached_data(config).map(Some),
+            false => offline_data(config).map(Some),
+        }
+    } else if let Some(reason) = refused {
+        warning!("not polling the provider: {}", reason);
+        fallback = Some("not polled, cost guard");
+        Ok(None)
+    } else {
+        state.record_poll().wrap_err("Unable to update state file")?;
+        let polled = provider.poll().and_then(|data| match data {
+            None => Ok(None),
+            Some(data) => {
+                let data = within_limit(config, data)?;
+                decoded(config, data).wrap_err("Unable to decode provider data").map(Some)
+            }
+        });
+        match (polled, config.allow_stale) {
+            (Ok(data), _) => {
+                state.record_contact().wrap_err("Unable to update state file")?;
+                Ok(data)
+            }
+            (Err(e), Some(allow_stale)) => {
+                fallback = Some("stale");
+                stale_data(config, state, allow_stale, e).map(Some)
+            }
+            (Err(e), None) => Err(e),
+        }
+    };
+
+    // Changes normalize leaves out are noise, not new data
+    let polled = match polled {
+        Ok(Some(data)) if fallback.is_none() && !config.normalize.is_empty() => {
+            normalized(config, state, data)
+        }
+        polled => polled,
+    };
+
+    // Whether the first data ever received is applied is up to
+    // settings.bootstrap, --bootstrap applies the data in any case
+    let skip_first = first_poll && !opts.bootstrap && !config.settings.bootstrap.unwrap_or(true);
+    let polled = match polled {
+        Ok(None) if opts.bootstrap => {
+            fallback = Some("bootstrap");
+            cached_data(config).map(Some)
+        }
+        Ok(Some(_)) if skip_first => {
+            fallback = Some("first poll, not applied");
+            Ok(None)
+        }
+        polled => polled,
+    };
+
+    // New data waits while changes are frozen, outside the maintenance
+    // windows, and less than min_apply_interval after data was last
+    // applied, so a burst of changes is applied once.  It is then applied
+    // from the cache, the provider has it as unchanged.  The cached data
+    // applied offline waits just the same, only without approval when it
+    // was approved, or is not a change waiting for it.
+    let (_, pending) = state.applies()?;
+    let approved = opts.offline && (opts.approve || opts.replay || !pending);
+    let hold = hold(config, state, approved)?;
+    let polled = match (polled, hold) {
+        (Ok(Some(_)), Some((held, reason))) if fallback.is_none() => {
+            info!("Data changed, applying it later: {}", reason);
+            state.record_pending().wrap_err("Unable to update state file")?;
+            fallback = Some(held);
+            Ok(None)
+        }
+        (Ok(Some(_)), Some((held, reason))) if opts.offline => {
+            warning!("not applying the cached data: {}", reason);
+            fallback = Some(held);
+            Ok(None)
+        }
+        (Ok(None), None) if fallback.is_none() && pending => {
+            fallback = Some("deferred change");
+            cached_data(config).map(Some)
+        }
+        (polled, _) => polled,
+    };
+
+    // With settings.coordination.concurrency, only so many hosts apply new
+    // data at once.  The others leave it waiting until a lease is free.
+    let rollout = config.coordinator.as_ref().filter(|_| !opts.offline);
+    let rollout = rollout.and_then(|c| c.concurrency().map(|slots| (c, slots)));
+    let mut lease = None;
+    let polled = match (polled, rollout) {
+        (Ok(Some(data)), Some((coordinator, slots))) => match coordinator.lease("rollout", slots) {
+            Ok(Some(taken)) => {
+                lease = Some(taken);
+                Ok(Some(data))
+            }
+            Ok(None) => {
+                info!("Data changed, applying it later: {} hosts are applying it now", slots);
+                state.record_pending().wrap_err("Unable to update state file")?;
+                fallback = Some("deferred, rollout lease");
+                Ok(None)
+            }
+            Err(e) => Err(e.wrap_err("Unable to take a rollout lease")),
+        },
+        (polled, _) => polled,
+    };
+
+    let (status, mut detail) = match (&polled, fallback) {
+        (Ok(_), Some(fallback)) => ("ok", fallback.to_string()),
+        (Ok(Some(data)), None) => match data.describe_version() {
+            Some(version) => ("ok", format!("changed, {}", version)),
+            None => ("ok", "changed".to_string()),
+        },
+        (Ok(None), None) => ("ok", "unchanged".to_string()),
+        (Err(e), _) => ("error", format!("{:#}", e)),
+    };
+    // Providers that fail over between regions say which one answered
+    let region = provider.region().filter(|_| polled.is_ok() && fallback.is_none());
+    if let Some(region) = &region {
+        detail = format!("{}, served by {}", detail, region);
+    }
+    run.poll(&detail);
+    let sha = match &polled {
+        Ok(Some(data)) => Some(data.sha256().to_string()),
+        _ => None,
+    };
+    let entry = AuditEntry::new(
+        "poll",
+        config.provider.kind(),
+        status,
+        &detail,
+        sha,
+        started.elapsed(),
+    );
+    state.audit(&entry).wrap_err("Unable to write audit log")?;
+    tracer.record(
+        "poll",
+        start_time,
+        started.elapsed(),
+        vec![
+            ("provider.kind", config.provider.kind().to_string()),
+            ("provider.region", region.unwrap_or_default()),
+            ("poll.result", detail.clone()),
+        ],
+        polled.as_ref().err().map(|e| format!("{:#}", e)),
+    );
+
+    // Unchanged data leaves the files rendered from it, unless they drifted
+    let polled = match (polled, &config.settings.on_drift) {
+        (Ok(None), Some(on_drift)) if fallback.is_none() => {
+            check_drift(config, state, *on_drift)
+        }
+        (polled, _) => polled,
+    };
+
+    let (data, res) = match polled {
+        Ok(Some(data)) => {
+            run.data(&data);
+            state.record_apply().wrap_err("Unable to update state file")?;
+            // Hooks keep what they make along the way in a workspace of the
+            // run, removed once it is over
+            let mut workspace = Workspace::create(&config.name())?;
+            let data = data.in_workspace(workspace.path());
+            // Hooks that spawn processes pass the trace on to them
+            let data = match config.settings.otlp.is_some() {
+                true => data.with_traceparent(tracer.traceparent()),
+                false => data,
+            };
+            // Hooks only remove what the pipeline wrote before
+            let data = data.with_manifest(state.files()?);
+            // Hooks that want it get the data applied before, as kept
+            let hooks = || config.hooks.iter().chain(&config.pre_hooks).chain(&config.post_hooks);
+            let uses_previous = hooks().any(|hook| hook.uses_previous());
+            let data = match state.applied_data()? {
+                Some(previous) if uses_previous => {
+                    let previous = ConfigData::new(previous, config.provider.kind(), None);
+                    data.with_previous(previous.sensitive(config.sensitive()))
+                }
+                _ => data,
+            };
+            // A replay picks the hooks up where the run that failed left off
+            match state.progress()? {
+                Some((sha, completed)) if opts.replay && sha == data.sha256() => {
+                    run.resume(completed)
+                }
+                _ => state.begin_hooks(data.sha256()).wrap_err("Unable to update state file")?,
+            }
+            // Malformed data never reaches the hooks
+            let res = config
+                .validate(&data)
+                .wrap_err("Provider data failed validation, no hooks were run")
+                .and_then(|_| run_pipeline(config, &data, state, &tracer, &run));
+            if res.is_ok() {
+                state.end_hooks().wrap_err("Unable to update state file")?;
+                if uses_previous {
+                    state.record_applied_data(data.raw()).wrap_err("Unable to update state file")?;
+                }
+            }
+            if res.is_err() && config.settings.keep_failed_workspace.unwrap_or(false) {
+                workspace.keep();
+                info!("Kept the workspace of the failed run, {}", workspace.path().display());
+            }
+            (Some(data), res)
+        }
+        Ok(None) => (None, Ok(())),
+        Err(e) => (None, Err(e)),
+    };
+
+    // The lease is let go once the hooks passed, health checks included.  A
+    // host they failed on holds the rollout back until its lease_ttl is over.
+    if let Some(lease) = lease {
+        match &res {
+            Ok(()) => {
+                if let Err(e) = lease.release() {
+                    warning!("{:#}", e);
+                }
+            }
+            Err(_) => warning!("keeping the rollout lease {}, the hooks failed", lease.key()),
+        }
+    }
+
+    // Report the outcome, failing to do so should not fail the run
+    let report = run.finish(&res);
+    if let Err(e) = state.record_run(&report) {
+        warning!("unable to record the run: {}", e);
+    }
+    emit_outcome(config, file, data.as_ref(), &res);
+    if let Some(data) = &data {
+        if let Err(e) = config.provider.report_apply(data, &res) {
+            warning!("{:#}", e);
+        }
+    }
+
+    if let Err(e) = notify_on_error(config, state, &res) {
+        warning!("{:#}", e);
+    }
+
+    let error = res.as_ref().err().map(|e| format!("{:#}", e));
+    if let (Some(reporter), Some(error)) = (&reporter, &error) {
+        if let Err(e) = reporter.report("error", error) {
+            warning!("{}", e);
+        }
+    }
+    if let Err(e) = tracer.export("check", error) {
+        warning!("{}", e);
+    }
+
+    res
+}
+
+
+/// Leave out the leader_only hooks of <config>, unless this host leads its
+/// replicas.  The election failing, or being <offline>, makes it a replica
+/// like the others, the hooks applying the data locally still run.
+/// The hooks left out are put back once the check is over, the host may
+/// lead at the next one.
+fn elect(config: &mut Config, state: &State, offline: bool) -> LeftOut {
+    let coordinator = match &config.coordinator {
+        Some(coordinator) => coordinator,
+        None => return LeftOut::default(),
+    };
+    let hooks = || config.hooks.iter().chain(&config.pre_hooks).chain(&config.post_hooks);
+    if !hooks().any(|hook| hook.leader_only()) {
+        return LeftOut::default();
+    }
+
+    let leader = match offline {
+        true => false,
+        false => {
+            let elected = state
+                .session("leader")
+                .map_err(eyre::Report::from)
+                .and_then(|session| coordinator.lead(session.as_deref()));
+            match elected {
+                Ok((session, leader)) => {
+                    if let Err(e) = state.record_session("leader", &session) {
+                        warning!("unable to keep the leader session: {}", e);
+                    }
+                    leader
+                }
+                Err(e) => {
+                    warning!("no leader election, leaving out leader_only hooks: {:#}", e);
+                    false
+                }
+            }
+        }
+    };
+    match leader {
+        true => LeftOut::default(),
+        false => LeftOut {
+            hooks: LeftOut::take(&mut config.hooks),
+            pre_hooks: LeftOut::take(&mut config.pre_hooks),
+            post_hooks: LeftOut::take(&mut config.post_hooks),
+        },
+    }
+}
+
+
+/// The leader_only hooks elect() left out, each with the place it had
+#[derive(Default)]
+struct LeftOut {
+    hooks: Vec<(usize, Box<dyn Hook>)>,
+    pre_hooks: Vec<(usize, Box<dyn Hook>)>,
+    post_hooks: Vec<(usize, Box<dyn Hook>)>,
+}
+
+impl LeftOut {
+    /// Take the leader_only hooks out of <hooks>
+    fn take(hooks: &mut Vec<Box<dyn Hook>>) -> Vec<(usize, Box<dyn Hook>)> {
+        let mut taken = Vec::new();
+        for (i, hook) in std::mem::take(hooks).into_iter().enumerate() {
+            match hook.leader_only() {
+                true => taken.push((i, hook)),
+                false => hooks.push(hook),
+            }
+        }
+        taken
+    }
+
+    /// Put the hooks back in <config>, where they were
+    fn put_back(self, config: &mut Config) {
+        let lists = vec![
+            (&mut config.hooks, self.hooks),
+            (&mut config.pre_hooks, self.pre_hooks),
+            (&mut config.post_hooks, self.post_hooks),
+        ];
+        for (hooks, taken) in lists {
+            for (i, hook) in taken {
+                hooks.insert(i, hook);
+            }
+        }
+    }
+}
+
+
+/// Why new data has to wait before it is applied, if it has to, along with
+/// what the run reports: changes are frozen, wait for approval unless
+/// <approved>, no maintenance window is open, or data was last applied less
+/// than min_apply_interval ago
+fn hold(
+    config: &Config,
+    state: &State,
+    approved: bool,
+) -> eyre::Result<Option<(&'static str, String)>> {
+    if let Some((time, reason)) = state.frozen()? {
+        let reason = format!("changes are frozen since {}: {}", time, reason);
+        return Ok(Some(("deferred, frozen", reason)));
+    }
+    if config.settings.require_approval.unwrap_or(false) && !approved {
+        let reason = "changes wait for app_config approve".to_string();
+        return Ok(Some(("deferred, awaiting approval", reason)));
+    }
+    let now = Utc::now();
+    if !config.windows.is_empty() && !config.windows.iter().any(|w| w.is_open(now)) {
+        let reason = "no maintenance window is open".to_string();
+        return Ok(Some(("deferred, maintenance window", reason)));
+    }
+    if let Some(wait) = apply_wait(config.min_apply_interval, state)? {
+        let reason = format!("{:?} left of min_apply_interval", wait);
+        return Ok(Some(("deferred, min_apply_interval", reason)));
+    }
+    Ok(None)
+}
+
+
+/// How soon a change left waiting may be applied, unless changes are
+/// frozen: once min_apply_interval is over and one of <windows> is open
+fn apply_due(
+    min_interval: Option<Duration>,
+    windows: &[Window],
+    state: &State,
+) -> eyre::Result<Option<Duration>> {
+    if state.frozen()?.is_some() {
+        return Ok(None);
+    }
+    let now = Utc::now();
+    let window_wait = match windows.iter().any(|w| w.is_open(now)) || windows.is_empty() {
+        true => None,
+        false => match windows.iter().filter_map(|w| w.next_open(now)).min() {
+            Some(next_open) => Some((next_open - now).to_std().unwrap_or_default()),
+            // Not within sight, the regular interval will do
+            None => return Ok(None),
+        },
+    };
+    let wait = apply_wait(min_interval, state)?;
+    Ok(wait.max(window_wait))
+}
+
+
+/// How long new data has to wait before it is applied, if it has to: data
+/// was last applied less than <min_interval> ago
+fn apply_wait(min_interval: Option<Duration>, state: &State) -> eyre::Result<Option<Duration>> {
+    let (min_interval, last_apply) = match (min_interval, state.applies()?.0) {
+        (Some(min_interval), Some(last_apply)) => (min_interval, last_apply),
+        _ => return Ok(None),
+    };
+    let elapsed = (Utc::now() - last_apply).to_std().unwrap_or_default();
+    Ok(min_interval.checked_sub(elapsed).filter(|wait| *wait > Duration::from_secs(0)))
+}
+
+
+/// Why the provider must not be polled now, if it must not: it was polled
+/// less than settings.min_poll_interval ago, or settings.daily_poll_budget
+/// polls were made today already
+fn cost_guard(config: &Config, state: &State) -> eyre::Result<Option<String>> {
+    let (last_poll, polls_today) = state.polls()?;
+
+    if let (Some(min_interval), Some(last_poll)) = (config.min_poll_interval, last_poll) {
+        let elapsed = (Utc::now() - last_poll).to_std().unwrap_or_default();
+        if elapsed < min_interval {
+            return Ok(Some(format!(
+                "last polled at {}, min_poll_interval is {:?}",
+                last_poll.to_rfc3339(),
+                min_interval
+            )));
+        }
+    }
+
+    match config.settings.daily_poll_budget {
+        Some(budget) if polls_today >= budget => Ok(Some(format!(
+            "daily_poll_budget of {} polls is used up",
+            budget
+        ))),
+        _ => Ok(None),
+    }
+}
+
+
+/// Check the files the hooks rendered from the cached data against their
+/// checksum sidecars, logging any that were changed by hand since.  With
+/// <on_drift> Rerender the cached data is returned, to render them again.
+fn check_drift(
+    config: &Config,
+    state: &State,
+    on_drift: OnDrift,
+) -> eyre::Result<Option<ConfigData>> {
+    let data = cached_data(config)?;
+    let elements = config.for_each(&data)?;
+    let elements = match &elements {
+        None => vec![&data],
+        Some(elements) => elements.iter().collect(),
+    };
+
+    let mut drifted = Vec::new();
+    for element in elements {
+        for hook in &config.hooks {
+            drifted.extend(hook.drifted(element)?);
+        }
+    }
+    if drifted.is_empty() {
+        return Ok(None);
+    }
+
+    let files = drifted.join(", ");
+    let entry = AuditEntry::new(
+        "drift",
+        "checksum",
+        "drifted",
+        &files,
+        Some(data.sha256().to_string()),
+        Duration::default(),
+    );
+    state.audit(&entry).wrap_err("Unable to write audit log")?;
+    match on_drift {
+        OnDrift::Report => {
+            warning!("changed since they were rendered: {}", files);
+            Ok(None)
+        }
+        OnDrift::Rerender => {
+            warning!("changed since they were rendered, rendering again: {}", files);
+            Ok(Some(data))
+        }
+    }
+}
+
+
+/// The provider's cached data, for runs that do not reach it
+fn cached_data(config: &Config) -> eyre::Result<ConfigData> {
+    let provider = &config.provider;
+    let data = provider.query().wrap_err("Unable to read cached data")?;
+    if data.is_empty() {
+        return Err(eyre::eyre!("There is no cached data to apply"));
+    }
+    decoded(config, data).wrap_err("Unable to decode cached data")
+}
+
+/// The <raw> data of the provider of <config> run through its decode chain,
+/// no larger than settings.max_payload_size once decoded.  Data larger than
+/// settings.spool_payload_size is decoded straight into a spool file, unless
+/// it is sensitive, which is never written to disk.
+fn decoded(config: &Config, raw: Vec<u8>) -> eyre::Result<ConfigData> {
+    let provider = &config.provider;
+    let threshold = config.settings.spool_payload_size.filter(|_| !config.sensitive());
+    let mut spool = Spool::new(threshold);
+    decode::decode_to(&config.decode, raw, config.settings.max_payload_size, &mut spool)?;
+    let data = ConfigData::spooled(spool, provider.kind(), provider.version())?
+        .with_version_info(provider.version_info());
+    Ok(data.sensitive(config.sensitive()))
+}
+
+/// The cached data, for runs that must not reach the provider.  Providers
+/// keeping no data in the state file, only versions, have none to give.
+fn offline_data(config: &Config) -> eyre::Result<ConfigData> {
+    if !config.provider.caches_data() {
+        return Err(eyre::eyre!(
+            "{} keeps no data in the state file, it can not be applied without reaching it",
+            config.provider.kind()
+        ));
+    }
+    cached_data(config)
+}
+
+/// <data> from the provider, unless it is larger than settings.max_payload_size
+fn within_limit(config: &Config, data: Vec<u8>) -> eyre::Result<Vec<u8>> {
+    match config.settings.max_payload_size {
+        Some(max) if data.len() > max => Err(PayloadTooLarge {
+            provider: config.provider.kind(),
+            size: data.len(),
+            max,
+        }
+        .into()),
+        _ => Ok(data),
+    }
+}
+
+/// <data> just polled, unless it is the same as the data polled before
+/// once config.normalize is applied to both
+fn normalized(
+    config: &Config,
+    state: &State,
+    data: ConfigData,
+) -> eyre::Result<Option<ConfigData>> {
+    let source_type = config.settings.source_type.clone().unwrap_or(DataType::YAML);
+    let fingerprint = normalize::fingerprint(&config.normalize, &source_type, data.raw())?;
+    if state.fingerprint()?.as_deref() == Some(fingerprint.as_str()) {
+        info!("Only what normalize leaves out changed, the data is taken as unchanged");
+        return Ok(None);
+    }
+    state.record_fingerprint(&fingerprint).wrap_err("Unable to update state file")?;
+    Ok(Some(data))
+}
+
+/// The cached data, standing in for a provider that failed with <error>, as
+/// long as the provider was last reached within <allow_stale>
+fn stale_data(
+    config: &Config,
+    state: &State,
+    allow_stale: Duration,
+    error: eyre::Report,
+) -> eyre::Result<ConfigData> {
+    let last_contact = match state.last_contact()? {
+        Some(last_contact) => last_contact,
+        None => {
+            return Err(error.wrap_err("The provider was never reached, no data to fall back on"))
+        }
+    };
+
+    let age = (Utc::now() - last_contact).to_std().unwrap_or_default();
+    if age > allow_stale {
+        return Err(error.wrap_err(format!(
+            "Cached data from {} is older than allow_stale",
+            last_contact.to_rfc3339()
+        )));
+    }
+
+    let data = match offline_data(config) {
+        Ok(data) => data,
+        Err(e) => return Err(error.wrap_err(format!("{:#}", e))),
+    };
+    warning!(
+        "using cached data from {}: {:#}",
+        last_contact.to_rfc3339(),
+        error
+    );
+    Ok(data)
+}
+
+
+/// Run the hooks on <data>, between the pre_hooks and the post_hooks.
+/// The post_hooks run whatever happened before them, e.g. to put a service
+/// back in its load balancer, though the first error is the one returned.
+fn run_pipeline(
+    config: &Config,
+    data: &ConfigData,
+    state: &State,
+    tracer: &Tracer,
+    run: &Run,
+) -> eyre::Result<()> {
+    let res = run_hooks(&config.pre_hooks, "pre_hook", None, data, state, tracer, run)
+        .wrap_err("Error running pre_hooks")
+        .and_then(|_| run_main_hooks(config, data, state, tracer, run));
+
+    let post = run_hooks(&config.post_hooks, "post_hook", None, data, state, tracer, run)
+        .wrap_err("Error running post_hooks");
+    match (res, post) {
+        (Err(e), Err(post)) => {
+            warning!("{:#}", post);
+            Err(e)
+        }
+        (res, post) => res.and(post),
+    }
+}
+
+
+/// Run the hooks on <data>, or with settings.for_each once on each of its
+/// elements in turn.  Stops at the first element the hooks fail on.
+fn run_main_hooks(
+    config: &Config,
+    data: &ConfigData,
+    state: &State,
+    tracer: &Tracer,
+    run: &Run,
+) -> eyre::Result<()> {
+    let elements = match config.for_each(data)? {
+        None => return run_hooks(&config.hooks, "hook", None, data, state, tracer, run),
+        Some(elements) => elements,
+    };
+
+    let count = elements.len();
+    for (i, element) in elements.iter().enumerate() {
+        run_hooks(&config.hooks, "hook", Some(i), element, state, tracer, run)
+            .wrap_err_with(|| format!("Hooks failed on element {} of {}", i + 1, count))?;
+    }
+    Ok(())
+}
+
+
+/// We have data, let's run each of the hooks in order, logging each as an
+/// <event> in the audit log, the trace and the run report.  With
+/// settings.for_each, the data is the <element> of that index.
+/// Stops at the first hook that fails.  The hooks that complete are kept in
+/// the state file, those of a run resuming a failed one are not run again.
+/// The post_hooks are not kept, they follow whatever hooks ran.
+fn run_hooks(
+    hooks: &[Box<dyn Hook>],
+    event: &str,
+    element: Option<usize>,
+    data: &ConfigData,
+    state: &State,
+    tracer: &Tracer,
+    run: &Run,
+) -> eyre::Result<()> {
+    let sha = data.sha256().to_string();
+    for (i, hook) in hooks.iter().enumerate() {
+        let step = match element {
+            None => format!("{} {}", event, i + 1),
+            Some(element) => format!("{} {} of element {}", event, i + 1, element + 1),
+        };
+        if run.completed(&step) {
+            info!("{} completed before, not running it again", hook.name());
+            run.hook(event, hook.kind(), "skipped", Duration::default(), None, "completed");
+            continue;
+        }
+
+        let start_time = SystemTime::now();
+        let started = Instant::now();
+        let res = hook.run(data);
+
+        let (status, detail) = match &res {
+            Ok(()) => ("ok", String::new()),
+            Err(e) => ("error", format!("{:#}", e)),
+        };
+        let entry = AuditEntry::new(
+            event,
+            hook.kind(),
+            status,
+            &detail,
+            Some(sha.clone()),
+            started.elapsed(),
+        );
+        state.audit(&entry).wrap_err("Unable to write audit log")?;
+        let output = res.as_ref().ok().and_then(|_| hook.output_sha256());
+        run.hook(event, hook.kind(), status, started.elapsed(), output, &detail);
+        data.record_outcome(hook.name(), status);
+        tracer.record(
+            event,
+            start_time,
+            started.elapsed(),
+            vec![
+                ("hook.kind", hook.kind().to_string()),
+                ("data.sha256", sha.clone()),
+                ("data.provider", data.provider().to_string()),
+                ("data.version", data.version().unwrap_or("-").to_string()),
+                ("data.received", data.received().to_rfc3339()),
+            ],
+            res.as_ref().err().map(|e| format!("{:#}", e)),
+        );
+
+        res.wrap_err(HookFailed)?;
+        let files: Vec<String> = hook.files(data)?.iter().map(|file| absolute(file)).collect();
+        state.record_files(&files).wrap_err("Unable to update state file")?;
+        if event != "post_hook" {
+            state.record_completed(&step).wrap_err("Unable to update state file")?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Send the outcome of the run of <file> to CloudWatch, if configured to
+#[cfg(feature = "aws")]
+fn emit_outcome(config: &Config, file: &str, data: Option<&ConfigData>, res: &eyre::Result<()>) {
+    if let Some(cw) = &config.settings.cloudwatch {
+        let outcome = RunOutcome {
+            config: file.to_string(),
+            applied: data.is_some(),
+            sha256: data.map(|d| d.sha256().to_string()),
+            error: res.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        if let Err(e) = cw.convert().emit(&outcome) {
+            warning!("{}", e);
+        }
+    }
+}
+
+/// Builds without the aws feature have no CloudWatch to send it to
+#[cfg(not(feature = "aws"))]
+fn emit_outcome(_: &Config, _: &str, _: Option<&ConfigData>, _: &eyre::Result<()>) {}
+
+
+/// Keep count of consecutive failed runs.  Once settings.failure_threshold
+/// is reached the on_error hooks get the error, and when a run succeeds
+/// after that they are told to resolve it.
+fn notify_on_error(config: &Config, state: &State, res: &eyre::Result<()>) -> eyre::Result<()> {
+    if config.on_error.is_empty() {
+        return Ok(());
+    }
+    let threshold = config.settings.failure_threshold.unwrap_or(1);
+
+    match res {
+        Err(e) => {
+            let failures = state.record_failure()?;
+            if failures >= threshold {
+                let msg = ConfigData::new(format!("{:#}", e), config.provider.kind(), None);
+                for hook in &config.on_error {
+                    hook.run(&msg).wrap_err("Error running on_error hook")?;
+                }
+            }
+        }
+        Ok(()) => {
+            let failures = state.reset_failures()?;
+            if failures >= threshold {
+                for hook in &config.on_error {
+                    hook.resolve().wrap_err("Error resolving on_error hook")?;
+                }
+            }
         }
     }
     Ok(())
@@ -54,12 +1293,340 @@ fn check_for_updates(matches: &ArgMatches) -> eyre::Result<()> {
 
 
 /// Check local cache and print out the latest
-/// version of the data we have
-fn query_data(matches: &ArgMatches) -> eyre::Result<()> {
-    let file = matches.value_of("FILE").unwrap();
+/// version of the data we have, or with --path and --format the values in
+/// it that scripts are after, in the format they want
+fn query_data(file: &str, args: &QueryArgs) -> eyre::Result<()> {
     let config = Config::from_file(file);
+    if args.refresh {
+        refresh(&config, args.max_age)?;
+    }
 
+    // Written as is, the data need not be text
     let data = config.provider.query()?;
-    println!("{}", data);
+    let data = decode::decode(&config.decode, data, config.settings.max_payload_size)
+        .wrap_err("Unable to decode cached data")?;
+    if args.format.is_none() && args.path.is_none() {
+        std::io::stdout().write_all(&data)?;
+        return Ok(());
+    }
+
+    let source_type = config.settings.source_type.clone().unwrap_or(DataType::YAML);
+    let data = ConfigData::new(data, config.provider.kind(), config.provider.version())
+        .sensitive(config.sensitive());
+    let value = data
+        .parsed(&source_type)
+        .wrap_err_with(|| format!("Cached data is not valid {:?}", source_type))?;
+
+    // Each value selected is printed in turn.  Without a format, strings and
+    // other scalars are printed bare and anything else as json.
+    let mut stdout = std::io::stdout();
+    for value in path::select(&value, args.path.as_deref().unwrap_or("."))? {
+        let text = match (args.format, value) {
+            (None, serde_yaml::Value::String(s)) => s,
+            (None, value @ serde_yaml::Value::Sequence(_))
+            | (None, value @ serde_yaml::Value::Mapping(_)) => {
+                formats::serialize(&DataType::JSON, &value)?
+            }
+            (None, value) => serde_json::to_string(&value)?,
+            (Some(format), value) => formats::serialize(&format.data_type(), &value)?,
+        };
+        stdout.write_all(text.as_bytes())?;
+        if !text.ends_with('\n') {
+            stdout.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Poll the provider of <config> if nothing is cached yet, or it was last
+/// reached longer than <max_age> ago, so query has data to print.  The
+/// hooks never saw data polled this way, it is left pending for the next
+/// check to apply, unless it is the first data and settings.bootstrap
+/// says not to.
+fn refresh(config: &Config, max_age: Option<Duration>) -> eyre::Result<()> {
+    http::configure(&config.settings.http.clone().unwrap_or_default());
+    #[cfg(feature = "aws")]
+    credentials::configure(&config.settings.aws_credentials.clone().unwrap_or_default());
+
+    let _lock = RunLock::acquire(&config.settings.state_file, &config.name())?;
+    let state = State::new(
+        &config.settings.state_file,
+        &config.name(),
+        config.settings.audit.unwrap_or(false),
+    );
+    let last_contact = state.last_contact()?;
+    let stale = match (last_contact, max_age) {
+        (None, _) => true,
+        (Some(time), Some(max_age)) => (Utc::now() - time).to_std().unwrap_or_default() > max_age,
+        (Some(_), None) => false,
+    };
+    let empty = config.provider.query().map(|data| data.is_empty()).unwrap_or(true);
+    if !empty && !stale {
+        return Ok(());
+    }
+    if let Some(reason) = cost_guard(config, &state)? {
+        warning!("not refreshing the cache: {}", reason);
+        return Ok(());
+    }
+
+    state.record_poll().wrap_err("Unable to update state file")?;
+    let polled = config.provider.poll().wrap_err("Unable to refresh the cache")?;
+    state.record_contact().wrap_err("Unable to update state file")?;
+    let skip_first = last_contact.is_none() && !config.settings.bootstrap.unwrap_or(true);
+    if polled.is_some() && !skip_first {
+        state.record_pending().wrap_err("Unable to update state file")?;
+    }
+    Ok(())
+}
+
+
+/// Print the cached data as `export NAME=value` lines, for init scripts to
+/// eval.  The data is parsed as settings.source_type.
+fn export_data(file: &str, args: &ExportArgs) -> eyre::Result<()> {
+    let config = Config::from_file(file);
+
+    let source_type = config.settings.source_type.clone().unwrap_or(DataType::YAML);
+    let value = offline_data(&config)?
+        .parsed(&source_type)
+        .wrap_err_with(|| format!("Cached data is not valid {:?}", source_type))?;
+    for line in export::exports(&value, &args.prefix)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+
+/// Print the latest entries of the audit log kept in settings.state_file
+fn print_audit_log(file: &str, args: &AuditArgs) -> eyre::Result<()> {
+    let config = Config::from_file(file);
+
+    if config.settings.state_file.is_none() {
+        eprintln!("Error, the audit log requires a settings.state_file");
+        std::process::exit(exitcode::CONFIG);
+    }
+    let state = State::new(&config.settings.state_file, &config.name(), true);
+    for entry in state.audit_log(args.limit)? {
+        if args.json {
+            println!("{}", serde_json::to_string(&entry)?);
+        } else {
+            println!(
+                "{}  {:<5} {:<12} {:<6} {:>6}ms  {}  {}",
+                entry.time,
+                entry.event,
+                entry.target,
+                entry.status,
+                entry.duration_ms,
+                entry.sha256.as_deref().unwrap_or("-"),
+                entry.detail
+            );
+        }
+    }
+    Ok(())
+}
+
+
+/// Print the report of the latest run kept in settings.state_file
+fn print_report(file: &str, args: &ReportArgs) -> eyre::Result<()> {
+    let config = Config::from_file(file);
+
+    if config.settings.state_file.is_none() {
+        eprintln!("Error, run reports require a settings.state_file");
+        std::process::exit(exitcode::CONFIG);
+    }
+
+    let state = State::new(&config.settings.state_file, &config.name(), false);
+    let report = match state.latest_run()? {
+        Some(report) => report,
+        None => return Err(eyre::eyre!("{} has not run yet", config.name())),
+    };
+    if args.json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
+
+
+/// The state of the pipeline in <config>, which has to be kept in a
+/// settings.state_file for <what> to last
+fn kept_state(config: &Config, what: &str) -> State {
+    if config.settings.state_file.is_none() {
+        eprintln!("Error, {} requires a settings.state_file", what);
+        std::process::exit(exitcode::CONFIG);
+    }
+    pipeline_state(config)
+}
+
+
+/// The state of the pipeline in <config>, kept in settings.state_file if it
+/// names one
+fn pipeline_state(config: &Config) -> State {
+    State::new(&config.settings.state_file, &config.name(), config.settings.audit.unwrap_or(false))
+}
+
+
+/// <path> made absolute, relative to the current directory, for the files
+/// hooks write to be told apart wherever app_config is run from
+fn absolute(path: &str) -> String {
+    match std::env::current_dir() {
+        Ok(dir) => dir.join(path).to_string_lossy().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+
+/// Freeze changes to the pipelines in <files>: they are polled and cached
+/// as usual, but not applied until thawed
+fn freeze_pipelines(files: &[String], args: &FreezeArgs) -> eyre::Result<()> {
+    for file in files {
+        let config = Config::from_file(file);
+        let state = kept_state(&config, "a freeze");
+        state.freeze(&args.reason)?;
+        let entry =
+            AuditEntry::new("freeze", "pipeline", "ok", &args.reason, None, Duration::default());
+        state.audit(&entry)?;
+        info!("Froze {}", config.name());
+    }
+    Ok(())
+}
+
+
+/// Thaw the pipelines in <files>, changes held back are applied on their
+/// next check
+fn thaw_pipelines(files: &[String]) -> eyre::Result<()> {
+    for file in files {
+        let config = Config::from_file(file);
+        let state = kept_state(&config, "a freeze");
+        if !state.thaw()? {
+            info!("{} was not frozen", config.name());
+            continue;
+        }
+        let entry = AuditEntry::new("thaw", "pipeline", "ok", "", None, Duration::default());
+        state.audit(&entry)?;
+        info!("Thawed {}", config.name());
+    }
+    Ok(())
+}
+
+
+/// Apply the change to the pipeline in <file> that waits for approval, from
+/// the cache, if it is the version given
+fn approve_change(file: &str, args: &ApproveArgs) -> eyre::Result<()> {
+    let mut config = Config::from_file(file);
+    let state = kept_state(&config, "an approval");
+    if !state.applies()?.1 {
+        return Err(eyre::eyre!("{} has no change waiting for approval", config.name()));
+    }
+    let version = config.provider.version();
+    if let Some(approved) = &args.version {
+        if version.as_ref() != Some(approved) {
+            return Err(eyre::eyre!(
+                "version {} is not the one waiting, {} is",
+                approved,
+                version.as_deref().unwrap_or("one without a version")
+            ));
+        }
+    }
+
+    // Approving a change does not lift a freeze, nor open a window
+    if let Some((_, reason)) = hold(&config, &state, true)? {
+        return Err(eyre::eyre!("the change can not be applied now, {}", reason));
+    }
+
+    let sha = Some(cached_data(&config)?.sha256().to_string());
+    let detail = version.map(|v| format!("version {}", v)).unwrap_or_default();
+    let entry = AuditEntry::new("approve", "pipeline", "ok", &detail, sha, Duration::default());
+    state.audit(&entry)?;
+
+    // The waiting change is the cached data, applied as check --offline does
+    let opts = CheckOptions {
+        offline: true,
+        approve: true,
+        ..CheckOptions::default()
+    };
+    check_config(file, &mut config, &state, &opts)
+}
+
+
+/// Resume the run of the pipeline in <file> that failed part way, on the
+/// cached data, running only the hooks that did not complete.  Hooks are
+/// expected to be idempotent, see Hook, the one that failed runs again.
+fn replay_run(file: &str) -> eyre::Result<()> {
+    let mut config = Config::from_file(file);
+    let state = kept_state(&config, "a replay");
+    let (sha, completed) = match state.progress()? {
+        Some(progress) => progress,
+        None => return Err(eyre::eyre!("{} has no failed run to resume", config.name())),
+    };
+    if offline_data(&config)?.sha256() != sha {
+        return Err(eyre::eyre!(
+            "the cached data is not what the failed run applied, check --offline applies it"
+        ));
+    }
+
+    let detail = format!("{} hooks completed before", completed.len());
+    let entry =
+        AuditEntry::new("replay", "pipeline", "ok", &detail, Some(sha), Duration::default());
+    state.audit(&entry)?;
+
+    let opts = CheckOptions {
+        offline: true,
+        replay: true,
+        ..CheckOptions::default()
+    };
+    check_config(file, &mut config, &state, &opts)
+}
+
+
+/// Remove the files the hooks of the pipelines in <files> wrote, that they
+/// no longer write for the cached data, along with their checksums
+fn cleanup_files(files: &[String], args: &CleanupArgs) -> eyre::Result<()> {
+    for file in files {
+        let config = Config::from_file(file);
+        let state = kept_state(&config, "a cleanup");
+        let data = cached_data(&config)?;
+
+        // As check does, the pre_hooks and post_hooks get the data, the
+        // hooks each element of it with settings.for_each
+        let for_each = config.for_each(&data)?;
+        let elements: Vec<&ConfigData> = match &for_each {
+            None => vec![&data],
+            Some(elements) => elements.iter().collect(),
+        };
+        let mut written = HashSet::new();
+        for hook in config.pre_hooks.iter().chain(config.post_hooks.iter()) {
+            written.extend(hook.files(&data)?.iter().map(|file| absolute(file)));
+        }
+        for element in elements {
+            for hook in &config.hooks {
+                written.extend(hook.files(element)?.iter().map(|file| absolute(file)));
+            }
+        }
+
+        let orphans: Vec<String> =
+            state.files()?.into_iter().filter(|file| !written.contains(file)).collect();
+        if args.dry_run {
+            for orphan in &orphans {
+                println!("{}", orphan);
+            }
+            continue;
+        }
+        for orphan in &orphans {
+            match std::fs::remove_file(orphan) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).wrap_err_with(|| format!("Could not remove {}", orphan));
+                }
+                _ => checksum::remove(orphan)?,
+            }
+            info!("Removed {}", orphan);
+        }
+        state.forget_files(&orphans)?;
+        let detail = format!("removed {} files", orphans.len());
+        let entry =
+            AuditEntry::new("cleanup", "pipeline", "ok", &detail, None, Duration::default());
+        state.audit(&entry)?;
+    }
     Ok(())
 }
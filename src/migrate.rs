@@ -0,0 +1,211 @@
+use eyre::{eyre, Result, WrapErr};
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Version of the layout of state files this build reads and writes
+pub const SCHEMA_VERSION: i64 = 3;
+
+/// A step from one version of the layout to the next.  Steps that hand
+/// state over to a pipeline give it to the one opening the file.
+type Migration = fn(&Connection, &str) -> rusqlite::Result<()>;
+
+/// Every step, MIGRATIONS[n] upgrades a file from version n + 1 to n + 2
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("key rows by pipeline", key_by_pipeline),
+    ("param_store keeps versions", param_store_versions),
+];
+
+/// Upgrade the state file behind <db_conn> to SCHEMA_VERSION, on behalf of
+/// <pipeline>.  New files are at the current version from the start, files
+/// written before versions were recorded are at version 1.
+/// Files written by a newer version are left alone, and are an error.
+pub fn migrate(db_conn: &Connection, pipeline: &str) -> Result<()> {
+    immediate(db_conn, || {
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                id      INTEGER PRIMARY KEY,
+                version INTEGER NOT NULL
+                )",
+            params![],
+        )?;
+        let version: Option<i64> = db_conn
+            .query_row("SELECT version FROM schema_version WHERE id=0", params![], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        let version = match version {
+            Some(version) => version,
+            None if table_names(db_conn)?.len() > 1 => 1,
+            None => SCHEMA_VERSION,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(eyre!(
+                "State file is at version {}, this build only knows up to version {}",
+                version,
+                SCHEMA_VERSION
+            ));
+        }
+
+        for (n, (name, migration)) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+            migration(db_conn, pipeline)
+                .wrap_err_with(|| format!("Migration to version {} ({}) failed", n + 2, name))?;
+        }
+
+        db_conn.execute(
+            "INSERT OR REPLACE INTO schema_version (id, version) VALUES (0, ?1)",
+            params![SCHEMA_VERSION],
+        )?;
+        Ok(())
+    })
+    .wrap_err("Unable to upgrade the state file")
+}
+
+/// Run <f> in a transaction holding the write lock from the start, so
+/// pipelines sharing the state file do not both upgrade it at once
+fn immediate<F>(db_conn: &Connection, f: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    db_conn.execute_batch("BEGIN IMMEDIATE;")?;
+    match f() {
+        Ok(()) => Ok(db_conn.execute_batch("COMMIT;")?),
+        Err(e) => {
+            db_conn.execute_batch("ROLLBACK;")?;
+            Err(e)
+        }
+    }
+}
+
+fn table_names(db_conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db_conn.prepare("SELECT name FROM sqlite_master WHERE type='table'")?;
+    let names = stmt.query_map(params![], |row| row.get(0))?;
+    names.collect()
+}
+
+/// Names of the columns of <table>, none if it does not exist
+fn table_columns(db_conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db_conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map(params![], |row| row.get(1))?;
+    columns.collect()
+}
+
+
+// // // // // // // // // // Migrations // // // // // // // // // //
+
+/// 1 -> 2: Rows were kept with id 0, as a state file could only serve one
+/// pipeline.  They now are keyed by pipeline, so one file can serve many.
+fn key_by_pipeline(db_conn: &Connection, pipeline: &str) -> rusqlite::Result<()> {
+    let tables = [
+        ("appConfig", "version INTEGER NOT NULL, data TEXT NOT NULL", "version, data"),
+        ("param_store", "data TEXT NOT NULL", "data"),
+        ("exec", "version TEXT, data TEXT NOT NULL", "version, data"),
+        ("failures", "count INTEGER NOT NULL", "count"),
+        ("contact", "time TEXT NOT NULL", "time"),
+    ];
+    for (table, layout, columns) in tables.iter() {
+        if !table_columns(db_conn, table)?.iter().any(|c| c == "id") {
+            continue;
+        }
+        db_conn.execute_batch(&format!(
+            "ALTER TABLE {0} RENAME TO {0}_old;
+            CREATE TABLE {0} (pipeline TEXT PRIMARY KEY, {1});",
+            table, layout
+        ))?;
+        db_conn.execute(
+            &format!(
+                "INSERT INTO {0} (pipeline, {1}) SELECT ?1, {1} FROM {0}_old WHERE id=0",
+                table, columns
+            ),
+            params![pipeline],
+        )?;
+        db_conn.execute_batch(&format!("DROP TABLE {}_old;", table))?;
+    }
+
+    let audit = table_columns(db_conn, "audit")?;
+    if !audit.is_empty() && !audit.iter().any(|c| c == "pipeline") {
+        db_conn.execute("ALTER TABLE audit ADD COLUMN pipeline TEXT", params![])?;
+        db_conn.execute("UPDATE audit SET pipeline = ?1", params![pipeline])?;
+    }
+    Ok(())
+}
+
+/// 2 -> 3: param_store kept the values of its parameters, secrets more often
+/// than not.  It keeps their versions instead, in a column of their own.
+/// The values are dropped, and overwritten on disk, so the first poll after
+/// is a change.
+fn param_store_versions(db_conn: &Connection, _pipeline: &str) -> rusqlite::Result<()> {
+    if !table_columns(db_conn, "param_store")?.iter().any(|c| c == "data") {
+        return Ok(());
+    }
+    db_conn.execute_batch(
+        "PRAGMA secure_delete = ON;
+        ALTER TABLE param_store RENAME TO param_store_old;
+        CREATE TABLE param_store (pipeline TEXT PRIMARY KEY, versions TEXT NOT NULL);
+        INSERT INTO param_store (pipeline, versions) SELECT pipeline, '' FROM param_store_old;
+        DROP TABLE param_store_old;",
+    )
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn version(db_conn: &Connection) -> i64 {
+        db_conn
+            .query_row("SELECT version FROM schema_version WHERE id=0", params![], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_new_file() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn, "web").unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+
+        // Already up to date, nothing to do
+        migrate(&conn, "db").unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_version_1() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE param_store (id INTEGER PRIMARY KEY, data TEXT NOT NULL);
+            INSERT INTO param_store (id, data) VALUES (0, 'hello');
+            CREATE TABLE audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT, time TEXT NOT NULL,
+                event TEXT NOT NULL, target TEXT NOT NULL, status TEXT NOT NULL,
+                detail TEXT NOT NULL, sha256 TEXT, duration_ms INTEGER NOT NULL);",
+        )
+        .unwrap();
+
+        migrate(&conn, "web").unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+
+        // The value param_store cached is gone, only versions are kept
+        let res: String = conn
+            .query_row("SELECT versions FROM param_store WHERE pipeline='web'", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(res, "");
+        assert!(table_columns(&conn, "audit").unwrap().contains(&"pipeline".to_string()));
+        assert!(!table_names(&conn).unwrap().contains(&"param_store_old".to_string()));
+    }
+
+    #[test]
+    fn test_newer_file() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE schema_version (id INTEGER PRIMARY KEY, version INTEGER NOT NULL);
+            INSERT INTO schema_version (id, version) VALUES (0, 99);",
+        )
+        .unwrap();
+
+        let res = format!("{:#}", migrate(&conn, "web").unwrap_err());
+        assert!(res.contains("State file is at version 99"), "{}", res);
+        assert_eq!(version(&conn), 99);
+    }
+}
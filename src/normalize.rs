@@ -0,0 +1,185 @@
+use crate::hooks::formats;
+use crate::hooks::sha256;
+use crate::hooks::template::DataType;
+use eyre::{eyre, Result, WrapErr};
+use serde_derive::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::convert::TryFrom;
+
+/// Normalizer:
+/// One step of `normalize = [...]` on the provider.  The steps decide what
+/// counts as a change of the data: when the data is the same once they are
+/// applied, the hooks are not run.  The hooks still get the data as it is.
+/// - sort_keys: the order of the keys of maps does not matter
+/// - strip_whitespace: whitespace around lines and values, and blank lines,
+///   do not matter
+/// - ignore_fields: [a.b, c]: the values at these dotted paths do not
+///   matter, e.g. a timestamp set on every publish
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Normalizer {
+    SortKeys,
+    StripWhitespace,
+    IgnoreFields(Vec<String>),
+}
+
+impl TryFrom<String> for Normalizer {
+    type Error = String;
+
+    fn try_from(step: String) -> std::result::Result<Normalizer, String> {
+        match step.trim() {
+            "sort_keys" => return Ok(Normalizer::SortKeys),
+            "strip_whitespace" => return Ok(Normalizer::StripWhitespace),
+            _ => {}
+        }
+        let fields = match step.split_once(':') {
+            Some((name, fields)) if name.trim() == "ignore_fields" => fields,
+            _ => return Err(format!("unknown normalize step {}", step)),
+        };
+        match serde_yaml::from_str::<Vec<String>>(fields) {
+            Ok(fields) if !fields.is_empty() => Ok(Normalizer::IgnoreFields(fields)),
+            _ => Err(format!("ignore_fields takes a list of fields, e.g. [a.b], not {}", fields)),
+        }
+    }
+}
+
+/// The fingerprint of <data>, parsed as <format>, once <chain> is applied.
+/// Data that only differs in what the chain leaves out has the same one.
+pub fn fingerprint(chain: &[Normalizer], format: &DataType, data: &[u8]) -> Result<String> {
+    let text = std::str::from_utf8(data).wrap_err("Data to normalize is not text")?;
+    let strip = chain.contains(&Normalizer::StripWhitespace);
+    let text = match strip {
+        true => strip_lines(text),
+        false => text.to_string(),
+    };
+    // Whitespace is all there is to normalize about data that is not parsed
+    if chain.iter().all(|step| *step == Normalizer::StripWhitespace) {
+        return Ok(sha256(&text));
+    }
+
+    let mut value = formats::parse(format, &text)
+        .wrap_err_with(|| format!("Data to normalize is not valid {:?}", format))?;
+    for step in chain {
+        match step {
+            Normalizer::SortKeys => value = sort_keys(value),
+            Normalizer::StripWhitespace => value = strip_values(value),
+            Normalizer::IgnoreFields(fields) => {
+                for field in fields {
+                    remove(&mut value, field);
+                }
+            }
+        }
+    }
+    let text = serde_json::to_string(&value).map_err(|e| eyre!("Unable to normalize: {}", e))?;
+    Ok(sha256(&text))
+}
+
+/// <text> without blank lines, or whitespace around lines
+fn strip_lines(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    lines.join("\n")
+}
+
+/// <value> with the keys of every map in order
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Mapping(map) => {
+            let mut entries: Vec<(Value, Value)> =
+                map.into_iter().map(|(k, v)| (k, sort_keys(v))).collect();
+            entries.sort_by(|(a, _), (b, _)| {
+                serde_json::to_string(a).ok().cmp(&serde_json::to_string(b).ok())
+            });
+            Value::Mapping(entries.into_iter().collect::<Mapping>())
+        }
+        Value::Sequence(seq) => Value::Sequence(seq.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+/// <value> with whitespace trimmed off every string in it
+fn strip_values(value: Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(s.trim().to_string()),
+        Value::Mapping(map) => {
+            Value::Mapping(map.into_iter().map(|(k, v)| (k, strip_values(v))).collect())
+        }
+        Value::Sequence(seq) => Value::Sequence(seq.into_iter().map(strip_values).collect()),
+        value => value,
+    }
+}
+
+/// Remove the value at the dotted path <field>, if there is one
+fn remove(value: &mut Value, field: &str) {
+    let (parent, key) = match field.rsplit_once('.') {
+        Some((parent, key)) => (Some(parent), key),
+        None => (None, field),
+    };
+    let mut target = value;
+    for step in parent.into_iter().flat_map(|p| p.split('.')) {
+        target = match target.get_mut(step) {
+            Some(next) => next,
+            None => return,
+        };
+    }
+    if let Value::Mapping(map) = target {
+        map.remove(&Value::from(key));
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain(steps: &[&str]) -> Vec<Normalizer> {
+        steps.iter().map(|s| Normalizer::try_from(s.to_string()).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_normalizer() {
+        assert_eq!(
+            chain(&["sort_keys", "strip_whitespace", "ignore_fields: [metadata.updatedAt, id]"]),
+            vec![
+                Normalizer::SortKeys,
+                Normalizer::StripWhitespace,
+                Normalizer::IgnoreFields(vec!["metadata.updatedAt".to_string(), "id".to_string()]),
+            ]
+        );
+        assert!(Normalizer::try_from("sort".to_string()).is_err());
+        assert!(Normalizer::try_from("ignore_fields: []".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let json = DataType::JSON;
+        let old = br#"{"port": 80, "metadata": {"updatedAt": "monday", "by": "cms"}}"#;
+        let new = br#"{"metadata": {"by": "cms ", "updatedAt": "tuesday"},
+                       "port": 80}"#;
+
+        let full = chain(&["sort_keys", "strip_whitespace", "ignore_fields: [metadata.updatedAt]"]);
+        assert_eq!(
+            fingerprint(&full, &json, old).unwrap(),
+            fingerprint(&full, &json, new).unwrap()
+        );
+
+        // Each step only leaves out what it is about
+        let no_ignore = chain(&["sort_keys", "strip_whitespace"]);
+        assert_ne!(
+            fingerprint(&no_ignore, &json, old).unwrap(),
+            fingerprint(&no_ignore, &json, new).unwrap()
+        );
+        let changed = br#"{"port": 8080, "metadata": {"updatedAt": "monday", "by": "cms"}}"#;
+        assert_ne!(
+            fingerprint(&full, &json, old).unwrap(),
+            fingerprint(&full, &json, changed).unwrap()
+        );
+
+        // Data that is not parsed only has its whitespace stripped
+        let strip = chain(&["strip_whitespace"]);
+        assert_eq!(
+            fingerprint(&strip, &DataType::YAML, b"a \n\n b").unwrap(),
+            fingerprint(&strip, &DataType::YAML, b"a\nb\n").unwrap()
+        );
+        assert!(fingerprint(&full, &json, b"not json").is_err());
+    }
+}
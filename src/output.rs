@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Stdout only ever carries what a subcommand was asked to print: the data of
+// query, the audit log, a report, or what the hooks were set up to write to
+// stdout.  Everything meant for people, warnings, progress and the output of
+// hooks, goes to stderr, so app_config can be used in pipes and scripts.
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+pub const YELLOW: &str = "33";
+
+/// Set how messages are printed for the rest of the process, from the global
+/// --quiet and --no-color.  Colors are only used on a terminal, and not when
+/// NO_COLOR is set (https://no-color.org).
+pub fn configure(quiet: bool, no_color: bool) {
+    let color =
+        !no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal();
+    QUIET.store(quiet, Ordering::Relaxed);
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Whether messages other than errors are left out
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// An error, always printed
+pub fn error(args: fmt::Arguments) {
+    eprintln!("{}, {}", paint("Error", RED), args);
+}
+
+/// A warning, unless --quiet
+pub fn warning(args: fmt::Arguments) {
+    if !quiet() {
+        eprintln!("{}, {}", paint("Warning", YELLOW), args);
+    }
+}
+
+/// Progress and other chatter, unless --quiet
+pub fn info(args: fmt::Arguments) {
+    if !quiet() {
+        eprintln!("{}", args);
+    }
+}
+
+/// <text> in the ANSI <color>, if colors are on
+pub fn paint(text: &str, color: &str) -> String {
+    ansi(text, color, COLOR.load(Ordering::Relaxed))
+}
+
+fn ansi(text: &str, color: &str, on: bool) -> String {
+    match on {
+        true => format!("\x1b[{}m{}\x1b[0m", color, text),
+        false => text.to_string(),
+    }
+}
+
+/// Print "Error, <message>" to stderr, formatted as with eprintln!
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::output::error(format_args!($($arg)*)) };
+}
+
+/// Print "Warning, <message>" to stderr unless --quiet
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::output::warning(format_args!($($arg)*)) };
+}
+
+/// Print <message> to stderr unless --quiet
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::output::info(format_args!($($arg)*)) };
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint("Error", RED), "Error");
+        assert_eq!(ansi("Error", RED, true), "\x1b[31mError\x1b[0m");
+    }
+}
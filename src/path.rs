@@ -0,0 +1,151 @@
+use eyre::{eyre, Result};
+use serde_yaml::Value;
+
+/// One step of a path, see select
+#[derive(Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    Each,
+}
+
+/// Select the values <path> points to in <value>.
+/// Paths are written jq style: `.` is the whole value, `.tenants` a key of
+/// a map, `.hosts[0]` an element of a list, and `.tenants[]` every element
+/// of a list (or every value of a map).  Only `[]` can select more than one
+/// value, e.g. `.clusters[].nodes[]` selects every node of every cluster.
+pub fn select(value: &Value, path: &str) -> Result<Vec<Value>> {
+    let mut selected = vec![value.clone()];
+    for step in parse(path)? {
+        let mut next = Vec::new();
+        for value in selected {
+            match (&step, value) {
+                (Step::Key(key), Value::Mapping(map)) => {
+                    match map.get(&Value::from(key.as_str())) {
+                        Some(v) => next.push(v.clone()),
+                        None => return Err(eyre!("Path {}: no key {} in the data", path, key)),
+                    }
+                }
+                (Step::Index(i), Value::Sequence(seq)) => match seq.get(*i) {
+                    Some(v) => next.push(v.clone()),
+                    None => return Err(eyre!("Path {}: no element {} in the data", path, i)),
+                },
+                (Step::Each, Value::Sequence(seq)) => next.extend(seq),
+                (Step::Each, Value::Mapping(map)) => next.extend(map.into_iter().map(|(_, v)| v)),
+                (step, _) => {
+                    return Err(eyre!("Path {}: can not apply {:?} to the data", path, step))
+                }
+            }
+        }
+        selected = next;
+    }
+    Ok(selected)
+}
+
+/// Split <path> into its steps
+fn parse(path: &str) -> Result<Vec<Step>> {
+    let invalid = || eyre!("Invalid path {}", path);
+    if !path.starts_with('.') {
+        return Err(invalid());
+    }
+
+    let mut steps = Vec::new();
+    let mut rest = &path[1..];
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let index = &after[..end];
+            if index.is_empty() {
+                steps.push(Step::Each);
+            } else {
+                steps.push(Step::Index(index.parse().map_err(|_| invalid())?));
+            }
+            rest = &after[end + 1..];
+        } else {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            steps.push(Step::Key(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+
+        // A step is followed by another, or by a '.' and a key
+        if let Some(after) = rest.strip_prefix('.') {
+            if after.is_empty() || after.starts_with('[') {
+                return Err(invalid());
+            }
+            rest = after;
+        }
+    }
+    Ok(steps)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen_data() -> Value {
+        serde_yaml::from_str(
+            "
+tenants:
+  - name: acme
+    hosts: [a1, a2]
+  - name: umbrella
+    hosts: [u1]
+",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(".").unwrap(), vec![]);
+        assert_eq!(
+            parse(".tenants[].hosts[0]").unwrap(),
+            vec![
+                Step::Key("tenants".to_string()),
+                Step::Each,
+                Step::Key("hosts".to_string()),
+                Step::Index(0)
+            ]
+        );
+        assert!(parse("tenants").is_err());
+        assert!(parse(".tenants[").is_err());
+        assert!(parse(".tenants[x]").is_err());
+        assert!(parse(".tenants..name").is_err());
+        assert!(parse(".tenants.").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let data = gen_data();
+
+        assert_eq!(select(&data, ".").unwrap(), vec![data.clone()]);
+
+        let res = select(&data, ".tenants[].name").unwrap();
+        assert_eq!(res, vec![Value::from("acme"), Value::from("umbrella")]);
+
+        let res = select(&data, ".tenants[].hosts[]").unwrap();
+        assert_eq!(res.len(), 3);
+
+        let res = select(&data, ".tenants[1].hosts[0]").unwrap();
+        assert_eq!(res, vec![Value::from("u1")]);
+
+        let res = select(&data, ".tenants[0][]").unwrap();
+        assert_eq!(res.len(), 2);
+    }
+
+    #[test]
+    fn test_select_errors() {
+        let data = gen_data();
+
+        let res = format!("{}", select(&data, ".clusters[]").unwrap_err());
+        assert_eq!(res, "Path .clusters[]: no key clusters in the data");
+
+        assert!(select(&data, ".tenants[2]").is_err());
+        assert!(select(&data, ".tenants.name").is_err());
+        assert!(select(&data, ".tenants[0].name[]").is_err());
+    }
+}
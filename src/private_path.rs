@@ -0,0 +1,242 @@
+use crate::sandbox::User;
+use eyre::{eyre, Result, WrapErr};
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+// Keys and the files granting access are written by app_config as root, often
+// in a directory someone else owns, as a user's ~/.ssh.  A symlink planted
+// there would have root write, chmod or chown whatever it points to.  So the
+// directory is opened once, without following a symlink to it, and the file
+// is only ever reached from it, again without following symlinks.
+
+/// PrivatePath:
+/// A file, or directory, only its owner may use.  It belongs to <owner> if
+/// given, and to the user app_config runs as otherwise.
+pub struct PrivatePath {
+    path: String,
+    dir: File,
+    name: CString,
+    tmp: CString,
+    owner: Option<User>,
+}
+
+impl PrivatePath {
+    /// The file at <path>.  Fails if the directory it is in is a symlink, or
+    /// belongs to someone else than root, app_config or <owner>.
+    pub fn new(path: &str, owner: Option<&User>) -> Result<PrivatePath> {
+        let (dir, name) = match (Path::new(path).parent(), Path::new(path).file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(eyre!("{} is not the path of a file", path)),
+        };
+        let dir = match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        };
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+            .open(dir)
+            .wrap_err_with(|| format!("Could not open {} unless a directory", dir.display()))?;
+        let uid = dir.metadata()?.uid();
+        let euid = unsafe { libc::geteuid() };
+        if uid != 0 && uid != euid && Some(uid) != owner.map(|owner| owner.uid) {
+            return Err(eyre!("The directory of {} belongs to another user", path));
+        }
+
+        let tmp = format!("{}.app_config.tmp", name.to_string_lossy());
+        Ok(PrivatePath {
+            path: path.to_string(),
+            dir,
+            name: CString::new(name.as_bytes())?,
+            tmp: CString::new(tmp)?,
+            owner: owner.cloned(),
+        })
+    }
+
+    /// Open <name> in the directory with <flags>, never following a symlink
+    fn open_at(&self, name: &CStr, flags: libc::c_int) -> std::io::Result<File> {
+        let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(self.dir.as_raw_fd(), name.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// The file, if it exists.  Fails if it is a symlink, or not a regular
+    /// file of its own, as a hard link to a file elsewhere.
+    fn existing(&self) -> Result<Option<File>> {
+        match self.open_at(&self.name, libc::O_RDONLY | libc::O_NONBLOCK) {
+            Ok(file) => {
+                let meta = file.metadata()?;
+                if !meta.is_file() || meta.nlink() != 1 {
+                    return Err(eyre!("{} is not a regular file of its own", self.path));
+                }
+                Ok(Some(file))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                Err(eyre!("{} is a symlink, it is not followed", self.path))
+            }
+            Err(e) => Err(e).wrap_err_with(|| format!("Could not open {}", self.path)),
+        }
+    }
+
+    /// What the file holds, None if it does not exist
+    pub fn read(&self) -> Result<Option<String>> {
+        match self.existing()? {
+            Some(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)
+                    .wrap_err_with(|| format!("Could not read {}", self.path))?;
+                Ok(Some(contents))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Write <contents> to a temp file next to the file, for commit to rename
+    /// over it.  The temp file is created only readable by, and owned by, the
+    /// owner before the contents go in.
+    pub fn stage(&self, contents: &str) -> Result<()> {
+        self.existing()?;
+        // One left by a run that was killed goes, as would a symlink put there
+        self.discard();
+        let mut file = self
+            .open_at(&self.tmp, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL)
+            .wrap_err_with(|| format!("Could not create a temp file for {}", self.path))?;
+        let written = self
+            .restrict_file(&file)
+            .and_then(|_| file.write_all(contents.as_bytes()).map_err(Into::into))
+            .and_then(|_| file.sync_all().map_err(Into::into));
+        if written.is_err() {
+            self.discard();
+        }
+        written.wrap_err_with(|| format!("Could not write {}", self.path))
+    }
+
+    /// Rename the file staged over the file
+    pub fn commit(&self) -> Result<()> {
+        let fd = self.dir.as_raw_fd();
+        if unsafe { libc::renameat(fd, self.tmp.as_ptr(), fd, self.name.as_ptr()) } != 0 {
+            let e = std::io::Error::last_os_error();
+            self.discard();
+            return Err(e).wrap_err_with(|| format!("Could not replace {}", self.path));
+        }
+        Ok(())
+    }
+
+    /// Remove the file staged, if there is one
+    pub fn discard(&self) {
+        unsafe { libc::unlinkat(self.dir.as_raw_fd(), self.tmp.as_ptr(), 0) };
+    }
+
+    /// Replace the file with <contents>
+    pub fn write(&self, contents: &str) -> Result<()> {
+        self.stage(contents)?;
+        self.commit()
+    }
+
+    /// Make the file only readable by its owner again, should it have been
+    /// loosened since it was written
+    pub fn restrict(&self) -> Result<()> {
+        match self.existing()? {
+            Some(file) => self.restrict_file(&file),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the path as a directory only its owner may enter, unless it
+    /// exists
+    pub fn create_dir(&self) -> Result<()> {
+        let made = unsafe { libc::mkdirat(self.dir.as_raw_fd(), self.name.as_ptr(), 0o700) };
+        if made != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::AlreadyExists {
+                return Ok(());
+            }
+            return Err(e).wrap_err_with(|| format!("Could not create {}", self.path));
+        }
+        let dir = self
+            .open_at(&self.name, libc::O_RDONLY | libc::O_DIRECTORY)
+            .wrap_err_with(|| format!("Could not open {}", self.path))?;
+        self.own(&dir)?;
+        dir.set_permissions(std::fs::Permissions::from_mode(0o700))?;
+        Ok(())
+    }
+
+    /// Have the owner own <file>, open, only readable by them
+    fn restrict_file(&self, file: &File) -> Result<()> {
+        self.own(file)?;
+        if file.metadata()?.mode() & 0o777 != 0o600 {
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// Have the owner own <file>, open, unless they do already
+    fn own(&self, file: &File) -> Result<()> {
+        let owner = match &self.owner {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+        let meta = file.metadata()?;
+        if meta.uid() == owner.uid && meta.gid() == owner.gid {
+            return Ok(());
+        }
+        fchown(file, Some(owner.uid), Some(owner.gid))
+            .wrap_err_with(|| format!("Could not have {} own {}", owner.name, self.path))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_private_path() {
+        let dir = std::env::temp_dir().join(format!("app_config_private_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("key.pem").to_string_lossy().to_string();
+
+        let private = PrivatePath::new(&file, None).unwrap();
+        assert_eq!(private.read().unwrap(), None);
+        private.write("secret").unwrap();
+        assert_eq!(private.read().unwrap().as_deref(), Some("secret"));
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o600);
+        assert!(!dir.join("key.pem.app_config.tmp").exists());
+
+        // A symlink planted as the temp file is removed, not written through
+        let target = dir.join("target");
+        fs::write(&target, "untouched").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("key.pem.app_config.tmp")).unwrap();
+        private.write("renewed").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+        assert_eq!(private.read().unwrap().as_deref(), Some("renewed"));
+
+        // Nor is one, or a hard link, in place of the file
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        for link in &[fs::hard_link::<&Path, &Path>, std::os::unix::fs::symlink::<&Path, &Path>] {
+            fs::remove_file(&file).unwrap();
+            link(target.as_path(), Path::new(&file)).unwrap();
+            assert!(private.read().is_err());
+            assert!(private.restrict().is_err());
+            assert!(private.write("stolen").is_err());
+            assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+            assert_eq!(fs::metadata(&target).unwrap().mode() & 0o777, 0o644);
+        }
+
+        // Nor is the directory, if it is a symlink
+        let linked = dir.join("linked");
+        std::os::unix::fs::symlink(&dir, &linked).unwrap();
+        assert!(PrivatePath::new(&linked.join("key.pem").to_string_lossy(), None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,27 @@
-use rusoto_appconfig::{AppConfig, GetConfigurationRequest};
+use rusoto_appconfig::{
+    AppConfig, DeploymentSummary, GetConfigurationRequest, GetHostedConfigurationVersionRequest,
+    ListDeploymentsRequest,
+};
+use rusoto_cloudwatch::{Dimension, MetricDatum};
 use rusoto_core::Region;
 use serde_derive::Deserialize;
 
 // use crate::providers::{BoxResult, Provider};
-use crate::providers::Provider;
-use eyre::Result;
-
-use rusqlite::{params, Connection};
+use crate::cloudwatch;
+use crate::data::{ConfigData, VersionInfo};
+use crate::hooks::sha256;
+use crate::credentials;
+use crate::http;
+use crate::state::{self, Db};
+#[cfg(not(feature = "state-sqlite"))]
+use crate::state::Cached;
+use crate::providers::{failover, parse_regions, with_timeout, Provider, Registry};
+use eyre::{eyre, Result};
+
+#[cfg(feature = "state-sqlite")]
+use rusqlite::params;
+use std::cell::RefCell;
+use std::time::Duration;
 
 /// AWSConf is used to parse a config file via serde and instantiate the
 /// AWS Provider struct
@@ -17,64 +32,92 @@ pub struct AppCfgConf {
     pub environment: String,
     pub configuration: String,
     pub client_id: String,
+    pub canary: Option<bool>,
+    pub regions: Option<Vec<String>>,
     pub state_file: Option<String>,
+    pub report_health: Option<bool>,
+    pub health_namespace: Option<String>,
 }
 
 impl AppCfgConf {
-    pub fn convert(&self) -> AppCfg {
-        AppCfg::new(
+    pub fn convert(&self, pipeline: &str) -> AppCfg {
+        let mut appcfg = AppCfg::new(
             &self.application,
             &self.environment,
             &self.configuration,
             &self.client_id,
+            self.canary.unwrap_or(false),
             &self.state_file,
-        )
+            pipeline,
+        );
+        appcfg.regions = parse_regions(&self.regions);
+        if self.report_health.unwrap_or(false) {
+            let namespace = self.health_namespace.as_deref().unwrap_or("app_config");
+            appcfg.health_namespace = Some(namespace.to_string());
+        }
+        appcfg
     }
 }
 
+/// Make appconfig providers from [providers.appconfig]
+pub fn register(registry: &mut Registry) {
+    registry.register("appconfig", |section, pipeline| {
+        let conf: AppCfgConf = section.try_into()?;
+        Ok(Box::new(conf.convert(pipeline)))
+    });
+}
+
 /// Provider for AWS AppConfig.  This allows us to check app config for updates
 /// and cache any results into a local sqlite db.  The caching helps avoid charges
 /// for polls when there are no new updates.
+/// With <canary>, a new version still being deployed is only taken once the
+/// deployment has grown past this client, see eligible().  Deployments are
+/// listed by <application> and <environment>, which have to be ids for it.
+/// Calls go to the first of <regions> that answers, the default one unless
+/// configured.
+/// The description of each new version is looked up along with it, for
+/// hooks and the audit log to tell what was applied.  That takes
+/// <application> and <configuration> to be ids too, the version is
+/// described by its number alone otherwise.  The SDK predates version
+/// labels, there is no label.
+/// With a <health_namespace>, whether the hooks applied the data is put as
+/// a CloudWatch metric in the region that served it, for the alarm of the
+/// deployment to roll a bad version back.
 #[derive(Debug)]
 pub struct AppCfg {
     application: String,
     environment: String,
     configuration: String,
     client_id: String,
+    canary: bool,
+    regions: Vec<Region>,
+    served_by: RefCell<Option<Region>>,
+    version_info: RefCell<VersionInfo>,
     current_version: usize,
-    db_conn: Connection,
+    health_namespace: Option<String>,
+    timeout: Option<Duration>,
+    pipeline: String,
+    in_memory: bool,
+    db_conn: Db,
 }
 
 impl AppCfg {
-    /// Creates new AWS AppConfig client
+    /// Creates new AWS AppConfig client, caching the data of <pipeline>
     /// The client will use the default user or system AWS credentials
     pub fn new(
         application: &str,
         environment: &str,
         configuration: &str,
         client_id: &str,
+        canary: bool,
         state_file: &Option<String>,
+        pipeline: &str,
     ) -> AppCfg {
         // Open sqlitedb using in-memory if no file specified
-        let conn = match state_file {
-            &None => match Connection::open_in_memory() {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Error, unable to open in-memory db: {:?}", e);
-                    std::process::exit(exitcode::SOFTWARE);
-                }
-            },
-            Some(file_name) => match Connection::open(file_name) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Error, unable to open state file {}: {:?}", file_name, e);
-                    std::process::exit(exitcode::OSFILE);
-                }
-            },
-        };
+        let conn = state::open_db(state_file, pipeline);
 
         // Setup the tables if they do not already exist
-        match AppCfg::create_cache(&conn) {
+        match AppCfg::create_cache(&conn, pipeline) {
             Ok(()) => {}
             Err(e) => {
                 eprintln!("Error, unable to create cache: {:?}", e);
@@ -82,7 +125,7 @@ impl AppCfg {
             }
         };
 
-        let version = match AppCfg::pull_latest_version(&conn) {
+        let version = match AppCfg::pull_latest_version(&conn, pipeline) {
             Ok(ver) => ver as usize,
             Err(e) => {
                 eprintln!("Error, unable to query cache: {:?}", e);
@@ -97,6 +140,14 @@ impl AppCfg {
             environment: environment.to_string(),
             configuration: configuration.to_string(),
             client_id: client_id.to_string(),
+            canary,
+            regions: vec![Region::default()],
+            served_by: RefCell::new(None),
+            version_info: RefCell::new(VersionInfo::default()),
+            health_namespace: None,
+            timeout: None,
+            pipeline: pipeline.to_string(),
+            in_memory: state_file.is_none(),
             db_conn: conn,
         }
     }
@@ -104,55 +155,137 @@ impl AppCfg {
     /// To avoid high charges the AWS AppConfig service needs us to supply
     /// the latest version of the config we have in cache.  
     /// This setup a sqlite table to store the version & data between runs
-    fn create_cache(db_conn: &Connection) -> rusqlite::Result<()> {
+    #[cfg(feature = "state-sqlite")]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
         db_conn.execute(
             "CREATE TABLE IF NOT EXISTS appConfig (
-                id      INTEGER PRIMARY KEY,
-                version INTEGER NOT NULL,
-                data    TEXT NOT NULL
+                pipeline TEXT PRIMARY KEY,
+                version  INTEGER NOT NULL,
+                data     TEXT NOT NULL
                 )",
             params![],
         )?;
         db_conn.execute(
-            "INSERT INTO appConfig (id, version, data) 
-                SELECT 0, ?1, ?2
+            "INSERT INTO appConfig (pipeline, version, data)
+                SELECT ?1, ?2, ?3
                 WHERE NOT EXISTS (
-                    SELECT * FROM appConfig WHERE id=0 )",
-            params![0, ""],
+                    SELECT * FROM appConfig WHERE pipeline=?1 )",
+            params![pipeline, 0, ""],
         )?;
         Ok(())
     }
 
     /// Hit the local cache and pull out the latest version we have successfully
     /// loaded from the aws appConfig service
-    fn pull_latest_version(db_conn: &Connection) -> rusqlite::Result<isize> {
+    #[cfg(feature = "state-sqlite")]
+    fn pull_latest_version(db_conn: &Db, pipeline: &str) -> state::Result<isize> {
         let res: isize = db_conn.query_row(
-            "SELECT version FROM appConfig WHERE id=0",
-            params![],
+            "SELECT version FROM appConfig WHERE pipeline=?1",
+            params![pipeline],
             |row| row.get(0),
         )?;
         Ok(res)
     }
 
+    /// Hit the local cache and pull out the latest data.  Caches written
+    /// before data was kept as bytes hold it as text, hence the cast
+    #[cfg(feature = "state-sqlite")]
+    fn pull_latest_data(&self) -> state::Result<Vec<u8>> {
+        self.db_conn.query_row(
+            "SELECT CAST(data AS BLOB) FROM appConfig WHERE pipeline=?1",
+            params![self.pipeline],
+            |row| row.get(0),
+        )
+    }
+
     /// Store the latest data in the local cache
-    fn update_cache(&self, version: usize, data: &str) -> rusqlite::Result<()> {
+    #[cfg(feature = "state-sqlite")]
+    fn update_cache(&self, version: usize, data: &[u8]) -> state::Result<()> {
         let _stmt = self.db_conn.execute(
             "UPDATE appConfig SET
                             version = ?1, data = ?2
-                            WHERE id=0",
-            params![version as isize, data],
+                            WHERE pipeline=?3",
+            params![version as isize, data, self.pipeline],
         )?;
 
         Ok(())
     }
+
+    /// The DeploymentApplied and DeploymentFailed metrics of a new version
+    /// the hooks did, or did not, apply.  Both carry the Application,
+    /// Environment and Configuration dimensions the alarm is scoped by.
+    fn health_data(&self, applied: bool) -> Vec<MetricDatum> {
+        let dimension = |name: &str, value: &str| Dimension {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let dimensions = vec![
+            dimension("Application", &self.application),
+            dimension("Environment", &self.environment),
+            dimension("Configuration", &self.configuration),
+        ];
+        let datum = |name: &str, value: bool| MetricDatum {
+            metric_name: name.to_string(),
+            dimensions: Some(dimensions.clone()),
+            unit: Some("Count".to_string()),
+            value: Some(if value { 1.0 } else { 0.0 }),
+            ..Default::default()
+        };
+
+        vec![datum("DeploymentApplied", applied), datum("DeploymentFailed", !applied)]
+    }
+
+    /// Without sqlite, the version and data are kept in the state file
+    #[cfg(not(feature = "state-sqlite"))]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
+        if db_conn.get::<Cached>("appConfig", pipeline)?.is_none() {
+            let cached = Cached {
+                version: Some("0".to_string()),
+                data: Vec::new(),
+            };
+            db_conn.put("appConfig", pipeline, &cached)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn pull_latest_version(db_conn: &Db, pipeline: &str) -> state::Result<isize> {
+        let cached: Cached = db_conn.get("appConfig", pipeline)?.unwrap_or_default();
+        Ok(cached.version.and_then(|version| version.parse().ok()).unwrap_or(0))
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn pull_latest_data(&self) -> state::Result<Vec<u8>> {
+        let cached: Cached = self.db_conn.get("appConfig", &self.pipeline)?.unwrap_or_default();
+        Ok(cached.data)
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn update_cache(&self, version: usize, data: &[u8]) -> state::Result<()> {
+        let cached = Cached {
+            version: Some(version.to_string()),
+            data: data.to_vec(),
+        };
+        self.db_conn.put("appConfig", &self.pipeline, &cached)
+    }
 }
 
 impl Provider for AppCfg {
+    fn kind(&self) -> &'static str {
+        "appconfig"
+    }
+
+    /// Each poll is billed, the cached version is what makes it cheap when
+    /// nothing changed
+    fn pays_every_run(&self) -> bool {
+        self.in_memory
+    }
+
     /// Polls the AWS AppConfig service and checks for new data
     /// If we are up to date and already have the latest data
     /// returns None, else, retuns the new data
-    /// Panics if we can not reach AWS, or check in with the service
-    fn poll(&self) -> Result<Option<String>> {
+    /// Errors if we can not reach AWS, or check in with the service
+    fn poll(&self) -> Result<Option<Vec<u8>>> {
         let request = GetConfigurationRequest {
             application: self.application.clone(),
             environment: self.environment.clone(),
@@ -161,14 +294,14 @@ impl Provider for AppCfg {
             client_configuration_version: Some(self.current_version.to_string()),
         };
 
-        let configuration = get_config(request);
+        let (configuration, region) = failover("appconfig", &self.regions, |region| {
+            get_config(request.clone(), region.clone(), self.timeout)
+        })?;
+        self.served_by.replace(Some(region.clone()));
 
         // Check if there was a new version, if not, do nothing
         let version = match configuration.configuration_version {
-            None => {
-                eprintln!("An error occurred - no data received.");
-                std::process::exit(exitcode::UNAVAILABLE);
-            }
+            None => return Err(eyre!("An error occurred - no data received.")),
             Some(version) => usize::from_str_radix(&version, 10).unwrap(),
         };
 
@@ -177,11 +310,53 @@ impl Provider for AppCfg {
             return Ok(None);
         }
 
+        // Leave a version being rolled out to the clients it has reached.
+        // It is not cached, so we check again on the next poll.
+        if self.canary {
+            let request = ListDeploymentsRequest {
+                application_id: self.application.clone(),
+                environment_id: self.environment.clone(),
+                ..Default::default()
+            };
+            let deployments = list_deployments(request, region.clone(), self.timeout)?;
+            let version_str = version.to_string();
+            let deployment = deployments
+                .into_iter()
+                .filter(|d| d.configuration_name.as_deref() == Some(self.configuration.as_str()))
+                .filter(|d| d.configuration_version.as_deref() == Some(version_str.as_str()))
+                .max_by_key(|d| d.deployment_number);
+            if let Some(deployment) = deployment {
+                if !eligible(&self.client_id, &deployment) {
+                    info!(
+                        "Version {} is deployed to {}% of clients, not yet to this one",
+                        version,
+                        deployment.percentage_complete.unwrap_or(0.0)
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+
         // We have a new update.  Extract the data,
         // update local cache, and return the new data
-        let data = std::str::from_utf8(&configuration.content.unwrap())
-            .unwrap()
-            .to_string();
+        let data = configuration.content.map(|c| c.to_vec()).unwrap_or_default();
+
+        let request = GetHostedConfigurationVersionRequest {
+            application_id: self.application.clone(),
+            configuration_profile_id: self.configuration.clone(),
+            version_number: version as i64,
+        };
+        let description = match get_hosted_version(request, region, self.timeout) {
+            Ok(hosted) => hosted.description,
+            Err(e) => {
+                warning!("Unable to describe version {}: {:#}", version, e);
+                None
+            }
+        };
+        self.version_info.replace(VersionInfo {
+            label: None,
+            description,
+        });
 
         match self.update_cache(version, &data) {
             Ok(()) => {}
@@ -195,50 +370,146 @@ impl Provider for AppCfg {
     /// Returns the latest version of the config from our local cache
     /// Does not contact the upstream source.
     // fn query(&self) -> BoxResult<String> {
-    fn query(&self) -> Result<String> {
-        let res: String =
-            self.db_conn
-                .query_row("SELECT data FROM appConfig WHERE id=0", params![], |row| {
-                    row.get(0)
-                })?;
-        Ok(res)
+    fn query(&self) -> Result<Vec<u8>> {
+        Ok(self.pull_latest_data()?)
+    }
+
+    /// The version of the config in our local cache, none before the first
+    /// successful poll
+    fn version(&self) -> Option<String> {
+        match AppCfg::pull_latest_version(&self.db_conn, &self.pipeline) {
+            Ok(version) if version > 0 => Some(version.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The description of the version last received, not kept in the
+    /// cache
+    fn version_info(&self) -> VersionInfo {
+        self.version_info.borrow().clone()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    fn region(&self) -> Option<String> {
+        self.served_by.borrow().as_ref().map(|region| region.name().to_string())
+    }
+
+    /// Put whether the hooks applied the data, with a health_namespace.
+    /// Data applied without reaching AppConfig, from the cache, is left out:
+    /// there is no deployment to tell, nor a region it is in.
+    fn report_apply(&self, _data: &ConfigData, res: &Result<()>) -> Result<()> {
+        let region = self.served_by.borrow().clone();
+        match (&self.health_namespace, region) {
+            (Some(namespace), Some(region)) => {
+                cloudwatch::put_metrics(namespace, self.health_data(res.is_ok()), region)
+            }
+            _ => Ok(()),
+        }
     }
 }
 
 /// get_config()
-/// Make the call to AWS appConfig and wait for the reply
+/// Make the call to AWS appConfig in <region> and wait for the reply, for at
+/// most <timeout> if there is one
 #[tokio::main]
-async fn get_config(request: GetConfigurationRequest) -> rusoto_appconfig::Configuration {
-    let client = rusoto_appconfig::AppConfigClient::new(Region::default());
-
-    let result = client.get_configuration(request).await;
+async fn get_config(
+    request: GetConfigurationRequest,
+    region: Region,
+    timeout: Option<Duration>,
+) -> Result<rusoto_appconfig::Configuration> {
+    let client = rusoto_appconfig::AppConfigClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        region,
+    );
+
+    let result = with_timeout("appconfig", timeout, client.get_configuration(request)).await?;
 
     match result {
         // Ok(configuration) => configuration.unwrap(),
-        Ok(configuration) => configuration,
-        Err(e) => {
-            eprintln!(
-                "An error occurred - {:?} - when trying to fetch configuration",
-                e
-            );
-            std::process::exit(exitcode::UNAVAILABLE);
-        }
+        Ok(configuration) => Ok(configuration),
+        Err(e) => Err(eyre!("An error occurred - {:?} - when trying to fetch configuration", e)),
+    }
+}
+
+/// list_deployments()
+/// Fetch the deployments to an environment from <region>, most recent first,
+/// waiting for at most <timeout> if there is one
+#[tokio::main]
+async fn list_deployments(
+    request: ListDeploymentsRequest,
+    region: Region,
+    timeout: Option<Duration>,
+) -> Result<Vec<DeploymentSummary>> {
+    let client = rusoto_appconfig::AppConfigClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        region,
+    );
+
+    let result = with_timeout("appconfig", timeout, client.list_deployments(request)).await?;
+
+    match result {
+        Ok(deployments) => Ok(deployments.items.unwrap_or_default()),
+        Err(e) => Err(eyre!("An error occurred - {:?} - when trying to list deployments", e)),
+    }
+}
+
+/// get_hosted_version()
+/// Fetch a version of a hosted configuration from <region>, waiting for at
+/// most <timeout> if there is one
+#[tokio::main]
+async fn get_hosted_version(
+    request: GetHostedConfigurationVersionRequest,
+    region: Region,
+    timeout: Option<Duration>,
+) -> Result<rusoto_appconfig::HostedConfigurationVersion> {
+    let client = rusoto_appconfig::AppConfigClient::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        region,
+    );
+
+    let request = client.get_hosted_configuration_version(request);
+    let result = with_timeout("appconfig", timeout, request).await?;
+
+    match result {
+        Ok(hosted) => Ok(hosted),
+        Err(e) => Err(eyre!("An error occurred - {:?} - when trying to describe the version", e)),
     }
 }
 
+/// Whether <deployment> has reached the client <client_id>.  Every client
+/// falls in a fixed slot between 0 and 100 derived from its id, and is
+/// reached once the deployment's percentage is past it.  Deployments that
+/// are baking or complete have reached every client.
+fn eligible(client_id: &str, deployment: &DeploymentSummary) -> bool {
+    if deployment.state.as_deref() != Some("DEPLOYING") {
+        return true;
+    }
+
+    let hash = sha256(client_id);
+    let slot = u32::from_str_radix(&hash[..8], 16).unwrap_or(0) % 10000;
+    let slot = slot as f32 / 100.0;
+    slot < deployment.percentage_complete.unwrap_or(0.0)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     fn gen_appconfig_struct() -> AppCfg {
-        AppCfg::new(&"myApp", &"dev", &"myConf", &"42", &None)
+        AppCfg::new(&"myApp", &"dev", &"myConf", &"42", false, &None, "test")
     }
 
     #[test]
     fn test_create_db() {
         let appconfig = gen_appconfig_struct();
 
-        let res = AppCfg::create_cache(&appconfig.db_conn);
+        let res = AppCfg::create_cache(&appconfig.db_conn, "test");
         assert_eq!(res, Ok(()));
     }
 
@@ -246,7 +517,7 @@ mod test {
     fn test_pull_latest_version() {
         let appconfig = gen_appconfig_struct();
 
-        let res = AppCfg::pull_latest_version(&appconfig.db_conn);
+        let res = AppCfg::pull_latest_version(&appconfig.db_conn, "test");
         assert_eq!(res, Ok(0));
     }
 
@@ -254,17 +525,100 @@ mod test {
     fn test_update_cache() {
         let appconfig = gen_appconfig_struct();
 
-        let res = AppCfg::pull_latest_version(&appconfig.db_conn);
+        let res = AppCfg::pull_latest_version(&appconfig.db_conn, "test");
         assert_eq!(res, Ok(0));
 
-        let res = appconfig.update_cache(12, &"something");
+        let res = appconfig.update_cache(12, &[0x1f, 0x8b, 0xff]);
         assert_eq!(res, Ok(()));
 
-        let res = AppCfg::pull_latest_version(&appconfig.db_conn);
+        let res = AppCfg::pull_latest_version(&appconfig.db_conn, "test");
         assert_eq!(res, Ok(12));
 
         let res = appconfig.query().unwrap();
-        assert_eq!(res, "something".to_string());
+        assert_eq!(res, vec![0x1f, 0x8b, 0xff]);
+    }
+
+    #[test]
+    #[cfg(feature = "state-sqlite")]
+    fn test_text_cache() {
+        let appconfig = gen_appconfig_struct();
+
+        let res = appconfig.db_conn.execute(
+            "UPDATE appConfig SET version = 3, data = ?1 WHERE pipeline='test'",
+            params!["something"],
+        );
+        assert_eq!(res, Ok(1));
+
+        let res = appconfig.query().unwrap();
+        assert_eq!(res, b"something".to_vec());
+    }
+
+    #[test]
+    #[cfg(feature = "state-sqlite")]
+    fn test_migrate_cache() {
+        // query.db was written by a version keeping the data of one pipeline
+        let path = std::env::temp_dir().join(format!("app_config_cfg_{}.db", std::process::id()));
+        std::fs::copy("./tests/query.db", &path).unwrap();
+        let state_file = Some(path.to_str().unwrap().to_string());
+
+        let web = AppCfg::new(&"myApp", &"dev", &"myConf", &"42", false, &state_file, "web");
+        let db = AppCfg::new(&"myApp", &"dev", &"myConf", &"42", false, &state_file, "db");
+
+        assert_eq!(web.version(), Some("1".to_string()));
+        assert_eq!(web.query().unwrap(), b"Where am I".to_vec());
+        assert_eq!(db.version(), None);
+        assert_eq!(db.query().unwrap(), b"".to_vec());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_eligible() {
+        let deployment = |state: &str, percentage: f32| DeploymentSummary {
+            state: Some(state.to_string()),
+            percentage_complete: Some(percentage),
+            ..Default::default()
+        };
+
+        // Every client is reached by the end, none at the start
+        assert!(eligible("42", &deployment("DEPLOYING", 100.0)));
+        assert!(!eligible("42", &deployment("DEPLOYING", 0.0)));
+        assert!(eligible("42", &deployment("BAKING", 0.0)));
+        assert!(eligible("42", &deployment("COMPLETE", 0.0)));
+
+        // Clients are reached gradually, in the same order every time
+        let clients: Vec<String> = (0..1000).map(|i| format!("host-{}", i)).collect();
+        let reached = |percentage: f32| {
+            let d = deployment("DEPLOYING", percentage);
+            clients.iter().filter(|c| eligible(c, &d)).count()
+        };
+        assert!(reached(10.0) > 50 && reached(10.0) < 150, "{}", reached(10.0));
+        assert!(reached(50.0) > 400 && reached(50.0) < 600, "{}", reached(50.0));
+        assert!(reached(10.0) < reached(50.0));
+    }
+
+    #[test]
+    fn test_health_data() {
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let mut section = maps["providers"]["appconfig"].clone();
+        let conf: AppCfgConf = section.clone().try_into().unwrap();
+        assert_eq!(conf.convert("test").health_namespace, None);
+        section.as_table_mut().unwrap().insert("report_health".into(), true.into());
+        let conf: AppCfgConf = section.try_into().unwrap();
+        let appconfig = conf.convert("test");
+        assert_eq!(appconfig.health_namespace, Some("app_config".to_string()));
+
+        let data = appconfig.health_data(false);
+        let values: Vec<(&str, Option<f64>)> =
+            data.iter().map(|d| (d.metric_name.as_str(), d.value)).collect();
+        assert_eq!(values, vec![("DeploymentApplied", Some(0.0)), ("DeploymentFailed", Some(1.0))]);
+        let dimensions = data[0].dimensions.as_ref().unwrap();
+        assert_eq!(dimensions[1].name, "Environment");
+        assert_eq!(dimensions[1].value, "dev");
+
+        // Nothing is put for data that did not come from AppConfig
+        let data = ConfigData::new("hosts: [web]", "appconfig", None);
+        assert!(appconfig.report_apply(&data, &Ok(())).is_ok());
     }
 
     fn gen_config() -> String {
@@ -280,12 +634,12 @@ mod test {
 
     #[test]
     fn parse_config() {
-        let exp = AppCfg::new(&"myApp", &"dev", &"myConf", &"42", &None);
+        let exp = AppCfg::new(&"myApp", &"dev", &"myConf", &"42", false, &None, "test");
         let expected = format!("{:?}", exp);
 
         let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
         let conf: AppCfgConf = maps["providers"]["appconfig"].clone().try_into().unwrap();
-        let res = conf.convert();
+        let res = conf.convert("test");
         let result = format!("{:?}", res);
 
         assert_eq!(result, expected);
@@ -0,0 +1,393 @@
+use crate::providers::{Provider, Registry};
+use crate::redact::redacted;
+use crate::state::{self, Db};
+#[cfg(not(feature = "state-sqlite"))]
+use crate::state::Cached;
+use serde_derive::{Deserialize, Serialize};
+use eyre::{eyre, Result, WrapErr};
+#[cfg(feature = "state-sqlite")]
+use rusqlite::params;
+
+use std::process::Stdio;
+
+/// Version of the JSON protocol spoken with plugins.  Bump it on any
+/// incompatible change to ExecRequest or ExecResponse.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+#[derive(Deserialize)]
+#[serde(rename = "exec")]
+pub struct ExecConf {
+    pub command: String,
+    pub args: Option<Vec<String>>,
+    pub config: Option<toml::Value>,
+    pub state_file: Option<String>,
+}
+
+impl std::fmt::Debug for ExecConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExecConf")
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .field("config", &self.config.as_ref().map(|c| redacted(c.to_string())))
+            .field("state_file", &self.state_file)
+            .finish()
+    }
+}
+
+impl ExecConf {
+    pub fn convert(&self, pipeline: &str) -> Exec {
+        let config = match &self.config {
+            None => serde_json::Value::Null,
+            Some(c) => serde_json::to_value(c).unwrap(),
+        };
+
+        Exec::new(
+            &self.command,
+            self.args.clone().unwrap_or_default(),
+            config,
+            &self.state_file,
+            pipeline,
+        )
+    }
+}
+
+/// Make exec providers from [providers.exec]
+pub fn register(registry: &mut Registry) {
+    registry.register("exec", |section, pipeline| {
+        let conf: ExecConf = section.try_into()?;
+        Ok(Box::new(conf.convert(pipeline)))
+    });
+}
+
+
+// // // // // // // // // // Protocol // // // // // // // // // //
+
+/// Written as json to the plugin's stdin
+#[derive(PartialEq, Serialize)]
+pub struct ExecRequest<'a> {
+    pub protocol_version: u32,
+    pub method: &'static str,
+    pub config: &'a serde_json::Value,
+    pub version: Option<&'a str>,
+}
+
+impl std::fmt::Debug for ExecRequest<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExecRequest")
+            .field("protocol_version", &self.protocol_version)
+            .field("method", &self.method)
+            .field("config", &redacted(self.config.to_string()))
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+/// Read as json from the plugin's stdout.  A null <data> means nothing
+/// changed since <version>.  If the plugin does not track versions, the
+/// data itself is compared with the cache.
+#[derive(PartialEq, Deserialize)]
+pub struct ExecResponse {
+    pub protocol_version: u32,
+    pub data: Option<String>,
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+impl std::fmt::Debug for ExecResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExecResponse")
+            .field("protocol_version", &self.protocol_version)
+            .field("data", &self.data.as_ref().map(redacted))
+            .field("version", &self.version)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+
+// // // // // // // // // // Provider // // // // // // // // // //
+
+/// Exec provider delegates polling to an external plugin, so providers can
+/// be written in any language without forking app_config.  The latest data
+/// is cached locally, so query never calls the plugin.
+pub struct Exec {
+    command: String,
+    args: Vec<String>,
+    config: serde_json::Value,
+    pipeline: String,
+    db_conn: Db,
+}
+
+impl std::fmt::Debug for Exec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Exec")
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .field("config", &redacted(self.config.to_string()))
+            .field("pipeline", &self.pipeline)
+            .field("db_conn", &self.db_conn)
+            .finish()
+    }
+}
+
+impl Exec {
+    /// Creates new Exec provider, caching the data of <pipeline>
+    pub fn new(
+        command: &str,
+        args: Vec<String>,
+        config: serde_json::Value,
+        state_file: &Option<String>,
+        pipeline: &str,
+    ) -> Exec {
+        // Open sqlitedb using in-memory if no file specified
+        let conn = state::open_db(state_file, pipeline);
+
+        // Setup the tables if they do not already exist
+        match Exec::create_cache(&conn, pipeline) {
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("Error, unable to create cache: {:?}", e);
+                std::process::exit(exitcode::SOFTWARE);
+            }
+        };
+
+        Exec {
+            command: command.to_string(),
+            args,
+            config,
+            pipeline: pipeline.to_string(),
+            db_conn: conn,
+        }
+    }
+
+    /// Cache the latest version and data received from the plugin
+    #[cfg(feature = "state-sqlite")]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS exec (
+                pipeline TEXT PRIMARY KEY,
+                version  TEXT,
+                data     TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        db_conn.execute(
+            "INSERT INTO exec (pipeline, version, data)
+                SELECT ?1, NULL, ?2
+                WHERE NOT EXISTS (
+                    SELECT * FROM exec WHERE pipeline=?1 )",
+            params![pipeline, ""],
+        )?;
+        Ok(())
+    }
+
+    /// Hit the local cache and pull out the latest version and data
+    #[cfg(feature = "state-sqlite")]
+    fn pull_latest(&self) -> state::Result<(Option<String>, String)> {
+        self.db_conn.query_row(
+            "SELECT version, data FROM exec WHERE pipeline=?1",
+            params![self.pipeline],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Store the latest data in the local cache
+    #[cfg(feature = "state-sqlite")]
+    fn update_cache(&self, version: &Option<String>, data: &str) -> state::Result<()> {
+        self.db_conn.execute(
+            "UPDATE exec SET
+                            version = ?1, data = ?2
+                            WHERE pipeline=?3",
+            params![version, data, self.pipeline],
+        )?;
+        Ok(())
+    }
+
+    /// Without sqlite, the version and data are kept in the state file
+    #[cfg(not(feature = "state-sqlite"))]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
+        if db_conn.get::<Cached>("exec", pipeline)?.is_none() {
+            db_conn.put("exec", pipeline, &Cached::default())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn pull_latest(&self) -> state::Result<(Option<String>, String)> {
+        let cached: Cached = self.db_conn.get("exec", &self.pipeline)?.unwrap_or_default();
+        Ok((cached.version, String::from_utf8_lossy(&cached.data).to_string()))
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn update_cache(&self, version: &Option<String>, data: &str) -> state::Result<()> {
+        let cached = Cached {
+            version: version.clone(),
+            data: data.as_bytes().to_vec(),
+        };
+        self.db_conn.put("exec", &self.pipeline, &cached)
+    }
+
+    /// Run the plugin with <request> on stdin, and parse its reply
+    fn call(&self, request: &ExecRequest) -> Result<ExecResponse> {
+        let mut child = std::process::Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to start plugin {}", self.command))?;
+
+        let stdin = child.stdin.as_mut().expect("Failed to open stdin");
+        serde_json::to_writer(stdin, request)?;
+
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(eyre!(
+                "Plugin {} failed ({}): {}",
+                self.command,
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+
+        parse_response(&self.command, &out.stdout)
+    }
+}
+
+impl Provider for Exec {
+    fn kind(&self) -> &'static str {
+        "exec"
+    }
+
+    /// Ask the plugin for data, returning it if it differs from the cache
+    fn poll(&self) -> Result<Option<Vec<u8>>> {
+        let (old_version, old_data) = self.pull_latest()?;
+
+        let request = ExecRequest {
+            protocol_version: PROTOCOL_VERSION,
+            method: "poll",
+            config: &self.config,
+            version: old_version.as_deref(),
+        };
+        let response = self.call(&request)?;
+
+        let data = match response.data {
+            None => return Ok(None),
+            Some(data) => data,
+        };
+
+        let unchanged = match &response.version {
+            Some(version) => Some(version) == old_version.as_ref(),
+            None => data == old_data,
+        };
+        if unchanged {
+            return Ok(None);
+        }
+
+        // We have new data, update the cache and return it
+        self.update_cache(&response.version, &data)?;
+        Ok(Some(data.into_bytes()))
+    }
+
+    /// Returns the latest data from our local cache
+    fn query(&self) -> Result<Vec<u8>> {
+        let (_, data) = self.pull_latest()?;
+        Ok(data.into_bytes())
+    }
+
+    /// The version last reported by the plugin, if it reports any
+    fn version(&self) -> Option<String> {
+        self.pull_latest().ok().and_then(|(version, _)| version)
+    }
+}
+
+/// Parse and validate a plugin's reply
+fn parse_response(command: &str, stdout: &[u8]) -> Result<ExecResponse> {
+    let response: ExecResponse = serde_json::from_slice(stdout)
+        .wrap_err_with(|| format!("Plugin {} returned an invalid response", command))?;
+
+    if response.protocol_version != PROTOCOL_VERSION {
+        return Err(eyre!(
+            "Plugin {} speaks protocol version {}, expected {}",
+            command,
+            response.protocol_version,
+            PROTOCOL_VERSION
+        ));
+    }
+    if let Some(e) = &response.error {
+        return Err(eyre!("Plugin {} reported an error: {}", command, e));
+    }
+    Ok(response)
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen_config() -> String {
+        r#"
+        [providers.exec]
+        command = "./tests/exec_plugin.sh"
+
+        [providers.exec.config]
+        greeting = "Hello"
+        "#
+        .to_string()
+    }
+
+    #[test]
+    fn parse_config() {
+        let exp = Exec::new(
+            &"./tests/exec_plugin.sh",
+            vec![],
+            serde_json::json!({ "greeting": "Hello" }),
+            &None,
+            "test",
+        );
+        let expected = format!("{:?}", exp);
+
+        let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
+        let conf: ExecConf = maps["providers"]["exec"].clone().try_into().unwrap();
+        let res = conf.convert("test");
+        let result = format!("{:?}", res);
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let res = parse_response("p", br#"{"protocol_version": 1, "data": "hi", "version": "3"}"#)
+            .unwrap();
+        assert_eq!(res.data, Some("hi".to_string()));
+        assert_eq!(res.version, Some("3".to_string()));
+
+        assert!(parse_response("p", br#"{"protocol_version": 2, "data": "hi"}"#).is_err());
+        assert!(parse_response("p", br#"{"protocol_version": 1, "error": "nope"}"#).is_err());
+        assert!(parse_response("p", b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_poll() {
+        let p = Exec::new(
+            &"./tests/exec_plugin.sh",
+            vec![],
+            serde_json::Value::Null,
+            &None,
+            "test",
+        );
+
+        let res = p.poll().unwrap();
+        assert_eq!(res, Some(b"Hello from exec".to_vec()));
+
+        // Same version again, nothing new
+        let res = p.poll().unwrap();
+        assert_eq!(res, None);
+
+        let res = p.query().unwrap();
+        assert_eq!(res, b"Hello from exec".to_vec());
+    }
+}
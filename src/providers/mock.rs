@@ -1,23 +1,40 @@
-use crate::providers::Provider;
+use crate::providers::{Provider, Registry};
+use crate::redact::redacted;
 use serde_derive::Deserialize;
 use eyre::Result;
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 #[serde(rename = "mock")]
 pub struct MockConf {
     pub data: String,
 }
 
+impl std::fmt::Debug for MockConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MockConf")
+            .field("data", &redacted(&self.data))
+            .finish()
+    }
+}
+
 impl MockConf {
-    pub fn convert(&self) -> Mock {
+    pub fn convert(&self, _pipeline: &str) -> Mock {
         Mock::new(&self.data)
     }
 }
 
+/// Make mock providers from [providers.mock]
+pub fn register(registry: &mut Registry) {
+    registry.register("mock", |section, pipeline| {
+        let conf: MockConf = section.try_into()?;
+        Ok(Box::new(conf.convert(pipeline)))
+    });
+}
+
 /// Mock is a dummy provider that just returns whatever data it was given
 /// It is mainly useful for dialing in templates as it lets you quickly
 /// test input data against the desired output format
-#[derive(Debug, PartialEq)]
+#[derive(PartialEq)]
 pub struct Mock {
     data: String,
 }
@@ -31,15 +48,28 @@ impl Mock {
     }
 }
 
+// The data may well be a copy of real secrets
+impl std::fmt::Debug for Mock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Mock")
+            .field("data", &redacted(&self.data))
+            .finish()
+    }
+}
+
 impl Provider for Mock {
+    fn kind(&self) -> &'static str {
+        "mock"
+    }
+
     /// Just return the data contained in the Mock struct
-    fn poll(&self) -> Result<Option<String>> {
-        Ok(Some(self.data.clone()))
+    fn poll(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.data.clone().into_bytes()))
     }
 
     /// Just return the data contained in the Mock struct
-    fn query(&self) -> Result<String> {
-        Ok(self.data.clone())
+    fn query(&self) -> Result<Vec<u8>> {
+        Ok(self.data.clone().into_bytes())
     }
 }
 
@@ -56,10 +86,13 @@ mod test {
         let mock = gen_mock_struct();
 
         let res = mock.poll().unwrap().unwrap();
-        assert_eq!(res, String::from("Am I a mock"));
+        assert_eq!(res, b"Am I a mock".to_vec());
 
         let res = mock.query().unwrap();
-        assert_eq!(res, String::from("Am I a mock"));
+        assert_eq!(res, b"Am I a mock".to_vec());
+
+        // The data may be secrets, it is never shown
+        assert!(!format!("{:?}", mock).contains("Am I a mock"));
     }
 
     fn gen_config() -> String {
@@ -76,7 +109,7 @@ mod test {
 
         let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
         let conf: MockConf = maps["providers"]["mock"].clone().try_into().unwrap();
-        let res = conf.convert();
+        let res = conf.convert("test");
 
         assert_eq!(res, exp);
     }
@@ -1,14 +1,283 @@
+#[cfg(feature = "aws")]
 pub mod appcfg;
-pub use crate::providers::appcfg::{AppCfgConf, AppCfg};
 pub mod mock;
-pub use crate::providers::mock::{Mock, MockConf};
+#[cfg(feature = "aws")]
 pub mod param_store;
-pub use crate::providers::param_store::{ParamStore, ParamStoreConf};
+pub mod exec;
 
+use crate::data::{ConfigData, VersionInfo};
 use eyre::Result;
+#[cfg(feature = "aws")]
+use rusoto_core::Region;
+#[cfg(feature = "aws")]
+use std::future::Future;
+use std::time::Duration;
+
+/// Providers left out of builds without the feature they are behind
+const FEATURES: [(&str, &str); 2] = [("appconfig", "aws"), ("param_store", "aws")];
 
 pub trait Provider: std::fmt::Debug {
-    fn poll(&self) -> Result<Option<String>>;
+    /// The config file section this provider is configured by, e.g. "mock"
+    fn kind(&self) -> &'static str;
+
+    /// New data since the last poll, if there is any.  Data is passed on as
+    /// bytes, providers must not assume it is text.
+    fn poll(&self) -> Result<Option<Vec<u8>>>;
+
+    fn query(&self) -> Result<Vec<u8>>;
+
+    /// The provider's version of the latest data, for providers that have
+    /// a notion of versions
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    /// The label and description of the version of the latest data, for
+    /// providers whose versions have them
+    fn version_info(&self) -> VersionInfo {
+        VersionInfo::default()
+    }
+
+    /// Tell the upstream source whether the hooks applied <data>, <res>,
+    /// for providers whose rollouts act on it
+    fn report_apply(&self, _data: &ConfigData, _res: &Result<()>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Give up on calls to the upstream source after <timeout>, for
+    /// providers that make network calls
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    /// The AWS region the last successful poll was served from, for
+    /// providers that can fail over between regions
+    fn region(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether every run pays to download the data again, for providers
+    /// billed per download that have no state_file to remember it in
+    fn pays_every_run(&self) -> bool {
+        false
+    }
+
+    /// Whether query reads the data last polled from the state file.
+    /// Providers keeping secrets off disk fetch it again instead, at the
+    /// version last polled, so they have no data to give when the upstream
+    /// source is not to be reached.
+    fn caches_data(&self) -> bool {
+        true
+    }
+}
+
+/// ProviderTimeout:
+/// The upstream source of a provider did not answer in time.  Hung
+/// connections fail the run like any other error, but can be told apart
+/// from other failures, e.g. to retry later.
+#[derive(Debug)]
+pub struct ProviderTimeout {
+    pub provider: &'static str,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for ProviderTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} provider timed out after {:?}", self.provider, self.timeout)
+    }
+}
+
+impl std::error::Error for ProviderTimeout {}
+
+/// PayloadTooLarge:
+/// A provider sent more data than settings.max_payload_size
+#[derive(Debug)]
+pub struct PayloadTooLarge {
+    pub provider: &'static str,
+    pub size: usize,
+    pub max: usize,
+}
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} provider data is {} bytes, more than max_payload_size {}",
+            self.provider, self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Build:
+/// Makes a provider from its section of the config file, keeping its state
+/// under the name of the pipeline
+pub type Build = Box<dyn Fn(toml::Value, &str) -> Result<Box<dyn Provider>, toml::de::Error>>;
+
+/// Registry:
+/// The kinds of providers known, by the config file section each is
+/// configured by, along with how to make them.  Every provider module
+/// registers its own kind.
+pub struct Registry {
+    kinds: Vec<(String, Build)>,
+}
+
+impl Registry {
+    /// Make providers configured by a <kind> section with <build>, in place
+    /// of any registered before
+    pub fn register<F>(&mut self, kind: &str, build: F)
+    where
+        F: Fn(toml::Value, &str) -> Result<Box<dyn Provider>, toml::de::Error> + 'static,
+    {
+        self.kinds.retain(|(k, _)| k != kind);
+        self.kinds.push((kind.to_string(), Box::new(build)));
+    }
+
+    /// How providers of <kind> are made, if they are known
+    pub fn get(&self, kind: &str) -> Option<&Build> {
+        self.kinds.iter().find(|(k, _)| k == kind).map(|(_, build)| build)
+    }
+
+    /// The feature <kind> is behind, when this build was made without it
+    pub fn missing_feature(&self, kind: &str) -> Option<&'static str> {
+        match self.get(kind) {
+            Some(_) => None,
+            None => FEATURES.iter().find(|(k, _)| *k == kind).map(|(_, feature)| *feature),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry { kinds: Vec::new() };
+        mock::register(&mut registry);
+        #[cfg(feature = "aws")]
+        appcfg::register(&mut registry);
+        #[cfg(feature = "aws")]
+        param_store::register(&mut registry);
+        exec::register(&mut registry);
+        registry
+    }
+}
+
+/// Wait for <future>, the call of <provider> to its upstream source, for at
+/// most <timeout> if there is one
+#[cfg(feature = "aws")]
+pub async fn with_timeout<F: Future>(
+    provider: &'static str,
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output> {
+    match timeout {
+        None => Ok(future.await),
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ProviderTimeout { provider, timeout }.into()),
+    }
+}
+
+/// Parse the AWS <regions> a provider may fail over between, in order of
+/// preference.  None means the default region only.
+/// Will panic if a region is invalid.
+#[cfg(feature = "aws")]
+pub fn parse_regions(regions: &Option<Vec<String>>) -> Vec<Region> {
+    let regions = match regions {
+        None => return vec![Region::default()],
+        Some(regions) => regions,
+    };
+    if regions.is_empty() {
+        eprintln!("Error, regions must list at least one region");
+        std::process::exit(exitcode::CONFIG);
+    }
+    regions.iter().map(|region| parse_region(region)).collect()
+}
+
+/// Parse one AWS <region>, e.g. for hooks calling AWS.
+/// Will panic if the region is invalid.
+#[cfg(feature = "aws")]
+pub fn parse_region(region: &str) -> Region {
+    match region.parse() {
+        Ok(region) => region,
+        Err(e) => {
+            eprintln!("Error, invalid region {}: {}", region, e);
+            std::process::exit(exitcode::CONFIG);
+        }
+    }
+}
+
+/// Call <f> with each of <regions> in turn, until one succeeds.  Returns its
+/// result along with the region that served it, or the last error if they
+/// all fail.
+#[cfg(feature = "aws")]
+pub fn failover<T, F>(provider: &str, regions: &[Region], mut f: F) -> Result<(T, Region)>
+where
+    F: FnMut(&Region) -> Result<T>,
+{
+    let mut error = None;
+    for region in regions {
+        if let Some(e) = error.take() {
+            warning!("{} failing over to {}: {:#}", provider, region.name(), e);
+        }
+        match f(region) {
+            Ok(res) => return Ok((res, region.clone())),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| eyre::eyre!("{} has no region to call", provider)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    #[cfg(feature = "aws")]
+    async fn test_with_timeout() {
+        let res = with_timeout("mock", None, async { 42 }).await.unwrap();
+        assert_eq!(res, 42);
+
+        let timeout = Some(Duration::from_millis(10));
+        let slow = tokio::time::delay_for(Duration::from_secs(5));
+        let res = with_timeout("mock", timeout, slow).await.unwrap_err();
+        assert_eq!(format!("{}", res), "mock provider timed out after 10ms");
+        assert!(res.downcast_ref::<ProviderTimeout>().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "aws")]
+    fn test_failover() {
+        let regions = vec![Region::default(), Region::default()];
+        let mut calls = 0;
+        let res = failover("mock", &regions, |_| {
+            calls += 1;
+            match calls {
+                1 => Err(eyre::eyre!("primary is down")),
+                _ => Ok(42),
+            }
+        });
+        assert_eq!(res.unwrap().0, 42);
+        assert_eq!(calls, 2);
+
+        let res = failover("mock", &regions, |_| -> Result<()> { Err(eyre::eyre!("down")) });
+        assert_eq!(format!("{}", res.unwrap_err()), "down");
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::default();
+        let build = registry.get("mock").unwrap();
+        let section = toml::from_str("data = \"hello\"").unwrap();
+        assert_eq!(build(section, "test").unwrap().kind(), "mock");
+        assert!(registry.get("nope").is_none());
+        assert_eq!(registry.missing_feature("mock"), None);
+        assert_eq!(registry.missing_feature("nope"), None);
+        #[cfg(not(feature = "aws"))]
+        assert_eq!(registry.missing_feature("param_store"), Some("aws"));
 
-    fn query(&self) -> Result<String>;
+        // A kind registered again is made the new way
+        let mut registry = Registry::default();
+        registry.register("mock", |_, _| Ok(Box::new(mock::Mock::new("replaced"))));
+        let build = registry.get("mock").unwrap();
+        assert_eq!(build(toml::Value::from(0), "test").unwrap().query().unwrap(), b"replaced");
+    }
 }
@@ -1,9 +1,18 @@
-use crate::providers::Provider;
+use crate::credentials;
+use crate::http;
+use crate::state::{self, Db};
+#[cfg(not(feature = "state-sqlite"))]
+use crate::state::Cached;
+use crate::providers::{failover, parse_regions, with_timeout, Provider, Registry};
 use serde_derive::Deserialize;
 use eyre::{eyre, Result};
-use rusqlite::{params, Connection};
+#[cfg(feature = "state-sqlite")]
+use rusqlite::params;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-use rusoto_ssm::{Ssm, SsmClient, GetParametersRequest};
+use rusoto_ssm::{Ssm, SsmClient, GetParametersRequest, Parameter};
 use rusoto_core::Region;
 
 
@@ -11,51 +20,120 @@ use rusoto_core::Region;
 #[derive(Debug, Deserialize)]
 #[serde(rename = "param_store")]
 pub struct ParamStoreConf {
-    pub key: String,
+    pub key: Option<String>,
+    pub keys: Option<Vec<String>>,
+    pub label: Option<String>,
+    pub regions: Option<Vec<String>>,
     pub state_file: Option<String>,
 }
 
 impl ParamStoreConf {
-    pub fn convert(&self) -> ParamStore {
-        ParamStore::new(&self.key, &self.state_file)
+    /// Will panic unless exactly one of key and keys is given, or if keys
+    /// is empty
+    pub fn convert(&self, pipeline: &str) -> ParamStore {
+        let params = match (&self.key, &self.keys) {
+            (Some(key), None) => Params::One(key.clone()),
+            (None, Some(keys)) if !keys.is_empty() => Params::Many(keys.clone()),
+            (None, Some(_)) => {
+                eprintln!("Error, param_store keys is empty");
+                std::process::exit(exitcode::CONFIG);
+            }
+            _ => {
+                eprintln!("Error, param_store needs either a key or keys");
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let key = self.key.clone().unwrap_or_default();
+        let mut param_store = ParamStore::new(&key, &self.state_file, pipeline);
+        param_store.params = params;
+        param_store.label = self.label.clone();
+        param_store.regions = parse_regions(&self.regions);
+        param_store
     }
 }
 
+/// Make param_store providers from [providers.param_store]
+pub fn register(registry: &mut Registry) {
+    registry.register("param_store", |section, pipeline| {
+        let conf: ParamStoreConf = section.try_into()?;
+        Ok(Box::new(conf.convert(pipeline)))
+    });
+}
+
 
 // // // // // // // // // // Provider // // // // // // // // // //
 
+/// How many parameters SSM returns in one GetParameters call
+const MAX_PER_CALL: usize = 10;
+
+/// Params:
+/// The parameters a ParamStore watches: one, whose value is the data as is,
+/// or several, whose values make the data as a JSON map by name.  In the
+/// map, the values of StringList parameters are split into arrays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Params {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Params {
+    fn keys(&self) -> &[String] {
+        match self {
+            Params::One(key) => std::slice::from_ref(key),
+            Params::Many(keys) => keys,
+        }
+    }
+
+    /// The data <fetched> make
+    fn data(&self, mut fetched: Vec<Parameter>) -> Result<String> {
+        match self {
+            Params::One(_) => match fetched.pop().and_then(|param| param.value) {
+                Some(value) => Ok(value),
+                None => Err(eyre!("AWS Param Store value empty")),
+            },
+            Params::Many(keys) => Ok(serde_json::to_string(&params_map(keys, fetched)?)?),
+        }
+    }
+}
+
+/// The versions of <fetched> by name, as the cache keeps them
+fn versions(fetched: &[Parameter]) -> Result<String> {
+    let mut versions = BTreeMap::new();
+    for param in fetched {
+        match (&param.name, param.version) {
+            (Some(name), Some(version)) => versions.insert(name.clone(), version),
+            (name, _) => return Err(eyre!("AWS Param Store sent {:?} without a version", name)),
+        };
+    }
+    Ok(serde_json::to_string(&versions)?)
+}
+
 /// ParamStore povider polls an AWS SSM Parameter and triggers hooks
-/// When the value changes from a previously cached value
+/// When its version changes from the previously cached version, or the
+/// version <label> points to if it has one.  Only versions are cached,
+/// values are secrets more often than not, so query fetches the values
+/// at the cached versions.
+/// The value is read from the first of <regions> that answers.
 #[derive(Debug)]
 pub struct ParamStore {
-    key: String,
-    db_conn: Connection,
+    params: Params,
+    label: Option<String>,
+    regions: Vec<Region>,
+    served_by: RefCell<Option<String>>,
+    timeout: Option<Duration>,
+    pipeline: String,
+    db_conn: Db,
 }
 
 impl ParamStore {
-    /// Creates new ParamStore provider
-    pub fn new(key: &str, state_file: &Option<String>) -> ParamStore {
+    /// Creates new ParamStore provider, caching the value for <pipeline>
+    pub fn new(key: &str, state_file: &Option<String>, pipeline: &str) -> ParamStore {
 
         // Open sqlitedb using in-memory if no file specified
-        let conn = match state_file {
-            &None => match Connection::open_in_memory() {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Error, unable to open in-memory db: {:?}", e);
-                    std::process::exit(exitcode::SOFTWARE);
-                }
-            },
-            Some(file_name) => match Connection::open(file_name) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Error, unable to open state file {}: {:?}", file_name, e);
-                    std::process::exit(exitcode::OSFILE);
-                }
-            },
-        };
+        let conn = state::open_db(state_file, pipeline);
 
         // Setup the tables if they do not already exist
-        match ParamStore::create_cache(&conn) {
+        match ParamStore::create_cache(&conn, pipeline) {
             Ok(()) => {}
             Err(e) => {
                 eprintln!("Error, unable to create cache: {:?}", e);
@@ -64,113 +142,246 @@ impl ParamStore {
         };
 
         ParamStore {
-            key: key.to_string(),
+            params: Params::One(key.to_string()),
+            label: None,
+            regions: vec![Region::default()],
+            served_by: RefCell::new(None),
+            timeout: None,
+            pipeline: pipeline.to_string(),
             db_conn: conn,
         }
     }
 
     /// To know when the value of the parameter has changed, we need to 
-    /// store the value locally. We will do so in a sqlite db.
-    fn create_cache(db_conn: &Connection) -> rusqlite::Result<()> {
+    /// store its version locally. We will do so in a sqlite db.
+    #[cfg(feature = "state-sqlite")]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
         db_conn.execute(
             "CREATE TABLE IF NOT EXISTS param_store (
-                id      INTEGER PRIMARY KEY,
-                data    TEXT NOT NULL
+                pipeline TEXT PRIMARY KEY,
+                versions TEXT NOT NULL
                 )",
             params![],
         )?;
         db_conn.execute(
-            "INSERT INTO param_store (id, data) 
-                SELECT 0, ?1
+            "INSERT INTO param_store (pipeline, versions)
+                SELECT ?1, ?2
                 WHERE NOT EXISTS (
-                    SELECT * FROM param_store WHERE id=0 )",
-            params![""],
+                    SELECT * FROM param_store WHERE pipeline=?1 )",
+            params![pipeline, ""],
         )?;
         Ok(())
     }
 
-    /// Hit the local cache and pull out the latest data
-    fn pull_latest_data(db_conn: &Connection) -> rusqlite::Result<String> {
+    /// Hit the local cache and pull out the latest versions
+    #[cfg(feature = "state-sqlite")]
+    fn pull_latest_versions(db_conn: &Db, pipeline: &str) -> state::Result<String> {
         let res: String = db_conn.query_row(
-            "SELECT data FROM param_store WHERE id=0",
-            params![],
+            "SELECT versions FROM param_store WHERE pipeline=?1",
+            params![pipeline],
             |row| row.get(0),
         )?;
         Ok(res)
     }
 
-    /// Store the latest data in the local cache
-    fn update_cache(db_conn: &Connection, data: &str) -> rusqlite::Result<()> {
+    /// Store the latest versions in the local cache
+    #[cfg(feature = "state-sqlite")]
+    fn update_cache(db_conn: &Db, pipeline: &str, versions: &str) -> state::Result<()> {
         let _stmt = db_conn.execute(
             "UPDATE param_store SET
-                            data = ?1
-                            WHERE id=0",
-            params![data,],
+                            versions = ?1
+                            WHERE pipeline=?2",
+            params![versions, pipeline],
         )?;
 
         Ok(())
     }
+
+    /// Without sqlite, the versions are kept in the state file as is
+    #[cfg(not(feature = "state-sqlite"))]
+    fn create_cache(db_conn: &Db, pipeline: &str) -> state::Result<()> {
+        if db_conn.get::<Cached>("param_store", pipeline)?.is_none() {
+            db_conn.put("param_store", pipeline, &Cached::default())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn pull_latest_versions(db_conn: &Db, pipeline: &str) -> state::Result<String> {
+        let cached: Cached = db_conn.get("param_store", pipeline)?.unwrap_or_default();
+        Ok(cached.version.unwrap_or_default())
+    }
+
+    #[cfg(not(feature = "state-sqlite"))]
+    fn update_cache(db_conn: &Db, pipeline: &str, versions: &str) -> state::Result<()> {
+        let cached = Cached {
+            version: Some(versions.to_string()),
+            data: Vec::new(),
+        };
+        db_conn.put("param_store", pipeline, &cached)
+    }
+
+    /// The versions last polled by name, none if nothing was polled yet.
+    /// State files without sqlite from before versions were kept hold a
+    /// value instead, which is taken as nothing polled.
+    fn cached_versions(&self) -> Result<Option<BTreeMap<String, i64>>> {
+        let versions = ParamStore::pull_latest_versions(&self.db_conn, &self.pipeline)?;
+        Ok(serde_json::from_str(&versions).ok())
+    }
+
+    /// Fetch <names>, or the versions they select, from the first region
+    /// that answers
+    fn fetch(&self, names: &[String]) -> Result<Vec<Parameter>> {
+        let (fetched, region) = failover("param_store", &self.regions, |region| {
+            get_parameters_in(names, region.clone(), self.timeout)
+        })?;
+        self.served_by.replace(Some(region.name().to_string()));
+        Ok(fetched)
+    }
 }
 
 impl Provider for ParamStore {
-    /// Just return the data contained in the Mock struct
-    fn poll(&self) -> Result<Option<String>> {
+    fn kind(&self) -> &'static str {
+        "param_store"
+    }
 
-        let value = get_params(&self.key)?;
+    /// The values of the parameters, if any of their versions changed
+    fn poll(&self) -> Result<Option<Vec<u8>>> {
+        let keys = self.params.keys().iter();
+        let names: Vec<String> = match &self.label {
+            None => keys.cloned().collect(),
+            Some(label) => keys.map(|key| format!("{}:{}", key, label)).collect(),
+        };
+        let fetched = self.fetch(&names)?;
 
-        // Check for new data
-        let old_value = ParamStore::pull_latest_data(&self.db_conn)?;
-        if value == old_value {
+        // Check for new versions
+        let versions = versions(&fetched)?;
+        let old_versions = ParamStore::pull_latest_versions(&self.db_conn, &self.pipeline)?;
+        if versions == old_versions {
             return Ok(None)
         }
 
         // We have new data, update the cache and return it
-        ParamStore::update_cache(&self.db_conn, &value)?;
+        let data = self.params.data(fetched)?;
+        ParamStore::update_cache(&self.db_conn, &self.pipeline, &versions)?;
     
-        Ok(Some(value))
+        Ok(Some(data.into_bytes()))
     }
 
-    /// Just return the data contained in the Mock struct
-    fn query(&self) -> Result<String> {
-        let res = ParamStore::pull_latest_data(&self.db_conn)?;
-        Ok(res)
+    /// The values at the versions last polled, fetched again as they are
+    /// not cached.  Nothing if no version of each parameter was polled yet.
+    fn query(&self) -> Result<Vec<u8>> {
+        let versions = match self.cached_versions()? {
+            Some(versions) => versions,
+            None => return Ok(Vec::new()),
+        };
+        let mut names = Vec::new();
+        for key in self.params.keys() {
+            match versions.get(key) {
+                Some(version) => names.push(format!("{}:{}", key, version)),
+                None => return Ok(Vec::new()),
+            }
+        }
+        let data = self.params.data(self.fetch(&names)?)?;
+        Ok(data.into_bytes())
+    }
+
+    /// The version of the parameter last polled, for a single key
+    fn version(&self) -> Option<String> {
+        match &self.params {
+            Params::One(key) => self.cached_versions().ok()??.get(key).map(|v| v.to_string()),
+            Params::Many(_) => None,
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    fn region(&self) -> Option<String> {
+        self.served_by.borrow().clone()
+    }
+
+    /// Only versions are cached, query fetches the values
+    fn caches_data(&self) -> bool {
+        false
     }
 }
 
 
 /// get_params()
-/// Make the call to SSM ParamStore and wait for the reply
-#[tokio::main]
-pub async fn get_params(key: &str) -> eyre::Result<String> {
-
-    let request = GetParametersRequest {
-        // names: vec![self.key.clone(),],
-        names: vec![key.to_string(),],
-        with_decryption: Some(true),
-    };
+/// Make the call to SSM ParamStore in the default region and wait for the
+/// reply, for at most <timeout> if there is one
+pub fn get_params(key: &str, timeout: Option<Duration>) -> eyre::Result<String> {
+    get_params_in(key, Region::default(), timeout)
+}
 
-    let client = SsmClient::new(Region::default());
+/// get_params_in()
+/// Like get_params(), calling SSM ParamStore in <region>
+pub fn get_params_in(key: &str, region: Region, timeout: Option<Duration>) -> eyre::Result<String> {
+    let fetched = get_parameters_in(&[key.to_string()], region, timeout)?;
+    if fetched.is_empty() {
+        return Err(eyre!("AWS Param Store: parameter not found"));
+    }
+    Params::One(key.to_string()).data(fetched)
+}
 
-    let result = match client.get_parameters(request).await {
-        Ok(res) => res,
-        Err(e) => {
-            eprintln!("Error when fetching parameter: {:?}", e);
-            std::process::exit(exitcode::UNAVAILABLE);
-        }
-    };
-
-    let value: String = match result.parameters {
-        None => return Err(eyre!("AWS Param Store returned no data")),
-        Some(mut res) => match res.pop() {
-            None => return Err(eyre!("AWS Param Store: parameter not found")),
-            Some(param) => match param.value {
-                None => return Err(eyre!("AWS Param Store value empty")),
-                Some(value) => value,
+/// get_parameters_in()
+/// The parameters <names> in <region>, decrypted.  Names may select a
+/// version or label, as in `name:3` or `name:current`.
+#[tokio::main]
+async fn get_parameters_in(
+    names: &[String],
+    region: Region,
+    timeout: Option<Duration>,
+) -> eyre::Result<Vec<Parameter>> {
+    let client =
+        SsmClient::new_with(http::aws_client()?, credentials::aws_credentials().await?, region);
+
+    let mut params = Vec::new();
+    for names in names.chunks(MAX_PER_CALL) {
+        let request = GetParametersRequest {
+            names: names.to_vec(),
+            with_decryption: Some(true),
+        };
+        let call = client.get_parameters(request);
+        let result = match with_timeout("param_store", timeout, call).await? {
+            Ok(res) => res,
+            Err(e) => return Err(eyre!("Error when fetching parameters {:?}: {}", names, e)),
+        };
+        match result.invalid_parameters {
+            Some(invalid) if !invalid.is_empty() => {
+                return Err(eyre!("AWS Param Store: parameters not found: {}", invalid.join(", ")))
             }
+            _ => {}
         }
-    };
+        params.extend(result.parameters.unwrap_or_default());
+    }
+    Ok(params)
+}
 
-    Ok(value)
+/// The values of <params> by name, StringList values split into arrays.
+/// Every one of <keys> has to be there.
+fn params_map(
+    keys: &[String],
+    params: Vec<Parameter>,
+) -> eyre::Result<serde_json::Map<String, serde_json::Value>> {
+    let mut map = serde_json::Map::new();
+    for param in params {
+        let (name, value) = match (param.name, param.value) {
+            (Some(name), Some(value)) => (name, value),
+            (name, _) => return Err(eyre!("AWS Param Store value of {:?} empty", name)),
+        };
+        let value = match param.type_.as_deref() {
+            Some("StringList") => value.split(',').map(serde_json::Value::from).collect(),
+            _ => serde_json::Value::String(value),
+        };
+        map.insert(name, value);
+    }
+    match keys.iter().find(|key| !map.contains_key(*key)) {
+        Some(key) => Err(eyre!("AWS Param Store: parameter {} not found", key)),
+        None => Ok(map),
+    }
 }
 
 
@@ -180,14 +391,14 @@ mod test {
     use super::*;
 
     fn gen_ps_struct() -> ParamStore {
-        ParamStore::new(&"Hello", &None)
+        ParamStore::new(&"Hello", &None, "test")
     }
 
     #[test]
     fn test_create_db() {
         let p = gen_ps_struct();
 
-        let res = ParamStore::create_cache(&p.db_conn);
+        let res = ParamStore::create_cache(&p.db_conn, "test");
         assert_eq!(res, Ok(()));
     }
 
@@ -195,17 +406,42 @@ mod test {
     fn test_db_updates() {
         let p = gen_ps_struct();
 
-        let res = ParamStore::create_cache(&p.db_conn);
+        let res = ParamStore::create_cache(&p.db_conn, "test");
         assert_eq!(res, Ok(()));
 
-        let res = ParamStore::pull_latest_data(&p.db_conn);
+        let res = ParamStore::pull_latest_versions(&p.db_conn, "test");
         assert_eq!(res, Ok("".to_string()));
 
-        let res = ParamStore::update_cache(&p.db_conn, &"Yo");
+        let res = ParamStore::update_cache(&p.db_conn, "test", r#"{"Hello":3}"#);
         assert_eq!(res, Ok(()));
 
-        let res = ParamStore::pull_latest_data(&p.db_conn);
-        assert_eq!(res, Ok("Yo".to_string()));
+        let res = ParamStore::pull_latest_versions(&p.db_conn, "test");
+        assert_eq!(res, Ok(r#"{"Hello":3}"#.to_string()));
+        assert_eq!(p.version(), Some("3".to_string()));
+        assert!(!p.caches_data());
+
+        // A value cached before versions were is as good as nothing
+        ParamStore::update_cache(&p.db_conn, "test", "Yo").unwrap();
+        assert_eq!(p.cached_versions().unwrap(), None);
+        assert!(p.query().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_versions() {
+        let param = |name: &str, version: i64| Parameter {
+            name: Some(name.to_string()),
+            value: Some("secret".to_string()),
+            version: Some(version),
+            ..Parameter::default()
+        };
+        let fetched = vec![param("/app/hosts", 7), param("/app/db", 3)];
+        assert_eq!(versions(&fetched).unwrap(), r#"{"/app/db":3,"/app/hosts":7}"#);
+
+        let res = versions(&[Parameter::default()]).unwrap_err();
+        assert_eq!(res.to_string(), "AWS Param Store sent None without a version");
+
+        let data = Params::One("/app/db".to_string()).data(vec![param("/app/db", 3)]);
+        assert_eq!(data.unwrap(), "secret");
     }
 
 
@@ -214,7 +450,7 @@ mod test {
         let p = gen_ps_struct();
 
         let res = p.query().unwrap();
-        assert_eq!(res, String::from(""));
+        assert!(res.is_empty());
     }
 
     fn gen_config() -> String {
@@ -227,15 +463,44 @@ mod test {
 
     #[test]
     fn parse_config() {
-        let exp = ParamStore::new(&"Hello", &None);
+        let exp = ParamStore::new(&"Hello", &None, "test");
         let expected = format!("{:?}", exp);
 
         let maps: toml::Value = toml::from_str(&gen_config()).unwrap();
         let conf: ParamStoreConf = maps["providers"]["param_store"]
                                     .clone().try_into().unwrap();
-        let res = conf.convert();
+        let res = conf.convert("test");
         let result = format!("{:?}", res);
 
         assert_eq!(result, expected);
+
+        let maps: toml::Value = toml::from_str("keys = [\"/app/db\", \"/app/hosts\"]").unwrap();
+        let conf: ParamStoreConf = maps.try_into().unwrap();
+        let keys = vec!["/app/db".to_string(), "/app/hosts".to_string()];
+        assert_eq!(conf.convert("test").params, Params::Many(keys));
+    }
+
+    #[test]
+    fn test_params_map() {
+        let param = |name: &str, type_: &str, value: &str| Parameter {
+            name: Some(name.to_string()),
+            type_: Some(type_.to_string()),
+            value: Some(value.to_string()),
+            ..Parameter::default()
+        };
+        let keys = vec!["/app/db".to_string(), "/app/hosts".to_string()];
+        let params = vec![
+            param("/app/hosts", "StringList", "web1,web2"),
+            param("/app/db", "SecureString", "db.local,5432"),
+        ];
+        let map = params_map(&keys, params).unwrap();
+        assert_eq!(
+            serde_json::to_string(&map).unwrap(),
+            r#"{"/app/db":"db.local,5432","/app/hosts":["web1","web2"]}"#
+        );
+
+        let params = vec![param("/app/db", "String", "db.local")];
+        let res = params_map(&keys, params).unwrap_err();
+        assert_eq!(res.to_string(), "AWS Param Store: parameter /app/hosts not found");
     }
 }
@@ -0,0 +1,43 @@
+use crate::hooks::sha256;
+
+// Providers and hooks hold data and secrets that must not end up in logs or
+// error reports through Debug.  Their Debug shows a Redacted in place of
+// these: enough to tell two apart, as tests comparing structs need to, but
+// not what they hold.
+
+/// Redacted:
+/// The length and the start of the sha256 of a payload or secret
+pub struct Redacted {
+    len: usize,
+    sha256: String,
+}
+
+/// <data> as Debug may show it
+pub fn redacted<T: AsRef<[u8]>>(data: T) -> Redacted {
+    let data = data.as_ref();
+    Redacted {
+        len: data.len(),
+        sha256: sha256(data)[..12].to_string(),
+    }
+}
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<{} bytes, sha256 {}>", self.len, self.sha256)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let debug = format!("{:?}", redacted("hunter2"));
+        assert_eq!(debug, format!("<7 bytes, sha256 {}>", &sha256("hunter2")[..12]));
+        assert_ne!(debug, format!("{:?}", redacted("hunter3")));
+        let token = Some(redacted("s3cr3t"));
+        assert_eq!(format!("{:?}", token), "Some(<6 bytes, sha256 4e738ca5563c>)");
+    }
+}
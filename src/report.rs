@@ -0,0 +1,181 @@
+use crate::data::ConfigData;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// What one hook did during a run
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HookReport {
+    pub event: String,
+    pub kind: String,
+    pub status: String,
+    pub duration_ms: u64,
+    pub output_sha256: Option<String>,
+    pub detail: String,
+}
+
+/// RunReport:
+/// The record of one run of a pipeline: how the poll went, the data applied
+/// if any, and what each hook did with it.  Every run leaves one in the
+/// state file, this is what reporting on past runs reads from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RunReport {
+    pub run_id: String,
+    pub pipeline: String,
+    pub started: String,
+    pub finished: String,
+    pub status: String,
+    pub poll: String,
+    pub error: Option<String>,
+    pub provider: String,
+    pub data_version: Option<String>,
+    pub data_sha256: Option<String>,
+    pub hooks: Vec<HookReport>,
+}
+
+impl RunReport {
+    /// The report as printed by the report subcommand
+    pub fn print(&self) {
+        println!("run       {}", self.run_id);
+        println!("pipeline  {}", self.pipeline);
+        println!("started   {}", self.started);
+        println!("finished  {}", self.finished);
+        println!("status    {}", self.status);
+        println!("poll      {} {}", self.provider, self.poll);
+        if let Some(sha) = &self.data_sha256 {
+            println!(
+                "data      version {}, sha256 {}",
+                self.data_version.as_deref().unwrap_or("-"),
+                sha
+            );
+        }
+        if let Some(error) = &self.error {
+            println!("error     {}", error);
+        }
+        for hook in &self.hooks {
+            println!(
+                "  {:<9} {:<12} {:<6} {:>6}ms  {}  {}",
+                hook.event,
+                hook.kind,
+                hook.status,
+                hook.duration_ms,
+                hook.output_sha256.as_deref().unwrap_or("-"),
+                hook.detail
+            );
+        }
+    }
+}
+
+/// Run:
+/// The report of the run in progress, filled in as it goes.  A run resuming
+/// one that failed knows the steps of the hooks that <completed> before.
+#[derive(Debug)]
+pub struct Run {
+    report: RefCell<RunReport>,
+    completed: RefCell<Vec<String>>,
+}
+
+impl Run {
+    /// Start the report of a run of <pipeline> polling <provider>
+    pub fn new(pipeline: &str, provider: &str) -> Run {
+        Run {
+            report: RefCell::new(RunReport {
+                run_id: random_id(),
+                pipeline: pipeline.to_string(),
+                started: chrono::Utc::now().to_rfc3339(),
+                finished: String::new(),
+                status: String::new(),
+                poll: String::new(),
+                error: None,
+                provider: provider.to_string(),
+                data_version: None,
+                data_sha256: None,
+                hooks: Vec::new(),
+            }),
+            completed: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Resume a run that failed, its hooks at <completed> are not run again
+    pub fn resume(&self, completed: Vec<String>) {
+        self.completed.replace(completed);
+    }
+
+    /// Whether the hook at <step> completed before the run resumed
+    pub fn completed(&self, step: &str) -> bool {
+        self.completed.borrow().iter().any(|s| s == step)
+    }
+
+    /// The outcome of the poll, e.g. "changed"
+    pub fn poll(&self, detail: &str) {
+        self.report.borrow_mut().poll = detail.to_string();
+    }
+
+    /// The run applies <data>
+    pub fn data(&self, data: &ConfigData) {
+        let mut report = self.report.borrow_mut();
+        report.data_version = data.version().map(String::from);
+        report.data_sha256 = Some(data.sha256().to_string());
+    }
+
+    /// A hook ran as an <event>, e.g. "hook" or "pre_hook"
+    pub fn hook(
+        &self,
+        event: &str,
+        kind: &str,
+        status: &str,
+        duration: Duration,
+        output_sha256: Option<String>,
+        detail: &str,
+    ) {
+        self.report.borrow_mut().hooks.push(HookReport {
+            event: event.to_string(),
+            kind: kind.to_string(),
+            status: status.to_string(),
+            duration_ms: duration.as_millis() as u64,
+            output_sha256,
+            detail: detail.to_string(),
+        });
+    }
+
+    /// The finished report of a run that ended with <res>
+    pub fn finish(self, res: &eyre::Result<()>) -> RunReport {
+        let mut report = self.report.into_inner();
+        report.finished = chrono::Utc::now().to_rfc3339();
+        report.status = match res {
+            Ok(()) => "ok".to_string(),
+            Err(_) => "error".to_string(),
+        };
+        report.error = res.as_ref().err().map(|e| format!("{:#}", e));
+        report
+    }
+}
+
+fn random_id() -> String {
+    (0..8).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let run = Run::new("web", "mock");
+        run.poll("changed");
+        run.data(&ConfigData::new("port: 80", "mock", Some("3".to_string())));
+        let d = Duration::from_millis(12);
+        run.hook("hook", "template", "ok", d, Some("abc".to_string()), "");
+        run.hook("hook", "command", "error", d, None, "exit 1");
+
+        let report = run.finish(&Err(eyre::eyre!("Error running hook")));
+        assert_eq!(report.run_id.len(), 16);
+        assert_eq!(report.status, "error");
+        assert_eq!(report.error, Some("Error running hook".to_string()));
+        assert_eq!(report.data_version, Some("3".to_string()));
+        assert_eq!(report.hooks.len(), 2);
+        assert_eq!(report.hooks[0].output_sha256, Some("abc".to_string()));
+        assert_eq!(report.hooks[1].duration_ms, 12);
+    }
+}
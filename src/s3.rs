@@ -0,0 +1,34 @@
+use eyre::{eyre, Result};
+use crate::credentials;
+use crate::http;
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, S3Client, S3};
+use tokio::io::AsyncReadExt;
+
+/// get_object()
+/// Fetch the object <key> in <bucket>, using the default AWS credentials
+#[tokio::main]
+pub async fn get_object(bucket: &str, key: &str) -> Result<Vec<u8>> {
+    let client = S3Client::new_with(
+        http::aws_client()?,
+        credentials::aws_credentials().await?,
+        Region::default(),
+    );
+
+    let request = GetObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        ..Default::default()
+    };
+    let output = client
+        .get_object(request)
+        .await
+        .map_err(|e| eyre!("Unable to fetch s3://{}/{}: {}", bucket, key, e))?;
+
+    let mut data = Vec::new();
+    match output.body {
+        None => return Err(eyre!("s3://{}/{} has no content", bucket, key)),
+        Some(body) => body.into_async_read().read_to_end(&mut data).await?,
+    };
+    Ok(data)
+}
@@ -0,0 +1,282 @@
+use serde_derive::Deserialize;
+use std::ffi::OsString;
+use std::os::unix::process::CommandExt;
+
+/// What a clean environment starts from
+const CLEAN_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// SandboxConf holds the options of the hooks running commands that limit
+// what those commands may do
+#[derive(Debug, Default, Deserialize)]
+pub struct SandboxConf {
+    pub run_as: Option<String>,
+    pub clean_env: Option<bool>,
+    pub harden: Option<bool>,
+}
+
+impl SandboxConf {
+    /// Will panic if the run_as user does not exist, or harden is asked for
+    /// off Linux
+    pub fn convert(&self) -> Sandbox {
+        let user = self.run_as.as_ref().map(|name| {
+            let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+            match lookup_user(&passwd, name) {
+                Some(user) => user,
+                None => {
+                    eprintln!("Error, no user {} to run hooks as", name);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            }
+        });
+        let harden = self.harden.unwrap_or(false);
+        if harden && !cfg!(target_os = "linux") {
+            eprintln!("Error, harden is only supported on Linux");
+            std::process::exit(exitcode::CONFIG);
+        }
+        Sandbox::new(user, self.clean_env.unwrap_or(false), harden)
+    }
+}
+
+
+// // // // // // // // // // // Sandbox // // // // // // // // // // //
+
+/// User:
+/// Who a sandboxed command runs as
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+/// Sandbox:
+/// How the commands of a hook are run.  By default as app_config runs, often
+/// root with the daemon's environment.  With <user> they run as that user
+/// and its primary group instead, with <clean_env> they only get PATH, and
+/// HOME, USER and LOGNAME of <user>.  With <harden>, on Linux, they may not
+/// gain privileges through setuid binaries, and the syscalls that change
+/// the system rather than a service (mount, ptrace, reboot, loading kernel
+/// modules, ...) fail with EPERM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    user: Option<User>,
+    clean_env: bool,
+    harden: bool,
+}
+
+impl Sandbox {
+    pub fn new(user: Option<User>, clean_env: bool, harden: bool) -> Sandbox {
+        Sandbox {
+            user,
+            clean_env,
+            harden,
+        }
+    }
+
+    /// Set up <cmd> to run in the sandbox
+    pub fn apply(&self, cmd: &mut std::process::Command) {
+        if self.clean_env {
+            cmd.env_clear().env("PATH", CLEAN_PATH);
+        }
+        if let Some(user) = &self.user {
+            // Also drops the supplementary groups of root
+            cmd.uid(user.uid).gid(user.gid);
+            if self.clean_env {
+                cmd.env("HOME", &user.home).env("USER", &user.name).env("LOGNAME", &user.name);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if self.harden {
+            harden::apply(cmd);
+        }
+    }
+
+    /// Run <command> through bash in the sandbox, with <env> added, failing
+    /// unless it exits with 0.  Its output is dropped.
+    pub fn run_checked(&self, command: &str, env: Vec<(&str, OsString)>) -> eyre::Result<()> {
+        let mut cmd = std::process::Command::new("/bin/bash");
+        cmd.arg("-c").arg(command);
+        self.apply(&mut cmd);
+        cmd.envs(env);
+        let status = cmd.stdout(std::process::Stdio::null()).status()?;
+        if !status.success() {
+            return Err(eyre::eyre!("{} exited with {}", command, status));
+        }
+        Ok(())
+    }
+}
+
+/// The user named, or numbered, <name> in the /etc/passwd style <passwd>
+pub fn lookup_user(passwd: &str, name: &str) -> Option<User> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 || (fields[0] != name && fields[2] != name) {
+            return None;
+        }
+        Some(User {
+            name: fields[0].to_string(),
+            uid: fields[2].parse().ok()?,
+            gid: fields[3].parse().ok()?,
+            home: fields[5].to_string(),
+        })
+    })
+}
+
+
+// // // // // // // // // // // Harden // // // // // // // // // // //
+
+#[cfg(target_os = "linux")]
+mod harden {
+    use std::os::unix::process::CommandExt;
+
+    /// Syscalls a hook has no business making
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_sethostname,
+    ];
+
+    // The architecture the syscall numbers above are for, as seccomp sees it
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: u32 = 0;
+
+    // Classic BPF, and the seccomp verdicts
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    const RET_ALLOW: u32 = 0x7fff_0000;
+    const RET_ERRNO: u32 = 0x0005_0000;
+    const RET_KILL_PROCESS: u32 = 0x8000_0000;
+    // Where the syscall number and architecture are in seccomp_data
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// Have <cmd> set no_new_privs and install the seccomp filter before it
+    /// starts.  A filter for another architecture would deny the wrong
+    /// syscalls, so there is only no_new_privs there.
+    pub fn apply(cmd: &mut std::process::Command) {
+        // Built here, between fork and exec nothing may allocate
+        let filter = filter();
+        let seccomp = AUDIT_ARCH != 0;
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if seccomp {
+                    let prog = libc::sock_fprog {
+                        len: filter.len() as u16,
+                        filter: filter.as_ptr() as *mut libc::sock_filter,
+                    };
+                    let prog = &prog as *const libc::sock_fprog;
+                    if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, prog) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Kill a process calling in through another architecture, deny the
+    /// DENIED syscalls and allow the rest
+    fn filter() -> Vec<libc::sock_filter> {
+        let stmt = |code, k| libc::sock_filter { code, jt: 0, jf: 0, k };
+        let jeq = |k, jt, jf| libc::sock_filter { code: BPF_JMP_JEQ_K, jt, jf, k };
+
+        let mut filter = vec![
+            stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+            jeq(AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, NR_OFFSET),
+        ];
+        for nr in DENIED {
+            filter.push(jeq(*nr as u32, 0, 1));
+            filter.push(stmt(BPF_RET_K, RET_ERRNO | libc::EPERM as u32));
+        }
+        filter.push(stmt(BPF_RET_K, RET_ALLOW));
+        filter
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup_user() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      deploy:x:1001:100:Deploy:/home/deploy:/bin/sh\n";
+        let exp = User {
+            name: "deploy".to_string(),
+            uid: 1001,
+            gid: 100,
+            home: "/home/deploy".to_string(),
+        };
+        assert_eq!(lookup_user(passwd, "deploy"), Some(exp.clone()));
+        assert_eq!(lookup_user(passwd, "1001"), Some(exp));
+        assert_eq!(lookup_user(passwd, "nobody"), None);
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let run = |sandbox: Sandbox, script: &str| {
+            let mut cmd = std::process::Command::new("/bin/bash");
+            cmd.arg("-c").arg(script);
+            sandbox.apply(&mut cmd);
+            let out = cmd.output().unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        let clean = Sandbox::new(None, true, false);
+        let env = run(clean, "env | cut -d= -f1 | grep -v -e '^_$' -e '^SHLVL$' -e '^PWD$'");
+        assert_eq!(env, "PATH\n");
+
+        #[cfg(target_os = "linux")]
+        {
+            let hardened = Sandbox::new(None, false, true);
+            let status = run(hardened, "grep -e NoNewPrivs -e Seccomp: /proc/self/status");
+            assert!(status.contains("NoNewPrivs:\t1"), "{}", status);
+            assert!(status.contains("Seccomp:\t2"), "{}", status);
+        }
+    }
+
+    #[test]
+    fn test_run_checked() {
+        let sandbox = Sandbox::new(None, true, false);
+        let env = vec![("RELOAD", OsString::from("yes"))];
+        assert!(sandbox.run_checked("test \"$RELOAD\" = yes", env).is_ok());
+
+        let err = sandbox.run_checked("exit 3", Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "exit 3 exited with exit status: 3");
+    }
+}
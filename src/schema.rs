@@ -0,0 +1,133 @@
+use eyre::{eyre, Result};
+use jsonschema::JSONSchema;
+
+use std::fmt;
+
+/// Report at most this many violations, the rest are only counted
+const MAX_REPORTED: usize = 10;
+
+/// Schema:
+/// A JSON Schema loaded from a file, used to check data before it is passed
+/// on.  Violations are reported together with the path of the offending
+/// value, e.g. `/hosts/1/port`.
+pub struct Schema {
+    path: String,
+    compiled: JSONSchema,
+}
+
+impl Schema {
+    /// Load and compile the schema in <path>
+    /// Exits if the file can not be read or is not a valid schema, since it
+    /// is part of the configuration.
+    pub fn from_file(path: &str) -> Schema {
+        let expanded_path = shellexpand::tilde(path).to_string();
+        let contents = match std::fs::read_to_string(&expanded_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Could not open {}: {}", path, e);
+                std::process::exit(exitcode::OSFILE);
+            }
+        };
+
+        match Schema::compile(path, &contents) {
+            Ok(schema) => schema,
+            Err(e) => {
+                eprintln!("Error, {}", e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+    }
+
+    /// Compile the schema in <contents>, <path> is only used in messages
+    pub fn compile(path: &str, contents: &str) -> Result<Schema> {
+        let value: serde_json::Value = serde_json::from_str(contents)
+            .map_err(|e| eyre!("Schema {} is not valid JSON: {}", path, e))?;
+        let compiled = JSONSchema::compile(&value)
+            .map_err(|e| eyre!("Schema {} is not a valid JSON Schema: {}", path, e))?;
+
+        Ok(Schema {
+            path: path.to_string(),
+            compiled,
+        })
+    }
+
+    /// Check <value> against the schema, listing every violation on failure
+    pub fn validate(&self, value: &serde_json::Value) -> Result<()> {
+        let errors = match self.compiled.validate(value) {
+            Ok(()) => return Ok(()),
+            Err(errors) => errors,
+        };
+
+        let errors: Vec<String> = errors
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                let path = if path.is_empty() { "/".to_string() } else { path };
+                format!("at {}: {}", path, e)
+            })
+            .collect();
+
+        let mut report = format!("{} violation(s) of schema {}", errors.len(), self.path);
+        for e in errors.iter().take(MAX_REPORTED) {
+            report.push_str("\n  ");
+            report.push_str(e);
+        }
+        if errors.len() > MAX_REPORTED {
+            report.push_str(&format!("\n  ... and {} more", errors.len() - MAX_REPORTED));
+        }
+        Err(eyre!(report))
+    }
+}
+
+impl fmt::Debug for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Schema").field("path", &self.path).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen_schema() -> Schema {
+        Schema::compile(
+            "hosts.json",
+            r#"{
+                "type": "object",
+                "required": ["hosts"],
+                "properties": {
+                    "hosts": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": { "port": { "type": "integer" } }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = gen_schema();
+
+        let good = serde_json::json!({"hosts": [{"port": 80}]});
+        assert!(schema.validate(&good).is_ok());
+
+        let bad = serde_json::json!({"hosts": [{"port": 80}, {"port": "http"}]});
+        let res = format!("{}", schema.validate(&bad).unwrap_err());
+        assert!(res.starts_with("1 violation(s) of schema hosts.json"), "{}", res);
+        assert!(res.contains("at /hosts/1/port:"), "{}", res);
+
+        let res = format!("{}", schema.validate(&serde_json::json!({})).unwrap_err());
+        assert!(res.contains("at /:"), "{}", res);
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        assert!(Schema::compile("bad.json", "{").is_err());
+        assert!(Schema::compile("bad.json", r#"{"type": 12}"#).is_err());
+    }
+}
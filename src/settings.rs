@@ -0,0 +1,57 @@
+use serde_derive::Deserialize;
+
+use crate::checksum::OnDrift;
+#[cfg(feature = "aws")]
+use crate::cloudwatch::CloudWatchConf;
+use crate::coordination::CoordinationConf;
+#[cfg(feature = "aws")]
+use crate::credentials::CredentialsConf;
+use crate::hooks::template::DataType;
+use crate::http::HttpConf;
+#[cfg(feature = "aws")]
+use crate::listen::ListenConf;
+use crate::reporting::ErrorReportingConf;
+use crate::telemetry::OtlpConf;
+use crate::window::WindowConf;
+
+/// Settings:
+/// Global options that apply to the whole run rather than to the provider or
+/// to a single hook.  Stored under [settings] in the config file, every
+/// option is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename = "settings")]
+pub struct Settings {
+    pub name: Option<String>,
+    pub interval: Option<String>,
+    pub enabled: Option<bool>,
+    pub state_file: Option<String>,
+    // state_dir is read before the rest, see Config::derive_state_files
+    pub failure_threshold: Option<usize>,
+    pub audit: Option<bool>,
+    #[cfg(feature = "aws")]
+    pub cloudwatch: Option<CloudWatchConf>,
+    pub otlp: Option<OtlpConf>,
+    pub error_reporting: Option<ErrorReportingConf>,
+    pub schema: Option<String>,
+    pub source_type: Option<DataType>,
+    pub for_each: Option<String>,
+    pub timeout: Option<String>,
+    pub allow_stale: Option<String>,
+    pub min_poll_interval: Option<String>,
+    pub min_apply_interval: Option<String>,
+    pub maintenance_windows: Option<Vec<WindowConf>>,
+    pub require_approval: Option<bool>,
+    pub sensitive: Option<bool>,
+    pub keep_failed_workspace: Option<bool>,
+    pub max_payload_size: Option<usize>,
+    pub spool_payload_size: Option<usize>,
+    pub coordination: Option<CoordinationConf>,
+    pub daily_poll_budget: Option<usize>,
+    pub bootstrap: Option<bool>,
+    pub on_drift: Option<OnDrift>,
+    pub http: Option<HttpConf>,
+    #[cfg(feature = "aws")]
+    pub aws_credentials: Option<CredentialsConf>,
+    #[cfg(feature = "aws")]
+    pub listen: Option<ListenConf>,
+}
@@ -0,0 +1,182 @@
+use eyre::{eyre, Result, WrapErr};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Payloads of tens of megabytes held on the heap stay there for as long as a
+// run, or the daemon, holds on to them.  Spooled to a file and mapped, the
+// kernel can drop their pages under memory pressure and read them back when
+// a hook needs them.
+
+// Spooled payloads of one process each get a file of their own
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Payload:
+/// Where data is kept, on the heap or in a spool file
+pub enum Payload {
+    Memory(Vec<u8>),
+    Spooled(Spooled),
+}
+
+impl Payload {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Payload::Memory(raw) => raw,
+            Payload::Spooled(spooled) => spooled.as_slice(),
+        }
+    }
+}
+
+/// Spool:
+/// Where data is written as it is decoded.  It is kept on the heap until
+/// it grows larger than <threshold> bytes, if there is one, then moved to a
+/// spool file and written there as it comes, so it is never held on the
+/// heap whole.  The file is removed as soon as it is created, the handle
+/// and then the mapping are all that keep it, so nothing is left behind
+/// should app_config be killed.
+pub struct Spool {
+    threshold: Option<usize>,
+    buf: Vec<u8>,
+    file: Option<File>,
+    len: usize,
+}
+
+impl Spool {
+    pub fn new(threshold: Option<usize>) -> Spool {
+        Spool {
+            threshold,
+            buf: Vec::new(),
+            file: None,
+            len: 0,
+        }
+    }
+
+    /// The data written, mapped from the spool file if it went there
+    pub fn finish(self) -> Result<Payload> {
+        let mut file = match self.file {
+            Some(file) => file,
+            None => return Ok(Payload::Memory(self.buf)),
+        };
+        file.flush().wrap_err("Unable to write spool file")?;
+        Ok(Payload::Spooled(Spooled::map(&file, self.len)?))
+    }
+
+    /// A new spool file in the temp dir, already removed
+    fn create() -> std::io::Result<File> {
+        let path = std::env::temp_dir().join(format!(
+            "app_config-spool-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let spill = self.threshold.is_some_and(|threshold| self.len + data.len() > threshold);
+        if self.file.is_none() && spill {
+            let mut file = Spool::create()?;
+            file.write_all(&self.buf)?;
+            self.buf = Vec::new();
+            self.file = Some(file);
+        }
+        let written = match &mut self.file {
+            Some(file) => file.write(data)?,
+            None => {
+                self.buf.extend_from_slice(data);
+                data.len()
+            }
+        };
+        self.len += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Spooled:
+/// A payload written to a spool file and mapped read only
+pub struct Spooled {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read only and owned by the Spooled alone
+unsafe impl Send for Spooled {}
+unsafe impl Sync for Spooled {}
+
+impl Spooled {
+    /// Map the <len> bytes of <file>, which may not be empty
+    fn map(file: &File, len: usize) -> Result<Spooled> {
+        if len == 0 {
+            return Err(eyre!("Empty data can not be spooled"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).wrap_err("Unable to map spool file");
+        }
+        Ok(Spooled { ptr, len })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spooled() {
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut spool = Spool::new(Some(1024));
+        for chunk in data.chunks(300) {
+            spool.write_all(chunk).unwrap();
+        }
+        assert!(spool.buf.is_empty());
+        let payload = spool.finish().unwrap();
+        assert!(matches!(payload, Payload::Spooled(_)));
+        assert_eq!(payload.as_slice(), data.as_slice());
+
+        let mut spool = Spool::new(Some(1024));
+        spool.write_all(b"hosts: [web]").unwrap();
+        assert!(matches!(spool.finish().unwrap(), Payload::Memory(_)));
+        let mut spool = Spool::new(None);
+        spool.write_all(&data).unwrap();
+        assert!(matches!(spool.finish().unwrap(), Payload::Memory(_)));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::hooks::sha256;
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use shellexpand::tilde;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// State is kept in sqlite files, or with the state-sqlite feature off in
+// plain JSON files, for builds that link no C code, e.g. a static musl
+// binary.  Either backend gives the same State, and the same Db providers
+// keep their cache in.
+#[cfg(not(feature = "state-sqlite"))]
+mod file;
+#[cfg(feature = "state-sqlite")]
+mod sqlite;
+#[cfg(not(feature = "state-sqlite"))]
+pub use file::{open_db, Cached, Db, Result, State};
+#[cfg(feature = "state-sqlite")]
+pub use sqlite::{open_db, Db, Result, State};
+
+/// Number of run reports kept for each pipeline
+const RUN_HISTORY: i64 = 100;
+
+/// The settings of each kind of provider that tell which data it polls.
+/// The others, e.g. its timeout, may change without the pipeline getting
+/// a new state file.
+const PROVIDER_IDENTITY: [(&str, &[&str]); 4] = [
+    ("appconfig", &["application", "environment", "configuration"]),
+    ("param_store", &["key", "keys", "label"]),
+    ("exec", &["command", "args", "config"]),
+    ("mock", &[]),
+];
+
+/// One entry of the audit log
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct AuditEntry {
+    pub time: String,
+    pub event: String,
+    pub target: String,
+    pub status: String,
+    pub detail: String,
+    pub sha256: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl AuditEntry {
+    /// Create a new entry timestamped now
+    pub fn new(
+        event: &str,
+        target: &str,
+        status: &str,
+        detail: &str,
+        sha256: Option<String>,
+        duration: std::time::Duration,
+    ) -> AuditEntry {
+        AuditEntry {
+            time: chrono::Utc::now().to_rfc3339(),
+            event: event.to_string(),
+            target: target.to_string(),
+            status: status.to_string(),
+            detail: detail.to_string(),
+            sha256,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// StateDir:
+/// Where pipelines keep their state when they name no state_file, from
+/// settings.state_dir: `true` for the default directory, see default_dir(),
+/// or a directory of one's own.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum StateDir {
+    Default(bool),
+    Path(String),
+}
+
+impl StateDir {
+    /// The directory, unless state_dir is false
+    pub fn path(&self) -> Option<PathBuf> {
+        match self {
+            StateDir::Default(false) => None,
+            StateDir::Default(true) => Some(default_dir()),
+            StateDir::Path(path) => Some(PathBuf::from(tilde(path).to_string())),
+        }
+    }
+}
+
+/// /var/lib/app_config when running as root, else app_config in the XDG
+/// data directory of the user
+pub fn default_dir() -> PathBuf {
+    let root = std::fs::metadata("/proc/self").map(|m| m.uid() == 0).unwrap_or(false);
+    if root {
+        return PathBuf::from("/var/lib/app_config");
+    }
+    match std::env::var("XDG_DATA_HOME") {
+        Ok(data_home) if !data_home.is_empty() => PathBuf::from(data_home).join("app_config"),
+        _ => PathBuf::from(tilde("~/.local/share/app_config").to_string()),
+    }
+}
+
+/// The state file in <dir> of <pipeline>, polling a provider of <kind>
+/// configured by <provider>.  The name ends in a hash of what the provider
+/// polls, see PROVIDER_IDENTITY, so that pipelines of the same name polling
+/// different data never share it.  Providers of other kinds are known by
+/// their whole section.
+pub fn derived_file(dir: &Path, pipeline: &str, kind: &str, provider: &toml::Value) -> String {
+    let identity = match PROVIDER_IDENTITY.iter().find(|(known, _)| *known == kind) {
+        Some((_, keys)) => keys
+            .iter()
+            .filter_map(|key| provider.get(key).map(|value| format!("{}={}", key, value)))
+            .fold(kind.to_string(), |identity, value| identity + "\n" + &value),
+        None => format!("{}\n{}", kind, provider),
+    };
+    let file = format!("{}-{:.12}.db", pipeline, sha256(identity));
+    dir.join(file).to_string_lossy().to_string()
+}
+
+/// The current day, as polls are counted by
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_dir() {
+        let dir: StateDir = toml::Value::from("~/state").try_into().unwrap();
+        assert_eq!(dir.path(), Some(PathBuf::from(tilde("~/state").to_string())));
+        let dir: StateDir = toml::Value::from(false).try_into().unwrap();
+        assert_eq!(dir.path(), None);
+
+        let provider: toml::Value = toml::from_str("key = \"/myApp/db\"").unwrap();
+        let file = derived_file(Path::new("/var/lib/app_config"), "db", "param_store", &provider);
+        assert!(file.starts_with("/var/lib/app_config/db-"), "{}", file);
+        assert_eq!(file.len(), "/var/lib/app_config/db-".len() + 12 + ".db".len());
+
+        let other: toml::Value = toml::from_str("key = \"/otherApp/db\"").unwrap();
+        let path = Path::new("/var/lib/app_config");
+        assert_ne!(derived_file(path, "db", "param_store", &other), file);
+
+        // Settings that leave the data polled alone keep the file
+        let tuned: toml::Value =
+            toml::from_str("key = \"/myApp/db\"\ntimeout = \"5s\"\nregions = [\"eu-west-1\"]")
+                .unwrap();
+        assert_eq!(derived_file(path, "db", "param_store", &tuned), file);
+        let labelled: toml::Value =
+            toml::from_str("key = \"/myApp/db\"\nlabel = \"prod\"").unwrap();
+        assert_ne!(derived_file(path, "db", "param_store", &labelled), file);
+    }
+
+    #[test]
+    fn test_failures() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.failures(), Ok(0));
+
+        assert_eq!(state.record_failure(), Ok(1));
+        assert_eq!(state.record_failure(), Ok(2));
+
+        assert_eq!(state.reset_failures(), Ok(2));
+        assert_eq!(state.failures(), Ok(0));
+    }
+
+    #[test]
+    fn test_contact() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.last_contact(), Ok(None));
+
+        let before = Utc::now();
+        assert_eq!(state.record_contact(), Ok(()));
+        let res = state.last_contact().unwrap().unwrap();
+        assert!(res >= before - chrono::Duration::seconds(1) && res <= Utc::now());
+    }
+
+    #[test]
+    fn test_progress() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.progress(), Ok(None));
+
+        state.begin_hooks("abc").unwrap();
+        assert_eq!(state.progress(), Ok(Some(("abc".to_string(), vec![]))));
+        state.record_completed("pre_hook 1").unwrap();
+        state.record_completed("hook 1 of element 2").unwrap();
+        let (sha256, completed) = state.progress().unwrap().unwrap();
+        assert_eq!(sha256, "abc");
+        assert_eq!(completed, vec!["pre_hook 1", "hook 1 of element 2"]);
+
+        // Hooks starting on other data start over
+        state.begin_hooks("def").unwrap();
+        assert_eq!(state.progress(), Ok(Some(("def".to_string(), vec![]))));
+        state.end_hooks().unwrap();
+        assert_eq!(state.progress(), Ok(None));
+    }
+
+    #[test]
+    fn test_applied_data() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.applied_data(), Ok(None));
+
+        state.record_applied_data(&[0x1f, 0x8b, 0xff]).unwrap();
+        state.record_applied_data(b"port: 80").unwrap();
+        assert_eq!(state.applied_data(), Ok(Some(b"port: 80".to_vec())));
+    }
+
+    #[test]
+    fn test_files() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.files(), Ok(vec![]));
+
+        state.record_files(&["/etc/b.conf".to_string(), "/etc/a.conf".to_string()]).unwrap();
+        state.record_files(&["/etc/a.conf".to_string()]).unwrap();
+        assert_eq!(state.files(), Ok(vec!["/etc/a.conf".to_string(), "/etc/b.conf".to_string()]));
+        state.forget_files(&["/etc/a.conf".to_string()]).unwrap();
+        assert_eq!(state.files(), Ok(vec!["/etc/b.conf".to_string()]));
+    }
+
+    #[test]
+    fn test_audit() {
+        let state = State::new(&None, "test", true);
+        let d = std::time::Duration::from_millis(12);
+
+        for n in 0..3 {
+            let e = AuditEntry::new("hook", &format!("h{}", n), "ok", "", None, d);
+            assert_eq!(state.audit(&e), Ok(()));
+        }
+
+        let res = state.audit_log(2).unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].target, "h1");
+        assert_eq!(res[1].target, "h2");
+        assert_eq!(res[1].duration_ms, 12);
+    }
+
+    #[test]
+    fn test_audit_disabled() {
+        let state = State::new(&None, "test", false);
+        let e = AuditEntry::new("poll", "mock", "ok", "changed", None, Default::default());
+        assert_eq!(state.audit(&e), Ok(()));
+
+        assert_eq!(state.audit_log(10).unwrap(), vec![]);
+    }
+}
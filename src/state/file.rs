@@ -0,0 +1,603 @@
+use super::{today, AuditEntry, RUN_HISTORY};
+use crate::lock::flock;
+use crate::report::RunReport;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// What a state file holds: tables of values by key, most of them by pipeline
+type Tables = BTreeMap<String, BTreeMap<String, serde_json::Value>>;
+
+/// Error:
+/// A state file that could not be read or written, or holding something
+/// other than expected
+#[derive(Debug, PartialEq)]
+pub struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error(e.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Db:
+/// A state file kept without sqlite, as JSON.  It is read again whenever a
+/// value is needed, as other pipelines and processes may share it.  Writes
+/// take a lock on the file next to it, then replace it whole, so it is
+/// never seen half written.  Without a file the tables are kept in memory.
+/// Debug only shows the path, the tables may hold secrets.
+pub struct Db {
+    path: Option<PathBuf>,
+    memory: RefCell<Tables>,
+}
+
+impl std::fmt::Debug for Db {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Db").field("path", &self.path).finish()
+    }
+}
+
+/// Open the state file <state_file>, or keep state in memory if there is
+/// none.  Files hold no layout to upgrade, the tables of <pipeline> are
+/// created as values are first written to them.
+/// Will panic if the file can not be read.
+pub fn open_db(state_file: &Option<String>, _pipeline: &str) -> Db {
+    let db = Db {
+        path: state_file.as_ref().map(PathBuf::from),
+        memory: RefCell::new(Tables::new()),
+    };
+    if let Err(e) = db.tables() {
+        eprintln!("Error, unable to open state file: {}", e);
+        std::process::exit(exitcode::OSFILE);
+    }
+    db
+}
+
+impl Db {
+    /// The value of <key> in <table>, if there is one
+    pub fn get<T: DeserializeOwned>(&self, table: &str, key: &str) -> Result<Option<T>> {
+        match self.tables()?.get(table).and_then(|values| values.get(key)) {
+            Some(value) => Ok(Some(serde_json::from_value(value.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set <key> in <table> to <value>
+    pub fn put<T: serde::Serialize>(&self, table: &str, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        self.update(|tables| {
+            tables.entry(table.to_string()).or_default().insert(key.to_string(), value);
+            Ok(())
+        })
+    }
+
+    /// Remove <key> from <table>, returning whether it was there
+    pub fn delete(&self, table: &str, key: &str) -> Result<bool> {
+        self.update(|tables| {
+            Ok(tables.get_mut(table).and_then(|values| values.remove(key)).is_some())
+        })
+    }
+
+    /// Set <key> in <table> to what <change> makes of its current value,
+    /// with no other write in between, and return the new value
+    pub fn modify<T, F>(&self, table: &str, key: &str, change: F) -> Result<T>
+    where
+        T: DeserializeOwned + serde::Serialize,
+        F: FnOnce(Option<T>) -> T,
+    {
+        self.update(|tables| {
+            let values = tables.entry(table.to_string()).or_default();
+            let current = match values.remove(key) {
+                Some(value) => Some(serde_json::from_value(value)?),
+                None => None,
+            };
+            let value = change(current);
+            values.insert(key.to_string(), serde_json::to_value(&value)?);
+            Ok(value)
+        })
+    }
+
+    /// All the file holds, nothing if it does not exist yet
+    fn tables(&self) -> Result<Tables> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(self.memory.borrow().clone()),
+        };
+        match std::fs::read(path) {
+            Ok(raw) if raw.is_empty() => Ok(Tables::new()),
+            Ok(raw) => serde_json::from_slice(&raw).map_err(|e| {
+                let path = path.display();
+                Error(format!("{} is not a state file written without sqlite: {}", path, e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Tables::new()),
+            Err(e) => Err(io_error("read", path, e)),
+        }
+    }
+
+    /// Apply <change> to the tables and write them back, holding the lock
+    fn update<R, F>(&self, change: F) -> Result<R>
+    where
+        F: FnOnce(&mut Tables) -> Result<R>,
+    {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return change(&mut self.memory.borrow_mut()),
+        };
+        let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+        let lock = open_private(&lock_path, false)?;
+        flock(&lock, libc::LOCK_EX).map_err(|e| io_error("lock", &lock_path, e))?;
+
+        let mut tables = self.tables()?;
+        let res = change(&mut tables)?;
+
+        let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut tmp = open_private(&tmp_path, true)?;
+        let raw = serde_json::to_vec(&tables)?;
+        tmp.write_all(&raw)
+            .and_then(|_| tmp.sync_all())
+            .map_err(|e| io_error("write", &tmp_path, e))?;
+        std::fs::rename(&tmp_path, path).map_err(|e| io_error("replace", path, e))?;
+        Ok(res)
+    }
+}
+
+/// Open <path> for writing, created readable by its owner alone
+fn open_private(path: &Path, truncate: bool) -> Result<std::fs::File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(truncate)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| io_error("open", path, e))
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> Error {
+    Error(format!("unable to {} {}: {}", action, path.display(), e))
+}
+
+/// Cached:
+/// The data a provider keeps between runs, with its version if the source
+/// has versions.  Data may be anything, it is kept as base64.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Cached {
+    pub version: Option<String>,
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+}
+
+mod base64_data {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(&encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// When the provider was last polled, and how often on that day
+#[derive(Deserialize, Serialize)]
+struct Polls {
+    time: String,
+    day: String,
+    count: usize,
+}
+
+/// When data was last applied, and whether a change waits for
+/// min_apply_interval to be over
+#[derive(Deserialize, Serialize)]
+struct Applies {
+    time: String,
+    pending: bool,
+}
+
+/// The hooks that completed on the data being applied, until every hook did
+#[derive(Deserialize, Serialize)]
+struct Progress {
+    sha256: String,
+    completed: Vec<String>,
+}
+
+/// State:
+/// Run level state that has to survive between runs, as opposed to the data
+/// cached by each provider.  Kept in the file named by settings.state_file,
+/// or in memory if there is none.  The file can be shared by every pipeline
+/// of a host, each only sees its own state.
+#[derive(Debug)]
+pub struct State {
+    db_conn: Db,
+    pipeline: String,
+    audit: bool,
+}
+
+impl State {
+    /// Open (or create) the state file, for the state of <pipeline>
+    /// If <audit> is false, entries passed to audit() are dropped.
+    /// Will panic if the file can not be read.
+    pub fn new(state_file: &Option<String>, pipeline: &str, audit: bool) -> State {
+        State {
+            db_conn: open_db(state_file, pipeline),
+            pipeline: pipeline.to_string(),
+            audit,
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, table: &str) -> Result<Option<T>> {
+        self.db_conn.get(table, &self.pipeline)
+    }
+
+    fn put<T: serde::Serialize>(&self, table: &str, value: &T) -> Result<()> {
+        self.db_conn.put(table, &self.pipeline, value)
+    }
+
+    fn modify<T, F>(&self, table: &str, change: F) -> Result<T>
+    where
+        T: DeserializeOwned + serde::Serialize,
+        F: FnOnce(Option<T>) -> T,
+    {
+        self.db_conn.modify(table, &self.pipeline, change)
+    }
+
+    /// Number of consecutive failed runs
+    pub fn failures(&self) -> Result<usize> {
+        Ok(self.get("failures")?.unwrap_or(0))
+    }
+
+    /// Count another failed run, returning the new number of
+    /// consecutive failures
+    pub fn record_failure(&self) -> Result<usize> {
+        self.modify("failures", |count: Option<usize>| count.unwrap_or(0) + 1)
+    }
+
+    /// A run succeeded, returning the number of consecutive failures
+    /// that preceded it
+    pub fn reset_failures(&self) -> Result<usize> {
+        let failures = self.failures()?;
+        self.put("failures", &0)?;
+        Ok(failures)
+    }
+
+    /// When the provider was last reached, if ever
+    pub fn last_contact(&self) -> Result<Option<DateTime<Utc>>> {
+        let time: Option<String> = self.get("contact")?;
+        Ok(time.and_then(|t| parse_time(&t)))
+    }
+
+    /// The provider was reached just now
+    pub fn record_contact(&self) -> Result<()> {
+        self.put("contact", &Utc::now().to_rfc3339())
+    }
+
+    /// When the provider was last polled, if ever, and how many times it was
+    /// polled today (UTC)
+    pub fn polls(&self) -> Result<(Option<DateTime<Utc>>, usize)> {
+        Ok(match self.get::<Polls>("polls")? {
+            Some(polls) => {
+                let count = if polls.day == today() { polls.count } else { 0 };
+                (parse_time(&polls.time), count)
+            }
+            None => (None, 0),
+        })
+    }
+
+    /// The provider is being polled now, whether it answers or not
+    pub fn record_poll(&self) -> Result<()> {
+        let day = today();
+        self.modify("polls", |polls: Option<Polls>| {
+            let count = match polls {
+                Some(polls) if polls.day == day => polls.count + 1,
+                _ => 1,
+            };
+            Polls {
+                time: Utc::now().to_rfc3339(),
+                day,
+                count,
+            }
+        })?;
+        Ok(())
+    }
+
+    /// When data was last applied, if ever, and whether a change since
+    /// waits to be applied
+    pub fn applies(&self) -> Result<(Option<DateTime<Utc>>, bool)> {
+        Ok(match self.get::<Applies>("applies")? {
+            Some(applies) => (parse_time(&applies.time), applies.pending),
+            None => (None, false),
+        })
+    }
+
+    /// Data is being applied now, nothing waits any more
+    pub fn record_apply(&self) -> Result<()> {
+        let applies = Applies {
+            time: Utc::now().to_rfc3339(),
+            pending: false,
+        };
+        self.put("applies", &applies)
+    }
+
+    /// A change waits to be applied.  Data never applied has no time.
+    pub fn record_pending(&self) -> Result<()> {
+        self.modify("applies", |applies: Option<Applies>| Applies {
+            time: applies.map(|applies| applies.time).unwrap_or_default(),
+            pending: true,
+        })?;
+        Ok(())
+    }
+
+    /// When changes were frozen and why, if they are
+    pub fn frozen(&self) -> Result<Option<(String, String)>> {
+        self.get("freeze")
+    }
+
+    /// Freeze changes for <reason>, until thawed
+    pub fn freeze(&self, reason: &str) -> Result<()> {
+        self.put("freeze", &(Utc::now().to_rfc3339(), reason))
+    }
+
+    /// Let changes be applied again, returning whether they were frozen
+    pub fn thaw(&self) -> Result<bool> {
+        self.db_conn.delete("freeze", &self.pipeline)
+    }
+
+    /// The id of the session named <name>, if one was kept
+    pub fn session(&self, name: &str) -> Result<Option<String>> {
+        let sessions: Option<BTreeMap<String, String>> = self.get("sessions")?;
+        Ok(sessions.and_then(|mut sessions| sessions.remove(name)))
+    }
+
+    /// Keep <id> as the session named <name>
+    pub fn record_session(&self, name: &str, id: &str) -> Result<()> {
+        self.modify("sessions", |sessions: Option<BTreeMap<String, String>>| {
+            let mut sessions = sessions.unwrap_or_default();
+            sessions.insert(name.to_string(), id.to_string());
+            sessions
+        })?;
+        Ok(())
+    }
+
+    /// The normalized fingerprint of the data last polled, if any
+    pub fn fingerprint(&self) -> Result<Option<String>> {
+        self.get("fingerprints")
+    }
+
+    /// Remember <fingerprint> as that of the data just polled
+    pub fn record_fingerprint(&self, fingerprint: &str) -> Result<()> {
+        self.put("fingerprints", &fingerprint)
+    }
+
+    /// The data of a run whose hooks failed, by its hash, and the steps of
+    /// the hooks that completed on it.  None once every hook did.
+    pub fn progress(&self) -> Result<Option<(String, Vec<String>)>> {
+        let progress: Option<Progress> = self.get("progress")?;
+        Ok(progress.map(|progress| (progress.sha256, progress.completed)))
+    }
+
+    /// The hooks start on the data hashed to <sha256>, none completed yet
+    pub fn begin_hooks(&self, sha256: &str) -> Result<()> {
+        let progress = Progress {
+            sha256: sha256.to_string(),
+            completed: Vec::new(),
+        };
+        self.put("progress", &progress)
+    }
+
+    /// The hook at <step> completed
+    pub fn record_completed(&self, step: &str) -> Result<()> {
+        if let Some(mut progress) = self.get::<Progress>("progress")? {
+            progress.completed.push(step.to_string());
+            self.put("progress", &progress)?;
+        }
+        Ok(())
+    }
+
+    /// Every hook completed, there is nothing left to resume
+    pub fn end_hooks(&self) -> Result<()> {
+        self.db_conn.delete("progress", &self.pipeline)?;
+        Ok(())
+    }
+
+    /// The data last applied in full, if kept
+    pub fn applied_data(&self) -> Result<Option<Vec<u8>>> {
+        let applied: Option<Cached> = self.get("applied")?;
+        Ok(applied.map(|applied| applied.data))
+    }
+
+    /// Keep <data> as the data last applied in full
+    pub fn record_applied_data(&self, data: &[u8]) -> Result<()> {
+        let applied = Cached {
+            version: None,
+            data: data.to_vec(),
+        };
+        self.put("applied", &applied)
+    }
+
+    /// The files the hooks wrote, by path
+    pub fn files(&self) -> Result<Vec<String>> {
+        Ok(self.get("files")?.unwrap_or_default())
+    }
+
+    /// The hooks wrote <paths>
+    pub fn record_files(&self, paths: &[String]) -> Result<()> {
+        self.modify("files", |files: Option<BTreeSet<String>>| {
+            let mut files = files.unwrap_or_default();
+            files.extend(paths.iter().cloned());
+            files
+        })?;
+        Ok(())
+    }
+
+    /// <paths> were removed, no hook writes them any more
+    pub fn forget_files(&self, paths: &[String]) -> Result<()> {
+        self.modify("files", |files: Option<BTreeSet<String>>| {
+            let mut files = files.unwrap_or_default();
+            files.retain(|file| !paths.contains(file));
+            files
+        })?;
+        Ok(())
+    }
+
+    /// Append <entry> to the audit log, if auditing is enabled
+    pub fn audit(&self, entry: &AuditEntry) -> Result<()> {
+        if !self.audit {
+            return Ok(());
+        }
+        let entry = serde_json::to_value(entry)?;
+        self.modify("audit", |log: Option<Vec<serde_json::Value>>| {
+            let mut log = log.unwrap_or_default();
+            log.push(entry);
+            log
+        })?;
+        Ok(())
+    }
+
+    /// Keep <report> of the run that just ended, dropping the oldest ones
+    /// beyond the last RUN_HISTORY
+    pub fn record_run(&self, report: &RunReport) -> Result<()> {
+        let report = serde_json::to_value(report)?;
+        self.modify("runs", |runs: Option<Vec<serde_json::Value>>| {
+            let mut runs = runs.unwrap_or_default();
+            runs.push(report);
+            let excess = runs.len().saturating_sub(RUN_HISTORY as usize);
+            runs.drain(..excess);
+            runs
+        })?;
+        Ok(())
+    }
+
+    /// The report of the latest run of the pipeline, if there was one
+    pub fn latest_run(&self) -> Result<Option<RunReport>> {
+        let runs: Option<Vec<RunReport>> = self.get("runs")?;
+        Ok(runs.and_then(|mut runs| runs.pop()))
+    }
+
+    /// The latest <limit> audit log entries of the pipeline, oldest first
+    pub fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let mut log: Vec<AuditEntry> = self.get("audit")?.unwrap_or_default();
+        let excess = log.len().saturating_sub(limit);
+        log.drain(..excess);
+        Ok(log)
+    }
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time).ok().map(|t| t.with_timezone(&Utc))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::Run;
+
+    #[test]
+    fn test_shared_file() {
+        let name = format!("app_config_state_{}.json", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let state_file = Some(path.to_str().unwrap().to_string());
+
+        let web = State::new(&state_file, "web", true);
+        let db = State::new(&state_file, "db", true);
+        assert_eq!(web.record_failure(), Ok(1));
+        assert_eq!(db.record_contact(), Ok(()));
+        assert_eq!(db.record_session("lock", "abc"), Ok(()));
+
+        // A new run only has the file to go on
+        let web = State::new(&state_file, "web", true);
+        assert_eq!(web.failures(), Ok(1));
+        assert_eq!(web.last_contact(), Ok(None));
+        assert_eq!(web.session("lock"), Ok(None));
+        assert_eq!(db.session("lock"), Ok(Some("abc".to_string())));
+        assert!(db.last_contact().unwrap().is_some());
+
+        let mode = std::os::unix::fs::PermissionsExt::mode(&path.metadata().unwrap().permissions());
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::write(&path, "SQLite format 3").unwrap();
+        let res = web.failures().unwrap_err();
+        assert!(res.to_string().contains("not a state file written without sqlite"), "{}", res);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+    }
+
+    #[test]
+    fn test_polls() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.polls(), Ok((None, 0)));
+
+        state.record_poll().unwrap();
+        state.record_poll().unwrap();
+        let (time, count) = state.polls().unwrap();
+        assert!(time.is_some());
+        assert_eq!(count, 2);
+
+        // Polls of an earlier day do not count against today
+        let earlier = Polls {
+            time: Utc::now().to_rfc3339(),
+            day: "2020-12-01".to_string(),
+            count: 5,
+        };
+        state.put("polls", &earlier).unwrap();
+        assert_eq!(state.polls().unwrap().1, 0);
+        state.record_poll().unwrap();
+        assert_eq!(state.polls().unwrap().1, 1);
+    }
+
+    #[test]
+    fn test_runs() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.latest_run(), Ok(None));
+
+        for n in 0..RUN_HISTORY + 2 {
+            let run = Run::new("test", "mock");
+            run.poll(&format!("run {}", n));
+            let d = std::time::Duration::from_millis(12);
+            run.hook("hook", "command", "error", d, None, "exit 1");
+            state.record_run(&run.finish(&Ok(()))).unwrap();
+        }
+
+        let report = state.latest_run().unwrap().unwrap();
+        assert_eq!(report.poll, format!("run {}", RUN_HISTORY + 1));
+        assert_eq!(report.hooks[0].detail, "exit 1");
+
+        // Only the latest RUN_HISTORY runs are kept
+        let runs: Vec<RunReport> = state.get("runs").unwrap().unwrap();
+        assert_eq!(runs.len(), RUN_HISTORY as usize);
+        assert_eq!(runs[0].poll, "run 2");
+    }
+
+    #[test]
+    fn test_cached() {
+        let db = open_db(&None, "test");
+        assert_eq!(db.get::<Cached>("exec", "test"), Ok(None));
+
+        let cached = Cached {
+            version: Some("3".to_string()),
+            data: vec![0x1f, 0x8b, 0xff],
+        };
+        db.put("exec", "test", &cached).unwrap();
+        assert_eq!(db.get("exec", "test"), Ok(Some(cached)));
+        assert_eq!(db.delete("exec", "test"), Ok(true));
+        assert_eq!(db.delete("exec", "test"), Ok(false));
+    }
+}
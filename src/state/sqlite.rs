@@ -0,0 +1,769 @@
+use super::{today, AuditEntry, RUN_HISTORY};
+use crate::migrate::migrate;
+use crate::report::{HookReport, RunReport};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::Duration;
+
+/// Seconds to wait for another pipeline to be done with the state file
+const BUSY_TIMEOUT: u64 = 10;
+
+/// Db:
+/// The state file, as providers keep their cache in it
+pub type Db = Connection;
+
+pub type Result<T> = rusqlite::Result<T>;
+
+/// Open the sqlite file <state_file>, or an in-memory db if there is none,
+/// and upgrade it to the current layout on behalf of <pipeline>.
+/// Pipelines checked at the same time may share the file, as may processes,
+/// so while another one writes to it, we wait for a while rather than fail.
+/// Files are kept in WAL mode, so reading them never has to wait.
+/// Will panic if the file can not be opened or upgraded.
+pub fn open_db(state_file: &Option<String>, pipeline: &str) -> Connection {
+    let conn = match state_file {
+        None => match Connection::open_in_memory() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error, unable to open in-memory db: {:?}", e);
+                std::process::exit(exitcode::SOFTWARE);
+            }
+        },
+        Some(file_name) => match Connection::open(file_name) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error, unable to open state file {}: {:?}", file_name, e);
+                std::process::exit(exitcode::OSFILE);
+            }
+        },
+    };
+
+    if let Err(e) = conn.busy_timeout(Duration::from_secs(BUSY_TIMEOUT)) {
+        eprintln!("Error, unable to configure state file: {:?}", e);
+        std::process::exit(exitcode::SOFTWARE);
+    }
+    // Cached data may be secrets, what is replaced is overwritten on disk
+    if let Err(e) = conn.execute_batch("PRAGMA secure_delete = ON") {
+        eprintln!("Error, unable to configure state file: {:?}", e);
+        std::process::exit(exitcode::SOFTWARE);
+    }
+    if state_file.is_some() {
+        if let Err(e) = conn.query_row("PRAGMA journal_mode = WAL", params![], |_| Ok(())) {
+            eprintln!("Error, unable to configure state file: {:?}", e);
+            std::process::exit(exitcode::SOFTWARE);
+        }
+    }
+    if let Err(e) = migrate(&conn, pipeline) {
+        eprintln!("Error, unable to migrate state file: {:#}", e);
+        std::process::exit(exitcode::DATAERR);
+    }
+    conn
+}
+
+/// State:
+/// Run level state that has to survive between runs, as opposed to the data
+/// cached by each provider.  Kept in the sqlite file named by
+/// settings.state_file, or in memory if there is none.  The file can be
+/// shared by every pipeline of a host, each only sees its own state.
+#[derive(Debug)]
+pub struct State {
+    db_conn: Connection,
+    pipeline: String,
+    audit: bool,
+}
+
+impl State {
+    /// Open (or create) the state file, for the state of <pipeline>
+    /// If <audit> is false, entries passed to audit() are dropped.
+    /// Will panic if the file can not be opened or initialized.
+    pub fn new(state_file: &Option<String>, pipeline: &str, audit: bool) -> State {
+        let conn = open_db(state_file, pipeline);
+
+        // Setup the tables if they do not already exist
+        match State::create_tables(&conn, pipeline) {
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("Error, unable to create state tables: {:?}", e);
+                std::process::exit(exitcode::SOFTWARE);
+            }
+        };
+
+        State {
+            db_conn: conn,
+            pipeline: pipeline.to_string(),
+            audit,
+        }
+    }
+
+    fn create_tables(db_conn: &Connection, pipeline: &str) -> rusqlite::Result<()> {
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS failures (
+                pipeline TEXT PRIMARY KEY,
+                count    INTEGER NOT NULL
+                )",
+            params![],
+        )?;
+        db_conn.execute(
+            "INSERT INTO failures (pipeline, count)
+                SELECT ?1, 0
+                WHERE NOT EXISTS (
+                    SELECT * FROM failures WHERE pipeline=?1 )",
+            params![pipeline],
+        )?;
+        // When the provider was last reached, successful polls update it
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS contact (
+                pipeline TEXT PRIMARY KEY,
+                time     TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        // When the provider was last polled, and how often on that day
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS polls (
+                pipeline TEXT PRIMARY KEY,
+                time     TEXT NOT NULL,
+                day      TEXT NOT NULL,
+                count    INTEGER NOT NULL
+                )",
+            params![],
+        )?;
+        // When data was last applied, and whether a change waits for
+        // min_apply_interval to be over
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS applies (
+                pipeline TEXT PRIMARY KEY,
+                time     TEXT NOT NULL,
+                pending  INTEGER NOT NULL
+                )",
+            params![],
+        )?;
+        // Whether changes are frozen, since when and why
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS freeze (
+                pipeline TEXT PRIMARY KEY,
+                time     TEXT NOT NULL,
+                reason   TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        // Sessions kept with the coordination backend between runs
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                pipeline TEXT NOT NULL,
+                name     TEXT NOT NULL,
+                id       TEXT NOT NULL,
+                PRIMARY KEY (pipeline, name)
+                )",
+            params![],
+        )?;
+        // The fingerprint of the data last polled, with normalize set
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS fingerprints (
+                pipeline TEXT PRIMARY KEY,
+                sha256   TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        // The hooks that completed on the data being applied, one per line,
+        // until every hook did
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS progress (
+                pipeline  TEXT PRIMARY KEY,
+                sha256    TEXT NOT NULL,
+                completed TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        // The data last applied, for hooks that want what it was before
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS applied (
+                pipeline TEXT PRIMARY KEY,
+                data     BLOB NOT NULL
+                )",
+            params![],
+        )?;
+        // The files the hooks wrote, for cleanup to remove those no hook
+        // writes any more
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS files (
+                pipeline TEXT NOT NULL,
+                path     TEXT NOT NULL,
+                PRIMARY KEY (pipeline, path)
+                )",
+            params![],
+        )?;
+        // The audit log is append only, rows are never updated or removed
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS audit (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                time        TEXT NOT NULL,
+                event       TEXT NOT NULL,
+                target      TEXT NOT NULL,
+                status      TEXT NOT NULL,
+                detail      TEXT NOT NULL,
+                sha256      TEXT,
+                duration_ms INTEGER NOT NULL,
+                pipeline    TEXT NOT NULL
+                )",
+            params![],
+        )?;
+        // A report of every run, and of the hooks each one ran
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS runs (
+                id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                run_id       TEXT NOT NULL,
+                pipeline     TEXT NOT NULL,
+                started      TEXT NOT NULL,
+                finished     TEXT NOT NULL,
+                status       TEXT NOT NULL,
+                poll         TEXT NOT NULL,
+                error        TEXT,
+                provider     TEXT NOT NULL,
+                data_version TEXT,
+                data_sha256  TEXT
+                )",
+            params![],
+        )?;
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS run_hooks (
+                run           INTEGER NOT NULL,
+                seq           INTEGER NOT NULL,
+                event         TEXT NOT NULL,
+                kind          TEXT NOT NULL,
+                status        TEXT NOT NULL,
+                duration_ms   INTEGER NOT NULL,
+                output_sha256 TEXT,
+                detail        TEXT NOT NULL,
+                PRIMARY KEY (run, seq)
+                )",
+            params![],
+        )?;
+        Ok(())
+    }
+
+    /// Number of consecutive failed runs
+    pub fn failures(&self) -> rusqlite::Result<usize> {
+        let res: isize = self.db_conn.query_row(
+            "SELECT count FROM failures WHERE pipeline=?1",
+            params![self.pipeline],
+            |row| row.get(0),
+        )?;
+        Ok(res as usize)
+    }
+
+    /// Count another failed run, returning the new number of
+    /// consecutive failures
+    pub fn record_failure(&self) -> rusqlite::Result<usize> {
+        self.db_conn.execute(
+            "UPDATE failures SET count = count + 1 WHERE pipeline=?1",
+            params![self.pipeline],
+        )?;
+        self.failures()
+    }
+
+    /// A run succeeded, returning the number of consecutive failures
+    /// that preceded it
+    pub fn reset_failures(&self) -> rusqlite::Result<usize> {
+        let failures = self.failures()?;
+        self.db_conn.execute(
+            "UPDATE failures SET count = 0 WHERE pipeline=?1",
+            params![self.pipeline],
+        )?;
+        Ok(failures)
+    }
+
+    /// When the provider was last reached, if ever
+    pub fn last_contact(&self) -> rusqlite::Result<Option<DateTime<Utc>>> {
+        let res: Option<String> = self
+            .db_conn
+            .query_row(
+                "SELECT time FROM contact WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(res.and_then(|t| DateTime::parse_from_rfc3339(&t).ok()).map(|t| t.with_timezone(&Utc)))
+    }
+
+    /// The provider was reached just now
+    pub fn record_contact(&self) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO contact (pipeline, time) VALUES (?1, ?2)",
+            params![self.pipeline, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// When the provider was last polled, if ever, and how many times it was
+    /// polled today (UTC)
+    pub fn polls(&self) -> rusqlite::Result<(Option<DateTime<Utc>>, usize)> {
+        let res: Option<(String, String, isize)> = self
+            .db_conn
+            .query_row(
+                "SELECT time, day, count FROM polls WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (time, day, count) = match res {
+            Some(res) => res,
+            None => return Ok((None, 0)),
+        };
+
+        let time = DateTime::parse_from_rfc3339(&time).ok().map(|t| t.with_timezone(&Utc));
+        let count = if day == today() { count as usize } else { 0 };
+        Ok((time, count))
+    }
+
+    /// The provider is being polled now, whether it answers or not
+    pub fn record_poll(&self) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT INTO polls (pipeline, time, day, count) VALUES (?1, ?2, ?3, 1)
+                ON CONFLICT(pipeline) DO UPDATE SET
+                    count = CASE WHEN day = excluded.day THEN count + 1 ELSE 1 END,
+                    time = excluded.time,
+                    day = excluded.day",
+            params![self.pipeline, Utc::now().to_rfc3339(), today()],
+        )?;
+        Ok(())
+    }
+
+    /// When data was last applied, if ever, and whether a change since
+    /// waits to be applied
+    pub fn applies(&self) -> rusqlite::Result<(Option<DateTime<Utc>>, bool)> {
+        let res: Option<(String, bool)> = self
+            .db_conn
+            .query_row(
+                "SELECT time, pending FROM applies WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match res {
+            Some((time, pending)) => {
+                let time = DateTime::parse_from_rfc3339(&time).ok().map(|t| t.with_timezone(&Utc));
+                (time, pending)
+            }
+            None => (None, false),
+        })
+    }
+
+    /// Data is being applied now, nothing waits any more
+    pub fn record_apply(&self) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO applies (pipeline, time, pending) VALUES (?1, ?2, 0)",
+            params![self.pipeline, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// A change waits to be applied.  Data never applied has no time.
+    pub fn record_pending(&self) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT INTO applies (pipeline, time, pending) VALUES (?1, '', 1)
+                ON CONFLICT(pipeline) DO UPDATE SET pending = 1",
+            params![self.pipeline],
+        )?;
+        Ok(())
+    }
+
+    /// When changes were frozen and why, if they are
+    pub fn frozen(&self) -> rusqlite::Result<Option<(String, String)>> {
+        self.db_conn
+            .query_row(
+                "SELECT time, reason FROM freeze WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    /// Freeze changes for <reason>, until thawed
+    pub fn freeze(&self, reason: &str) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO freeze (pipeline, time, reason) VALUES (?1, ?2, ?3)",
+            params![self.pipeline, Utc::now().to_rfc3339(), reason],
+        )?;
+        Ok(())
+    }
+
+    /// Let changes be applied again, returning whether they were frozen
+    pub fn thaw(&self) -> rusqlite::Result<bool> {
+        let thawed = self
+            .db_conn
+            .execute("DELETE FROM freeze WHERE pipeline=?1", params![self.pipeline])?;
+        Ok(thawed > 0)
+    }
+
+    /// The id of the session named <name>, if one was kept
+    pub fn session(&self, name: &str) -> rusqlite::Result<Option<String>> {
+        self.db_conn
+            .query_row(
+                "SELECT id FROM sessions WHERE pipeline=?1 AND name=?2",
+                params![self.pipeline, name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Keep <id> as the session named <name>
+    pub fn record_session(&self, name: &str, id: &str) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO sessions (pipeline, name, id) VALUES (?1, ?2, ?3)",
+            params![self.pipeline, name, id],
+        )?;
+        Ok(())
+    }
+
+    /// The normalized fingerprint of the data last polled, if any
+    pub fn fingerprint(&self) -> rusqlite::Result<Option<String>> {
+        self.db_conn
+            .query_row(
+                "SELECT sha256 FROM fingerprints WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Remember <fingerprint> as that of the data just polled
+    pub fn record_fingerprint(&self, fingerprint: &str) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO fingerprints (pipeline, sha256) VALUES (?1, ?2)",
+            params![self.pipeline, fingerprint],
+        )?;
+        Ok(())
+    }
+
+    /// The data of a run whose hooks failed, by its hash, and the steps of
+    /// the hooks that completed on it.  None once every hook did.
+    pub fn progress(&self) -> rusqlite::Result<Option<(String, Vec<String>)>> {
+        let progress: Option<(String, String)> = self
+            .db_conn
+            .query_row(
+                "SELECT sha256, completed FROM progress WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(progress.map(|(sha256, completed)| {
+            (sha256, completed.lines().map(String::from).collect())
+        }))
+    }
+
+    /// The hooks start on the data hashed to <sha256>, none completed yet
+    pub fn begin_hooks(&self, sha256: &str) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO progress (pipeline, sha256, completed) VALUES (?1, ?2, '')",
+            params![self.pipeline, sha256],
+        )?;
+        Ok(())
+    }
+
+    /// The hook at <step> completed
+    pub fn record_completed(&self, step: &str) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "UPDATE progress SET completed = completed || ?2 || char(10) WHERE pipeline=?1",
+            params![self.pipeline, step],
+        )?;
+        Ok(())
+    }
+
+    /// Every hook completed, there is nothing left to resume
+    pub fn end_hooks(&self) -> rusqlite::Result<()> {
+        self.db_conn
+            .execute("DELETE FROM progress WHERE pipeline=?1", params![self.pipeline])?;
+        Ok(())
+    }
+
+    /// The data last applied in full, if kept
+    pub fn applied_data(&self) -> rusqlite::Result<Option<Vec<u8>>> {
+        self.db_conn
+            .query_row(
+                "SELECT data FROM applied WHERE pipeline=?1",
+                params![self.pipeline],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Keep <data> as the data last applied in full
+    pub fn record_applied_data(&self, data: &[u8]) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT OR REPLACE INTO applied (pipeline, data) VALUES (?1, ?2)",
+            params![self.pipeline, data],
+        )?;
+        Ok(())
+    }
+
+    /// The files the hooks wrote, by path
+    pub fn files(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self
+            .db_conn
+            .prepare("SELECT path FROM files WHERE pipeline=?1 ORDER BY path")?;
+        let rows = stmt.query_map(params![self.pipeline], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// The hooks wrote <paths>
+    pub fn record_files(&self, paths: &[String]) -> rusqlite::Result<()> {
+        for path in paths {
+            self.db_conn.execute(
+                "INSERT OR IGNORE INTO files (pipeline, path) VALUES (?1, ?2)",
+                params![self.pipeline, path],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// <paths> were removed, no hook writes them any more
+    pub fn forget_files(&self, paths: &[String]) -> rusqlite::Result<()> {
+        for path in paths {
+            self.db_conn.execute(
+                "DELETE FROM files WHERE pipeline=?1 AND path=?2",
+                params![self.pipeline, path],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Append <entry> to the audit log, if auditing is enabled
+    pub fn audit(&self, entry: &AuditEntry) -> rusqlite::Result<()> {
+        if !self.audit {
+            return Ok(());
+        }
+        self.db_conn.execute(
+            "INSERT INTO audit
+                (time, event, target, status, detail, sha256, duration_ms, pipeline)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.time,
+                entry.event,
+                entry.target,
+                entry.status,
+                entry.detail,
+                entry.sha256,
+                entry.duration_ms as i64,
+                self.pipeline
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Keep <report> of the run that just ended, dropping the oldest ones
+    /// beyond the last RUN_HISTORY
+    pub fn record_run(&self, report: &RunReport) -> rusqlite::Result<()> {
+        self.db_conn.execute(
+            "INSERT INTO runs (run_id, pipeline, started, finished, status, poll, error,
+                provider, data_version, data_sha256)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                report.run_id,
+                self.pipeline,
+                report.started,
+                report.finished,
+                report.status,
+                report.poll,
+                report.error,
+                report.provider,
+                report.data_version,
+                report.data_sha256
+            ],
+        )?;
+        let run = self.db_conn.last_insert_rowid();
+        for (seq, hook) in report.hooks.iter().enumerate() {
+            self.db_conn.execute(
+                "INSERT INTO run_hooks
+                    (run, seq, event, kind, status, duration_ms, output_sha256, detail)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    run,
+                    seq as i64,
+                    hook.event,
+                    hook.kind,
+                    hook.status,
+                    hook.duration_ms as i64,
+                    hook.output_sha256,
+                    hook.detail
+                ],
+            )?;
+        }
+
+        self.db_conn.execute(
+            "DELETE FROM run_hooks WHERE run IN (
+                SELECT id FROM runs WHERE pipeline=?1 ORDER BY id DESC LIMIT -1 OFFSET ?2)",
+            params![self.pipeline, RUN_HISTORY],
+        )?;
+        self.db_conn.execute(
+            "DELETE FROM runs WHERE id IN (
+                SELECT id FROM runs WHERE pipeline=?1 ORDER BY id DESC LIMIT -1 OFFSET ?2)",
+            params![self.pipeline, RUN_HISTORY],
+        )?;
+        Ok(())
+    }
+
+    /// The report of the latest run of the pipeline, if there was one
+    pub fn latest_run(&self) -> rusqlite::Result<Option<RunReport>> {
+        let res = self
+            .db_conn
+            .query_row(
+                "SELECT id, run_id, started, finished, status, poll, error, provider,
+                    data_version, data_sha256
+                    FROM runs WHERE pipeline=?1 ORDER BY id DESC LIMIT 1",
+                params![self.pipeline],
+                |row| {
+                    let id: i64 = row.get(0)?;
+                    let report = RunReport {
+                        run_id: row.get(1)?,
+                        pipeline: self.pipeline.clone(),
+                        started: row.get(2)?,
+                        finished: row.get(3)?,
+                        status: row.get(4)?,
+                        poll: row.get(5)?,
+                        error: row.get(6)?,
+                        provider: row.get(7)?,
+                        data_version: row.get(8)?,
+                        data_sha256: row.get(9)?,
+                        hooks: Vec::new(),
+                    };
+                    Ok((id, report))
+                },
+            )
+            .optional()?;
+        let (id, mut report) = match res {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT event, kind, status, duration_ms, output_sha256, detail
+                FROM run_hooks WHERE run=?1 ORDER BY seq ASC",
+        )?;
+        let rows = stmt.query_map(params![id], |row| {
+            let duration_ms: i64 = row.get(3)?;
+            Ok(HookReport {
+                event: row.get(0)?,
+                kind: row.get(1)?,
+                status: row.get(2)?,
+                duration_ms: duration_ms as u64,
+                output_sha256: row.get(4)?,
+                detail: row.get(5)?,
+            })
+        })?;
+        report.hooks = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(Some(report))
+    }
+
+    /// The latest <limit> audit log entries of the pipeline, oldest first
+    pub fn audit_log(&self, limit: usize) -> rusqlite::Result<Vec<AuditEntry>> {
+        let mut stmt = self.db_conn.prepare(
+            "SELECT time, event, target, status, detail, sha256, duration_ms
+                FROM (SELECT * FROM audit WHERE pipeline=?2 ORDER BY id DESC LIMIT ?1)
+                ORDER BY id ASC",
+        )?;
+        let rows = stmt.query_map(params![limit as i64, self.pipeline], |row| {
+            let duration_ms: i64 = row.get(6)?;
+            Ok(AuditEntry {
+                time: row.get(0)?,
+                event: row.get(1)?,
+                target: row.get(2)?,
+                status: row.get(3)?,
+                detail: row.get(4)?,
+                sha256: row.get(5)?,
+                duration_ms: duration_ms as u64,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::Run;
+
+    #[test]
+    fn test_polls() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.polls(), Ok((None, 0)));
+
+        state.record_poll().unwrap();
+        state.record_poll().unwrap();
+        let (time, count) = state.polls().unwrap();
+        assert!(time.is_some());
+        assert_eq!(count, 2);
+
+        // Polls of an earlier day do not count against today
+        state.db_conn.execute("UPDATE polls SET day = '2020-12-01'", params![]).unwrap();
+        assert_eq!(state.polls().unwrap().1, 0);
+        state.record_poll().unwrap();
+        assert_eq!(state.polls().unwrap().1, 1);
+    }
+
+    #[test]
+    fn test_shared_file() {
+        let path = std::env::temp_dir().join(format!("app_config_state_{}.db", std::process::id()));
+        let state_file = Some(path.to_str().unwrap().to_string());
+
+        // A file written by a version keeping the state of one pipeline
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE failures (id INTEGER PRIMARY KEY, count INTEGER NOT NULL);
+            INSERT INTO failures (id, count) VALUES (0, 3);
+            CREATE TABLE audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT, time TEXT NOT NULL,
+                event TEXT NOT NULL, target TEXT NOT NULL, status TEXT NOT NULL,
+                detail TEXT NOT NULL, sha256 TEXT, duration_ms INTEGER NOT NULL);
+            INSERT INTO audit (time, event, target, status, detail, duration_ms)
+                VALUES ('2020-12-01T00:00:00+00:00', 'poll', 'mock', 'ok', 'changed', 1);",
+        )
+        .unwrap();
+
+        let web = State::new(&state_file, "web", true);
+        let db = State::new(&state_file, "db", true);
+
+        assert_eq!(web.failures(), Ok(3));
+        assert_eq!(web.audit_log(10).unwrap().len(), 1);
+        assert_eq!(db.failures(), Ok(0));
+        assert_eq!(db.audit_log(10).unwrap(), vec![]);
+
+        assert_eq!(db.record_failure(), Ok(1));
+        assert_eq!(db.record_contact(), Ok(()));
+        assert_eq!(web.failures(), Ok(3));
+        assert_eq!(web.last_contact(), Ok(None));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_runs() {
+        let state = State::new(&None, "test", false);
+        assert_eq!(state.latest_run(), Ok(None));
+
+        for n in 0..RUN_HISTORY + 2 {
+            let run = Run::new("test", "mock");
+            run.poll(&format!("run {}", n));
+            let d = std::time::Duration::from_millis(12);
+            run.hook("hook", "template", "ok", d, Some("abc".to_string()), "");
+            run.hook("hook", "command", "error", d, None, "exit 1");
+            state.record_run(&run.finish(&Ok(()))).unwrap();
+        }
+
+        let report = state.latest_run().unwrap().unwrap();
+        assert_eq!(report.poll, format!("run {}", RUN_HISTORY + 1));
+        assert_eq!(report.status, "ok");
+        assert_eq!(report.hooks.len(), 2);
+        assert_eq!(report.hooks[0].output_sha256, Some("abc".to_string()));
+        assert_eq!(report.hooks[1].detail, "exit 1");
+
+        // Only the latest RUN_HISTORY runs are kept
+        let count = |table: &str| -> i64 {
+            let sql = format!("SELECT COUNT(*) FROM {}", table);
+            state.db_conn.query_row(&sql, params![], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count("runs"), RUN_HISTORY);
+        assert_eq!(count("run_hooks"), RUN_HISTORY * 2);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::http;
+use serde_derive::Deserialize;
+use eyre::{eyre, Result};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// OtlpConf holds the [settings.otlp] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename = "otlp")]
+pub struct OtlpConf {
+    pub endpoint: String,
+    pub service_name: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+
+// // // // // // // // // // // Tracer // // // // // // // // // // //
+
+/// A finished span, waiting to be exported
+#[derive(Debug, PartialEq)]
+struct Span {
+    name: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: u128,
+    end: u128,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+/// Tracer collects one span per provider poll and per hook run, as children
+/// of a root span for the whole run, and exports them to an OTLP/HTTP
+/// collector at the end of the run.  If TRACEPARENT is set, the run joins
+/// that trace.  Without an [settings.otlp] section nothing is recorded.
+#[derive(Debug)]
+pub struct Tracer {
+    conf: Option<OtlpConf>,
+    trace_id: String,
+    root_span_id: String,
+    parent_span_id: Option<String>,
+    started: SystemTime,
+    spans: RefCell<Vec<Span>>,
+}
+
+impl Tracer {
+    /// Create a new Tracer, starting the root span
+    pub fn new(conf: Option<OtlpConf>) -> Tracer {
+        let parent = std::env::var("TRACEPARENT").ok().and_then(|t| parse_traceparent(&t));
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(16), None),
+        };
+
+        Tracer {
+            conf,
+            trace_id,
+            root_span_id: random_id(8),
+            parent_span_id,
+            started: SystemTime::now(),
+            spans: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// W3C traceparent of the root span, so hooks can continue the trace
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.root_span_id)
+    }
+
+    /// Record a child span of the root span
+    pub fn record(
+        &self,
+        name: &str,
+        start: SystemTime,
+        elapsed: Duration,
+        attributes: Vec<(&str, String)>,
+        error: Option<String>,
+    ) {
+        if self.conf.is_none() {
+            return;
+        }
+        let start = unix_nanos(start);
+        self.spans.borrow_mut().push(Span {
+            name: name.to_string(),
+            span_id: random_id(8),
+            parent_span_id: Some(self.root_span_id.clone()),
+            start,
+            end: start + elapsed.as_nanos(),
+            attributes: attributes.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            error,
+        });
+    }
+
+    /// End the root span named <name> and send every span to the collector
+    pub fn export(&self, name: &str, error: Option<String>) -> Result<()> {
+        let conf = match &self.conf {
+            Some(conf) => conf,
+            None => return Ok(()),
+        };
+
+        let mut spans = self.spans.borrow_mut();
+        spans.push(Span {
+            name: name.to_string(),
+            span_id: self.root_span_id.clone(),
+            parent_span_id: self.parent_span_id.clone(),
+            start: unix_nanos(self.started),
+            end: unix_nanos(SystemTime::now()),
+            attributes: vec![],
+            error,
+        });
+
+        let service_name = conf.service_name.as_deref().unwrap_or("app_config");
+        let body = self.payload(service_name, &spans);
+
+        let url = format!("{}/v1/traces", conf.endpoint.trim_end_matches('/'));
+        let mut req = http::post(&url);
+        for (k, v) in conf.headers.iter().flatten() {
+            req.set(k, v);
+        }
+
+        let resp = req.send_json(body);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(eyre!("Unable to reach OTLP collector at {}: {}", url, e));
+        }
+        if !resp.ok() {
+            return Err(eyre!("OTLP collector rejected spans: {}", resp.status_line()));
+        }
+        Ok(())
+    }
+
+    /// OTLP/HTTP JSON encoding of <spans>
+    fn payload(&self, service_name: &str, spans: &[Span]) -> serde_json::Value {
+        let spans: Vec<serde_json::Value> = spans
+            .iter()
+            .map(|span| {
+                let attributes: Vec<serde_json::Value> = span
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| serde_json::json!({"key": k, "value": {"stringValue": v}}))
+                    .collect();
+                let status = match &span.error {
+                    None => serde_json::json!({"code": 1}),
+                    Some(e) => serde_json::json!({"code": 2, "message": e}),
+                };
+
+                let mut s = serde_json::json!({
+                    "traceId": self.trace_id,
+                    "spanId": span.span_id,
+                    "name": span.name,
+                    "kind": 1,
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": span.end.to_string(),
+                    "attributes": attributes,
+                    "status": status,
+                });
+                if let Some(parent) = &span.parent_span_id {
+                    s["parentSpanId"] = serde_json::json!(parent);
+                }
+                s
+            })
+            .collect();
+
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        {"key": "service.name", "value": {"stringValue": service_name}}
+                    ]
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "app_config"},
+                    "spans": spans,
+                }]
+            }]
+        })
+    }
+}
+
+/// Random hex encoded id of <bytes> bytes
+fn random_id(bytes: usize) -> String {
+    (0..bytes).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+/// Extract the trace id and parent span id from a W3C traceparent header
+fn parse_traceparent(traceparent: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = traceparent.trim().split('-').collect();
+    let is_hex = |s: &str| s.chars().all(|c| c.is_ascii_hexdigit());
+
+    match parts.as_slice() {
+        [_, trace_id, span_id, _]
+            if trace_id.len() == 32 && span_id.len() == 16 && is_hex(trace_id) && is_hex(span_id) =>
+        {
+            Some((trace_id.to_lowercase(), span_id.to_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+
+// // // // // // // // // // // Tests // // // // // // // // // // //
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen_conf() -> OtlpConf {
+        OtlpConf {
+            endpoint: "http://localhost:4318".to_string(),
+            service_name: None,
+            headers: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        let res = parse_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+        assert_eq!(
+            res,
+            Some((
+                "0af7651916cd43dd8448eb211c80319c".to_string(),
+                "b7ad6b7169203331".to_string()
+            ))
+        );
+
+        assert_eq!(parse_traceparent("00-xyz-b7ad6b7169203331-01"), None);
+        assert_eq!(parse_traceparent(""), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let t = Tracer::new(None);
+        t.record("poll", SystemTime::now(), Duration::from_millis(1), vec![], None);
+
+        assert!(t.spans.borrow().is_empty());
+        assert!(t.export("check", None).is_ok());
+    }
+
+    #[test]
+    fn test_payload() {
+        let t = Tracer::new(Some(gen_conf()));
+        t.record(
+            "hook",
+            SystemTime::now(),
+            Duration::from_millis(5),
+            vec![("hook.kind", "raw".to_string())],
+            Some("boom".to_string()),
+        );
+
+        let res = t.payload("app_config", &t.spans.borrow());
+        let span = &res["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "hook");
+        assert_eq!(span["traceId"], t.trace_id.as_str());
+        assert_eq!(span["parentSpanId"], t.root_span_id.as_str());
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "raw");
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(t.traceparent().len(), 55);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::config::parse_duration;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Timelike, Utc};
+use chrono_tz::Tz;
+use eyre::{eyre, Result};
+use serde_derive::Deserialize;
+use std::time::Duration;
+
+/// How far ahead the next window is looked for, a window that does not
+/// open within a week or so is as good as none
+const LOOKAHEAD_DAYS: i64 = 8;
+
+
+// // // // // // // // // Handle Configuraion // // // // // // // //
+
+// WindowConf holds one [[settings.maintenance_windows]] of the config file
+#[derive(Debug, Deserialize)]
+#[serde(rename = "maintenance_windows")]
+pub struct WindowConf {
+    pub start: String,
+    pub duration: String,
+    pub timezone: Option<String>,
+}
+
+impl WindowConf {
+    /// Will panic if the start, duration or timezone is invalid
+    pub fn convert(&self) -> Window {
+        let start = match Cron::parse(&self.start) {
+            Ok(start) => start,
+            Err(e) => {
+                eprintln!("Error, invalid maintenance window start {}: {}", self.start, e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        let duration = parse_duration("maintenance window duration", &self.duration);
+        let timezone = match self.timezone.as_deref().unwrap_or("UTC").parse() {
+            Ok(timezone) => timezone,
+            Err(e) => {
+                eprintln!("Error, invalid maintenance window timezone: {}", e);
+                std::process::exit(exitcode::CONFIG);
+            }
+        };
+        Window::new(start, duration, timezone)
+    }
+}
+
+
+// // // // // // // // // // // Window // // // // // // // // // // //
+
+/// Window:
+/// A maintenance window: time during which changes may be applied.  It opens
+/// whenever the local time in <timezone> matches the cron style <start>, and
+/// stays open for <duration>.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    start: Cron,
+    duration: Duration,
+    timezone: Tz,
+}
+
+impl Window {
+    pub fn new(start: Cron, duration: Duration, timezone: Tz) -> Window {
+        Window {
+            start,
+            duration,
+            timezone,
+        }
+    }
+
+    /// Whether the window is open at <now>
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let now = minute(now);
+        let minutes = (self.duration.as_secs() as i64 + 59) / 60;
+        (0..minutes).any(|ago| self.starts_at(now - ChronoDuration::minutes(ago)))
+    }
+
+    /// When the window next opens after <now>, if it does within
+    /// LOOKAHEAD_DAYS
+    pub fn next_open(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let now = minute(now);
+        (1..=LOOKAHEAD_DAYS * 24 * 60)
+            .map(|ahead| now + ChronoDuration::minutes(ahead))
+            .find(|time| self.starts_at(*time))
+    }
+
+    /// Whether the window opens at <time>, to the minute
+    fn starts_at(&self, time: DateTime<Utc>) -> bool {
+        self.start.matches(&time.with_timezone(&self.timezone))
+    }
+}
+
+/// <time> without its seconds
+fn minute(time: DateTime<Utc>) -> DateTime<Utc> {
+    time - ChronoDuration::seconds(time.second() as i64)
+        - ChronoDuration::nanoseconds(time.nanosecond() as i64)
+}
+
+
+// // // // // // // // // // // Cron // // // // // // // // // // //
+
+/// Cron:
+/// The times given by a cron style spec of five fields: minute, hour, day
+/// of the month, month and day of the week, e.g. "0 2 * * sat" for 2am on
+/// Saturdays.  Fields take `*`, numbers, ranges and lists, with steps, e.g.
+/// `*/15` or `1-5`.  Months and days of the week may be given by name.  As
+/// in cron, when both days are restricted either one matching will do.
+/// Each field is a bit set of the values it matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: [&str; 12] =
+    ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Cron> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(eyre!("expected 5 fields, minute hour day month weekday"));
+        }
+        // Sunday is 0, and 7 as well
+        let weekdays = field(fields[4], 0, 7, &WEEKDAYS, 0)?;
+        let weekdays = match weekdays & (1 << 7) {
+            0 => weekdays,
+            _ => (weekdays | 1) & !(1 << 7),
+        };
+        Ok(Cron {
+            minutes: field(fields[0], 0, 59, &[], 0)?,
+            hours: field(fields[1], 0, 23, &[], 0)?,
+            days: field(fields[2], 1, 31, &[], 0)?,
+            months: field(fields[3], 1, 12, &MONTHS, 1)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether <time> is one of the times, to the minute
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let has = |set: u64, value: u32| set & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day
+    }
+}
+
+/// The values from <min> to <max> the cron field <text> matches, as bits.
+/// <names> stand for the values from <first> on.
+fn field(text: &str, min: u32, max: u32, names: &[&str], first: u32) -> Result<u64> {
+    let value = |text: &str| -> Result<u32> {
+        let lower = text.to_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(i) => i as u32 + first,
+            None => text.parse().map_err(|_| eyre!("invalid value {}", text))?,
+        };
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false => Err(eyre!("{} is not within {}-{}", value, min, max)),
+        }
+    };
+
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                (range, step.parse().map_err(|_| eyre!("invalid step {}", step))?)
+            }
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(eyre!("invalid step 0"));
+        }
+        let (low, high) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((low, high)) => (value(low)?, value(high)?),
+                // A single value with a step runs to the end, as in cron
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if low > high {
+            return Err(eyre!("invalid range {}", range));
+        }
+        for value in (low..=high).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_cron() {
+        let cron = Cron::parse("*/15 2-4 * * sat,SUN").unwrap();
+        // Saturday the 6th of March 2021
+        assert!(cron.matches(&at("2021-03-06T02:45:00Z")));
+        assert!(cron.matches(&at("2021-03-07T04:00:00Z")));
+        assert!(!cron.matches(&at("2021-03-06T02:40:00Z")));
+        assert!(!cron.matches(&at("2021-03-08T02:45:00Z")));
+
+        // Either day will do when both are given
+        let cron = Cron::parse("0 0 1 * 7").unwrap();
+        assert!(cron.matches(&at("2021-03-01T00:00:00Z")));
+        assert!(cron.matches(&at("2021-03-07T00:00:00Z")));
+        assert!(!cron.matches(&at("2021-03-02T00:00:00Z")));
+
+        assert!(Cron::parse("0 2 * *").is_err());
+        assert!(Cron::parse("60 2 * * *").is_err());
+        assert!(Cron::parse("0 2 * * funday").is_err());
+        assert!(Cron::parse("*/0 2 * * *").is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let start = Cron::parse("0 2 * * sat").unwrap();
+        let window = Window::new(start, Duration::from_secs(4 * 3600), chrono_tz::Europe::Berlin);
+
+        // 2am in Berlin is 1am UTC in March
+        assert!(window.is_open(at("2021-03-06T01:00:00Z")));
+        assert!(window.is_open(at("2021-03-06T04:59:30Z")));
+        assert!(!window.is_open(at("2021-03-06T05:00:00Z")));
+        assert!(!window.is_open(at("2021-03-06T00:59:00Z")));
+
+        let now = at("2021-03-03T12:30:10Z");
+        assert_eq!(window.next_open(now), Some(at("2021-03-06T01:00:00Z")));
+        let never = Window::new(Cron::parse("0 0 30 2 *").unwrap(), window.duration, Tz::UTC);
+        assert_eq!(never.next_open(now), None);
+    }
+}
@@ -0,0 +1,83 @@
+use eyre::{Result, WrapErr};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Name of the environment variable commands find the workspace in
+pub const ENV_VAR: &str = "APP_CONFIG_WORKSPACE";
+
+// Pipelines checked at once by the daemon each get their own
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Workspace:
+/// A directory of its own for a run of <pipeline>, where hooks keep what
+/// they make along the way.  Commands find it in APP_CONFIG_WORKSPACE and
+/// templates as {{workspace}}.  It is removed, with all it holds, once
+/// dropped, unless it is kept to look into why the run failed.
+#[derive(Debug)]
+pub struct Workspace {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Workspace {
+    /// Create the workspace of a run of <pipeline>, only its owner may use it
+    pub fn create(pipeline: &str) -> Result<Workspace> {
+        let name = format!(
+            "app_config-{}-{}-{}",
+            pipeline.replace('/', "_"),
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .wrap_err_with(|| format!("Unable to create workspace {}", path.display()))?;
+        Ok(Workspace { path, keep: false })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leave the workspace in place once dropped
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warning!("unable to remove workspace {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_workspace() {
+        let workspace = Workspace::create("web/east").unwrap();
+        let path = workspace.path().to_path_buf();
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("app_config-web_east-"));
+        std::fs::write(path.join("artifact"), "data").unwrap();
+        drop(workspace);
+        assert!(!path.exists());
+
+        let mut workspace = Workspace::create("web").unwrap();
+        assert_ne!(workspace.path(), path);
+        workspace.keep();
+        let path = workspace.path().to_path_buf();
+        drop(workspace);
+        assert!(path.is_dir());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
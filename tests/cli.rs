@@ -75,15 +75,938 @@ fn test_mock_check() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn test_multiple_pipelines() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check")
+        .arg("-j")
+        .arg("2")
+        .arg("-f")
+        .arg("./tests/mock.toml")
+        .arg("-f")
+        .arg("./tests/template_raw_stdout.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Where am I"))
+        .stdout(predicate::str::contains("public_key: abc"));
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_pipelines_failure() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    // The failing pipeline does not stop the other one
+    cmd.arg("check")
+        .arg("-f")
+        .arg("./tests/command_garbage.toml")
+        .arg("-f")
+        .arg("./tests/mock.toml");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("Where am I"))
+        .stderr(predicate::str::contains(
+            "pipeline ./tests/command_garbage.toml failed",
+        ))
+        .stderr(predicate::str::contains("1 of 2 pipelines failed"));
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_pipelines_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    // An invalid config is found before any pipeline runs
+    cmd.arg("check")
+        .arg("-f")
+        .arg("./tests/mock.toml")
+        .arg("-f")
+        .arg("./tests/invalid_config.toml");
+    cmd.assert()
+        .code(78)
+        .stdout(predicate::str::contains("Where am I").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_template_error() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check").arg("-f").arg("./tests/template_error.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("./tests/test_template.tmpl"))
+        .stderr(predicate::str::contains("panicked").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_schema_gate() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check").arg("-f").arg("./tests/schema.toml");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("hosts").not())
+        .stderr(predicate::str::contains("failed validation"))
+        .stderr(predicate::str::contains("at /hosts/0/port"));
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_chain() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check").arg("-f").arg("./tests/decode.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from gzip"));
+
+    Ok(())
+}
+
+#[test]
+fn test_normalize() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file("tests/normalize.db")?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/normalize.toml");
+    cmd.assert().success().stdout(predicate::str::contains("\"port\": 80"));
+
+    // Polled again, the data is the same once normalized
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/normalize.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Only what normalize leaves out changed"));
+
+    rm_file("tests/normalize.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_min_apply_interval() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file("tests/min_apply.db")?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/min_apply.toml");
+    cmd.assert().success().stdout(predicate::str::contains("port: 80"));
+
+    // Changes right after waits for min_apply_interval to be over
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/min_apply.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("left of min_apply_interval"));
+
+    rm_file("tests/min_apply.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_freeze() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file("tests/freeze.db")?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("freeze").arg("-f").arg("./tests/freeze.toml").arg("--reason").arg("release");
+    cmd.assert().success();
+
+    // Frozen, changes are held back
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/freeze.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("changes are frozen since").and(
+            predicate::str::contains("release"),
+        ));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("thaw").arg("-f").arg("./tests/freeze.toml");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/freeze.toml");
+    cmd.assert().success().stdout(predicate::str::contains("port: 80"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("audit").arg("-f").arg("./tests/freeze.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("freeze").and(predicate::str::contains("thaw")));
+
+    // Nor is the cached data applied offline
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("freeze").arg("-f").arg("./tests/freeze.toml").arg("--reason").arg("incident");
+    cmd.assert().success();
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/freeze.toml").arg("--offline");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("incident"));
+
+    rm_file("tests/freeze.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_approval() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file("tests/approval.db")?;
+
+    // Changes are staged, not applied
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/approval.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("changes wait for app_config approve"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("approve").arg("-f").arg("./tests/approval.toml").arg("--version").arg("3");
+    cmd.assert().failure().stderr(predicate::str::contains("version 3 is not the one waiting"));
+
+    // Neither approving the change nor applying it offline gets past a freeze
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/approval.toml").arg("--offline");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("changes wait for app_config approve"));
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("freeze").arg("-f").arg("./tests/approval.toml").arg("--reason").arg("release");
+    cmd.assert().success();
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("approve").arg("-f").arg("./tests/approval.toml");
+    cmd.assert().failure().stderr(predicate::str::contains("changes are frozen since"));
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("thaw").arg("-f").arg("./tests/approval.toml");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("approve").arg("-f").arg("./tests/approval.toml");
+    cmd.assert().success().stdout(predicate::str::contains("port: 80"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("approve").arg("-f").arg("./tests/approval.toml");
+    cmd.assert().failure().stderr(predicate::str::contains("no change waiting for approval"));
+
+    rm_file("tests/approval.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_replay() -> Result<(), Box<dyn std::error::Error>> {
+    for file in &["tests/replay.db", "tests/replay.txt", "tests/replay.ok"] {
+        rm_file(file)?;
+    }
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/replay.toml");
+    cmd.assert().failure();
+
+    // Only the hook that failed runs again, the pre_hook completed
+    std::fs::write("tests/replay.ok", "")?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("replay").arg("-f").arg("./tests/replay.toml");
+    cmd.assert().success();
+    assert_eq!(std::fs::read_to_string("tests/replay.txt")?, "drained\n");
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("replay").arg("-f").arg("./tests/replay.toml");
+    cmd.assert().failure().stderr(predicate::str::contains("no failed run to resume"));
+
+    for file in &["tests/replay.db", "tests/replay.txt", "tests/replay.ok"] {
+        rm_file(file)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_for_each() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check").arg("-f").arg("./tests/for_each.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("{\"name\":\"east\"}\n{\"name\":\"west\"}"));
+
+    Ok(())
+}
+
 #[test]
 fn test_mock_query() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("app_config")?;
 
-    cmd.arg("query").arg("-f").arg("./tests/mock.toml");
+    cmd.arg("query").arg("-f").arg("./tests/mock.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Where am I"));
+
+    Ok(())
+}
+
+#[test]
+fn test_query_format() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/for_each.toml");
+    cmd.arg("--path").arg(".clusters[0].name");
+    cmd.assert().success().stdout(predicate::str::similar("east\n"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/for_each.toml");
+    cmd.arg("--path").arg(".clusters[].name").arg("--format").arg("json");
+    cmd.assert().success().stdout(predicate::str::similar("\"east\"\n\"west\"\n"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/for_each.toml").arg("--format").arg("toml");
+    cmd.assert().success().stdout(predicate::str::similar(
+        "[[clusters]]\nname = \"east\"\n\n[[clusters]]\nname = \"west\"\n",
+    ));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/for_each.toml").arg("--path").arg(".nope");
+    cmd.assert().failure().stderr(predicate::str::contains("no key nope"));
+
+    Ok(())
+}
+
+#[test]
+fn test_query_refresh() -> Result<(), Box<dyn std::error::Error>> {
+    let state_file = "./tests/refresh.db";
+    rm_file(state_file)?;
+
+    // Nothing is cached before the first check
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/refresh.toml");
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("--refresh").arg("-f").arg("./tests/refresh.toml");
+    cmd.assert().success().stdout(predicate::str::similar("Hello from exec"));
+
+    // The hooks get the data polled by query on the next check
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/refresh.toml");
+    cmd.assert().success().stdout(predicate::str::contains("Hello from exec"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("--max-age").arg("10m").arg("-f").arg("./tests/refresh.toml");
+    cmd.assert().failure().stderr(predicate::str::contains("--refresh"));
+
+    rm_file(state_file)?;
+    rm_file(&format!("{}.lock", state_file))?;
+    Ok(())
+}
+
+#[test]
+fn test_export() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("export").arg("-f").arg("./tests/export.toml").arg("--prefix").arg("APP_");
+    cmd.assert().success().stdout(predicate::str::similar(
+        "export APP_DATABASE_HOST='db.local'\n\
+         export APP_DATABASE_PORT='5432'\n\
+         export APP_MOTD='it'\\''s up'\n",
+    ));
+
+    // What is printed is meant to be eval'd
+    let cmd = Command::new("/bin/bash")
+        .arg("-c")
+        .arg("eval \"$(\"$0\" export -f ./tests/export.toml)\" && echo \"$MOTD\"")
+        .arg(assert_cmd::cargo::cargo_bin("app_config"))
+        .output()?;
+    cmd.assert().success().stdout(predicate::str::similar("it's up\n"));
+
+    Ok(())
+}
+
+// // // // // // // // Audit Log // // // // // // // //
+
+#[test]
+fn test_audit_log() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file(&"tests/audit.db")?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/audit.toml");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("audit").arg("-f").arg("./tests/audit.toml").arg("--json");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(r#""event":"poll","target":"mock","status":"ok","detail":"changed""#))
+        .stdout(predicate::str::contains(r#""event":"hook","target":"raw","status":"ok""#));
+
+    rm_file(&"tests/audit.db")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_state_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = "tests/state_dir";
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+
+    // The state file is made up in state_dir, and kept between runs
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/state_dir.toml");
+    cmd.assert().success().stdout(predicate::str::contains("Hello from exec"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/state_dir.toml");
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    let files: Vec<String> = std::fs::read_dir(dir)?
+        .map(|f| f.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|f| f.ends_with(".db"))
+        .collect();
+    assert_eq!(files.len(), 1, "{:?}", files);
+    assert!(files[0].starts_with("state_dir-"), "{:?}", files);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_report() -> Result<(), Box<dyn std::error::Error>> {
+    rm_file("tests/report.db")?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("report").arg("-f").arg("./tests/report.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("report has not run yet"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/report.toml");
+    cmd.assert().success();
+
+    // The output of the command is known by its checksum, that of "hi\n"
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("report").arg("-f").arg("./tests/report.toml").arg("--json");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(r#""status":"ok","poll":"changed""#))
+        .stdout(predicate::str::contains(
+            r#""kind":"command","status":"ok","duration_ms":"#,
+        ))
+        .stdout(predicate::str::contains(
+            "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4",
+        ));
+
+    rm_file("tests/report.db")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_previous_data() -> Result<(), Box<dyn std::error::Error>> {
+    let config = "./tests/previous_tmp.toml";
+    rm_file("tests/previous.db")?;
+    let write_config = |port: u16| {
+        let toml = format!(
+            "[providers.mock]\ndata = \"port: {}\"\n\n\
+             [hooks.template]\nfile = \"./tests/previous.tmpl\"\n\
+             source_type = \"yaml\"\nprevious = true\n\n\
+             [settings]\nstate_file = \"./tests/previous.db\"\n",
+            port
+        );
+        std::fs::write(config, toml)
+    };
+
+    write_config(80)?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().success().stdout(predicate::str::similar("80\n"));
+
+    write_config(8080)?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().success().stdout(predicate::str::similar("80 -> 8080\n"));
+
+    rm_file(config)?;
+    rm_file("tests/previous.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_cleanup() -> Result<(), Box<dyn std::error::Error>> {
+    let config = "./tests/cleanup_tmp.toml";
+    let (old, new) = ("tests/cleanup_old.txt", "tests/cleanup_new.txt");
+    rm_file("tests/cleanup.db")?;
+    let write_config = |outfile: &str| {
+        let toml = format!(
+            "[providers.mock]\ndata = \"port: 80\"\n\n\
+             [hooks.file]\noutfile = \"./{}\"\n\n\
+             [settings]\nstate_file = \"./tests/cleanup.db\"\n",
+            outfile
+        );
+        std::fs::write(config, toml)
+    };
+
+    write_config(old)?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().success();
+
+    // The output is renamed, the old file is left behind
+    write_config(new)?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().success();
+    assert!(std::path::Path::new(old).exists());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("cleanup").arg("--dry-run").arg("-f").arg(config);
+    cmd.assert().success().stdout(predicate::str::ends_with("cleanup_old.txt\n"));
+    assert!(std::path::Path::new(old).exists());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("cleanup").arg("-f").arg(config);
+    cmd.assert().success();
+    assert!(!std::path::Path::new(old).exists());
+    assert!(std::path::Path::new(new).exists());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("cleanup").arg("--dry-run").arg("-f").arg(config);
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    rm_file(config)?;
+    rm_file(new)?;
+    rm_file("tests/cleanup.db")?;
+    Ok(())
+}
+
+#[test]
+fn test_daemon() -> Result<(), Box<dyn std::error::Error>> {
+    let config = "./tests/daemon_tmp.toml";
+    let outfile = "./tests/daemon_output.txt";
+    rm_file(outfile)?;
+    let write_config = |enabled: bool, data: &str| {
+        let toml = format!(
+            "[providers.mock]\ndata = \"{}\"\n\n\
+             [hooks.file]\noutfile = \"{}\"\n\n\
+             [settings]\ninterval = \"100ms\"\nenabled = {}\n",
+            data, outfile, enabled
+        );
+        std::fs::write(config, toml)
+    };
+    let wait = |ms| std::thread::sleep(std::time::Duration::from_millis(ms));
+    write_config(false, "Where am I")?;
+
+    let mut daemon = Command::cargo_bin("app_config")?
+        .arg("daemon")
+        .arg("-f")
+        .arg(config)
+        .arg("-f")
+        .arg("./tests/daemon_fail.toml")
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    wait(1000);
+    assert!(!std::path::Path::new(outfile).exists());
+
+    // Enabled on SIGHUP, while the failing pipeline keeps failing
+    write_config(true, "Where am I")?;
+    Command::new("kill").arg("-HUP").arg(daemon.id().to_string()).assert().success();
+    wait(1000);
+    assert_eq!(std::fs::read_to_string(outfile)?, "Where am I");
+
+    // A broken edit leaves the pipeline running as it was
+    std::fs::write(config, "[providers.mock]\ndata = \"Here I am\"\n\n[hooks.file]\n")?;
+    wait(2000);
+    assert_eq!(std::fs::read_to_string(outfile)?, "Where am I");
+
+    // A valid one is picked up once the file changes
+    write_config(true, "Here I am")?;
+    wait(2000);
+    assert_eq!(std::fs::read_to_string(outfile)?, "Here I am");
+
+    daemon.kill()?;
+    let output = daemon.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Pipeline ./tests/daemon_tmp.toml enabled, every 100ms"), "{}", stderr);
+    assert!(stderr.contains("pipeline ./tests/daemon_fail.toml failed"), "{}", stderr);
+    assert!(stderr.contains("keeping the config ./tests/daemon_tmp.toml had"), "{}", stderr);
+    assert!(stderr.contains("Reloaded ./tests/daemon_tmp.toml"), "{}", stderr);
+
+    rm_file(config)?;
+    rm_file(outfile)?;
+    Ok(())
+}
+
+#[test]
+fn test_daemon_trigger() -> Result<(), Box<dyn std::error::Error>> {
+    let outfile = "./tests/trigger_output.txt";
+    rm_file(outfile)?;
+    let wait = |ms| std::thread::sleep(std::time::Duration::from_millis(ms));
+
+    let mut daemon = Command::cargo_bin("app_config")?
+        .arg("daemon")
+        .arg("-f")
+        .arg("./tests/trigger.toml")
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    wait(1000);
+    assert_eq!(std::fs::read_to_string(outfile)?.lines().count(), 1);
+
+    // Checked again long before the hour is up
+    Command::new("kill").arg("-USR1").arg(daemon.id().to_string()).assert().success();
+    wait(1000);
+    assert_eq!(std::fs::read_to_string(outfile)?.lines().count(), 2);
+
+    daemon.kill()?;
+    let output = daemon.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Checking every pipeline now"), "{}", stderr);
+
+    rm_file(outfile)?;
+    Ok(())
+}
+
+#[test]
+fn test_validate() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("validate").arg("-f").arg("./tests/mock.toml");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("./tests/mock.toml is valid"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("validate")
+        .arg("-f")
+        .arg("./tests/mock.toml")
+        .arg("-f")
+        .arg("./tests/missing_field.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Could not parse"));
+
+    Ok(())
+}
+
+#[test]
+fn test_lint() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("lint").arg("-f").arg("./tests/mock.toml");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("./tests/mock.toml looks good"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("lint").arg("-f").arg("./tests/template_raw_stdout.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("hooks.template, hooks.raw all write to stdout"))
+        .stderr(predicate::str::contains("1 warnings"));
+
+    Ok(())
+}
+
+#[test]
+fn test_golden_files() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("test").arg("--cases").arg("./tests/golden");
+    cmd.arg("-f").arg("./tests/template_hook.toml");
+    cmd.assert().success().stderr(predicate::str::contains("tests/golden/hosts: ok"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("test").arg("--cases").arg("./tests/golden_changed");
+    cmd.arg("-f").arg("./tests/template_hook.toml");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("-EndPoint = host3\n+EndPoint = host2"))
+        .stderr(predicate::str::contains("rendered.txt differs from its golden file"))
+        .stderr(predicate::str::contains("1 of 1 cases failed"));
+
+    Ok(())
+}
+
+#[test]
+fn test_bench() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("bench").arg("--iterations").arg("3").arg("-f").arg("./tests/template_hook.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_match(r"\npoll +3 ")?)
+        .stdout(predicate::str::is_match(r"\nrender template +3 ")?);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "aws")]
+fn test_strict() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("--strict").arg("-f").arg("./tests/appconfig_mem.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("appconfig_mem has no state_file"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(not(feature = "aws"))]
+fn test_missing_feature() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/appconfig_mem.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the appconfig provider needs app_config built with the aws feature",
+        ));
+
+    Ok(())
+}
+
+// // // // // // // Exec Provider // // // // // // //
+
+#[test]
+fn test_quiet() -> Result<(), Box<dyn std::error::Error>> {
+    // Only the data goes to stdout, what hooks print goes to stderr
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/quiet.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar("Where am I\n"))
+        .stderr(predicate::str::similar("Hello from a hook\n"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("--quiet").arg("check").arg("-f").arg("./tests/quiet.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar("Where am I\n"))
+        .stderr(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_payload_size() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/payload_size.toml");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("data is 10 bytes, more than max_payload_size 8"));
+
+    Ok(())
+}
+
+#[test]
+fn test_select_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/select.toml").arg("--skip").arg("print");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::similar("Hello from a hook\n"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/select.toml").arg("--only").arg("print");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar("Where am I\n"))
+        .stderr(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/select.toml").arg("--only").arg("nope");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Warning, select has no hook named nope"));
+
+    Ok(())
+}
+
+#[test]
+fn test_interactive() -> Result<(), Box<dyn std::error::Error>> {
+    let outfile = "./tests/interactive.txt";
+    std::fs::write(outfile, "port = 80\n")?;
+
+    // Declining the write leaves the file as it was
+    let mut cmd = assert_cmd::Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/interactive.toml").arg("--interactive");
+    cmd.write_stdin("n\ny\n");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("-port = 80\n+port = 8080\n"))
+        .stderr(predicate::str::contains("Write ./tests/interactive.txt? [y/N] Skipped"))
+        .stderr(predicate::str::contains("Run echo Hello from a hook? [y/N] Hello from a hook"));
+    assert_eq!(std::fs::read_to_string(outfile)?, "port = 80\n");
+
+    let mut cmd = assert_cmd::Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/interactive.toml").arg("--interactive");
+    cmd.write_stdin("y\n");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Run echo Hello from a hook? [y/N] Skipped"));
+    assert_eq!(std::fs::read_to_string(outfile)?, "port = 8080\n");
+
+    rm_file(outfile)?;
+    Ok(())
+}
+
+#[test]
+fn test_wait_for_initial() -> Result<(), Box<dyn std::error::Error>> {
+    let ready = "./tests/wait_ready";
+    rm_file(ready)?;
+
+    // The hook fails until the file is there
+    let child = Command::cargo_bin("app_config")?
+        .arg("check")
+        .arg("-f")
+        .arg("./tests/wait.toml")
+        .arg("--wait-for-initial")
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    std::fs::write(ready, "")?;
+
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("is not applied yet, trying again in 1s"), "{}", stderr);
+
+    rm_file(ready)?;
+    Ok(())
+}
+
+#[test]
+fn test_shared_state() -> Result<(), Box<dyn std::error::Error>> {
+    // The hook fails if another run of it has not ended, as both an init
+    // container and a sidecar on the same state volume would
+    let spawn = |args: &[&str]| {
+        Command::cargo_bin("app_config")
+            .unwrap()
+            .args(args)
+            .arg("-f")
+            .arg("./tests/shared.toml")
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+    };
+    let init = spawn(&["check", "--wait-for-initial"])?;
+    let sidecar = spawn(&["check"])?;
+
+    for child in vec![init, sidecar] {
+        let output = child.wait_with_output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", stderr);
+        assert!(!stderr.contains("not applied yet"), "{}", stderr);
+    }
+
+    let files = [
+        "shared.db",
+        "shared.db-wal",
+        "shared.db-shm",
+        "shared.db.lock",
+        "shared.db.shared.lock",
+    ];
+    for file in &files {
+        rm_file(&format!("./tests/{}", file))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_exec_check() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+
+    cmd.arg("check").arg("-f").arg("./tests/exec.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec"));
+
+    Ok(())
+}
+
+#[test]
+fn test_allow_stale() -> Result<(), Box<dyn std::error::Error>> {
+    let state_file = "./tests/allow_stale.db";
+    rm_file(state_file)?;
+
+    // With nothing cached, there is nothing to fall back on
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/allow_stale.toml");
+    cmd.env("EXEC_PLUGIN_FAIL", "1");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("The provider was never reached"));
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/allow_stale.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec"));
+
+    // The cached data is applied again while the plugin fails
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/allow_stale.toml");
+    cmd.env("EXEC_PLUGIN_FAIL", "1");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec"))
+        .stderr(predicate::str::contains("Warning, using cached data from"))
+        .stderr(predicate::str::contains("Upstream unreachable"));
+
+    rm_file(state_file)?;
+    Ok(())
+}
+
+#[test]
+fn test_bootstrap() -> Result<(), Box<dyn std::error::Error>> {
+    let state_file = "./tests/bootstrap.db";
+    rm_file(state_file)?;
+
+    // The first data received is cached, but not applied
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/bootstrap.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec").not());
+
+    // Unchanged data is only applied with --bootstrap
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/bootstrap.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec").not());
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("--bootstrap").arg("-f").arg("./tests/bootstrap.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Hello from exec"));
+
+    rm_file(state_file)?;
+    Ok(())
+}
+
+#[test]
+fn test_offline() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("--offline").arg("-f").arg("./tests/mock.toml");
     cmd.assert()
         .success()
         .stdout(predicate::str::contains("Where am I"));
 
+    // The exec provider has nothing cached without a state file
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("--offline").arg("-f").arg("./tests/exec.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("There is no cached data to apply"));
+
     Ok(())
 }
 
@@ -122,6 +1045,30 @@ fn test_file_hook() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn test_binary_file_hook() -> Result<(), Box<dyn std::error::Error>> {
+    let outfile = "./tests/binary_output.bin";
+    rm_file(&outfile)?;
+
+    // The mock data is base64, the file hook gets the decoded bytes
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/binary_file_hook.toml");
+    cmd.assert().success();
+
+    let res = std::fs::read(&outfile)?;
+    assert_eq!(res, vec![0x1f, 0x8b, 0x00, 0xff, 0x0a]);
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("query").arg("-f").arg("./tests/binary_file_hook.toml");
+    let out = cmd.output()?;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, vec![0x1f, 0x8b, 0x00, 0xff, 0x0a]);
+
+    rm_file(&outfile)?;
+
+    Ok(())
+}
+
 
 // // // // // // Template Hook // // // // // // 
 
@@ -223,7 +1170,8 @@ fn test_garbage_cmd() -> Result<(), Box<dyn std::error::Error>> {
     cmd.arg("check")
         .arg("-f")
         .arg("./tests/command_garbage.toml");
-    cmd.assert().failure().stderr(predicate::str::contains(
+    // A failing hook exits with EX_SOFTWARE
+    cmd.assert().code(70).stderr(predicate::str::contains(
         "Failed to execute cmd: /not/a/command",
     ));
 
@@ -257,3 +1205,207 @@ fn test_piped_cmd() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_on_error_cmd() -> Result<(), Box<dyn std::error::Error>> {
+    let outfile = &"./tests/on_error.txt";
+
+    // Ensure outfile is removed prior to our test
+    rm_file(outfile)?;
+
+    // The command hook fails, which should trigger the on_error hook
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/on_error.toml");
+    cmd.assert().failure();
+
+    // Test output is as expected
+    let cmd = Command::new("/bin/bash")
+        .arg("-c")
+        .arg("cat ./tests/on_error.txt")
+        .output()
+        .expect("failed to cat ./tests/on_error.txt");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Failed to execute cmd: /not/a/command"));
+
+    // Ensure outfile is removed post our test
+    rm_file(outfile)?;
+    rm_file("./tests/on_error.db")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_on_error_resolve() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let marker = "./tests/on_error_resolve.ok";
+    let db = "./tests/on_error_resolve.db";
+    let config = "./tests/on_error_resolve.toml";
+    rm_file(marker)?;
+    rm_file(db)?;
+
+    // Stands in for Opsgenie, passing on the request line of each request
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let (sender, requests) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                let header = header.to_lowercase();
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let reply = "HTTP/1.1 202 Accepted\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}";
+            stream.write_all(reply.as_bytes()).unwrap();
+            sender.send(line.trim().to_string()).unwrap();
+        }
+    });
+
+    std::fs::write(
+        config,
+        format!(
+            r#"
+[settings]
+state_file = "{}"
+
+[providers.mock]
+data = "Where am I"
+
+[hooks.command]
+command = "test -f {}"
+
+[on_error.opsgenie]
+api_key = "abc"
+alias = "app_config"
+api_url = "http://127.0.0.1:{}"
+"#,
+            db, marker, port
+        ),
+    )?;
+
+    // The command hook fails, which should open an alert
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().failure();
+    let timeout = std::time::Duration::from_secs(10);
+    assert_eq!(requests.recv_timeout(timeout)?, "POST /v2/alerts HTTP/1.1");
+
+    // And once it succeeds the alert should be closed
+    std::fs::write(marker, "")?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg(config);
+    cmd.assert().success();
+    assert_eq!(
+        requests.recv_timeout(timeout)?,
+        "POST /v2/alerts/app_config/close?identifierType=alias HTTP/1.1"
+    );
+
+    rm_file(marker)?;
+    rm_file(db)?;
+    rm_file(config)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_on_error_no_state_file() -> Result<(), Box<dyn std::error::Error>> {
+    // Without a state file failures can not be counted across runs
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/on_error_no_state.toml");
+    cmd.assert()
+        .code(78)
+        .stderr(predicate::str::contains("on_error hooks require a settings.state_file"));
+
+    Ok(())
+}
+
+#[test]
+fn test_pre_post_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    let outfile = &"./tests/pre_post_hooks.txt";
+    rm_file(outfile)?;
+
+    // The command hook fails, the post hook runs all the same
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/pre_post_hooks.toml");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to execute cmd: /not/a/command"));
+
+    let out = std::fs::read_to_string(outfile)?;
+    assert_eq!(out, "drained\nrestored\n");
+
+    rm_file(outfile)?;
+    Ok(())
+}
+
+#[test]
+fn test_cost_guard() -> Result<(), Box<dyn std::error::Error>> {
+    let state_file = "./tests/cost_guard.db";
+    rm_file(state_file)?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/cost_guard.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Where am I"));
+
+    // Polling again within min_poll_interval is refused
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/cost_guard.toml");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Where am I").not())
+        .stderr(predicate::str::contains("min_poll_interval is 3600s"));
+
+    rm_file(state_file)?;
+    Ok(())
+}
+
+#[test]
+fn test_drift() -> Result<(), Box<dyn std::error::Error>> {
+    let state_file = "./tests/drift.db";
+    let outfile = "./tests/drift_output.txt";
+    rm_file(state_file)?;
+    rm_file(outfile)?;
+
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/drift.toml");
+    cmd.assert().success();
+    assert!(std::fs::read_to_string("./tests/drift_output.txt.sha256")?
+        .ends_with("  drift_output.txt\n"));
+
+    // The data is unchanged, but the file was edited by hand
+    std::fs::write(outfile, "Hello from a human")?;
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/drift.toml");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("rendering again: ./tests/drift_output.txt"));
+    assert!(std::fs::read_to_string(outfile)?.contains("Hello from exec"));
+
+    // Which leaves nothing to do on the next run
+    let mut cmd = Command::cargo_bin("app_config")?;
+    cmd.arg("check").arg("-f").arg("./tests/drift.toml");
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("rendering again").not());
+
+    rm_file(state_file)?;
+    rm_file(outfile)?;
+    rm_file("./tests/drift_output.txt.sha256")?;
+    Ok(())
+}
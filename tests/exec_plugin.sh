@@ -0,0 +1,10 @@
+#!/bin/bash
+# Minimal provider plugin used by the tests, see src/providers/exec.rs
+# Reads the request from stdin and always returns the same data and version,
+# unless EXEC_PLUGIN_FAIL is set, then it fails like an unreachable source.
+cat > /dev/null
+if [ -n "$EXEC_PLUGIN_FAIL" ]; then
+    echo "Upstream unreachable" >&2
+    exit 1
+fi
+echo '{"protocol_version": 1, "data": "Hello from exec", "version": "1"}'